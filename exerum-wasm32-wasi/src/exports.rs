@@ -10,12 +10,22 @@ use runtime::runtime::JsRuntime;
 use runtime::loader::ExerumLoader;
 use runtime::cache::Memory;
 use runtime::resolver::ExerumResolver;
-use runtime::rquickjs::{Value, Function, Module};
+use runtime::bundle::Bundler;
+use runtime::bundle_loader;
+use runtime::rquickjs::{Value, Module};
 use transpilers::{register, AssetTranspiler, TKey};
-#[cfg(features = "ts")]
+#[cfg(feature = "ts")]
 use transpiler_typescript::TypescriptTranspiler;
-#[cfg(features = "jsx")]
+#[cfg(feature = "jsx")]
 use transpiler_jsx::JsxTranspiler;
+#[cfg(feature = "css")]
+use transpiler_css::CssTranspiler;
+#[cfg(feature = "yaml")]
+use transpiler_yaml::YamlTranspiler;
+#[cfg(feature = "toml")]
+use transpiler_toml::TomlTranspiler;
+#[cfg(feature = "wasm")]
+use transpiler_wasm::WasmTranspiler;
 use transpilers::Transpilers;
 use transpiler_js::JsTranspiler;
 
@@ -23,6 +33,42 @@ use transpiler_js::JsTranspiler;
 pub(crate) const WASM_MEMORY_BUFFER_SIZE: usize = 48 * 1024 * 1024;
 pub(crate) static mut WASM_MEMORY_BUFFER: [u8; WASM_MEMORY_BUFFER_SIZE] = [0; WASM_MEMORY_BUFFER_SIZE];
 
+/// Returned instead of a length by the exports writing their result to the parameter buffer
+/// when they fail. No length reaches it, an empty result has the length zero.
+pub const ERROR_LEN: u32 = u32::MAX;
+
+/// The part of the parameter buffer the host filled, `None` if it's out of the buffer
+fn parameter(start: usize, len: usize) -> Option<&'static [u8]> {
+    let end = start.checked_add(len).filter(|end| *end <= WASM_MEMORY_BUFFER_SIZE)?;
+    Some(unsafe { &WASM_MEMORY_BUFFER[start..end] })
+}
+
+/// Utf-8 string the host copied into the parameter buffer
+fn parameter_string(start: usize, len: usize) -> Result<String, String> {
+    let bytes = parameter(start, len).ok_or_else(|| {
+        format!("{} bytes at {} are out of the parameter buffer of {} bytes", len, start, WASM_MEMORY_BUFFER_SIZE)
+    })?;
+    String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+}
+
+/// Copies the result to the parameter buffer and returns its length, `ERROR_LEN` if it doesn't fit
+fn write_result(what: &str, result: &[u8]) -> u32 {
+    if result.len() > WASM_MEMORY_BUFFER_SIZE {
+        println!("Error {}: the result doesn't fit into the parameter buffer", what);
+        return ERROR_LEN;
+    }
+    unsafe {
+        WASM_MEMORY_BUFFER[0..result.len()].copy_from_slice(result);
+    }
+    result.len() as u32
+}
+
+/// The runtime behind a pointer/handle returned by `new_runtime` or `new_bundle_runtime`.
+/// It stays owned by the host until `free_runtime`.
+fn borrow_runtime<'a>(jsrt_ptr: u32) -> &'a JsRuntime {
+    unsafe { &*(jsrt_ptr as *const JsRuntime) }
+}
+
 /// Returns a pointer to the buffer which is used to pass
 /// function paramters and return values that take more space than 32 bits
 #[export_name = "parameter_buffer_ptr"]
@@ -46,23 +92,79 @@ pub extern "C" fn free_async_runtime(async_rt_ptr: u32) {
     unsafe { Box::from_raw(async_rt) };
 }
 
-/// There should be only one single javascript runtime per wasm module.
-/// This function returns a pointer/handle that can be used to call other functions
-/// to run javascript code.
-#[export_name = "new_runtime"]
-pub extern "C" fn new_runtime() -> u32 {
+/// Transpilers enabled by the crate features
+fn transpilers() -> Transpilers {
     let mut transpilers = Transpilers::default();
     #[cfg(feature = "ts")]
     register!(transpilers, "typescript", [.ts, .tsx], TypescriptTranspiler);
     #[cfg(feature = "jsx")]
     register!(transpilers, "javascript_react", [.jsx], JsxTranspiler);
+    #[cfg(feature = "css")]
+    register!(transpilers, "css", [.css], CssTranspiler);
+    #[cfg(feature = "yaml")]
+    register!(transpilers, "yaml", [.yaml, .yml], YamlTranspiler);
+    #[cfg(feature = "toml")]
+    register!(transpilers, "toml", [.toml], TomlTranspiler);
+    #[cfg(feature = "wasm")]
+    register!(transpilers, "wasm", [.wasm], WasmTranspiler);
     register!(transpilers, "javascript", [.js], JsTranspiler);
+    transpilers
+}
+
+/// There should be only one single javascript runtime per wasm module.
+/// This function returns a pointer/handle that can be used to call other functions
+/// to run javascript code.
+#[export_name = "new_runtime"]
+pub extern "C" fn new_runtime() -> u32 {
+    let transpilers = transpilers();
     let resolver = ExerumResolver::new(".");
     let loader = ExerumLoader::new(Box::new(Memory::default()), transpilers);
-    let rt = Box::new(JsRuntime::new(loader, resolver));
+    let mut rt = Box::new(JsRuntime::new(loader, resolver));
+    rt.fail_on_unhandled_rejection(true);
     Box::into_raw(rt) as u32
 }
 
+/// Creates a javascript runtime that loads modules only from a bundle archive,
+/// so it doesn't need filesystem access.
+///
+/// # Arguments
+/// `archive_len` - the length of the archive produced by `bundle` in bytes.
+///
+/// # Returns
+/// A pointer/handle like `new_runtime`. Zero if the archive is invalid or
+/// was compiled by an incompatible build. The runtime owns its executor,
+/// the functions running bundles don't take an async runtime.
+///
+/// # Notes
+/// The archive must be copied to the address pointed by `parameter_buffer_ptr`
+/// return value.
+#[export_name = "new_bundle_runtime"]
+pub extern "C" fn new_bundle_runtime(archive_len: u32) -> u32 {
+    let archive = match parameter(0, archive_len as usize) {
+        Some(archive) => archive,
+        None => {
+            println!("Error loading bundle: the archive length exceeds the parameter buffer");
+            return 0;
+        }
+    };
+    let (loader, resolver) = match bundle_loader::from_archive(archive) {
+        Ok(r) => r,
+        Err(err) => {
+            println!("Error loading bundle: {:?}", err);
+            return 0;
+        }
+    };
+    let mut rt = match JsRuntime::new(loader, resolver).with_executor() {
+        Ok(rt) => rt,
+        Err(err) => {
+            println!("Error loading bundle: {}", err);
+            return 0;
+        }
+    };
+    rt.fail_on_unhandled_rejection(true);
+    Box::into_raw(Box::new(rt)) as u32
+}
+
 /// Frees memory and all other resources taken by the javascript runtime.
 #[export_name = "free_runtime"]
 pub extern "C" fn free_runtime(rt_ptr: u32) {
@@ -81,29 +183,64 @@ pub extern "C" fn free_runtime(rt_ptr: u32) {
 /// return value.
 /// 
 /// # Returns
-/// Zero if no error occured.
+/// Zero if no error occured. A promise rejected without a handler is an error.
 #[export_name = "run"]
 pub extern "C" fn run(async_rt_ptr: u32, jsrt_ptr: u32, len: usize) -> u32 {
     // Init function arguments
+    let s = match parameter_string(0, len) {
+        Ok(s) => s,
+        Err(err) => {
+            println!("Error running code: {}", err);
+            return 1;
+        }
+    };
     let mut jsrt: Box<JsRuntime> = Box::from(jsrt_ptr);
     let async_rt: Box<AsyncRT> = Box::from(async_rt_ptr);
-    let s = unsafe {
-        String::from_utf8(WASM_MEMORY_BUFFER[0..len as usize].to_vec()).unwrap()
-    };
     // Do work
     let context = jsrt.context();
-    async_rt.block_on(async move {
+    let result = async_rt.block_on(async move {
         jsrt.spawn_executor();
         context.with(|ctx| {
             let _v: Value = ctx.eval(s).unwrap();
         });
-        jsrt.rt().idle().await;
+        let idle = jsrt.idle().await;
         // Don't drop JsRuntime
         Box::into_raw(jsrt);
+        idle
     });
     // Don't drop AsyncRT
     Box::into_raw(async_rt);
-    0
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            println!("Error running code: {}", err);
+            1
+        }
+    }
+}
+
+/// Writes the memory usage of the javascript runtime to the parameter buffer as an utf-8
+/// encoded json object, see `runtime::memory::MemoryUsage` for the fields.
+/// The host can recycle runtimes using too much memory.
+///
+/// # Arguments
+/// `rt_ptr` - a pointer or handle returned by the `new_runtime` or `new_bundle_runtime`
+///
+/// # Returns
+/// The length of the json string in bytes. `ERROR_LEN` if it doesn't fit into the parameter buffer.
+#[export_name = "memory_usage"]
+pub extern "C" fn memory_usage(jsrt_ptr: u32) -> u32 {
+    let json = borrow_runtime(jsrt_ptr).memory_usage().to_json();
+    write_result("reading the memory usage", json.as_bytes())
+}
+
+/// Runs the garbage collector of the javascript runtime.
+///
+/// # Arguments
+/// `rt_ptr` - a pointer or handle returned by the `new_runtime` or `new_bundle_runtime`
+#[export_name = "run_gc"]
+pub extern "C" fn run_gc(jsrt_ptr: u32) {
+    borrow_runtime(jsrt_ptr).run_gc();
 }
 
 /// Compiles the javascript code to bytecode and writes it to the location pointed by `parameter_buffer_ptr`
@@ -114,7 +251,7 @@ pub extern "C" fn run(async_rt_ptr: u32, jsrt_ptr: u32, len: usize) -> u32 {
 /// `len` - the length of the utf-8 encoded javascript string in bytes.
 /// 
 /// # Returns
-/// The size of the bytecode in bytes. Zero if error occured.
+/// The size of the bytecode in bytes. `ERROR_LEN` if error occured.
 /// 
 /// # Notes
 /// The source code string must be copied to the address pointed by `parameter_buffer_ptr`
@@ -122,30 +259,67 @@ pub extern "C" fn run(async_rt_ptr: u32, jsrt_ptr: u32, len: usize) -> u32 {
 #[export_name = "compile_module"]
 pub extern "C" fn compile_module(async_rt_ptr: u32, jsrt_ptr: u32, source_len: u32) -> u32 {
     // Init function arguments
+    let module_source = match parameter_string(0, source_len as usize) {
+        Ok(source) => source,
+        Err(err) => {
+            println!("Error compiling module: {}", err);
+            return ERROR_LEN;
+        }
+    };
     let mut jsrt: Box<JsRuntime> = Box::from(jsrt_ptr);
     let async_rt: Box<AsyncRT> = Box::from(async_rt_ptr);
-    let module_source = unsafe {
-        String::from_utf8(WASM_MEMORY_BUFFER[0..source_len as usize].to_vec()).unwrap()
-    };
     // Do work
     let context = jsrt.context();
     let buffer = async_rt.block_on(async move {
         jsrt.spawn_executor();
         let res = context.with(|ctx| {
-            let module = Module::new(ctx, "mod1", module_source.as_bytes()).unwrap();
-            let buffer = module.write_object(false).unwrap();
-            buffer
+            let module = Module::new(ctx, "mod1", module_source.as_bytes())?;
+            module.write_object(false)
         });
         jsrt.rt().idle().await;
         Box::into_raw(jsrt);
         res
     });
     Box::into_raw(async_rt);
-    // Write output value
-    unsafe {
-        WASM_MEMORY_BUFFER[0..buffer.len()].copy_from_slice(buffer.as_slice());
+    match buffer {
+        Ok(buffer) => write_result("compiling module", &buffer),
+        Err(err) => {
+            println!("Error compiling module: {}", err);
+            ERROR_LEN
+        }
+    }
+}
+
+/// Bundles a module and its imports into an archive of precompiled bytecode
+/// and writes it to the location pointed by `parameter_buffer_ptr` return value.
+///
+/// # Arguments
+/// `entry_len` - the length of the utf-8 encoded path of the entry module in bytes.
+///
+/// # Returns
+/// The size of the archive in bytes. `ERROR_LEN` if error occured.
+///
+/// # Notes
+/// The entry path must be copied to the address pointed by `parameter_buffer_ptr`
+/// return value.
+#[export_name = "bundle"]
+pub extern "C" fn bundle(entry_len: u32) -> u32 {
+    let entry = match parameter_string(0, entry_len as usize) {
+        Ok(entry) => entry,
+        Err(err) => {
+            println!("Error bundling: {}", err);
+            return ERROR_LEN;
+        }
     };
-    buffer.len() as u32
+    let mut bundler = Bundler::new(ExerumResolver::new("."), transpilers());
+    let archive = match bundler.bundle(&entry) {
+        Ok(bundle) => bundle.to_bytes(),
+        Err(err) => {
+            println!("Error bundling {}: {:?}", entry, err);
+            return ERROR_LEN;
+        }
+    };
+    write_result(&format!("bundling {}", entry), &archive)
 }
 
 /// Evaluates javascript string as a named module for later use.
@@ -157,31 +331,90 @@ pub extern "C" fn compile_module(async_rt_ptr: u32, jsrt_ptr: u32, source_len: u
 /// `source_len` the length of the source code in bytes.
 /// 
 /// # Returns
-/// Zero if no error occured
+/// Zero if no error occured. A promise rejected without a handler is an error.
 #[export_name = "eval_module"]
 pub extern "C" fn eval_module(async_rt_ptr: u32, jsrt_ptr: u32, name_len: u32, source_len: u32) -> u32 {
     // Init function arguments
+    let (name_len, source_len) = (name_len as usize, source_len as usize);
+    let parameters = parameter_string(0, name_len).and_then(|name| Ok((name, parameter_string(name_len, source_len)?)));
+    let (module_name, module_source) = match parameters {
+        Ok(parameters) => parameters,
+        Err(err) => {
+            println!("Error evaluating module: {}", err);
+            return 1;
+        }
+    };
     let mut jsrt: Box<JsRuntime> = Box::from(jsrt_ptr);
     let async_rt: Box<AsyncRT> = Box::from(async_rt_ptr);
-    let module_name = unsafe {
-        String::from_utf8(WASM_MEMORY_BUFFER[0..name_len as usize].to_vec()).unwrap()
-    };
-    let module_source = unsafe {
-        String::from_utf8(WASM_MEMORY_BUFFER[(name_len as usize)..(name_len as usize + source_len as usize)].to_vec()).unwrap()
-    };
     // Do work
     let context = jsrt.context();
-    async_rt.block_on(async move {
+    let result = async_rt.block_on(async move {
         jsrt.spawn_executor();
         context.with(|ctx| {
             let module = Module::new(ctx, module_name, module_source.as_bytes()).unwrap();
             module.eval().unwrap();
         });
-        jsrt.rt().idle().await;
+        let idle = jsrt.idle().await;
         Box::into_raw(jsrt);
+        idle
     });
     Box::into_raw(async_rt);
-    0
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            println!("Error evaluating module: {}", err);
+            1
+        }
+    }
+}
+
+/// Runs a function exported by a bundled module.
+/// The parameter buffer should contain the name of the module, the name of the
+/// function and the json argument encoded as utf-8 strings.
+/// # Arguments
+/// `rt_ptr` - a pointer or handle returned by the `new_bundle_runtime`
+/// `module_len`, `name_len`, `json_len` - the lengths of the parts in bytes.
+///
+/// # Returns
+/// The length of the utf-8 encoded string returned by the function, or by the promise
+/// it returns, written to the parameter buffer. `ERROR_LEN` if error occured.
+#[export_name = "run_bundle_function"]
+pub extern "C" fn run_bundle_function(jsrt_ptr: u32, module_len: u32, name_len: u32, json_len: u32) -> u32 {
+    // Init function arguments
+    let (module_len, name_len, json_len) = (module_len as usize, name_len as usize, json_len as usize);
+    let parameters = (|| -> Result<_, String> {
+        let module_name = parameter_string(0, module_len)?;
+        let name = parameter_string(module_len, name_len)?;
+        let json = parameter_string(module_len.saturating_add(name_len), json_len)?;
+        Ok((module_name, name, json))
+    })();
+    let (module_name, name, json) = match parameters {
+        Ok(parameters) => parameters,
+        Err(err) => {
+            println!("Error running bundle function: {}", err);
+            return ERROR_LEN;
+        }
+    };
+    let jsrt = borrow_runtime(jsrt_ptr);
+    // Do work
+    let result = jsrt.block_on(async {
+        // The bundle resolver lets any module import bundled modules by name. The module
+        // is evaluated by the first call, later calls reuse its namespace.
+        let result = match jsrt.import(&module_name) {
+            Ok(module) => module.call_export::<_, Option<String>>(&name, (json,)).await,
+            Err(err) => Err(err),
+        };
+        let idle = jsrt.idle().await;
+        result.and_then(|r| idle.map(|_| r.unwrap_or_default()))
+    });
+    let result = match result {
+        Ok(r) => r,
+        Err(err) => {
+            println!("Error running bundle function: {}", err);
+            return ERROR_LEN;
+        }
+    };
+    write_result("running bundle function", result.as_bytes())
 }
 
 /// Evaluates javascript bytecode or string as a module and
@@ -193,30 +426,30 @@ pub extern "C" fn eval_module(async_rt_ptr: u32, jsrt_ptr: u32, name_len: u32, s
 /// too Rust specific.
 /// May take bytecode or string as a source code
 /// The return value of the function is written to the parameter buffer
-/// as utf-8 encoded json string.
+/// as utf-8 encoded json string, its length is returned. `ERROR_LEN` if error occured.
 #[export_name = "run_module_function"]
 pub extern "C" fn run_module_function(async_rt_ptr: u32, arguments_len: u32) -> u32 {
     // Init function arguments
-    let mut async_rt: Box<AsyncRT> = Box::from(async_rt_ptr);
-    let data = unsafe {
-        &WASM_MEMORY_BUFFER[0..arguments_len as usize]
+    let parameters = parameter(0, arguments_len as usize)
+        .ok_or_else(|| "the arguments length exceeds the parameter buffer".to_owned())
+        .and_then(|data| bincode::deserialize::<RunModuleFunctionParameters>(data).map_err(|e| e.to_string()));
+    let parameters = match parameters {
+        Ok(parameters) => parameters,
+        Err(err) => {
+            println!("Error running module function: {}", err);
+            return ERROR_LEN;
+        }
     };
-    let parameters: RunModuleFunctionParameters = bincode::deserialize(data).unwrap();
+    let mut async_rt: Box<AsyncRT> = Box::from(async_rt_ptr);
     let mut jsrt: Box<JsRuntime> = Box::from(parameters.rt());
     // Do work
-    let result = match execute_module_function(&mut async_rt, &mut jsrt, parameters) {
-        Ok(r) => r,
-        Err(_) => {
-            return 0;
-        }
-    };
+    let result = execute_module_function(&mut async_rt, &mut jsrt, parameters);
     Box::into_raw(jsrt);
     Box::into_raw(async_rt);
-    // Write output value
-    unsafe {
-        WASM_MEMORY_BUFFER[0..result.as_bytes().len()].copy_from_slice(result.as_bytes());
+    match result {
+        Ok(result) => write_result("running module function", result.as_bytes()),
+        Err(_) => ERROR_LEN,
     }
-    result.as_bytes().len() as u32
 }
 
 pub fn execute_module_function(
@@ -224,37 +457,24 @@ pub fn execute_module_function(
     rt: &mut JsRuntime,
     parameters: RunModuleFunctionParameters,
 ) -> Result<String, ()> {
-    let context = rt.context();
+    let RunModuleFunctionParameters { name, json, code, .. } = parameters;
+    let module = match code {
+        Code::Bytecode(b) => rt.eval_bytecode(&name, &b[..]),
+        Code::Text(s) => rt.eval_module(&name, s),
+    };
+    let module = match module {
+        Ok(m) => m,
+        Err(err) => {
+            println!("Error evaluating module: {}", err);
+            return Err(());
+        }
+    };
     async_rt.block_on(async move {
         rt.spawn_executor();
-        context.with(|ctx| match parameters.code {
-            Code::Bytecode(b) => {
-                let module = Module::read_object(ctx, b).unwrap();
-                let evaluated: Module = match module.eval() {
-                    Ok(m) => m,
-                    Err(err) => {
-                        println!("Error evaluating bytecode: {:?}", err);
-                        return Err(());
-                    }
-                };
-                let f: Function = evaluated.get(&parameters.name).unwrap();
-                std::mem::forget(evaluated);
-                let result: Value = f.call((parameters.json,)).unwrap();
-                Ok(result.as_string().unwrap().to_string().unwrap())
-            }
-            Code::Text(s) => {
-                let evaluated: Module = match Module::new(ctx, parameters.name.as_bytes(), s) {
-                    Ok(m) => m.eval().unwrap(),
-                    Err(err) => {
-                        println!("Error creating module from js: {:?}", err);
-                        return Err(());
-                    }
-                };
-                let f: Function = evaluated.get(&parameters.name).unwrap();
-                std::mem::forget(evaluated);
-                let result: Value = f.call((&parameters.json,)).unwrap();
-                Ok(result.as_string().unwrap().to_string().unwrap())
-            }
-        })
+        let result = module.call_export::<_, String>(&name, (json,)).await;
+        let idle = rt.idle().await;
+        result
+            .and_then(|r| idle.map(|_| r))
+            .map_err(|err| println!("Error running module function: {}", err))
     })
-}
\ No newline at end of file
+}
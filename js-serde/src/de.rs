@@ -0,0 +1,400 @@
+//! `Deserialize` from javascript values.
+use std::str::FromStr;
+use rquickjs::{Array, Ctx, FromJs, Object, TypedArray, Value};
+use serde::de::value::{SeqDeserializer, StringDeserializer};
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Unexpected, Visitor};
+use serde::forward_to_deserialize_any;
+use crate::error::{Error, Result, Segment};
+use crate::{raw, tag, MAX_DEPTH, MAX_SAFE_INTEGER};
+
+pub struct Deserializer<'js> {
+    ctx: Ctx<'js>,
+    value: Value<'js>,
+    /// Levels above the value, cyclic values fail once it exceeds `MAX_DEPTH`
+    depth: usize,
+}
+
+impl<'js> Deserializer<'js> {
+    pub fn new(ctx: Ctx<'js>, value: Value<'js>) -> Result<Self> {
+        Ok(Deserializer { ctx, value, depth: 0 })
+    }
+
+    fn child(&self, value: Value<'js>) -> Result<Self> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::new(format!("the value is nested deeper than {} levels", MAX_DEPTH)));
+        }
+        Ok(Deserializer { ctx: self.ctx, value, depth: self.depth + 1 })
+    }
+
+    /// `typeof`, except for `null`, `"array"` and `"bytes"` for `Uint8Array`
+    fn tag(&self) -> Result<&'static str> {
+        Ok(tag(self.ctx, &self.value)?)
+    }
+
+    fn get<T: FromJs<'js>>(&self) -> Result<T> {
+        Ok(T::from_js(self.ctx, self.value.clone())?)
+    }
+
+    /// Decimal digits of a `BigInt`
+    fn digits(&self) -> Result<String> {
+        Ok(raw::to_string(self.ctx, &self.value)?)
+    }
+
+    fn bytes(&self) -> Result<Vec<u8>> {
+        let bytes: TypedArray<u8> = self.get()?;
+        Ok(AsRef::<[u8]>::as_ref(&bytes).to_vec())
+    }
+
+    /// Integers from numbers without loss, or from `BigInt`s
+    fn integer<T: TryFrom<i64> + FromStr>(&self, tag: &str) -> Result<T> {
+        let name = std::any::type_name::<T>();
+        if tag == "bigint" {
+            let digits = self.digits()?;
+            return digits.parse().map_err(|_| Error::new(format!("{}n is out of range for {}", digits, name)));
+        }
+        let n: f64 = self.get()?;
+        if n.fract() != 0.0 {
+            return Err(Error::new(format!("expected {}, found {}", name, n)));
+        }
+        if n.abs() > MAX_SAFE_INTEGER as f64 {
+            return Err(Error::new(format!("{} is not a safe integer, pass a BigInt for {}", n, name)));
+        }
+        T::try_from(n as i64).map_err(|_| Error::new(format!("{} is out of range for {}", n, name)))
+    }
+}
+
+/// Own enumerable string keys, like `Object.keys`
+fn keys(object: &Object) -> Result<Vec<String>> {
+    Ok(object.keys::<String>().collect::<rquickjs::Result<_>>()?)
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident => $visit:ident,)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+            match self.tag()? {
+                tag @ ("number" | "bigint") => visitor.$visit(self.integer(tag)?),
+                _ => self.deserialize_any(visitor),
+            }
+        }
+    )*};
+}
+
+impl<'de, 'js> de::Deserializer<'de> for Deserializer<'js> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.tag()? {
+            "undefined" | "null" => visitor.visit_unit(),
+            "boolean" => visitor.visit_bool(self.get()?),
+            "number" => {
+                let n: f64 = self.get()?;
+                if n.fract() != 0.0 || n.abs() > MAX_SAFE_INTEGER as f64 {
+                    visitor.visit_f64(n)
+                } else if n < 0.0 {
+                    visitor.visit_i64(n as i64)
+                } else {
+                    visitor.visit_u64(n as u64)
+                }
+            }
+            "bigint" => {
+                let digits = self.digits()?;
+                if let Ok(n) = digits.parse() {
+                    visitor.visit_i64(n)
+                } else if let Ok(n) = digits.parse() {
+                    visitor.visit_u64(n)
+                } else if let Ok(n) = digits.parse() {
+                    visitor.visit_i128(n)
+                } else if let Ok(n) = digits.parse() {
+                    visitor.visit_u128(n)
+                } else {
+                    Err(Error::new(format!("{}n is too large", digits)))
+                }
+            }
+            "string" => visitor.visit_string(self.get()?),
+            "bytes" => visitor.visit_byte_buf(self.bytes()?),
+            "array" => {
+                let array: Array = self.get()?;
+                let length = array.len();
+                visitor.visit_seq(Elements { de: self, array, index: 0, length })
+            }
+            "object" => {
+                let object: Object = self.get()?;
+                let keys = keys(&object)?;
+                visitor.visit_map(Properties { de: self, object, keys: keys.into_iter(), key: None })
+            }
+            tag => Err(de::Error::invalid_type(Unexpected::Other(tag), &visitor)),
+        }
+    }
+
+    deserialize_integer! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.tag()? {
+            "number" => visitor.visit_f64(self.get()?),
+            "bigint" => {
+                let digits = self.digits()?;
+                visitor.visit_f64(digits.parse().map_err(|_| Error::new(format!("{}n is not a number", digits)))?)
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.tag()? {
+            "bytes" => visitor.visit_byte_buf(self.bytes()?),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.tag()? {
+            "undefined" | "null" => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Also takes a `Uint8Array`, for `Vec<u8>`
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.tag()? {
+            "bytes" => {
+                let mut bytes: SeqDeserializer<_, Error> = SeqDeserializer::new(self.bytes()?.into_iter());
+                let value = visitor.visit_seq(&mut bytes)?;
+                bytes.end()?;
+                Ok(value)
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    /// Unit variants from their name, others from `{ [variant]: data }`
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.tag()? {
+            "string" => {
+                let variant: StringDeserializer<Error> = self.get::<String>()?.into_deserializer();
+                visitor.visit_enum(variant)
+            }
+            "object" => {
+                let object: Object = self.get()?;
+                let mut keys = keys(&object)?;
+                if keys.len() != 1 {
+                    return Err(Error::new(format!(
+                        "expected an object with a single key for enum {}, found {} keys",
+                        name,
+                        keys.len()
+                    )));
+                }
+                let variant = keys.remove(0);
+                let data = self.child(object.get(variant.as_str())?);
+                let data = data.map_err(|e| e.at(Segment::Key(variant.clone())))?;
+                visitor.visit_enum(Enum { variant, data })
+            }
+            tag => Err(de::Error::invalid_type(Unexpected::Other(tag), &"a string or an object with a single key")),
+        }
+    }
+
+    /// Skips the value without reading it
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool char str string unit unit_struct map struct identifier
+    }
+}
+
+struct Elements<'js> {
+    de: Deserializer<'js>,
+    array: Array<'js>,
+    index: usize,
+    length: usize,
+}
+
+impl<'de, 'js> de::SeqAccess<'de> for Elements<'js> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.index >= self.length {
+            return Ok(None);
+        }
+        let index = self.index;
+        self.index += 1;
+        let element = self.de.child(self.array.get(index as u32)?);
+        element.and_then(|element| seed.deserialize(element)).map(Some).map_err(|e| e.at(Segment::Index(index)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.length - self.index)
+    }
+}
+
+struct Properties<'js> {
+    de: Deserializer<'js>,
+    object: Object<'js>,
+    keys: std::vec::IntoIter<String>,
+    /// Key of the property whose value comes next
+    key: Option<String>,
+}
+
+impl<'de, 'js> de::MapAccess<'de> for Properties<'js> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.keys.next() {
+            Some(key) => {
+                self.key = Some(key.clone());
+                seed.deserialize(PropertyKey(key)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let key = self.key.take().ok_or_else(|| Error::new("property value read before its key"))?;
+        let value = self.de.child(self.object.get(key.as_str())?);
+        value.and_then(|value| seed.deserialize(value)).map_err(|e| e.at(Segment::Key(key)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.keys.len())
+    }
+}
+
+/// Property names, which are parsed for maps with integer keys
+struct PropertyKey(String);
+
+macro_rules! deserialize_key {
+    ($($method:ident => $visit:ident,)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+            match self.0.parse() {
+                Ok(n) => visitor.$visit(n),
+                Err(_) => self.deserialize_any(visitor),
+            }
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for PropertyKey {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_string(self.0)
+    }
+
+    deserialize_key! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let variant: StringDeserializer<Error> = self.0.into_deserializer();
+        visitor.visit_enum(variant)
+    }
+
+    forward_to_deserialize_any! {
+        char str string bytes byte_buf option unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct Enum<'js> {
+    variant: String,
+    data: Deserializer<'js>,
+}
+
+impl<'de, 'js> de::EnumAccess<'de> for Enum<'js> {
+    type Error = Error;
+    type Variant = Variant<'js>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Variant<'js>)> {
+        let deserializer: StringDeserializer<Error> = self.variant.clone().into_deserializer();
+        let value = seed.deserialize(deserializer)?;
+        Ok((value, Variant { name: self.variant, data: self.data }))
+    }
+}
+
+struct Variant<'js> {
+    name: String,
+    data: Deserializer<'js>,
+}
+
+impl<'de, 'js> de::VariantAccess<'de> for Variant<'js> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.data.tag()? {
+            "undefined" | "null" => Ok(()),
+            tag => Err(Error::new(format!("expected null for a unit variant, found {}", tag)).at(Segment::Key(self.name))),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        let Variant { name, data } = self;
+        seed.deserialize(data).map_err(|e| e.at(Segment::Key(name)))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        let Variant { name, data } = self;
+        de::Deserializer::deserialize_seq(data, visitor).map_err(|e| e.at(Segment::Key(name)))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value> {
+        let Variant { name, data } = self;
+        de::Deserializer::deserialize_map(data, visitor).map_err(|e| e.at(Segment::Key(name)))
+    }
+}
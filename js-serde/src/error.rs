@@ -0,0 +1,105 @@
+use std::fmt;
+
+/// A step from a value to one of its children
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    /// Property of an object, field of a struct or variant of an enum
+    Key(String),
+    /// Element of an array or tuple
+    Index(usize),
+}
+
+/// Conversion error, with the path of the value that failed in the converted value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    path: Vec<Segment>,
+    message: String,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new(message: impl fmt::Display) -> Self {
+        Error { path: vec![], message: message.to_string() }
+    }
+
+    /// Prefixes the path with the child the error happened in
+    pub(crate) fn at(mut self, segment: Segment) -> Self {
+        self.path.insert(0, segment);
+        self
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.path
+    }
+
+    /// The path like `users[0].name`, empty for the converted value itself
+    pub fn path(&self) -> String {
+        let mut path = String::new();
+        for segment in &self.path {
+            match segment {
+                Segment::Key(key) if is_identifier(key) => {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path.push_str(key);
+                }
+                Segment::Key(key) => path.push_str(&format!("[{:?}]", key)),
+                Segment::Index(index) => path.push_str(&format!("[{}]", index)),
+            }
+        }
+        path
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.path(), self.message)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::new(message)
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::new(message)
+    }
+}
+
+impl From<rquickjs::Error> for Error {
+    fn from(e: rquickjs::Error) -> Self {
+        Error::new(e)
+    }
+}
+
+#[test]
+fn test_path() {
+    let e = Error::new("expected u32")
+        .at(Segment::Key("age".to_owned()))
+        .at(Segment::Index(0))
+        .at(Segment::Key("all users".to_owned()))
+        .at(Segment::Key("data".to_owned()));
+    assert_eq!(e.path(), "data[\"all users\"][0].age");
+    assert_eq!(e.to_string(), "data[\"all users\"][0].age: expected u32");
+    assert_eq!(Error::new("expected u32").to_string(), "expected u32");
+}
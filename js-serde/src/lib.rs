@@ -0,0 +1,222 @@
+//! Converts Rust values to and from javascript values with serde.
+//!
+//! ```ignore
+//! let value = js_serde::to_value(ctx, &config)?;
+//! let config: Config = js_serde::from_value(ctx, value)?;
+//! ```
+//! Structs and maps become objects, sequences and tuples arrays, `None` and `()` null.
+//! Integers up to 32 bits are numbers. 64 and 128 bit integers are `BigInt`s by default, so
+//! their type doesn't depend on their value, `Serializer::with_integers` can make them numbers
+//! instead. Numbers and `BigInt`s are read into any integer they fit. Bytes (`serde_bytes`) become a `Uint8Array`, and `Vec<u8>` can be
+//! read from one. Unit variants are their name, other variants `{ [variant]: data }`.
+//!
+//! Errors carry the path of the value that failed, like `users[0].age: invalid type: ...`.
+use rquickjs::{Ctx, FromJs, IntoJs, TypedArray, Value};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::raw::Type;
+
+pub mod de;
+pub mod error;
+pub mod raw;
+pub mod ser;
+
+pub use de::Deserializer;
+pub use error::{Error, Result, Segment};
+pub use ser::{Integers, Serializer};
+
+/// `Number.MAX_SAFE_INTEGER`, larger integers are `BigInt`s
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+/// Deeper values, like cyclic ones, fail instead of overflowing the stack
+const MAX_DEPTH: usize = 512;
+
+/// Converts the Rust value into a javascript value
+pub fn to_value<'js, T: Serialize + ?Sized>(ctx: Ctx<'js>, value: &T) -> Result<Value<'js>> {
+    value.serialize(&Serializer::new(ctx)?)
+}
+
+/// Converts the javascript value into a Rust value
+pub fn from_value<'js, T: DeserializeOwned>(ctx: Ctx<'js>, value: Value<'js>) -> Result<T> {
+    T::deserialize(Deserializer::new(ctx, value)?)
+}
+
+/// Converts with serde where rquickjs expects `IntoJs` or `FromJs`,
+/// like in the arguments and results of `Func`.
+pub struct Serde<T>(pub T);
+
+impl<'js, T: Serialize> IntoJs<'js> for Serde<T> {
+    fn into_js(self, ctx: Ctx<'js>) -> rquickjs::Result<Value<'js>> {
+        to_value(ctx, &self.0)
+            .map_err(|e| rquickjs::Error::new_into_js_message(std::any::type_name::<T>(), "value", e.to_string()))
+    }
+}
+
+impl<'js, T: DeserializeOwned> FromJs<'js> for Serde<T> {
+    fn from_js(ctx: Ctx<'js>, value: Value<'js>) -> rquickjs::Result<Self> {
+        from_value(ctx, value)
+            .map(Serde)
+            .map_err(|e| rquickjs::Error::new_from_js_message("value", std::any::type_name::<T>(), e.to_string()))
+    }
+}
+
+/// `typeof`, except for `"null"`, `"array"` and `"bytes"` for a `Uint8Array`
+fn tag<'js>(ctx: Ctx<'js>, value: &Value<'js>) -> rquickjs::Result<&'static str> {
+    Ok(match raw::type_of(ctx, value)? {
+        Type::Error => "object",
+        Type::Object if raw::is_typed_array(ctx, value)? && TypedArray::<u8>::from_js(ctx, value.clone()).is_ok() => {
+            "bytes"
+        }
+        t => t.name(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use rquickjs::{Context, Func, Runtime, Value};
+    use serde::{Deserialize, Serialize, Serializer};
+    use super::{from_value, to_value, Integers, Serde};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Line(i32, i32),
+        Rect { w: u32, h: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u8,
+        id: u64,
+        balance: i128,
+        nickname: Option<String>,
+        shapes: Vec<Shape>,
+        scores: BTreeMap<u32, f64>,
+        avatar: Vec<u8>,
+    }
+
+    /// Serialized as bytes, like `serde_bytes`
+    struct Bytes(Vec<u8>);
+
+    impl Serialize for Bytes {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+
+    fn user() -> User {
+        User {
+            name: "ada".to_owned(),
+            age: 36,
+            id: u64::MAX,
+            balance: -5,
+            nickname: None,
+            shapes: vec![Shape::Empty, Shape::Circle(1.5), Shape::Line(1, -2), Shape::Rect { w: 3, h: 4 }],
+            scores: [(1, 0.5), (20, 2.0)].into_iter().collect(),
+            avatar: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let rt = Runtime::new().unwrap();
+        let context = Context::full(&rt).unwrap();
+        context.with(|ctx| {
+            let value = to_value(ctx, &user()).unwrap();
+            ctx.globals().set("user", value.clone()).unwrap();
+            let shape: String = ctx
+                .eval(
+                    r#"[
+                    typeof user.id, String(user.id), typeof user.balance, typeof user.age, user.nickname === null,
+                    JSON.stringify(user.shapes), JSON.stringify(user.scores), Array.isArray(user.avatar)
+                ].join("|")"#,
+                )
+                .unwrap();
+            assert_eq!(
+                shape,
+                r#"bigint|18446744073709551615|bigint|number|true|["Empty",{"Circle":1.5},{"Line":[1,-2]},{"Rect":{"w":3,"h":4}}]|{"1":0.5,"20":2}|true"#
+            );
+            assert_eq!(from_value::<User>(ctx, value).unwrap(), user());
+
+            let bytes = to_value(ctx, &Bytes(vec![4, 5])).unwrap();
+            ctx.globals().set("bytes", bytes.clone()).unwrap();
+            assert!(ctx.eval::<bool, _>("bytes instanceof Uint8Array && bytes[1] === 5").unwrap());
+            assert_eq!(from_value::<Vec<u8>>(ctx, bytes).unwrap(), vec![4, 5]);
+
+            let value: Value = ctx.eval("({ id: 7n, n: 2 ** 53 - 1, missing: undefined })").unwrap();
+            let read: BTreeMap<String, Option<u64>> = from_value(ctx, value).unwrap();
+            assert_eq!(read["id"], Some(7));
+            assert_eq!(read["n"], Some((1 << 53) - 1));
+            assert_eq!(read["missing"], None);
+
+            let numbers = super::Serializer::with_integers(ctx, Integers::Number).unwrap();
+            let value = (1u64 << 40, -3i128).serialize(&numbers).unwrap();
+            ctx.globals().set("numbers", value).unwrap();
+            assert!(ctx.eval::<bool, _>("numbers[0] === 2 ** 40 && numbers[1] === -3").unwrap());
+            assert!(u64::MAX.serialize(&numbers).is_err());
+
+            let keys: BTreeMap<&str, u8> = [("__proto__", 1)].into_iter().collect();
+            ctx.globals().set("keys", to_value(ctx, &keys).unwrap()).unwrap();
+            assert!(ctx.eval::<bool, _>("Object.getPrototypeOf(keys) === Object.prototype && keys.__proto__ === 1").unwrap());
+        });
+    }
+
+    #[test]
+    fn test_errors() {
+        let rt = Runtime::new().unwrap();
+        let context = Context::full(&rt).unwrap();
+        context.with(|ctx| {
+            let value: Value = ctx.eval(r#"({ users: [{ name: "ada", age: "old" }] })"#).unwrap();
+            let e = from_value::<BTreeMap<String, Vec<User>>>(ctx, value).unwrap_err();
+            assert_eq!(e.path(), "users[0].age");
+            assert!(e.to_string().starts_with("users[0].age: invalid type: string \"old\""));
+
+            let value: Value = ctx.eval("[1, 300]").unwrap();
+            let e = from_value::<Vec<u8>>(ctx, value).unwrap_err();
+            assert_eq!(e.to_string(), "[1]: 300 is out of range for u8");
+
+            let value: Value = ctx.eval("[2 ** 60, 1.5]").unwrap();
+            assert!(from_value::<Vec<u64>>(ctx, value.clone()).unwrap_err().to_string().contains("BigInt"));
+            assert_eq!(from_value::<Vec<f64>>(ctx, value).unwrap(), vec![2f64.powi(60), 1.5]);
+
+            let value: Value = ctx.eval(r#"[{ Rect: { w: 1, h: -1 } }]"#).unwrap();
+            let e = from_value::<Vec<Shape>>(ctx, value).unwrap_err();
+            assert_eq!(e.path(), "[0].Rect.h");
+
+            #[derive(Debug, Deserialize)]
+            struct Named {
+                name: String,
+            }
+            let value: Value = ctx.eval(r#"({ name: "ada", extra: { deep: [() => {}, Symbol()] } })"#).unwrap();
+            assert_eq!(from_value::<Named>(ctx, value).unwrap().name, "ada");
+
+            #[derive(Debug, Deserialize)]
+            struct Nested(#[allow(dead_code)] Vec<Nested>);
+            let value: Value = ctx.eval("const cyclic = []; cyclic.push(cyclic); cyclic").unwrap();
+            let e = from_value::<Nested>(ctx, value).unwrap_err();
+            assert!(e.to_string().ends_with("the value is nested deeper than 512 levels"), "{}", e);
+
+            let e = to_value(ctx, &[(vec![1], 2)].into_iter().collect::<BTreeMap<_, _>>()).unwrap_err();
+            assert_eq!(e.to_string(), "map keys must be strings or numbers, found array");
+        });
+    }
+
+    #[test]
+    fn test_func() {
+        let rt = Runtime::new().unwrap();
+        let context = Context::full(&rt).unwrap();
+        context.with(|ctx| {
+            let grow = Func::new("grow", |Serde(shape): Serde<Shape>| match shape {
+                Shape::Rect { w, h } => Serde(Shape::Rect { w: w * 2, h: h * 2 }),
+                shape => Serde(shape),
+            });
+            ctx.globals().set("grow", grow).unwrap();
+            let grown: String = ctx.eval(r#"JSON.stringify(grow({ Rect: { w: 1, h: 2 } }))"#).unwrap();
+            assert_eq!(grown, r#"{"Rect":{"w":2,"h":4}}"#);
+            let failed: String = ctx.eval(r#"try { grow({ Square: 1 }) } catch (e) { String(e) }"#).unwrap();
+            assert!(failed.contains("unknown variant `Square`"), "{}", failed);
+        });
+    }
+}
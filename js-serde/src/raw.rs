@@ -0,0 +1,216 @@
+//! Raw QuickJS operations rquickjs doesn't expose, also used by the stdlib.
+//!
+//! Values move between raw calls and rquickjs through a global property that is deleted
+//! again before any javascript runs. The type tests are native, scripts can't change their
+//! results by replacing globals or prototypes.
+use std::ffi::{c_char, CString};
+use rquickjs::{qjs, Ctx, Error, IntoJs, Object, Result, Value};
+
+const SLOT: &str = "__exerum_raw_value";
+
+/// Wraps a raw value, taking over its reference
+///
+/// # Safety
+/// `value` must be a value of the runtime of `ctx` the caller owns a reference to
+pub unsafe fn from_raw<'js>(ctx: Ctx<'js>, value: qjs::JSValue) -> Result<Value<'js>> {
+    let slot = CString::new(SLOT).unwrap();
+    let raw = ctx.as_ptr();
+    let global = qjs::JS_GetGlobalObject(raw);
+    // Defining doesn't call setters of the global object, unlike setting
+    qjs::JS_DefinePropertyValueStr(raw, global, slot.as_ptr(), value, qjs::JS_PROP_CONFIGURABLE as _);
+    qjs::JS_FreeValue(raw, global);
+    let globals = ctx.globals();
+    let value: Value = globals.get(SLOT)?;
+    globals.remove(SLOT)?;
+    Ok(value)
+}
+
+/// Runs `f` with the raw value, which stays owned by `value`
+pub fn with_raw<'js, R>(ctx: Ctx<'js>, value: Value<'js>, f: impl FnOnce(qjs::JSValue) -> R) -> Result<R> {
+    let globals = ctx.globals();
+    globals.set(SLOT, value)?;
+    let slot = CString::new(SLOT).unwrap();
+    let result = unsafe {
+        let raw = ctx.as_ptr();
+        let global = qjs::JS_GetGlobalObject(raw);
+        let value = qjs::JS_GetPropertyStr(raw, global, slot.as_ptr());
+        let result = f(value);
+        qjs::JS_FreeValue(raw, value);
+        qjs::JS_FreeValue(raw, global);
+        result
+    };
+    globals.remove(SLOT)?;
+    Ok(result)
+}
+
+/// A reference to a value for code that only has raw pointers, like the callbacks of QuickJS,
+/// which can't use `Persistent`. It must be dropped while the runtime is alive.
+pub struct Held {
+    rt: *mut qjs::JSRuntime,
+    value: qjs::JSValue,
+}
+
+impl Held {
+    /// Takes another reference to the value
+    ///
+    /// # Safety
+    /// `ctx` must be a live context and `value` one of its values
+    pub unsafe fn new(ctx: *mut qjs::JSContext, value: qjs::JSValue) -> Self {
+        Held { rt: qjs::JS_GetRuntime(ctx), value: qjs::JS_DupValue(ctx, value) }
+    }
+
+    /// Whether `value` is the same object
+    pub fn is(&self, value: qjs::JSValue) -> bool {
+        unsafe { qjs::JS_VALUE_GET_PTR(self.value) == qjs::JS_VALUE_GET_PTR(value) }
+    }
+
+    pub fn restore<'js>(&self, ctx: Ctx<'js>) -> Result<Value<'js>> {
+        unsafe { from_raw(ctx, qjs::JS_DupValue(ctx.as_ptr(), self.value)) }
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        unsafe { qjs::JS_FreeValueRT(self.rt, self.value) }
+    }
+}
+
+/// Type of a value, `Array`, `Error` and `Function` objects are told apart from other objects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Undefined,
+    Null,
+    Bool,
+    Number,
+    BigInt,
+    String,
+    Symbol,
+    Function,
+    Array,
+    Error,
+    Object,
+}
+
+impl Type {
+    /// Lowercase name, like `typeof`
+    pub fn name(self) -> &'static str {
+        match self {
+            Type::Undefined => "undefined",
+            Type::Null => "null",
+            Type::Bool => "boolean",
+            Type::Number => "number",
+            Type::BigInt => "bigint",
+            Type::String => "string",
+            Type::Symbol => "symbol",
+            Type::Function => "function",
+            Type::Array => "array",
+            Type::Error => "error",
+            Type::Object => "object",
+        }
+    }
+}
+
+pub fn type_of<'js>(ctx: Ctx<'js>, value: &Value<'js>) -> Result<Type> {
+    with_raw(ctx, value.clone(), |value| unsafe {
+        let raw = ctx.as_ptr();
+        match qjs::JS_VALUE_GET_NORM_TAG(value) {
+            qjs::JS_TAG_UNDEFINED => Type::Undefined,
+            qjs::JS_TAG_NULL => Type::Null,
+            qjs::JS_TAG_BOOL => Type::Bool,
+            qjs::JS_TAG_INT | qjs::JS_TAG_FLOAT64 => Type::Number,
+            qjs::JS_TAG_BIG_INT => Type::BigInt,
+            qjs::JS_TAG_STRING => Type::String,
+            qjs::JS_TAG_SYMBOL => Type::Symbol,
+            _ if qjs::JS_IsFunction(raw, value) != 0 => Type::Function,
+            // Fails for revoked proxies, which are no arrays
+            _ if qjs::JS_IsArray(raw, value) > 0 => Type::Array,
+            _ if qjs::JS_IsError(raw, value) != 0 => Type::Error,
+            _ => Type::Object,
+        }
+    })
+    .map(|t| {
+        clear_exception(ctx);
+        t
+    })
+}
+
+/// Identity of an object, valid while the object is alive
+pub fn object_id<'js>(ctx: Ctx<'js>, object: &Value<'js>) -> Result<usize> {
+    with_raw(ctx, object.clone(), |object| unsafe { qjs::JS_VALUE_GET_PTR(object) as usize })
+}
+
+/// Whether the value is a typed array
+pub fn is_typed_array<'js>(ctx: Ctx<'js>, value: &Value<'js>) -> Result<bool> {
+    with_raw(ctx, value.clone(), |value| unsafe {
+        let raw = ctx.as_ptr();
+        let (mut offset, mut length, mut element_size) = (0, 0, 0);
+        let buffer = qjs::JS_GetTypedArrayBuffer(raw, value, &mut offset, &mut length, &mut element_size);
+        let typed = qjs::JS_VALUE_GET_NORM_TAG(buffer) != qjs::JS_TAG_EXCEPTION;
+        if typed {
+            qjs::JS_FreeValue(raw, buffer);
+        } else {
+            clear_exception(ctx);
+        }
+        typed
+    })
+}
+
+/// `String(value)` for primitives, without calling `toString` methods of objects
+pub fn to_string<'js>(ctx: Ctx<'js>, value: &Value<'js>) -> Result<String> {
+    if matches!(type_of(ctx, value)?, Type::Function | Type::Array | Type::Error | Type::Object) {
+        return Err(Error::new_from_js("object", "string"));
+    }
+    let string = with_raw(ctx, value.clone(), |value| unsafe {
+        let raw = ctx.as_ptr();
+        let mut len = 0;
+        let chars = qjs::JS_ToCStringLen2(raw, &mut len, value, 0);
+        if chars.is_null() {
+            clear_exception(ctx);
+            return None;
+        }
+        let bytes = std::slice::from_raw_parts(chars as *const u8, len as usize);
+        let string = String::from_utf8_lossy(bytes).into_owned();
+        qjs::JS_FreeCString(raw, chars);
+        Some(string)
+    })?;
+    string.ok_or_else(|| Error::new_from_js("value", "string"))
+}
+
+/// The `BigInt` of an optional minus sign followed by decimal digits
+pub fn bigint<'js>(ctx: Ctx<'js>, digits: &str) -> Result<Value<'js>> {
+    let magnitude = digits.strip_prefix('-').unwrap_or(digits);
+    if magnitude.is_empty() || !magnitude.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::new_into_js_message("string", "bigint", format!("{:?} is not an integer", digits)));
+    }
+    if let Ok(n) = digits.parse::<i64>() {
+        return unsafe { from_raw(ctx, qjs::JS_NewBigInt64(ctx.as_ptr(), n)) };
+    }
+    // A literal is parsed by the engine, globals aren't involved
+    ctx.eval(format!("{}n", digits))
+}
+
+/// Defines an enumerable, writable and configurable property. Unlike setting it, this
+/// doesn't call setters and a `__proto__` key doesn't change the prototype.
+pub fn define_value<'js>(ctx: Ctx<'js>, object: &Object<'js>, key: &str, value: impl IntoJs<'js>) -> Result<()> {
+    let value = value.into_js(ctx)?;
+    // The object is kept alive by `object` while its raw value is used
+    let target = with_raw(ctx, object.clone().into_value(), |object| object)?;
+    let defined = with_raw(ctx, value, |value| unsafe {
+        let raw = ctx.as_ptr();
+        let atom = qjs::JS_NewAtomLen(raw, key.as_ptr() as *const c_char, key.len() as _);
+        let flags = (qjs::JS_PROP_CONFIGURABLE | qjs::JS_PROP_WRITABLE | qjs::JS_PROP_ENUMERABLE) as _;
+        let defined = qjs::JS_DefinePropertyValue(raw, target, atom, qjs::JS_DupValue(raw, value), flags);
+        qjs::JS_FreeAtom(raw, atom);
+        defined
+    })?;
+    if defined < 0 {
+        clear_exception(ctx);
+        return Err(Error::new_into_js_message("value", "property", format!("can't define {:?}", key)));
+    }
+    Ok(())
+}
+
+/// Drops the pending exception a failed raw call left
+pub fn clear_exception(ctx: Ctx) {
+    unsafe { qjs::JS_FreeValue(ctx.as_ptr(), qjs::JS_GetException(ctx.as_ptr())) }
+}
@@ -0,0 +1,365 @@
+//! `Serialize` into javascript values.
+use std::fmt;
+use rquickjs::{Array, Ctx, FromJs, IntoJs, Object, TypedArray, Value};
+use serde::ser::{self, Serialize};
+use crate::error::{Error, Result, Segment};
+use crate::{raw, tag, MAX_SAFE_INTEGER};
+
+/// How 64 and 128 bit integers are converted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Integers {
+    /// Always `BigInt`s
+    #[default]
+    BigInt,
+    /// Numbers, integers beyond `Number.MAX_SAFE_INTEGER` fail
+    Number,
+}
+
+fn unsafe_integer(n: impl fmt::Display) -> Error {
+    Error::new(format!("{} is beyond Number.MAX_SAFE_INTEGER, convert it to a BigInt", n))
+}
+
+pub struct Serializer<'js> {
+    ctx: Ctx<'js>,
+    integers: Integers,
+}
+
+impl<'js> Serializer<'js> {
+    pub fn new(ctx: Ctx<'js>) -> Result<Self> {
+        Self::with_integers(ctx, Integers::default())
+    }
+
+    pub fn with_integers(ctx: Ctx<'js>, integers: Integers) -> Result<Self> {
+        Ok(Serializer { ctx, integers })
+    }
+
+    fn value<T: IntoJs<'js>>(&self, value: T) -> Result<Value<'js>> {
+        Ok(value.into_js(self.ctx)?)
+    }
+
+    /// A number, for integers up to 32 bits
+    fn small(&self, n: i64) -> Result<Value<'js>> {
+        match i32::try_from(n) {
+            Ok(n) => self.value(n),
+            Err(_) => self.value(n as f64),
+        }
+    }
+
+    /// A `BigInt` or a number, depending on `integers`, for 64 and 128 bit integers
+    fn large(&self, n: i128) -> Result<Value<'js>> {
+        match self.integers {
+            Integers::BigInt => self.bigint(n.to_string()),
+            Integers::Number if n.unsigned_abs() <= MAX_SAFE_INTEGER as u128 => self.small(n as i64),
+            Integers::Number => Err(unsafe_integer(n)),
+        }
+    }
+
+    fn bigint(&self, digits: String) -> Result<Value<'js>> {
+        Ok(raw::bigint(self.ctx, &digits)?)
+    }
+
+    /// `{ [key]: value }`, for enum variants with data
+    fn single(&self, key: &str, value: Value<'js>) -> Result<Value<'js>> {
+        let object = Object::new(self.ctx)?;
+        raw::define_value(self.ctx, &object, key, value)?;
+        Ok(object.into_value())
+    }
+
+    /// Property name for a serialized map key
+    fn key(&self, key: Value<'js>) -> Result<String> {
+        match tag(self.ctx, &key)? {
+            "string" => Ok(String::from_js(self.ctx, key)?),
+            "number" | "bigint" | "boolean" => Ok(raw::to_string(self.ctx, &key)?),
+            tag => Err(Error::new(format!("map keys must be strings or numbers, found {}", tag))),
+        }
+    }
+}
+
+impl<'a, 'js> ser::Serializer for &'a Serializer<'js> {
+    type Ok = Value<'js>;
+    type Error = Error;
+    type SerializeSeq = SerializeArray<'a, 'js>;
+    type SerializeTuple = SerializeArray<'a, 'js>;
+    type SerializeTupleStruct = SerializeArray<'a, 'js>;
+    type SerializeTupleVariant = SerializeVariant<'a, 'js, SerializeArray<'a, 'js>>;
+    type SerializeMap = SerializeObject<'a, 'js>;
+    type SerializeStruct = SerializeObject<'a, 'js>;
+    type SerializeStructVariant = SerializeVariant<'a, 'js, SerializeObject<'a, 'js>>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value<'js>> {
+        self.value(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value<'js>> {
+        self.small(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value<'js>> {
+        self.small(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value<'js>> {
+        self.small(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value<'js>> {
+        self.large(v.into())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Value<'js>> {
+        self.large(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value<'js>> {
+        self.small(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value<'js>> {
+        self.small(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value<'js>> {
+        self.small(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value<'js>> {
+        self.large(v.into())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Value<'js>> {
+        match i128::try_from(v) {
+            Ok(v) => self.large(v),
+            Err(_) if self.integers == Integers::BigInt => self.bigint(v.to_string()),
+            Err(_) => Err(unsafe_integer(v)),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value<'js>> {
+        self.value(f64::from(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value<'js>> {
+        self.value(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value<'js>> {
+        self.value(v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value<'js>> {
+        self.value(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value<'js>> {
+        let bytes = TypedArray::<u8>::new(self.ctx, v.to_vec())?;
+        self.value(bytes)
+    }
+
+    fn serialize_none(self) -> Result<Value<'js>> {
+        Ok(Value::new_null(self.ctx))
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Value<'js>> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value<'js>> {
+        Ok(Value::new_null(self.ctx))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value<'js>> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<Value<'js>> {
+        self.value(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<Value<'js>> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value<'js>> {
+        let value = value.serialize(self).map_err(|e| e.at(Segment::Key(variant.to_owned())))?;
+        self.single(variant, value)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SerializeArray<'a, 'js>> {
+        Ok(SerializeArray { ser: self, array: Array::new(self.ctx)?, index: 0 })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray<'a, 'js>> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeArray<'a, 'js>> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Ok(SerializeVariant { ser: self, variant, inner: self.serialize_seq(Some(len))? })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeObject<'a, 'js>> {
+        Ok(SerializeObject { ser: self, object: Object::new(self.ctx)?, key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeObject<'a, 'js>> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Ok(SerializeVariant { ser: self, variant, inner: self.serialize_map(Some(len))? })
+    }
+}
+
+pub struct SerializeArray<'a, 'js> {
+    ser: &'a Serializer<'js>,
+    array: Array<'js>,
+    index: u32,
+}
+
+impl<'a, 'js> ser::SerializeSeq for SerializeArray<'a, 'js> {
+    type Ok = Value<'js>;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        let value = value.serialize(self.ser).map_err(|e| e.at(Segment::Index(self.index as usize)))?;
+        self.array.set(self.index, value)?;
+        self.index += 1;
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value<'js>> {
+        Ok(self.array.into_value())
+    }
+}
+
+impl<'a, 'js> ser::SerializeTuple for SerializeArray<'a, 'js> {
+    type Ok = Value<'js>;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value<'js>> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl<'a, 'js> ser::SerializeTupleStruct for SerializeArray<'a, 'js> {
+    type Ok = Value<'js>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value<'js>> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeObject<'a, 'js> {
+    ser: &'a Serializer<'js>,
+    object: Object<'js>,
+    /// Key of the map entry whose value comes next
+    key: Option<String>,
+}
+
+impl<'a, 'js> SerializeObject<'a, 'js> {
+    fn property<T: ?Sized + Serialize>(&mut self, key: &str, value: &T) -> Result<()> {
+        let value = value.serialize(self.ser).map_err(|e| e.at(Segment::Key(key.to_owned())))?;
+        // Defined, so a `__proto__` key is a property rather than the prototype
+        raw::define_value(self.ser.ctx, &self.object, key, value)?;
+        Ok(())
+    }
+}
+
+impl<'a, 'js> ser::SerializeMap for SerializeObject<'a, 'js> {
+    type Ok = Value<'js>;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        let key = key.serialize(self.ser)?;
+        self.key = Some(self.ser.key(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        let key = self.key.take().ok_or_else(|| Error::new("map value serialized before its key"))?;
+        self.property(&key, value)
+    }
+
+    fn end(self) -> Result<Value<'js>> {
+        Ok(self.object.into_value())
+    }
+}
+
+impl<'a, 'js> ser::SerializeStruct for SerializeObject<'a, 'js> {
+    type Ok = Value<'js>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.property(key, value)
+    }
+
+    fn end(self) -> Result<Value<'js>> {
+        Ok(self.object.into_value())
+    }
+}
+
+/// Tuple and struct variants, serialized as `{ [variant]: data }`
+pub struct SerializeVariant<'a, 'js, S> {
+    ser: &'a Serializer<'js>,
+    variant: &'static str,
+    inner: S,
+}
+
+impl<'a, 'js> ser::SerializeTupleVariant for SerializeVariant<'a, 'js, SerializeArray<'a, 'js>> {
+    type Ok = Value<'js>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(&mut self.inner, value)
+            .map_err(|e| e.at(Segment::Key(self.variant.to_owned())))
+    }
+
+    fn end(self) -> Result<Value<'js>> {
+        let data = ser::SerializeSeq::end(self.inner)?;
+        self.ser.single(self.variant, data)
+    }
+}
+
+impl<'a, 'js> ser::SerializeStructVariant for SerializeVariant<'a, 'js, SerializeObject<'a, 'js>> {
+    type Ok = Value<'js>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.inner.property(key, value).map_err(|e| e.at(Segment::Key(self.variant.to_owned())))
+    }
+
+    fn end(self) -> Result<Value<'js>> {
+        let data = ser::SerializeStruct::end(self.inner)?;
+        self.ser.single(self.variant, data)
+    }
+}
@@ -0,0 +1,345 @@
+//! Bundles an entry module and everything it imports into one archive of
+//! precompiled bytecode, so it can run without the sources, a resolver or transpilers.
+//!
+//! Archive layout (integers are little endian `u32` unless noted, strings and
+//! byte blobs are prefixed by their length):
+//! ```text
+//! magic "EXRMBNDL" | format version | engine fingerprint (u64) | entry name | module count
+//! per module: name | bytecode | import count | (specifier, module name)... | source map ("" if none)
+//! ```
+use std::collections::{HashSet, VecDeque};
+use rquickjs::{Context, Ctx, Error, Module, Resolver, Result, Runtime};
+use transpilers::Transpilers;
+use transpilers::source_map::extract_inline;
+use crate::graph::{Import as GraphImport, ModuleGraph, ModuleNode};
+use crate::imports::{scan_imports, Import};
+use crate::loader::transpile_source;
+use crate::module_specifier::is_native;
+use crate::vendor::Vendor;
+
+const MAGIC: &[u8; 8] = b"EXRMBNDL";
+pub const FORMAT_VERSION: u32 = 1;
+
+pub struct BundledModule {
+    pub name: String,
+    pub bytecode: Vec<u8>,
+    /// Import specifiers as written in the module and the names of the bundled modules they resolve to
+    pub imports: Vec<(String, String)>,
+    pub source_map: Option<String>,
+}
+
+pub struct Bundle {
+    pub entry: String,
+    /// Identifies the QuickJS build that produced the bytecode
+    pub fingerprint: u64,
+    pub modules: Vec<BundledModule>,
+}
+
+impl Bundle {
+    pub fn module(&self, name: &str) -> Option<&BundledModule> {
+        self.modules.iter().find(|m| m.name == name)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.fingerprint.to_le_bytes());
+        write_bytes(&mut out, self.entry.as_bytes());
+        out.extend_from_slice(&(self.modules.len() as u32).to_le_bytes());
+        for m in &self.modules {
+            write_bytes(&mut out, m.name.as_bytes());
+            write_bytes(&mut out, &m.bytecode);
+            out.extend_from_slice(&(m.imports.len() as u32).to_le_bytes());
+            for (specifier, name) in &m.imports {
+                write_bytes(&mut out, specifier.as_bytes());
+                write_bytes(&mut out, name.as_bytes());
+            }
+            write_bytes(&mut out, m.source_map.as_deref().unwrap_or("").as_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a bundle archive"));
+        }
+        let version = reader.u32()?;
+        if version != FORMAT_VERSION {
+            return Err(invalid(&format!(
+                "archive format version {} is not supported, expected {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        let fingerprint = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+        let entry = reader.string()?;
+        let count = reader.u32()?;
+        let mut modules = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name = reader.string()?;
+            let bytecode = reader.bytes()?.to_vec();
+            let import_count = reader.u32()?;
+            let mut imports = Vec::with_capacity(import_count as usize);
+            for _ in 0..import_count {
+                imports.push((reader.string()?, reader.string()?));
+            }
+            let source_map = Some(reader.string()?).filter(|m| !m.is_empty());
+            modules.push(BundledModule { name, bytecode, imports, source_map });
+        }
+        Ok(Bundle { entry, fingerprint, modules })
+    }
+
+    pub fn write_to(&self, path: &str) -> Result<()> {
+        Ok(std::fs::write(path, self.to_bytes())?)
+    }
+
+    pub fn read_from(path: &str) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+/// The manifest of the runtime, whose rquickjs dependency picks the QuickJS sources that are built
+const MANIFEST: &str = include_str!("../Cargo.toml");
+
+/// Module exercising the common opcodes, its bytecode changes when they are renumbered
+const PROBE: &str = r#"
+    export const probe = [1, 1.5, 'a', `b${1}`, /c+/g, { d: null, ...{ e: [undefined] } }];
+    export class Probe extends Array {
+        #f = 1;
+        static g() { return new.target; }
+        get h() { return this.#f ?? super.length; }
+    }
+    export async function* probe2(a, { b = 1, ...c } = {}, ...d) {
+        for (const e of a) yield await e;
+        for (const f in c) try { throw f; } catch { continue; } finally { delete c[f]; }
+        label: while (b--) { if (typeof b === "number" && b in d) break label; }
+        return (() => [arguments.length, this, b ** 2, b >>> 1, b?.c, void 0])();
+    }
+"#;
+
+/// Identifies the QuickJS build bytecode only runs on: the rquickjs dependency the runtime
+/// is built with, and the bytecode of a probe module, which starts with the bytecode format
+/// version (`BC_VERSION`). The bytecode depends on the count of predefined atoms and on
+/// the numbers of the opcodes the probe uses. Changes to the build that affect none of them,
+/// e.g. a new commit on the branch of the rquickjs dependency that renumbers an opcode the
+/// probe doesn't use, go unnoticed.
+pub fn engine_fingerprint(ctx: Ctx) -> Result<u64> {
+    let probe = Module::new(ctx, "exerum:fingerprint", PROBE)?;
+    let bytecode = probe.write_object(false)?;
+    let dependency = manifest_section(MANIFEST, "[dependencies.rquickjs]");
+    let hash = [dependency.as_bytes(), &bytecode[..]]
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(0xcbf29ce484222325u64, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3));
+    Ok(hash)
+}
+
+/// Lines of the manifest section with the header, up to the next section
+fn manifest_section<'a>(manifest: &'a str, header: &str) -> &'a str {
+    let start = match manifest.find(header) {
+        Some(start) => start,
+        None => return "",
+    };
+    let rest = &manifest[start + header.len()..];
+    let end = rest.find("\n[").map(|end| end + 1).unwrap_or(rest.len());
+    &manifest[start..start + header.len() + end]
+}
+
+pub struct Bundler {
+    resolver: Box<dyn Resolver>,
+    transpilers: Transpilers,
+    vendor: Option<Vendor>,
+}
+
+impl Bundler {
+    pub fn new(resolver: impl Resolver + 'static, transpilers: Transpilers) -> Self {
+        Bundler {
+            resolver: Box::new(resolver),
+            transpilers,
+            vendor: None,
+        }
+    }
+
+    /// Bundles remote modules from the vendor directory
+    pub fn with_vendor(mut self, vendor: Vendor) -> Self {
+        self.vendor = Some(vendor);
+        self
+    }
+
+    /// Compiles the entry module and all modules it imports statically or by
+    /// `import("...")` with a string literal. `entry` is a path the loader can read.
+    /// Dynamic imports that can't be resolved are left to fail at runtime.
+    pub fn bundle(&mut self, entry: &str) -> Result<Bundle> {
+        // A separate runtime keeps the compiled modules out of the application's context
+        let rt = Runtime::new()?;
+        let context = Context::full(&rt)?;
+        context.with(|ctx| {
+            let fingerprint = engine_fingerprint(ctx)?;
+            let mut modules = vec![];
+            self.walk(ctx, entry, |walked| {
+                let source_map = extract_inline(&walked.source);
+                let bytecode = Module::new(ctx, walked.name.as_str(), walked.source)?.write_object(false)?;
+                let imports = walked.imports.into_iter().map(|(import, resolved)| (import.specifier, resolved)).collect();
+                modules.push(BundledModule { name: walked.name, bytecode, imports, source_map });
+                Ok(())
+            })?;
+            Ok(Bundle {
+                entry: entry.to_owned(),
+                fingerprint,
+                modules,
+            })
+        })
+    }
+
+    /// Graph of the modules `bundle` would compile, without load stats
+    pub(crate) fn graph(&mut self, ctx: Ctx, entry: &str) -> Result<ModuleGraph> {
+        let mut modules = vec![];
+        self.walk(ctx, entry, |walked| {
+            let imports = walked
+                .imports
+                .into_iter()
+                .map(|(import, resolved)| GraphImport {
+                    specifier: import.specifier,
+                    resolved,
+                    dynamic: import.dynamic,
+                })
+                .collect();
+            modules.push(ModuleNode { name: walked.name, stats: None, imports });
+            Ok(())
+        })?;
+        Ok(ModuleGraph {
+            entry: entry.to_owned(),
+            modules,
+        })
+    }
+
+    /// Reads and transpiles the entry module and the modules it imports, breadth first,
+    /// without evaluating anything. Native modules are not walked.
+    fn walk(&mut self, ctx: Ctx, entry: &str, mut visit: impl FnMut(Walked) -> Result<()>) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        seen.insert(entry.to_owned());
+        queue.push_back(entry.to_owned());
+        while let Some(name) = queue.pop_front() {
+            let source = transpile_source(&mut self.transpilers, self.vendor.as_ref(), &name)?;
+            let mut imports: Vec<(Import, String)> = vec![];
+            for import in scan_imports(&source) {
+                if imports.iter().any(|(i, _)| i.specifier == import.specifier) {
+                    continue;
+                }
+                // Native modules are defined by the runtime running the bundle
+                if is_native(&import.specifier) {
+                    let specifier = import.specifier.clone();
+                    imports.push((import, specifier));
+                    continue;
+                }
+                let resolved = match self.resolver.resolve(ctx, &name, &import.specifier) {
+                    Ok(resolved) => resolved,
+                    Err(_) if import.dynamic => continue,
+                    Err(e) => return Err(e),
+                };
+                if seen.insert(resolved.clone()) {
+                    queue.push_back(resolved.clone());
+                }
+                imports.push((import, resolved));
+            }
+            visit(Walked { name, source, imports })?;
+        }
+        Ok(())
+    }
+}
+
+/// Module reached by `Bundler::walk`
+struct Walked {
+    name: String,
+    /// Transpiled source
+    source: String,
+    /// Imports and the names of the modules they resolve to, native modules resolve to their specifier
+    imports: Vec<(Import, String)>,
+}
+
+pub(crate) fn invalid(message: &str) -> Error {
+    Error::new_loading_message("bundle", message)
+}
+
+pub(crate) fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    /// The bytes not read yet
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        &self.bytes[self.position..]
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.position.checked_add(len).filter(|end| *end <= self.bytes.len());
+        match end {
+            Some(end) => {
+                let taken = &self.bytes[self.position..end];
+                self.position = end;
+                Ok(taken)
+            }
+            None => Err(invalid("unexpected end of the archive")),
+        }
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("invalid utf-8 in the archive"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{manifest_section, Bundle, Bundler, MANIFEST};
+    use crate::resolver::ExerumResolver;
+    use transpiler_js::JsTranspiler;
+    use transpiler_jsx::JsxTranspiler;
+    use transpiler_typescript::TypescriptTranspiler;
+    use transpilers::{register, Transpilers};
+
+    #[test]
+    fn test_bundle() {
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "typescript", [.ts, .tsx], TypescriptTranspiler);
+        register!(transpilers, "javascript_react", [.jsx], JsxTranspiler);
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let mut bundler = Bundler::new(ExerumResolver::new("."), transpilers);
+        let bundle = bundler.bundle("test_data/src/main.tsx").unwrap();
+
+        let bundle = Bundle::from_bytes(&bundle.to_bytes()).unwrap();
+        assert_eq!(bundle.entry, "test_data/src/main.tsx");
+        assert_eq!(bundle.modules.len(), 3);
+        let main = bundle.module("test_data/src/main.tsx").unwrap();
+        let specifiers: Vec<&str> = main.imports.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(specifiers, ["../src/a.jsx", "react/umd/react.js"]);
+        for (_, name) in &main.imports {
+            assert!(!bundle.module(name).unwrap().bytecode.is_empty());
+        }
+
+        assert!(Bundle::from_bytes(b"EXRMBNDL\x02\0\0\0").is_err());
+        assert!(Bundle::from_bytes(&bundle.to_bytes()[..20]).is_err());
+
+        let dependency = manifest_section(MANIFEST, "[dependencies.rquickjs]");
+        assert!(dependency.contains("version = ") && !dependency.contains("[dev-dependencies]"), "{}", dependency);
+    }
+}
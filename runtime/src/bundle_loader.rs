@@ -0,0 +1,137 @@
+use std::rc::Rc;
+use rquickjs::{generic_loader, Context, Ctx, Error, Loaded, Loader, Module, Resolver, Result, Runtime, Script};
+use crate::bundle::{engine_fingerprint, Bundle};
+
+/// Creates a loader and a resolver serving modules exclusively from the bundle archive.
+/// Fails if the archive is malformed or was compiled by an incompatible QuickJS build.
+pub fn from_archive(archive: &[u8]) -> Result<(BundleLoader, BundleResolver)> {
+    from_bundle(Bundle::from_bytes(archive)?)
+}
+
+/// Like `from_archive`, for a bundle already read
+pub fn from_bundle(bundle: Bundle) -> Result<(BundleLoader, BundleResolver)> {
+    verify(&bundle)?;
+    let bundle = Rc::new(bundle);
+    Ok((BundleLoader { bundle: bundle.clone() }, BundleResolver { bundle }))
+}
+
+/// Checks that the bytecode of the bundle can run on this QuickJS build.
+pub fn verify(bundle: &Bundle) -> Result<()> {
+    let rt = Runtime::new()?;
+    let context = Context::full(&rt)?;
+    let fingerprint = context.with(engine_fingerprint)?;
+    if fingerprint != bundle.fingerprint {
+        return Err(Error::new_loading_message(
+            &bundle.entry,
+            "the bundle was compiled by an incompatible QuickJS build",
+        ));
+    }
+    Ok(())
+}
+
+/// Resolves imports with the import maps recorded in the bundle
+pub struct BundleResolver {
+    bundle: Rc<Bundle>,
+}
+
+impl BundleResolver {
+    pub fn entry(&self) -> &str {
+        &self.bundle.entry
+    }
+}
+
+impl Resolver for BundleResolver {
+    fn resolve<'js>(&mut self, _ctx: Ctx<'js>, base: &str, name: &str) -> Result<String> {
+        let imported = self
+            .bundle
+            .module(base)
+            .and_then(|m| m.imports.iter().find(|(specifier, _)| specifier == name))
+            .map(|(_, resolved)| resolved.clone());
+        match imported {
+            Some(resolved) => Ok(resolved),
+            // Modules outside of the bundle can import bundled modules by their name
+            None if self.bundle.module(name).is_some() => Ok(name.to_owned()),
+            None => Err(Error::new_resolving(base, name)),
+        }
+    }
+}
+
+generic_loader! {
+    BundleLoader: Script,
+}
+
+/// Loads the precompiled bytecode of bundled modules
+pub struct BundleLoader {
+    bundle: Rc<Bundle>,
+}
+
+impl Loader<Script> for BundleLoader {
+    fn load<'js>(&mut self, ctx: Ctx<'js>, name: &str) -> Result<Module<'js, Loaded<Script>>> {
+        match self.bundle.module(name) {
+            Some(m) => Ok(Module::read_object(ctx, &m.bytecode)?),
+            None => Err(Error::new_loading(name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rquickjs::Module;
+    use transpiler_js::JsTranspiler;
+    use transpilers::{register, Transpilers};
+    use crate::bundle::Bundler;
+    use crate::native_module::NativeModule;
+    use crate::ops::OpError;
+    use crate::resolver::ExerumResolver;
+
+    #[test]
+    fn test_run_bundle() {
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let mut bundler = Bundler::new(ExerumResolver::new("."), transpilers);
+        let bundle = bundler.bundle("test_data/src/bundle/main.js").unwrap();
+        let (loader, resolver) = super::from_archive(&bundle.to_bytes()).unwrap();
+        assert_eq!(resolver.entry(), "test_data/src/bundle/main.js");
+
+        let rt = crate::runtime::JsRuntime::new(loader, resolver).with_executor().unwrap();
+        let main = rt.import("test_data/src/bundle/main.js").unwrap();
+        let result: String = rt.block_on(main.call_export("run", ())).unwrap();
+        assert_eq!(result, "static:lazy");
+        rt.block_on(rt.idle()).unwrap();
+    }
+
+    #[test]
+    fn test_bundle_native_module() {
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let mut bundler = Bundler::new(ExerumResolver::new("."), transpilers);
+        let bundle = bundler.bundle("test_data/src/bundle/native.js").unwrap();
+        assert_eq!(bundle.modules.len(), 1);
+        let (loader, resolver) = super::from_archive(&bundle.to_bytes()).unwrap();
+
+        let mut rt = crate::runtime::JsRuntime::new(loader, resolver);
+        let greeter = NativeModule::new().function("greeting", |name: String| Ok::<_, OpError>(format!("hello {}", name)));
+        rt.register_module("greeter", greeter).unwrap();
+        let message: String = rt.context().with(|ctx| {
+            Module::new(ctx, "main", r#"export * from "test_data/src/bundle/native.js";"#)
+                .unwrap()
+                .eval()
+                .unwrap()
+                .get("message")
+                .unwrap()
+        });
+        assert_eq!(message, "hello bundle");
+    }
+
+    #[test]
+    fn test_incompatible_bundle() {
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let mut bundler = Bundler::new(ExerumResolver::new("."), transpilers);
+        let mut bundle = bundler.bundle("test_data/src/bundle/lazy.js").unwrap();
+        bundle.fingerprint ^= 1;
+        let err = super::from_archive(&bundle.to_bytes()).err().unwrap();
+        assert!(err.to_string().contains("incompatible"));
+        assert!(super::from_archive(&bundle.to_bytes()[..12]).is_err());
+    }
+}
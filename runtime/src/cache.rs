@@ -3,6 +3,14 @@ use std::hash::Hash;
 
 pub type ModuleId = String;
 
+/// Compiled module and what the loader found in its source
+#[derive(Debug, Clone, Default)]
+pub struct CachedModule {
+    pub bytecode: Vec<u8>,
+    /// Specifiers the module imports with `import()`, if the loader looked for them
+    pub dynamic_imports: Option<Vec<String>>,
+}
+
 pub trait ModuleCache<I: Hash + Eq + PartialEq, M> {
     fn get(&self, key: &I) -> Option<&M>;
     fn insert(&mut self, key: I, data: M) -> Option<M>;
@@ -10,27 +18,27 @@ pub trait ModuleCache<I: Hash + Eq + PartialEq, M> {
 
 pub struct NoCache {}
 
-impl ModuleCache<ModuleId, Vec<u8>> for NoCache {
-    fn get(&self, _key: &ModuleId) -> Option<&Vec<u8>> {
+impl ModuleCache<ModuleId, CachedModule> for NoCache {
+    fn get(&self, _key: &ModuleId) -> Option<&CachedModule> {
         None
     }
 
-    fn insert(&mut self, _id: ModuleId, _data: Vec<u8>) -> Option<Vec<u8>> {
+    fn insert(&mut self, _id: ModuleId, _data: CachedModule) -> Option<CachedModule> {
         None
     }
 }
 
 #[derive(Default)]
 pub struct Memory {
-    inner: HashMap<ModuleId, Vec<u8>>
+    inner: HashMap<ModuleId, CachedModule>
 }
 
-impl ModuleCache<ModuleId, Vec<u8>> for Memory {
-    fn get(&self, key: &ModuleId) -> Option<&Vec<u8>> {
+impl ModuleCache<ModuleId, CachedModule> for Memory {
+    fn get(&self, key: &ModuleId) -> Option<&CachedModule> {
         self.inner.get(key)
     }
 
-    fn insert(&mut self, id: ModuleId, data: Vec<u8>) -> Option<Vec<u8>> {
+    fn insert(&mut self, id: ModuleId, data: CachedModule) -> Option<CachedModule> {
         self.inner.insert(id, data)
     }
 }
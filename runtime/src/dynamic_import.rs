@@ -0,0 +1,171 @@
+//! Asynchronous dynamic `import()`.
+//!
+//! QuickJS loads dynamically imported modules synchronously on the javascript thread.
+//! Once enabled, the loader rewrites `import(...)` in the modules it loads so the
+//! imported module is resolved first and then read and transpiled on a blocking thread.
+//! Only compiling the module is left to the javascript thread. The rewritten imports call
+//! a function on the `import.meta` of their module, there is no global for scripts to replace.
+//! Concurrent imports of
+//! the same module share one preparation, and failures reject the import promise.
+//!
+//! ```ignore
+//! let mut loader = ExerumLoader::new(cache, transpilers);
+//! let imports = loader.enable_async_imports(resolver.clone());
+//! let rt = JsRuntime::new(loader, resolver);
+//! imports.install(rt.context())?;
+//! ```
+//! Modules the host creates from source, like an entry module, keep the synchronous `import()`.
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use rquickjs::{Context, Ctx, Error, Func, Function, Object, Persistent, Promised, Resolver, Result};
+use transpilers::Transpilers;
+use crate::context_data;
+use crate::loader::{check_module, module_permissions, transpile_source};
+use stdlib::permissions::Permissions;
+use crate::module_specifier::{is_native, ModuleSpecifier, DENIED_SCHEME};
+
+/// Property of `import.meta` the rewritten `import(...)` calls
+pub(crate) const WRAPPER: &str = "__exerumImport";
+
+/// Defines the `import.meta` property of a module, stored in the context by `install`
+struct Define(Persistent<Function<'static>>);
+
+type Preparation = Shared<BoxFuture<'static, std::result::Result<(), String>>>;
+
+#[derive(Default)]
+struct State {
+    /// Modules being read and transpiled
+    preparing: HashMap<String, Preparation>,
+    /// Transpiled sources waiting for the loader
+    ready: HashMap<String, String>,
+    /// Modules the loader has loaded, QuickJS doesn't load them again
+    loaded: HashSet<String>,
+}
+
+/// State shared by the loader and the preparations
+#[derive(Clone, Default)]
+pub(crate) struct Prepared {
+    state: Arc<Mutex<State>>,
+}
+
+impl Prepared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Takes the prepared source of the module and marks it loaded
+    pub(crate) fn take(&self, name: &str) -> Option<String> {
+        let mut state = self.lock();
+        state.loaded.insert(name.to_owned());
+        state.ready.remove(name)
+    }
+
+    async fn prepare(&self, transpilers: Transpilers, name: String) -> std::result::Result<(), String> {
+        if ModuleSpecifier::from(&name).is_remote() {
+            // The vendor lives on the javascript thread, the loader reads remote modules itself
+            return Ok(());
+        }
+        let preparation = {
+            let mut state = self.lock();
+            if state.loaded.contains(&name) || state.ready.contains_key(&name) {
+                return Ok(());
+            }
+            match state.preparing.get(&name) {
+                Some(preparation) => preparation.clone(),
+                None => {
+                    let preparation = self.start(transpilers, name.clone());
+                    state.preparing.insert(name, preparation.clone());
+                    preparation
+                }
+            }
+        };
+        preparation.await
+    }
+
+    fn start(&self, mut transpilers: Transpilers, name: String) -> Preparation {
+        let prepared = self.clone();
+        async move {
+            let file = name.clone();
+            let source = tokio::task::spawn_blocking(move || transpile_source(&mut transpilers, None, &file))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|source| source.map_err(|e| e.to_string()));
+            let mut state = prepared.lock();
+            state.preparing.remove(&name);
+            state.ready.insert(name, source?);
+            Ok(())
+        }
+        .boxed()
+        .shared()
+    }
+}
+
+/// Host side of asynchronous dynamic imports, see the module documentation
+pub struct DynamicImports {
+    pub(crate) resolver: Box<dyn Resolver>,
+    pub(crate) transpilers: Transpilers,
+    pub(crate) prepared: Prepared,
+    pub(crate) permissions: Option<Permissions>,
+}
+
+impl DynamicImports {
+    /// Prepares the context for the rewritten imports, each module's `import.meta` gets
+    /// a function preparing the imports relative to the module.
+    /// Must be called before any module using `import()` is loaded.
+    pub fn install(self, context: Context) -> Result<()> {
+        let DynamicImports { resolver, transpilers, prepared, permissions } = self;
+        let resolver = RefCell::new(resolver);
+        context.with(|ctx| {
+            let prepare = Func::new("prepare", move |ctx: Ctx, base: String, specifier: String| {
+                // Native modules are served by the runtime's loader, there is nothing to prepare
+                if is_native(&specifier) {
+                    return Promised(async move { Ok(None) }.boxed_local());
+                }
+                let resolved = resolver.borrow_mut().resolve(ctx, &base, &specifier);
+                // The loader serves denied modules as a module throwing the `PermissionDenied` error
+                if matches!(&resolved, Ok(name) if name.starts_with(DENIED_SCHEME)) {
+                    return Promised(async move { Ok(None) }.boxed_local());
+                }
+                let denied = match &resolved {
+                    Ok(name) => check_module(&module_permissions(ctx, permissions.as_ref()), name).err(),
+                    Err(_) => None,
+                };
+                let (prepared, transpilers) = (prepared.clone(), transpilers.clone());
+                // Fails with the name of the error class and the message
+                Promised(async move {
+                    let error = match (resolved, denied) {
+                        (_, Some(denied)) => Some(("PermissionDenied", denied.to_string())),
+                        (Ok(name), None) => prepared.prepare(transpilers, name).await.err().map(|e| ("Error", e)),
+                        (Err(e), None) => Some(("Error", e.to_string())),
+                    };
+                    Ok(error)
+                }.boxed_local())
+            });
+            // The builtins are captured now, scripts replacing them don't affect the imports
+            let define: Function = ctx.eval(format!(
+                r#"((defineProperty, errors) => (prepare) => (meta, base) =>
+                    defineProperty(meta, "{}", {{
+                        value: (load) => (specifier, ...rest) =>
+                            prepare(base, String(specifier)).then((error) => {{
+                                if (error !== undefined) throw new errors[error[0]](error[1]);
+                                return load(specifier, ...rest);
+                            }}),
+                    }}))(Object.defineProperty, {{ Error, PermissionDenied: globalThis.PermissionDenied ?? Error }})"#,
+                WRAPPER
+            ))?;
+            let define: Function = define.call((prepare,))?;
+            context_data::set(ctx, Define(Persistent::save(ctx, define)));
+            Ok(())
+        })
+    }
+}
+
+/// Defines the function the rewritten imports of module `name` call on its `import.meta`
+pub(crate) fn define<'js>(ctx: Ctx<'js>, name: &str, meta: &Object<'js>) -> Result<()> {
+    match context_data::get::<Define>(ctx) {
+        Some(define) => define.0.clone().restore(ctx)?.call((meta.clone(), name)),
+        None => Err(Error::new_loading_message(name, "dynamic imports aren't installed in this context")),
+    }
+}
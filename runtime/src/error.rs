@@ -0,0 +1,61 @@
+use std::fmt;
+use serde::Deserialize;
+
+/// A javascript exception or an engine error, as seen from Rust
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct JsError {
+    name: String,
+    message: String,
+    /// Empty when the thrown value wasn't an `Error`
+    #[serde(default)]
+    stack: String,
+}
+
+impl JsError {
+    pub fn new(name: &str, message: impl fmt::Display) -> Self {
+        JsError { name: name.to_owned(), message: message.to_string(), stack: String::new() }
+    }
+
+    pub(crate) fn type_error(message: impl fmt::Display) -> Self {
+        Self::new("TypeError", message)
+    }
+
+    /// The error class, like `TypeError`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn stack(&self) -> &str {
+        &self.stack
+    }
+}
+
+impl fmt::Display for JsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.message)
+    }
+}
+
+impl std::error::Error for JsError {}
+
+/// Exceptions rquickjs caught don't keep the class of the thrown value, they're named
+/// "Error". Code evaluated by the runtime describes the thrown value itself.
+impl From<rquickjs::Error> for JsError {
+    fn from(e: rquickjs::Error) -> Self {
+        match e {
+            rquickjs::Error::Exception { message, stack, .. } => JsError { name: "Error".to_owned(), message, stack },
+            e => JsError::new("Error", e),
+        }
+    }
+}
+
+/// Conversions of arguments and results
+impl From<js_serde::Error> for JsError {
+    fn from(e: js_serde::Error) -> Self {
+        JsError::type_error(e)
+    }
+}
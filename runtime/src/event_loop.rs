@@ -0,0 +1,235 @@
+//! What keeps a runtime busy, and driving it until it's done.
+//!
+//! Pending work is made of QuickJS jobs (promise reactions), timers, async host ops in flight
+//! and open resources, like running workers or what embedders count with `JsRuntime::open_resource`.
+//! Timers are the calls of the ops extensions define with `Extension::timer_op`.
+//!
+//! ```ignore
+//! let deadline = Instant::now() + Duration::from_secs(5);
+//! let body: String = jsrt.run_until(module.call_export("handle", (request,)), Some(deadline)).await??;
+//! jsrt.run_event_loop(Some(deadline)).await?;
+//! ```
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+use rquickjs::Ctx;
+use tokio::sync::Notify;
+use crate::context_data;
+use crate::error::JsError;
+
+/// Snapshot of the work keeping a runtime busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PendingWork {
+    /// Whether QuickJS has jobs to run
+    pub jobs: bool,
+    /// Calls of timer ops that haven't fired
+    pub timers: usize,
+    /// Async host ops whose futures haven't completed
+    pub host_ops: usize,
+    pub resources: usize,
+}
+
+impl PendingWork {
+    pub fn is_idle(&self) -> bool {
+        !self.jobs && self.timers == 0 && self.host_ops == 0 && self.resources == 0
+    }
+}
+
+#[derive(Debug)]
+pub enum EventLoopError {
+    Js(JsError),
+    /// The deadline passed with work still pending
+    Timeout(PendingWork),
+    /// The awaited promise is pending but the runtime has nothing left that could settle it
+    Deadlock,
+}
+
+impl fmt::Display for EventLoopError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventLoopError::Js(e) => e.fmt(f),
+            EventLoopError::Timeout(pending) => write!(
+                f,
+                "the event loop timed out with {} timers, {} host ops and {} resources pending{}",
+                pending.timers,
+                pending.host_ops,
+                pending.resources,
+                if pending.jobs { " and jobs to run" } else { "" }
+            ),
+            EventLoopError::Deadlock => f.write_str("a promise is pending but nothing is left to settle it"),
+        }
+    }
+}
+
+impl std::error::Error for EventLoopError {}
+
+impl From<JsError> for EventLoopError {
+    fn from(e: JsError) -> Self {
+        EventLoopError::Js(e)
+    }
+}
+
+/// Counters of a runtime, found from its contexts
+#[derive(Default)]
+pub(crate) struct Activity {
+    timers: Cell<usize>,
+    host_ops: Cell<usize>,
+    resources: Cell<usize>,
+    /// Notified when work ends
+    released: Notify,
+}
+
+impl Activity {
+    pub(crate) fn timers(&self) -> usize {
+        self.timers.get()
+    }
+
+    pub(crate) fn host_ops(&self) -> usize {
+        self.host_ops.get()
+    }
+
+    pub(crate) fn resources(&self) -> usize {
+        self.resources.get()
+    }
+
+    /// Waits until some timer, host op or resource ended since the last wait
+    pub(crate) async fn released(&self) {
+        self.released.notified().await
+    }
+
+    fn counter(&self, kind: Kind) -> &Cell<usize> {
+        match kind {
+            Kind::Timer => &self.timers,
+            Kind::HostOp => &self.host_ops,
+            Kind::Resource => &self.resources,
+        }
+    }
+}
+
+/// The activity of the runtime in the data of its contexts
+struct RuntimeActivity(Rc<Activity>);
+
+/// Counts the work started in the context as work of `activity`
+pub(crate) fn register(ctx: Ctx, activity: &Rc<Activity>) {
+    context_data::set(ctx, RuntimeActivity(activity.clone()));
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Kind {
+    Timer,
+    HostOp,
+    Resource,
+}
+
+/// Counts as pending work until dropped
+pub struct WorkGuard {
+    activity: Rc<Activity>,
+    kind: Kind,
+}
+
+impl WorkGuard {
+    fn new(activity: Rc<Activity>, kind: Kind) -> Self {
+        let counter = activity.counter(kind);
+        counter.set(counter.get() + 1);
+        WorkGuard { activity, kind }
+    }
+}
+
+impl Drop for WorkGuard {
+    fn drop(&mut self) {
+        let counter = self.activity.counter(self.kind);
+        counter.set(counter.get() - 1);
+        self.activity.released.notify_one();
+    }
+}
+
+/// Counts work of the runtime of `ctx`, if it's a `JsRuntime`
+pub(crate) fn start(ctx: Ctx, kind: Kind) -> Option<WorkGuard> {
+    let activity = context_data::get::<RuntimeActivity>(ctx)?;
+    Some(WorkGuard::new(activity.0.clone(), kind))
+}
+
+/// Counts an open resource of the runtime of `ctx`, if it's a `JsRuntime`
+pub(crate) fn resource(ctx: Ctx) -> Option<WorkGuard> {
+    start(ctx, Kind::Resource)
+}
+
+/// Counts an open resource of `activity`
+pub(crate) fn open_resource(activity: &Rc<Activity>) -> WorkGuard {
+    WorkGuard::new(activity.clone(), Kind::Resource)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use transpilers::Transpilers;
+    use crate::cache::NoCache;
+    use crate::loader::ExerumLoader;
+    use crate::ops::{Extension, OpError};
+    use crate::resolver::ExerumResolver;
+    use crate::runtime::JsRuntime;
+    use super::EventLoopError;
+
+    fn runtime() -> JsRuntime {
+        let loader = ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        let mut jsrt = JsRuntime::new(loader, ExerumResolver::new("."));
+        let timers = Extension::new("timers").timer_op("sleep", |ms: u64| async move {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            Ok::<_, OpError>(())
+        });
+        jsrt.register_extension(timers).unwrap();
+        jsrt
+    }
+
+    #[test]
+    fn test_event_loop() {
+        let source = r#"
+        import { sleep } from "host:timers";
+        export const wait = async (ms) => { await sleep(ms); return ms; };
+        export const start = (ms) => { wait(ms); };
+        export const forever = () => new Promise(() => {});
+        "#;
+        let jsrt = runtime().with_executor().unwrap();
+        jsrt.block_on(async {
+            let module = jsrt.eval_module("loop", source).unwrap();
+            assert!(jsrt.pending().is_idle());
+
+            let started = module.call_export::<_, u64>("wait", (10,));
+            let waited = jsrt.run_until(started, None).await.unwrap().unwrap();
+            assert_eq!(waited, 10);
+
+            jsrt.run_until(module.call_export::<_, ()>("start", (200,)), None).await.unwrap().unwrap();
+            let deadline = Instant::now() + Duration::from_millis(20);
+            match jsrt.run_event_loop(Some(deadline)).await {
+                Err(EventLoopError::Timeout(pending)) => assert_eq!((pending.timers, pending.host_ops), (1, 0)),
+                other => panic!("expected a timeout, got {:?}", other),
+            }
+            jsrt.run_event_loop(None).await.unwrap();
+            assert_eq!(jsrt.pending(), Default::default());
+        });
+
+        let jsrt = runtime().with_executor().unwrap();
+        jsrt.block_on(async {
+            let module = jsrt.eval_module("loop", source).unwrap();
+            let never = module.call_export::<_, ()>("forever", ());
+            assert!(matches!(jsrt.run_until(never, None).await, Err(EventLoopError::Deadlock)));
+
+            let resource = jsrt.open_resource();
+            assert_eq!(jsrt.pending().resources, 1);
+            let deadline = Instant::now() + Duration::from_millis(10);
+            assert!(matches!(jsrt.run_event_loop(Some(deadline)).await, Err(EventLoopError::Timeout(_))));
+            drop(resource);
+            jsrt.run_event_loop(None).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_poll_once() {
+        let mut jsrt = runtime();
+        jsrt.run("globalThis.done = false; Promise.resolve().then(() => { done = true; });");
+        assert!(jsrt.pending().jobs);
+        assert!(jsrt.poll_once().unwrap());
+        assert!(!jsrt.poll_once().unwrap());
+        assert!(jsrt.context().with(|ctx| ctx.eval::<bool, _>("done").unwrap()));
+    }
+}
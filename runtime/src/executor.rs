@@ -0,0 +1,196 @@
+//! Running a `JsRuntime` without managing Tokio yourself.
+//!
+//! A runtime can own a current-thread Tokio runtime and a `LocalSet` to block on, see
+//! `JsRuntime::with_executor`. `JsHandle` runs a runtime on a dedicated thread and is `Send`,
+//! so multi-threaded servers can forward work to it:
+//! ```ignore
+//! let js = JsHandle::spawn(|| JsRuntime::new(loader(), resolver()));
+//! // In an axum handler
+//! let html: String = js.call_export("./src/render.js", "render", (path,)).await?;
+//! ```
+use std::future::Future;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use crate::error::JsError;
+use crate::runtime::JsRuntime;
+
+/// A current-thread Tokio runtime with the `LocalSet` the executor of a `JsRuntime` runs in
+pub struct Executor {
+    local_set: tokio::task::LocalSet,
+    tokio_rt: tokio::runtime::Runtime,
+}
+
+impl Executor {
+    pub fn new() -> std::io::Result<Self> {
+        let tokio_rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        Ok(Executor { local_set: tokio::task::LocalSet::new(), tokio_rt })
+    }
+
+    /// Runs the future to completion, with the tasks of the `LocalSet`
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.local_set.block_on(&self.tokio_rt, future)
+    }
+}
+
+type Task = Box<dyn FnOnce(Rc<JsRuntime>) -> LocalBoxFuture<'static, ()> + Send>;
+
+/// `Send` handle to a runtime running on its own thread. The thread runs the work sent
+/// by the handles concurrently, and ends with the event loop once every handle is dropped.
+/// Dropping the last handle waits for the thread, and resumes its panic if it panicked.
+#[derive(Clone)]
+pub struct JsHandle {
+    /// Declared first, the thread only ends once every sender is dropped
+    tasks: UnboundedSender<Task>,
+    thread: Arc<Thread>,
+}
+
+/// Joins the thread of the runtime when the last handle is dropped
+struct Thread(Option<JoinHandle<()>>);
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        let thread = match self.0.take() {
+            Some(thread) => thread,
+            None => return,
+        };
+        // A task of the runtime may hold the last handle, the thread can't wait for itself
+        if thread.thread().id() == std::thread::current().id() {
+            return;
+        }
+        if let Err(panic) = thread.join() {
+            if !std::thread::panicking() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+impl JsHandle {
+    /// Starts the thread, which creates the runtime with `factory`
+    pub fn spawn(factory: impl FnOnce() -> JsRuntime + Send + 'static) -> Self {
+        let (tasks, inbox) = unbounded_channel();
+        let thread = std::thread::spawn(move || run_thread(factory, inbox));
+        JsHandle { tasks, thread: Arc::new(Thread(Some(thread))) }
+    }
+
+    /// Runs `work` on the thread of the runtime, `work` creates the future there
+    pub async fn run<F, Fut, R>(&self, work: F) -> Result<R, JsError>
+    where
+        F: FnOnce(Rc<JsRuntime>) -> Fut + Send + 'static,
+        Fut: Future<Output = R> + 'static,
+        R: Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let task: Task = Box::new(move |jsrt| {
+            async move {
+                let _ = reply.send(work(jsrt).await);
+            }
+            .boxed_local()
+        });
+        let stopped = || JsError::new("Error", "the thread of the runtime stopped");
+        self.tasks.send(task).map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())
+    }
+
+    /// `JsRuntime::eval` on the thread of the runtime
+    pub async fn eval<R: DeserializeOwned + Send + 'static>(&self, code: &str) -> Result<R, JsError> {
+        let code = code.to_owned();
+        self.run(move |jsrt| async move { jsrt.eval(&code).await }).await?
+    }
+
+    /// Imports the module and calls its export, see `JsModule::call_export`
+    pub async fn call_export<A, R>(&self, specifier: &str, name: &str, args: A) -> Result<R, JsError>
+    where
+        A: Serialize + Send + 'static,
+        R: DeserializeOwned + Send + 'static,
+    {
+        let (specifier, name) = (specifier.to_owned(), name.to_owned());
+        self.run(move |jsrt| async move { jsrt.import(&specifier)?.call_export(&name, args).await }).await?
+    }
+}
+
+fn run_thread(factory: impl FnOnce() -> JsRuntime, mut inbox: UnboundedReceiver<Task>) {
+    let executor = match Executor::new() {
+        Ok(executor) => executor,
+        // The handles see the thread stopped
+        Err(_) => return,
+    };
+    executor.block_on(async move {
+        let mut jsrt = factory();
+        jsrt.spawn_executor();
+        let jsrt = Rc::new(jsrt);
+        while let Some(task) = inbox.recv().await {
+            tokio::task::spawn_local(task(jsrt.clone()));
+        }
+        let _ = jsrt.run_event_loop(None).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use transpilers::Transpilers;
+    use crate::cache::NoCache;
+    use crate::loader::ExerumLoader;
+    use crate::resolver::ExerumResolver;
+    use crate::runtime::JsRuntime;
+    use super::JsHandle;
+
+    fn runtime() -> JsRuntime {
+        let loader = ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        JsRuntime::new(loader, ExerumResolver::new("."))
+    }
+
+    #[test]
+    fn test_owned_executor() {
+        let jsrt = runtime().with_executor().unwrap();
+        let sum: i32 = jsrt.block_on_eval("Promise.resolve(40).then((n) => n + 2)").unwrap();
+        assert_eq!(sum, 42);
+        let e = jsrt.block_on_eval::<()>("Promise.reject(new TypeError('no'))").unwrap_err();
+        assert_eq!(e.to_string(), "TypeError: no");
+        jsrt.block_on(jsrt.run_event_loop(None)).unwrap();
+    }
+
+    #[test]
+    fn test_handle() {
+        let js = JsHandle::spawn(|| {
+            let jsrt = runtime();
+            jsrt.eval_module("greeter", "export const greet = async (name) => `hello ${name}`;").unwrap();
+            jsrt
+        });
+        let tokio_rt = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let greetings = tokio_rt.block_on(async move {
+            let tasks: Vec<_> = ["ada", "alan"]
+                .into_iter()
+                .map(|name| {
+                    let js = js.clone();
+                    tokio::spawn(async move { js.call_export::<_, String>("greeter", "greet", (name,)).await })
+                })
+                .collect();
+            let mut greetings = vec![];
+            for task in tasks {
+                greetings.push(task.await.unwrap().unwrap());
+            }
+            greetings.push(js.eval::<String>("typeof globalThis").await.unwrap());
+            greetings
+        });
+        assert_eq!(greetings, ["hello ada", "hello alan", "object"]);
+    }
+
+    #[test]
+    fn test_handle_panic() {
+        let js = JsHandle::spawn(|| panic!("no runtime"));
+        let tokio_rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let e = tokio_rt.block_on(js.eval::<()>("1")).unwrap_err();
+        assert_eq!(e.message(), "the thread of the runtime stopped");
+        // Dropping the last handle joins the thread and resumes its panic
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(js))).unwrap_err();
+        assert_eq!(panic.downcast_ref::<&str>(), Some(&"no runtime"));
+    }
+}
@@ -0,0 +1,327 @@
+//! Import graph of the modules loaded by a runtime.
+//!
+//! Share a `GraphRecorder` between the loader and the resolver, run the entry point
+//! and build the graph from what was recorded:
+//! ```ignore
+//! let recorder = GraphRecorder::default();
+//! let resolver = ExerumResolver::new(".").with_recorder(recorder.clone());
+//! let loader = ExerumLoader::new(cache, transpilers).with_recorder(recorder.clone());
+//! // ... run "src/main.js"
+//! println!("{}", recorder.graph("src/main.js").to_dot());
+//! ```
+//! `JsRuntime::module_graph` builds the graph from the imports found in the sources
+//! instead, without running anything. Its modules have no load stats.
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::Duration;
+use transpilers::data_module::js_string;
+
+#[derive(Debug, Clone)]
+pub struct LoadStats {
+    /// Transpiler label, see `Transpilers::label_by_path`. `None` for plain javascript.
+    pub transpiler: Option<String>,
+    pub cache_hit: bool,
+    /// Time spent reading, transpiling and compiling the module
+    pub load_time: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    loads: HashMap<String, LoadStats>,
+    /// Module names in load order
+    order: Vec<String>,
+    /// (importing module, specifier, resolved module name)
+    edges: Vec<(String, String, String)>,
+    /// Specifiers each module imports with `import()`
+    dynamic: HashMap<String, Vec<String>>,
+}
+
+/// Records resolutions and loads. Clones share the same record.
+#[derive(Debug, Clone, Default)]
+pub struct GraphRecorder {
+    inner: Rc<RefCell<Recorded>>,
+}
+
+impl GraphRecorder {
+    pub(crate) fn record_resolution(&self, base: &str, specifier: &str, resolved: &str) {
+        let mut inner = self.inner.borrow_mut();
+        let edge = (base.to_owned(), specifier.to_owned(), resolved.to_owned());
+        if !inner.edges.contains(&edge) {
+            inner.edges.push(edge);
+        }
+    }
+
+    pub(crate) fn record_load(&self, name: &str, stats: LoadStats, dynamic_imports: Option<Vec<String>>) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.loads.insert(name.to_owned(), stats).is_none() {
+            inner.order.push(name.to_owned());
+        }
+        if let Some(dynamic) = dynamic_imports {
+            inner.dynamic.insert(name.to_owned(), dynamic);
+        }
+    }
+
+    /// Stats of the modules loaded so far, in load order
+    pub fn loads(&self) -> Vec<(String, LoadStats)> {
+        let inner = self.inner.borrow();
+        inner.order.iter().map(|n| (n.clone(), inner.loads[n].clone())).collect()
+    }
+
+    /// Graph of the modules reachable from `entry`
+    pub fn graph(&self, entry: &str) -> ModuleGraph {
+        let inner = self.inner.borrow();
+        let mut modules: Vec<ModuleNode> = vec![];
+        let mut index: HashMap<String, usize> = HashMap::new();
+        let mut queue = vec![entry.to_owned()];
+        index.insert(entry.to_owned(), 0);
+        while let Some(name) = queue.pop() {
+            let dynamic = inner.dynamic.get(&name);
+            let imports: Vec<Import> = inner
+                .edges
+                .iter()
+                .filter(|(base, _, _)| *base == name)
+                .map(|(_, specifier, resolved)| Import {
+                    specifier: specifier.clone(),
+                    resolved: resolved.clone(),
+                    dynamic: dynamic.map(|d| d.contains(specifier)).unwrap_or(false),
+                })
+                .collect();
+            for import in &imports {
+                if !index.contains_key(&import.resolved) {
+                    index.insert(import.resolved.clone(), index.len());
+                    queue.push(import.resolved.clone());
+                }
+            }
+            modules.push(ModuleNode {
+                stats: inner.loads.get(&name).cloned(),
+                name,
+                imports,
+            });
+        }
+        modules.sort_by_key(|m| index[&m.name]);
+        ModuleGraph {
+            entry: entry.to_owned(),
+            modules,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub specifier: String,
+    pub resolved: String,
+    pub dynamic: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleNode {
+    pub name: String,
+    /// `None` if the module wasn't loaded by the loader, e.g. the entry module
+    /// created from source by the host
+    pub stats: Option<LoadStats>,
+    pub imports: Vec<Import>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleGraph {
+    pub entry: String,
+    /// Modules in the order they were discovered from the entry
+    pub modules: Vec<ModuleNode>,
+}
+
+impl ModuleGraph {
+    pub fn module(&self, name: &str) -> Option<&ModuleNode> {
+        self.modules.iter().find(|m| m.name == name)
+    }
+
+    /// Groups of modules importing each other, directly or through other modules.
+    /// Uses Tarjan's algorithm for strongly connected components.
+    pub fn cycles(&self) -> Vec<Vec<String>> {
+        struct State<'a> {
+            graph: &'a ModuleGraph,
+            index: HashMap<&'a str, usize>,
+            low: HashMap<&'a str, usize>,
+            stack: Vec<&'a str>,
+            on_stack: HashSet<&'a str>,
+            cycles: Vec<Vec<String>>,
+        }
+
+        fn connect<'a>(state: &mut State<'a>, node: &'a ModuleNode) {
+            let i = state.index.len();
+            state.index.insert(&node.name, i);
+            state.low.insert(&node.name, i);
+            state.stack.push(&node.name);
+            state.on_stack.insert(&node.name);
+            for import in &node.imports {
+                let next = match state.graph.module(&import.resolved) {
+                    Some(next) => next,
+                    None => continue,
+                };
+                if !state.index.contains_key(next.name.as_str()) {
+                    connect(state, next);
+                    let low = state.low[next.name.as_str()].min(state.low[node.name.as_str()]);
+                    state.low.insert(&node.name, low);
+                } else if state.on_stack.contains(next.name.as_str()) {
+                    let low = state.index[next.name.as_str()].min(state.low[node.name.as_str()]);
+                    state.low.insert(&node.name, low);
+                }
+            }
+            if state.low[node.name.as_str()] == state.index[node.name.as_str()] {
+                let mut component = vec![];
+                while let Some(member) = state.stack.pop() {
+                    state.on_stack.remove(member);
+                    component.push(member.to_owned());
+                    if member == node.name {
+                        break;
+                    }
+                }
+                let self_import = node.imports.iter().any(|i| i.resolved == node.name);
+                if component.len() > 1 || self_import {
+                    component.reverse();
+                    state.cycles.push(component);
+                }
+            }
+        }
+
+        let mut state = State {
+            graph: self,
+            index: HashMap::new(),
+            low: HashMap::new(),
+            stack: vec![],
+            on_stack: HashSet::new(),
+            cycles: vec![],
+        };
+        for node in &self.modules {
+            if !state.index.contains_key(node.name.as_str()) {
+                connect(&mut state, node);
+            }
+        }
+        state.cycles
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        write!(out, r#"{{"entry":{},"modules":["#, js_string(&self.entry)).unwrap();
+        for (i, m) in self.modules.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, r#"{{"name":{}"#, js_string(&m.name)).unwrap();
+            match &m.stats {
+                Some(stats) => write!(
+                    out,
+                    r#","transpiler":{},"cacheHit":{},"loadTimeMs":{}"#,
+                    stats.transpiler.as_deref().map(js_string).unwrap_or_else(|| "null".to_owned()),
+                    stats.cache_hit,
+                    millis(stats.load_time)
+                )
+                .unwrap(),
+                None => out.push_str(r#","transpiler":null,"cacheHit":null,"loadTimeMs":null"#),
+            }
+            out.push_str(r#","imports":["#);
+            for (j, import) in m.imports.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                write!(
+                    out,
+                    r#"{{"specifier":{},"resolved":{},"dynamic":{}}}"#,
+                    js_string(&import.specifier),
+                    js_string(&import.resolved),
+                    import.dynamic
+                )
+                .unwrap();
+            }
+            out.push_str("]}");
+        }
+        out.push_str(r#"],"cycles":["#);
+        let cycles: Vec<String> = self
+            .cycles()
+            .iter()
+            .map(|c| format!("[{}]", c.iter().map(|n| js_string(n)).collect::<Vec<_>>().join(",")))
+            .collect();
+        out.push_str(&cycles.join(","));
+        out.push_str("]}");
+        out
+    }
+
+    /// Graphviz graph. Dynamic imports are dashed, imports within cycles are red.
+    pub fn to_dot(&self) -> String {
+        let cycles = self.cycles();
+        let in_cycle = |a: &str, b: &str| cycles.iter().any(|c| c.iter().any(|n| n == a) && c.iter().any(|n| n == b));
+        let mut out = String::from("digraph modules {\n");
+        for m in &self.modules {
+            let label = match &m.stats {
+                Some(stats) => format!(
+                    "{}\\n{}{} {:.1}ms",
+                    m.name,
+                    stats.transpiler.as_deref().unwrap_or("js"),
+                    if stats.cache_hit { " cached" } else { "" },
+                    millis(stats.load_time)
+                ),
+                None => m.name.clone(),
+            };
+            writeln!(out, "    {} [label={}];", js_string(&m.name), js_string(&label).replace("\\\\n", "\\n")).unwrap();
+        }
+        for m in &self.modules {
+            for import in &m.imports {
+                let mut attributes = vec![];
+                if import.dynamic {
+                    attributes.push("style=dashed");
+                }
+                if in_cycle(&m.name, &import.resolved) {
+                    attributes.push("color=red");
+                }
+                let attributes = if attributes.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", attributes.join(", "))
+                };
+                writeln!(out, "    {} -> {}{};", js_string(&m.name), js_string(&import.resolved), attributes).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[test]
+fn test_module_graph() {
+    let recorder = GraphRecorder::default();
+    let stats = |cache_hit| LoadStats {
+        transpiler: Some("typescript".to_owned()),
+        cache_hit,
+        load_time: Duration::from_millis(2),
+    };
+    recorder.record_resolution("main.ts", "./a.ts", "a.ts");
+    recorder.record_resolution("main.ts", "./lazy.ts", "lazy.ts");
+    recorder.record_resolution("a.ts", "./b.ts", "b.ts");
+    recorder.record_resolution("b.ts", "./a.ts", "a.ts");
+    recorder.record_resolution("other.ts", "./a.ts", "a.ts");
+    recorder.record_load("a.ts", stats(false), Some(vec![]));
+    recorder.record_load("b.ts", stats(true), None);
+    recorder.record_load("lazy.ts", stats(false), Some(vec![]));
+    recorder.record_load("main.ts", stats(false), Some(vec!["./lazy.ts".to_owned()]));
+
+    let graph = recorder.graph("main.ts");
+    let names: Vec<&str> = graph.modules.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, ["main.ts", "a.ts", "lazy.ts", "b.ts"]);
+    assert!(graph.module("lazy.ts").is_some());
+    assert!(graph.module("main.ts").unwrap().imports[1].dynamic);
+    assert!(graph.module("b.ts").unwrap().stats.as_ref().unwrap().cache_hit);
+    assert_eq!(graph.cycles(), vec![vec!["a.ts".to_owned(), "b.ts".to_owned()]]);
+
+    let json = graph.to_json();
+    assert!(json.starts_with(r#"{"entry":"main.ts","modules":[{"name":"main.ts","transpiler":"typescript","cacheHit":false,"loadTimeMs":2"#));
+    assert!(json.ends_with(r#""cycles":[["a.ts","b.ts"]]}"#));
+    let dot = graph.to_dot();
+    assert!(dot.contains(r#""main.ts" -> "lazy.ts" [style=dashed];"#));
+    assert!(dot.contains(r#""a.ts" -> "b.ts" [color=red];"#));
+    assert!(dot.contains(r#""b.ts" [label="b.ts\ntypescript cached 2.0ms"];"#));
+}
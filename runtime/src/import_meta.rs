@@ -0,0 +1,102 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use rquickjs::{Ctx, Error, Func, Object, Resolver, Result};
+use crate::vendor::{is_remote, join_url};
+
+/// Populates `import.meta` of the modules loaded by `ExerumLoader`:
+/// - `url`: `file://` URL of the module, or the URL of a remote module
+/// - `filename`, `dirname`: absolute path of the module and its directory, undefined for remote modules
+/// - `resolve(specifier)`: URL of the module the specifier resolves to
+/// - `env`: variables set by the host
+#[derive(Default, Clone)]
+pub struct ImportMeta {
+    resolver: Option<Rc<RefCell<Box<dyn Resolver>>>>,
+    env: Vec<(String, String)>,
+}
+
+impl ImportMeta {
+    pub fn new() -> Self {
+        ImportMeta::default()
+    }
+
+    /// Resolver used by `import.meta.resolve`, it should resolve like the runtime's resolver.
+    /// Without one relative specifiers are resolved against the module's directory.
+    pub fn resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+        self.resolver = Some(Rc::new(RefCell::new(Box::new(resolver))));
+        self
+    }
+
+    /// Adds a variable to `import.meta.env`
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.retain(|(k, _)| k != key);
+        self.env.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Sets the properties on the `import.meta` object of the module `name`
+    pub fn populate<'js>(&self, ctx: Ctx<'js>, name: &str, meta: &Object<'js>) -> Result<()> {
+        let remote = is_remote(name);
+        let filename = absolute(Path::new(name));
+        let dirname = filename.parent().map(Path::to_path_buf).unwrap_or_default();
+        if remote {
+            meta.set("url", name)?;
+        } else {
+            meta.set("url", file_url(&filename))?;
+            meta.set("filename", filename.to_string_lossy().into_owned())?;
+            meta.set("dirname", dirname.to_string_lossy().into_owned())?;
+        }
+
+        let env = Object::new(ctx)?;
+        for (key, value) in &self.env {
+            env.set(key.as_str(), value.as_str())?;
+        }
+        meta.set("env", env)?;
+
+        let resolver = self.resolver.clone();
+        let base = name.to_owned();
+        let resolve = Func::new("resolve", move |ctx: Ctx, specifier: String| -> Result<String> {
+            let resolved = match &resolver {
+                Some(resolver) => resolver.borrow_mut().resolve(ctx, &base, &specifier)?,
+                None if remote => join_url(&base, &specifier).ok_or_else(|| Error::new_resolving(&base, &specifier))?,
+                None => dirname.join(&specifier).to_string_lossy().into_owned(),
+            };
+            if is_remote(&resolved) {
+                return Ok(resolved);
+            }
+            Ok(file_url(&absolute(Path::new(&resolved))))
+        });
+        meta.set("resolve", resolve)
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    match std::fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => std::env::current_dir().unwrap_or_default().join(path),
+    }
+}
+
+/// `file://` URL of an absolute path
+pub fn file_url(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut url = String::from("file://");
+    if !path.starts_with('/') {
+        // Windows drive letter
+        url.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' | b':' => url.push(byte as char),
+            _ => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    url
+}
+
+#[test]
+fn test_file_url() {
+    assert_eq!(file_url(Path::new("/srv/app/src/main.js")), "file:///srv/app/src/main.js");
+    assert_eq!(file_url(Path::new("/srv/my app/ü.js")), "file:///srv/my%20app/%C3%BC.js");
+    assert_eq!(file_url(Path::new("C:\\app\\main.js")), "file:///C:/app/main.js");
+}
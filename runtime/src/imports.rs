@@ -0,0 +1,175 @@
+use swc_common::{sync::Lrc, BytePos, FileName, SourceMap, Span};
+use swc_ecma_ast::{self as ast, EsVersion};
+use swc_ecma_parser::{lexer::Lexer, EsConfig, Parser, StringInput, Syntax};
+use swc_ecma_visit::{Visit, VisitWith};
+
+/// Module specifier found in javascript source
+#[derive(Debug, PartialEq)]
+pub struct Import {
+    pub specifier: String,
+    /// `import("...")` rather than an import or export declaration
+    pub dynamic: bool,
+}
+
+/// Finds the specifiers of import declarations, `export ... from` declarations
+/// and dynamic imports of string literals, in source order.
+/// Sources that don't parse have no imports, evaluating them reports the syntax error.
+pub fn scan_imports(source: &str) -> Vec<Import> {
+    let mut scanner = ImportScanner::default();
+    if let Some(module) = parse(source) {
+        module.visit_with(&mut scanner);
+    }
+    scanner.imports
+}
+
+/// Parses javascript module source
+fn parse(source: &str) -> Option<ast::Module> {
+    parse_with_start(source).map(|(module, _)| module)
+}
+
+/// Parses javascript module source, also returns the position of its first byte in the spans
+fn parse_with_start(source: &str) -> Option<(ast::Module, BytePos)> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let fm = cm.new_source_file(FileName::Anon, source.to_owned());
+    let lexer = Lexer::new(Syntax::Es(EsConfig::default()), EsVersion::EsNext, StringInput::from(&*fm), None);
+    let module = Parser::new_from(lexer).parse_module().ok()?;
+    Some((module, fm.start_pos))
+}
+
+#[derive(Default)]
+struct ImportScanner {
+    imports: Vec<Import>,
+}
+
+impl ImportScanner {
+    fn push(&mut self, specifier: &ast::Str, dynamic: bool) {
+        self.imports.push(Import { specifier: specifier.value.to_string(), dynamic });
+    }
+}
+
+impl Visit for ImportScanner {
+    fn visit_import_decl(&mut self, import: &ast::ImportDecl) {
+        self.push(&import.src, false);
+    }
+
+    fn visit_export_all(&mut self, export: &ast::ExportAll) {
+        self.push(&export.src, false);
+    }
+
+    fn visit_named_export(&mut self, export: &ast::NamedExport) {
+        if let Some(src) = &export.src {
+            self.push(src, false);
+        }
+    }
+
+    fn visit_call_expr(&mut self, call: &ast::CallExpr) {
+        if let ast::Callee::Import(_) = call.callee {
+            match call.args.first().map(|arg| &*arg.expr) {
+                Some(ast::Expr::Lit(ast::Lit::Str(specifier))) => self.push(specifier, true),
+                // import(`./lazy.js`)
+                Some(ast::Expr::Tpl(tpl)) if tpl.exprs.is_empty() => {
+                    if let Some(cooked) = tpl.quasis.first().and_then(|q| q.cooked.as_ref()) {
+                        self.imports.push(Import { specifier: cooked.to_string(), dynamic: true });
+                    }
+                }
+                _ => {}
+            }
+        }
+        call.visit_children_with(self);
+    }
+}
+
+/// Replaces the `import` keyword of every dynamic import with
+/// `import.meta.{property}((__s) => import(__s))`, so the host can prepare
+/// the imported module before it's loaded. The arguments are kept, an options argument
+/// is passed on. Sources that don't parse are returned unchanged.
+/// Columns of source maps shift on the changed lines.
+pub fn rewrite_dynamic_imports(source: &str, property: &str) -> String {
+    let mut finder = DynamicImportFinder::default();
+    let (module, start) = match parse_with_start(source) {
+        Some(parsed) => parsed,
+        None => return source.to_owned(),
+    };
+    module.visit_with(&mut finder);
+    let mut out = String::with_capacity(source.len());
+    let mut copied = 0;
+    for (span, args) in finder.imports {
+        let (lo, hi) = ((span.lo - start).0 as usize, (span.hi - start).0 as usize);
+        out.push_str(&source[copied..lo]);
+        let load = match args {
+            1 => "(__s) => import(__s)",
+            _ => "(__s, __o) => import(__s, __o)",
+        };
+        out.push_str(&format!("import.meta.{}({})", property, load));
+        copied = hi;
+    }
+    out.push_str(&source[copied..]);
+    out
+}
+
+/// Finds the `import` keyword of dynamic imports and their number of arguments
+#[derive(Default)]
+struct DynamicImportFinder {
+    imports: Vec<(Span, usize)>,
+}
+
+impl Visit for DynamicImportFinder {
+    fn visit_call_expr(&mut self, call: &ast::CallExpr) {
+        if let ast::Callee::Import(import) = &call.callee {
+            self.imports.push((import.span, call.args.len()));
+        }
+        call.visit_children_with(self);
+    }
+}
+
+#[test]
+fn test_scan_imports() {
+    let source = r#"
+        import React, { useState as state } from "react";
+        import * as utils from './utils.js'
+        import "./polyfill.js";
+        export { a, b as c } from "./a.js";
+        export * as ns from "./ns.js";
+        export { local };
+        // import "./commented.js";
+        const s = "import './in-string.js'";
+        const t = `import "${name}" ${ { x: import("./in-template.js") }.x } from "./not-an-import.js"`;
+        const r = /import "x"/g;
+        if (r) /import "y"/.test(s);
+        const d = a / 2, e = b / 3; import("./after-division.js");
+        const lazy = () => import("./lazy.js");
+        const computed = import("./" + name);
+        console.log(import.meta.url, obj.import("./method.js"), 4 / 2);
+    "#;
+    let found: Vec<(String, bool)> = scan_imports(source)
+        .into_iter()
+        .map(|i| (i.specifier, i.dynamic))
+        .collect();
+    let expected = [
+        ("react", false),
+        ("./utils.js", false),
+        ("./polyfill.js", false),
+        ("./a.js", false),
+        ("./ns.js", false),
+        ("./in-template.js", true),
+        ("./after-division.js", true),
+        ("./lazy.js", true),
+    ];
+    assert_eq!(found, expected.iter().map(|(s, d)| (s.to_string(), *d)).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_rewrite_dynamic_imports() {
+    let source = "const a = await import(\"./a.js\");\n\
+        const b = import (name + 'ö', { with: { type: 'json' } }); obj.import(x); import.meta;\n\
+        class C { import() { return import(`./c.js`); } }";
+    let rewritten = rewrite_dynamic_imports(source, "__load");
+    assert_eq!(
+        rewritten,
+        "const a = await import.meta.__load((__s) => import(__s))(\"./a.js\");\n\
+         const b = import.meta.__load((__s, __o) => import(__s, __o)) (name + 'ö', { with: { type: 'json' } }); \
+         obj.import(x); import.meta;\n\
+         class C { import() { return import.meta.__load((__s) => import(__s))(`./c.js`); } }"
+    );
+    assert_eq!(rewrite_dynamic_imports("import(", "__load"), "import(");
+}
@@ -4,3 +4,23 @@ pub mod loader;
 pub mod module_specifier;
 pub mod rquickjs;
 pub mod cache;
+pub mod imports;
+pub mod bundle;
+pub mod bundle_loader;
+pub mod graph;
+pub mod dynamic_import;
+pub mod import_meta;
+pub mod vendor;
+pub mod worker;
+pub mod ops;
+pub mod native_module;
+pub mod module;
+pub mod error;
+pub mod rejections;
+pub mod event_loop;
+pub mod executor;
+pub mod snapshot;
+pub mod memory;
+pub use stdlib::permissions;
+pub(crate) use stdlib::context_data;
+pub use js_serde;
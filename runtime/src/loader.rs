@@ -1,58 +1,258 @@
-use crate::cache::{ModuleCache, ModuleId};
-use super::module_specifier::ModuleSpecifier;
-use rquickjs::{generic_loader, Ctx, Error, Loaded, Loader, Module, Result, Script};
-use transpilers::Transpilers;
+use crate::cache::{CachedModule, ModuleCache, ModuleId};
+use crate::context_data;
+use super::module_specifier::{ModuleSpecifier, DENIED_SCHEME};
+use rquickjs::{generic_loader, Ctx, Error, Loaded, Loader, Module, Object, Resolver, Result, Script};
+use std::time::Instant;
+use transpilers::{AssetTranspiler, Transpilers};
+use crate::graph::{GraphRecorder, LoadStats};
+use crate::imports::{rewrite_dynamic_imports, scan_imports};
+use crate::dynamic_import::{self, DynamicImports, Prepared, WRAPPER};
+use crate::import_meta::ImportMeta;
+use crate::vendor::Vendor;
+use stdlib::permissions::{Permission, PermissionDenied, Permissions};
+use transpilers::data_module::js_string;
 
 generic_loader! {
     ExerumLoader: Script,
 }
 
 pub struct ExerumLoader {
-    cache: Box<dyn ModuleCache<ModuleId, Vec<u8>>>,
+    cache: Box<dyn ModuleCache<ModuleId, CachedModule>>,
     transpilers: Transpilers,
+    recorder: Option<GraphRecorder>,
+    prepared: Option<Prepared>,
+    import_meta: ImportMeta,
+    vendor: Option<Vendor>,
+    permissions: Option<Permissions>,
 }
 
 impl ExerumLoader {
-    pub fn new(cache: Box<dyn ModuleCache<ModuleId, Vec<u8>>>, transpilers: Transpilers) -> Self {
+    pub fn new(cache: Box<dyn ModuleCache<ModuleId, CachedModule>>, transpilers: Transpilers) -> Self {
         ExerumLoader {
             transpilers,
-            cache
+            cache,
+            recorder: None,
+            prepared: None,
+            import_meta: ImportMeta::default(),
+            vendor: None,
+            permissions: None,
         }
     }
+
+    /// Configures `import.meta.resolve` and `import.meta.env`
+    pub fn with_import_meta(mut self, import_meta: ImportMeta) -> Self {
+        self.import_meta = import_meta;
+        self
+    }
+
+    /// Only loads modules the permissions allow reading, or fetching for remote modules.
+    /// Must be set before `enable_async_imports`.
+    pub fn with_permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = Some(permissions);
+        self
+    }
+
+    /// Makes `import()` in the loaded modules resolve and transpile the imported
+    /// module off the javascript thread. The returned host side must be installed
+    /// into the context. `resolver` must resolve like the runtime's resolver.
+    pub fn enable_async_imports(&mut self, resolver: impl Resolver + 'static) -> DynamicImports {
+        let prepared = Prepared::default();
+        self.prepared = Some(prepared.clone());
+        DynamicImports {
+            resolver: Box::new(resolver),
+            transpilers: self.transpilers.clone(),
+            prepared,
+            permissions: self.permissions.clone(),
+        }
+    }
+
+    /// Serves remote modules (`https://...`, `npm:...`) from the vendor directory
+    pub fn with_vendor(mut self, vendor: Vendor) -> Self {
+        self.vendor = Some(vendor);
+        self
+    }
+
+    /// Records load stats for the module graph
+    pub fn with_recorder(mut self, recorder: GraphRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    fn transpiler_label(&self, specifier: &str) -> Option<String> {
+        let ms = ModuleSpecifier::from(specifier);
+        match ms.transpiler() {
+            Some(name) => Some(name.to_owned()),
+            None => self.transpilers.label_by_path(ms.path()),
+        }
+    }
+}
+
+/// Permissions `JsRuntime` gives a context, they apply to the modules it imports
+pub(crate) struct ContextPermissions(pub(crate) Permissions);
+
+/// The permissions modules imported in the context are checked against:
+/// the ones of the resolver or the loader and the ones of the context
+pub(crate) fn module_permissions(ctx: Ctx, configured: Option<&Permissions>) -> Vec<Permissions> {
+    let mut permissions: Vec<Permissions> = configured.into_iter().cloned().collect();
+    if let Some(context) = context_data::get::<ContextPermissions>(ctx) {
+        if !permissions.iter().any(|p| p.ptr_eq(&context.0)) {
+            permissions.push(context.0.clone());
+        }
+    }
+    permissions
+}
+
+/// Checks the permission to read a local module, or to fetch a remote one from its host
+pub(crate) fn check_module(permissions: &[Permissions], name: &str) -> std::result::Result<(), PermissionDenied> {
+    let permission = module_permission(name);
+    permissions.iter().try_for_each(|permissions| permissions.check(permission.clone()))
+}
+
+/// The permission to read a local module, or to fetch a remote one from its host
+fn module_permission(name: &str) -> Permission {
+    let ms = ModuleSpecifier::from(name);
+    if ms.is_remote() {
+        let host = ms.path().split_once("://").map(|(_, rest)| rest).unwrap_or(ms.path());
+        Permission::Net(host.split(['/', '?', '#']).next().unwrap_or(host).to_owned())
+    } else {
+        Permission::Read(ms.path().into())
+    }
+}
+
+/// Module throwing the `PermissionDenied` error, so importers get the same error as stdlib operations
+fn denied_module<'js>(ctx: Ctx<'js>, name: &str, denied: &PermissionDenied) -> Result<Module<'js, Loaded<Script>>> {
+    let source = format!(
+        "throw new PermissionDenied({}, {{ name: {}, target: {} }});",
+        js_string(&denied.to_string()),
+        js_string(denied.permission.name()),
+        js_string(&denied.permission.target()),
+    );
+    Module::new(ctx, name, source)
+}
+
+/// Turns the module into javascript with the transpiler picked by the module specifier.
+/// Remote modules are read from the vendor directory.
+pub(crate) fn transpile_source(transpilers: &mut Transpilers, vendor: Option<&Vendor>, name: &str) -> Result<String> {
+    with_transpiler(
+        transpilers,
+        vendor,
+        name,
+        |t, path| {
+            let source = t.load(path)?;
+            t.transform(path, source)
+        },
+        // Default to javascript
+        // TODO: change. Make a default key maybe.
+        |path| std::fs::read_to_string(path).map_err(|e| Error::new_loading_message(path, e.to_string())),
+    )
+}
+
+/// Tells the transpiler picked by the module specifier that the module is served from the cache
+fn cached_source(transpilers: &mut Transpilers, vendor: Option<&Vendor>, name: &str) -> Result<()> {
+    with_transpiler(transpilers, vendor, name, |t, path| t.cached(path), |_| Ok(()))
+}
+
+/// Calls `transpiled` with the transpiler picked by the module specifier and the path of the asset,
+/// or `plain` with the path when the module has no extension.
+fn with_transpiler<T>(
+    transpilers: &mut Transpilers,
+    vendor: Option<&Vendor>,
+    name: &str,
+    transpiled: impl FnOnce(&mut dyn AssetTranspiler, &str) -> Result<T>,
+    plain: impl FnOnce(&str) -> Result<T>,
+) -> Result<T> {
+    let ms = ModuleSpecifier::from(name);
+    let vendored;
+    let path = if ms.is_remote() {
+        let vendor = vendor.ok_or_else(|| Error::new_loading_message(ms.path(), "remote modules need a vendor directory"))?;
+        vendored = vendor.module_path(ms.path())?;
+        vendored
+            .to_str()
+            .ok_or_else(|| Error::new_loading_message(ms.path(), "the vendored path is not valid utf-8"))?
+    } else {
+        ms.path()
+    };
+    let extension = std::path::Path::new(path).extension().and_then(|ext| ext.to_str());
+    if let Some(transpiler_name) = ms.transpiler() {
+        // Pick transpiler by name
+        let mut t = transpilers
+            .by_name(transpiler_name)?
+            .ok_or_else(|| Error::new_loading_message(path, format!("unknown transpiler \"{}\"", transpiler_name)))?;
+        transpiled(&mut *t, path)
+    } else if let Some(mut t) = transpilers.by_path(path)? {
+        // Pick transpiler by path pattern or file extension
+        transpiled(&mut *t, path)
+    } else if let Some(ext) = extension {
+        Err(Error::new_loading_message(path, format!("no transpiler registered for .{} files", ext)))
+    } else {
+        plain(path)
+    }
 }
 
 impl Loader<Script> for ExerumLoader {
-    fn load<'js>(&mut self, ctx: Ctx<'js>, name: &str) -> Result<Module<'js, Loaded<Script>>> {
-        let ms = ModuleSpecifier::from(name);
+    fn load<'js>(&mut self, ctx: Ctx<'js>, specifier: &str) -> Result<Module<'js, Loaded<Script>>> {
+        let ms = ModuleSpecifier::from(specifier);
         let name = ms.path();
         let name_owned = name.to_owned();
+        let started = Instant::now();
+        if let Some(target) = specifier.strip_prefix(DENIED_SCHEME) {
+            return denied_module(ctx, specifier, &PermissionDenied { permission: module_permission(target) });
+        }
+        if let Err(denied) = check_module(&module_permissions(ctx, self.permissions.as_ref()), name) {
+            return denied_module(ctx, specifier, &denied);
+        }
         // if cach hit, retrieve from cache
-        if let Some(serialized_module) = self.cache.get(&name_owned) {
-            Ok(Module::read_object(ctx, serialized_module)?)
+        if let Some(cached) = self.cache.get(&name_owned) {
+            let m = Module::read_object(ctx, &cached.bytecode)?;
+            cached_source(&mut self.transpilers, self.vendor.as_ref(), specifier)?;
+            let meta = m.meta::<Object>()?;
+            self.import_meta.populate(ctx, name, &meta)?;
+            if let Some(prepared) = &self.prepared {
+                dynamic_import::define(ctx, name, &meta)?;
+                prepared.take(name);
+            }
+            if let Some(recorder) = &self.recorder {
+                let stats = LoadStats {
+                    transpiler: self.transpiler_label(specifier),
+                    cache_hit: true,
+                    load_time: started.elapsed(),
+                };
+                recorder.record_load(name, stats, cached.dynamic_imports.clone());
+            }
+            Ok(m)
         } else {
-            let m = if let Some(transpiler_name) = ms.transpiler() {
-                // Pick transpiler by name
-                let mut t = self
-                    .transpilers
-                    .by_name(transpiler_name)
-                    .ok_or(Error::new_loading(name))?;
-                t.transpile(ctx, name)?
-            } else if let Some(ext) = ms.extension() {
-                // Pick transpiler by file extension
-                let mut t = self
-                    .transpilers
-                    .by_ext(ext)
-                    .ok_or(Error::new_loading(name))?;
-                t.transpile(ctx, name)?
-            } else {
-                // Default to javascript
-                // TODO: change. Make a default key maybe.
-                let js_source = std::fs::read_to_string(name).unwrap();
-                let m = Module::new(ctx, name, js_source)?;
-                m
+            let prepared = self.prepared.as_ref().and_then(|p| p.take(name));
+            let source = match prepared {
+                Some(source) => source,
+                None => transpile_source(&mut self.transpilers, self.vendor.as_ref(), specifier)?,
             };
-            let serialized = m.write_object(false)?;
-            self.cache.insert(name_owned, serialized);
+            let dynamic_imports = self.recorder.as_ref().map(|_| {
+                scan_imports(&source)
+                    .into_iter()
+                    .filter(|i| i.dynamic)
+                    .map(|i| i.specifier)
+                    .collect()
+            });
+            let source = match self.prepared {
+                Some(_) => rewrite_dynamic_imports(&source, WRAPPER),
+                None => source,
+            };
+            let m = Module::new(ctx, name, source)?;
+            let meta = m.meta::<Object>()?;
+            self.import_meta.populate(ctx, name, &meta)?;
+            if self.prepared.is_some() {
+                dynamic_import::define(ctx, name, &meta)?;
+            }
+            let bytecode = m.write_object(false)?;
+            self.cache.insert(name_owned, CachedModule { bytecode, dynamic_imports: dynamic_imports.clone() });
+            if let Some(recorder) = &self.recorder {
+                let stats = LoadStats {
+                    transpiler: self.transpiler_label(specifier),
+                    cache_hit: false,
+                    load_time: started.elapsed(),
+                };
+                recorder.record_load(name, stats, dynamic_imports);
+            }
             Ok(m)
         }
     }
@@ -65,7 +265,9 @@ mod tests {
     use crate::cache::NoCache;
     use transpiler_jsx::JsxTranspiler;
     use transpiler_typescript::TypescriptTranspiler;
-    use transpilers::{Transpilers, register};
+    use transpilers::{AssetTranspiler, Pipeline, Transpilers, register};
+    use transpilers::rquickjs::Result;
+    use std::sync::{Arc, Mutex};
     #[test]
     fn test_resolver_loader() {
         fn print(msg: String) {
@@ -113,6 +315,261 @@ mod tests {
         register!(transpilers, "typescript", [.ts, .tsx], TypescriptTranspiler);
         register!(transpilers, "javascript_react", [.jsx], JsxTranspiler);
         register!(transpilers, "javascript", [.js], JsTranspiler);
-        transpilers.by_ext("js").unwrap();
+        transpilers.by_ext("js").unwrap().unwrap();
+    }
+
+    #[derive(Default)]
+    struct Banner {}
+
+    impl AssetTranspiler for Banner {
+        fn transform(&mut self, _path: &str, source: String) -> Result<String> {
+            Ok(format!("const banner = true;\n{}", source))
+        }
+    }
+
+    #[test]
+    fn test_transpiler_patterns() {
+        let mut transpilers = Transpilers::default();
+        let ts = transpilers.register("typescript", &["ts", "tsx"], TypescriptTranspiler::default());
+        let pipeline = Pipeline::new().stage(Banner::default()).shared_stage(ts);
+        transpilers.register_pattern("src/**/*.ts", 0, Arc::new(Mutex::new(pipeline)));
+        transpilers.register_pattern("*.raw.ts", 10, Arc::new(Mutex::new(JsTranspiler::default())));
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache{}), transpilers);
+        let rt = crate::runtime::JsRuntime::new(loader, crate::resolver::ExerumResolver::new("."));
+        let kinds: (String, String, String) = rt.context().with(|ctx| {
+            let entry = r#"
+                import { kind as matched } from "./test_data/src/patterns/a/b.ts";
+                import { kind as raw } from "./test_data/src/patterns/b.raw.ts";
+                import { kind as plain } from "./test_data/lib/plain.ts";
+                export const kinds = [matched, raw, plain];
+            "#;
+            Module::new(ctx, "entry", entry).unwrap().eval().unwrap().get("kinds").unwrap()
+        });
+        // The higher priority pattern wins, paths not matching any pattern fall back to the extension
+        assert_eq!(kinds, ("banner".to_owned(), "raw".to_owned(), "plain".to_owned()));
+    }
+
+    #[test]
+    fn test_plugin_transpiler() {
+        use transpiler_plugin::PluginTranspiler;
+        use transpilers::source_map::extract_inline;
+        let plugin = PluginTranspiler::new("upper", r#"
+            export function transform(source, path) {
+                return { code: "export default " + JSON.stringify(source.toUpperCase()), map: { version: 3 } }
+            }
+        "#).unwrap();
+        let mut transpilers = Transpilers::default();
+        transpilers.register("upper", &["txt"], plugin);
+        let out = transpilers.by_ext("txt").unwrap().unwrap().transform("a.txt", "hello".to_owned()).unwrap();
+        assert!(out.starts_with(r#"export default "HELLO""#));
+        assert_eq!(extract_inline(&out).unwrap(), r#"{"version":3}"#);
+
+        let err = PluginTranspiler::new("broken", "export function transform(").err();
+        assert!(err.is_some());
+
+        let mut plugin = PluginTranspiler::new("async", r#"
+            export async function transform(source, path) {
+                if (source === "fail") throw new Error("bad asset");
+                const code = await Promise.resolve("export default " + JSON.stringify(source));
+                return { code };
+            }
+        "#).unwrap();
+        let out = plugin.transform("a.txt", "hello".to_owned()).unwrap();
+        assert_eq!(out, r#"export default "hello""#);
+        let err = plugin.transform("b.txt", "fail".to_owned()).unwrap_err();
+        assert!(err.to_string().contains("Error: bad asset"));
+    }
+
+    #[test]
+    fn test_css_modules() {
+        use transpiler_css::{CssCollector, CssTranspiler};
+        let collector = CssCollector::default();
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript_react", [.jsx], JsxTranspiler);
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        transpilers.register("css", &["css"], CssTranspiler::with_collector(collector.clone()));
+        let resolver = crate::resolver::ExerumResolver::new(".");
+        let loader = crate::loader::ExerumLoader::new(Box::new(crate::cache::Memory::default()), transpilers);
+        let mut rt = crate::runtime::JsRuntime::new(loader, resolver);
+        let entry = r#"
+            import { Button, theme } from "test_data/src/css/Button.jsx";
+            const button = Button({ label: "OK" });
+            export const result = [button.type, button.props.className, button.children[0], theme];
+        "#;
+        let (tag, class_name, label, theme): (String, String, String, String) = rt.context().with(|ctx| {
+            Module::new(ctx, "entry", entry).unwrap().eval().unwrap().get("result").unwrap()
+        });
+        assert_eq!(tag, "button");
+        assert!(class_name.starts_with("button_"), "{}", class_name);
+        assert_eq!(label, "OK");
+        assert!(theme.contains(r#"font-family: "Helvetica Neue""#));
+        let css = collector.css();
+        assert!(css.contains(&format!(".{} {{", class_name)), "{}", css);
+        assert!(css.contains("margin: 0"));
+
+        // The second context loads the modules from the cache, their styles are still collected
+        collector.clear();
+        let second = rt.create_context("second", stdlib::StdlibConfig::default()).unwrap();
+        second.with(|ctx| {
+            Module::new(ctx, "entry", entry).unwrap().eval().unwrap();
+        });
+        assert_eq!(collector.css(), css);
+    }
+
+    #[test]
+    fn test_data_modules() {
+        use transpiler_toml::TomlTranspiler;
+        use transpiler_yaml::YamlTranspiler;
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        register!(transpilers, "yaml", [.yaml, .yml], YamlTranspiler);
+        register!(transpilers, "toml", [.toml], TomlTranspiler);
+        let resolver = crate::resolver::ExerumResolver::new(".");
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache{}), transpilers);
+        let rt = crate::runtime::JsRuntime::new(loader, resolver).with_executor().unwrap();
+        let main = rt.import("test_data/src/data/main.js").unwrap();
+        // A `then` key doesn't make the namespaces thenable
+        let loaded: (String, String, String, u32, String) = rt.block_on(main.call_export("load", ())).unwrap();
+        assert_eq!(loaded, ("app".to_owned(), "later".to_owned(), "undefined".to_owned(), 8080, "later".to_owned()));
+    }
+
+    #[test]
+    fn test_wasm_modules() {
+        use transpiler_wasm::WasmTranspiler;
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        register!(transpilers, "wasm", [.wasm], WasmTranspiler);
+        let resolver = crate::resolver::ExerumResolver::new(".");
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache{}), transpilers);
+        let rt = crate::runtime::JsRuntime::new(loader, resolver).with_executor().unwrap();
+        let main = rt.import("test_data/src/wasm/main.js").unwrap();
+        // `add` calls the `log` function the binary imports from ./env.js
+        let (sum, logged): (i32, Vec<i32>) = rt.block_on(main.call_export("run", (2, 3))).unwrap();
+        assert_eq!(sum, 5);
+        assert_eq!(logged, [5]);
+    }
+
+    #[test]
+    fn test_module_graph() {
+        use crate::graph::GraphRecorder;
+        use stdlib::StdlibConfig;
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let recorder = GraphRecorder::default();
+        let resolver = crate::resolver::ExerumResolver::new(".").with_recorder(recorder.clone());
+        let loader = crate::loader::ExerumLoader::new(Box::new(crate::cache::Memory::default()), transpilers)
+            .with_recorder(recorder.clone());
+
+        let mut rt = crate::runtime::JsRuntime::new(loader, resolver).with_executor().unwrap();
+        // The second context loads the modules from the cache
+        let contexts = [rt.context(), rt.create_context("second", StdlibConfig::default()).unwrap()];
+        for context in contexts {
+            let promise: Promise<String> = context.with(|ctx| {
+                let entry = r#"export { run } from "test_data/src/bundle/main.js";"#;
+                let loaded = Module::new(ctx, "entry", entry).unwrap().eval().unwrap();
+                let run: Function = loaded.get("run").unwrap();
+                run.call(()).unwrap()
+            });
+            assert_eq!(rt.block_on(promise).unwrap(), "static:lazy");
+        }
+        rt.block_on(rt.rt().idle());
+
+        let graph = recorder.graph("entry");
+        assert_eq!(graph.modules.len(), 4);
+        // The entry was created by the host
+        assert!(graph.modules[0].stats.is_none());
+        let main = graph.module("test_data/src/bundle/main.js").unwrap();
+        let stats = main.stats.as_ref().unwrap();
+        assert_eq!(stats.transpiler.as_deref(), Some("javascript"));
+        assert!(stats.cache_hit);
+        let imports: Vec<(&str, bool)> = main.imports.iter().map(|i| (i.specifier.as_str(), i.dynamic)).collect();
+        assert_eq!(imports, [("./static.js", false), ("./lazy.js", true)]);
+        assert!(graph.cycles().is_empty());
+        assert!(graph.to_dot().contains("static.js"));
+    }
+
+    #[test]
+    fn test_async_dynamic_import() {
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "typescript", [.ts, .tsx], TypescriptTranspiler);
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let resolver = crate::resolver::ExerumResolver::new(".");
+        let mut loader = crate::loader::ExerumLoader::new(Box::new(NoCache{}), transpilers);
+        let imports = loader.enable_async_imports(resolver.clone());
+
+        let rt = crate::runtime::JsRuntime::new(loader, resolver).with_executor().unwrap();
+        imports.install(rt.context()).unwrap();
+        let main = rt.import("test_data/src/dynamic/main.js").unwrap();
+        let result: String = rt.block_on(main.call_export("run", ())).unwrap();
+        let parts: Vec<&str> = result.split('|').collect();
+        assert_eq!(parts[0], "lazy:true");
+        assert!(parts[1].contains("missing.js"), "{}", parts[1]);
+        assert!(parts[2].contains("broken.ts:2:"), "{}", parts[2]);
+        rt.block_on(rt.rt().idle());
+    }
+
+    #[test]
+    fn test_import_meta() {
+        use crate::import_meta::ImportMeta;
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let resolver = crate::resolver::ExerumResolver::new(".");
+        let meta = ImportMeta::new().resolver(resolver.clone()).env("MODE", "test");
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache{}), transpilers).with_import_meta(meta);
+        let rt = crate::runtime::JsRuntime::new(loader, resolver);
+        let (url, dirname, resolved, mode): (String, String, String, String) = rt.context().with(|ctx| {
+            let entry = r#"export * from "test_data/src/meta/main.js";"#;
+            let loaded = Module::new(ctx, "entry", entry).unwrap().eval().unwrap();
+            (
+                loaded.get("url").unwrap(),
+                loaded.get("dirname").unwrap(),
+                loaded.get("resolved").unwrap(),
+                loaded.get("mode").unwrap(),
+            )
+        });
+        assert!(url.starts_with("file:///") && url.ends_with("/test_data/src/meta/main.js"), "{}", url);
+        assert!(dirname.ends_with("meta"), "{}", dirname);
+        assert!(resolved.starts_with("file:///") && resolved.ends_with("/test_data/src/bundle/lazy.js"), "{}", resolved);
+        assert_eq!(mode, "test");
+    }
+
+    #[test]
+    fn test_remote_modules() {
+        use crate::vendor::Vendor;
+        let dir = std::env::temp_dir().join(format!("exerum-remote-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // Stands in for an HTTP server
+        let fetcher = |url: &str| match url {
+            "https://example.com/lib/mod.js" => Ok(b"export { dep } from './dep.js'; export const url = import.meta.url;".to_vec()),
+            "https://example.com/lib/dep.js" => Ok(b"export const dep = 'remote';".to_vec()),
+            "https://esm.sh/left-pad@1.3.0" => Ok(b"export default (s) => ' ' + s;".to_vec()),
+            _ => Err("404 Not Found".to_owned()),
+        };
+        let run = |vendor: Vendor| -> Result<(String, String, String)> {
+            let mut transpilers = Transpilers::default();
+            register!(transpilers, "javascript", [.js], JsTranspiler);
+            let resolver = crate::resolver::ExerumResolver::new(".");
+            let loader = crate::loader::ExerumLoader::new(Box::new(NoCache{}), transpilers).with_vendor(vendor);
+            let rt = crate::runtime::JsRuntime::new(loader, resolver);
+            rt.context().with(|ctx| {
+                let entry = r#"
+                    import { dep, url } from "https://example.com/lib/mod.js";
+                    import pad from "npm:left-pad@1.3.0";
+                    export const result = [dep, url, pad("x")];
+                "#;
+                let loaded = Module::new(ctx, "entry", entry)?.eval()?;
+                loaded.get("result")
+            })
+        };
+        let result = run(Vendor::open(&dir).unwrap().with_fetcher(fetcher)).unwrap();
+        assert_eq!(result, ("remote".to_owned(), "https://example.com/lib/mod.js".to_owned(), " x".to_owned()));
+
+        // Offline from the vendor directory
+        assert_eq!(run(Vendor::open(&dir).unwrap()).unwrap(), result);
+        let dep = Vendor::open(&dir).unwrap().local_path("https://example.com/lib/dep.js");
+        std::fs::write(dep, "export const dep = 'tampered';").unwrap();
+        let err = run(Vendor::open(&dir).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("integrity check failed"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
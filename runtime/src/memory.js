@@ -0,0 +1,35 @@
+// Installs `performance.memory` (like Chrome's) and `process.memoryUsage()` (like Node's)
+// on top of the memory statistics of memory.rs, which cover the whole runtime.
+// Reading them throws `PermissionDenied` unless the context may call the "memory" op.
+((read) => {
+    const { PermissionDenied } = globalThis;
+    const memoryUsage = () => {
+        const usage = read();
+        if (usage.__error !== undefined) throw new PermissionDenied(usage.message, usage.permission);
+        return usage;
+    };
+    globalThis.performance ??= {};
+    Object.defineProperty(globalThis.performance, "memory", {
+        configurable: true,
+        enumerable: true,
+        get: () => {
+            const usage = memoryUsage();
+            return {
+                usedJSHeapSize: usage.memory_used_size,
+                totalJSHeapSize: usage.malloc_size,
+                jsHeapSizeLimit: usage.malloc_limit ?? Infinity,
+            };
+        },
+    });
+    globalThis.process ??= {};
+    globalThis.process.memoryUsage = () => {
+        const usage = memoryUsage();
+        return {
+            rss: usage.malloc_size,
+            heapTotal: usage.malloc_size,
+            heapUsed: usage.memory_used_size,
+            external: usage.binary_object_size,
+            arrayBuffers: usage.binary_object_size,
+        };
+    };
+})
@@ -0,0 +1,150 @@
+//! Memory statistics of QuickJS, for the host with `JsRuntime::memory_usage` and for
+//! javascript as `performance.memory` and `process.memoryUsage()`.
+//!
+//! The statistics cover the whole runtime, so javascript reads them only if the permissions
+//! of its context grant `Permission::Op("memory")`: other contexts could learn about each other.
+use rquickjs::{qjs, Ctx, Func, Function, IntoJs, Result, Value};
+use serde::Serialize;
+use js_serde::Serde;
+use stdlib::permissions::Permissions;
+
+const MEMORY_JS: &str = include_str!("memory.js");
+/// Op permission to read the memory usage from javascript
+pub const MEMORY_OP: &str = "memory";
+
+/// Memory used by a runtime, shared by all its contexts. Sizes are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct MemoryUsage {
+    /// Memory allocated by QuickJS
+    pub malloc_size: u64,
+    pub malloc_count: u64,
+    /// Memory used by its objects, strings and the like
+    pub memory_used_size: u64,
+    pub memory_used_count: u64,
+    /// Set with `Runtime::set_memory_limit`
+    pub malloc_limit: Option<u64>,
+    pub object_count: u64,
+    pub object_size: u64,
+    pub property_count: u64,
+    pub property_size: u64,
+    pub string_count: u64,
+    pub string_size: u64,
+    pub atom_count: u64,
+    pub atom_size: u64,
+    /// Javascript functions, with their bytecode
+    pub function_count: u64,
+    pub function_size: u64,
+    pub function_code_size: u64,
+    pub c_function_count: u64,
+    pub array_count: u64,
+    pub fast_array_count: u64,
+    pub fast_array_elements: u64,
+    /// Array buffers and typed arrays
+    pub binary_object_count: u64,
+    pub binary_object_size: u64,
+}
+
+impl MemoryUsage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl From<qjs::JSMemoryUsage> for MemoryUsage {
+    fn from(usage: qjs::JSMemoryUsage) -> Self {
+        let size = |n: i64| n.max(0) as u64;
+        MemoryUsage {
+            malloc_size: size(usage.malloc_size),
+            malloc_count: size(usage.malloc_count),
+            memory_used_size: size(usage.memory_used_size),
+            memory_used_count: size(usage.memory_used_count),
+            // QuickJS reports no limit as -1
+            malloc_limit: Some(usage.malloc_limit).filter(|limit| *limit >= 0).map(size),
+            object_count: size(usage.obj_count),
+            object_size: size(usage.obj_size),
+            property_count: size(usage.prop_count),
+            property_size: size(usage.prop_size),
+            string_count: size(usage.str_count),
+            string_size: size(usage.str_size),
+            atom_count: size(usage.atom_count),
+            atom_size: size(usage.atom_size),
+            function_count: size(usage.js_func_count),
+            function_size: size(usage.js_func_size),
+            function_code_size: size(usage.js_func_code_size),
+            c_function_count: size(usage.c_func_count),
+            array_count: size(usage.array_count),
+            fast_array_count: size(usage.fast_array_count),
+            fast_array_elements: size(usage.fast_array_elements),
+            binary_object_count: size(usage.binary_object_count),
+            binary_object_size: size(usage.binary_object_size),
+        }
+    }
+}
+
+/// Memory usage of the runtime of `ctx`
+pub(crate) fn usage(ctx: Ctx) -> MemoryUsage {
+    unsafe {
+        let mut usage = std::mem::zeroed::<qjs::JSMemoryUsage>();
+        qjs::JS_ComputeMemoryUsage(qjs::JS_GetRuntime(ctx.as_ptr()), &mut usage);
+        usage.into()
+    }
+}
+
+/// Defines `performance.memory` and `process.memoryUsage()` in the context, which check
+/// `permissions`
+pub(crate) fn init(ctx: Ctx, permissions: &Permissions) -> Result<()> {
+    let install: Function = ctx.eval(MEMORY_JS)?;
+    let permissions = permissions.clone();
+    let memory_usage = Func::new("memoryUsage", move |ctx: Ctx| -> Result<Value> {
+        match permissions.check_op(MEMORY_OP) {
+            Ok(()) => Serde(usage(ctx)).into_js(ctx),
+            Err(denied) => denied.to_js(ctx),
+        }
+    });
+    install.call((memory_usage,))
+}
+
+#[cfg(test)]
+mod tests {
+    use transpilers::Transpilers;
+    use crate::cache::NoCache;
+    use crate::loader::ExerumLoader;
+    use crate::permissions::Permissions;
+    use crate::resolver::ExerumResolver;
+    use crate::runtime::JsRuntime;
+    use stdlib::StdlibConfig;
+
+    #[test]
+    fn test_memory_usage() {
+        let loader = ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        let mut jsrt = JsRuntime::new(loader, ExerumResolver::new("."));
+        let before = jsrt.memory_usage();
+        assert!(before.object_count > 0 && before.malloc_size > 0);
+        assert_eq!(before.malloc_limit, None);
+
+        jsrt.run("globalThis.big = Array.from({ length: 100000 }, (_, i) => ({ i }));");
+        let grown = jsrt.memory_usage();
+        assert!(grown.object_count > before.object_count + 100000);
+        jsrt.run("delete globalThis.big;");
+        jsrt.run_gc();
+        assert!(jsrt.memory_usage().object_count < grown.object_count);
+
+        let visible: bool = jsrt
+            .context()
+            .with(|ctx| {
+                ctx.eval(
+                    r#"performance.memory.usedJSHeapSize > 0
+                    && performance.memory.jsHeapSizeLimit === Infinity
+                    && (({ heapUsed, heapTotal }) => heapUsed <= heapTotal)(process.memoryUsage())"#,
+                )
+            })
+            .unwrap();
+        assert!(visible);
+        let tenant = jsrt.create_context("tenant", StdlibConfig::new(Permissions::none())).unwrap();
+        let denied: bool = tenant
+            .with(|ctx| ctx.eval("try { performance.memory; false } catch (e) { e instanceof PermissionDenied }"))
+            .unwrap();
+        assert!(denied);
+        assert!(jsrt.memory_usage().to_json().starts_with(r#"{"malloc_size":"#));
+    }
+}
@@ -0,0 +1,33 @@
+// Helpers of module.rs: tracking of imports, calls of exports and settling of values.
+// Calls settle with `{ Ok: value }` or `{ Err: error }`, which deserialize to a `Result`.
+(() => {
+    const describe = (e) => e instanceof Error
+        ? { name: e.name, message: e.message, stack: e.stack ?? "" }
+        : { name: "Error", message: "Uncaught " + String(e), stack: "" };
+    return {
+        describe,
+        // State of the import, `settled` once it has the namespace or the error
+        track: (promise) => {
+            const state = {};
+            promise.then(
+                (namespace) => { state.namespace = namespace; state.settled = true; },
+                (e) => { state.error = describe(e); state.settled = true; },
+            );
+            return state;
+        },
+        call: async (f, args) => {
+            try {
+                return { Ok: await f(...args) };
+            } catch (e) {
+                return { Err: describe(e) };
+            }
+        },
+        settle: async (value) => {
+            try {
+                return { Ok: await value };
+            } catch (e) {
+                return { Err: describe(e) };
+            }
+        },
+    };
+})()
@@ -0,0 +1,331 @@
+//! Handles to evaluated modules, to call their exports from Rust.
+//!
+//! ```ignore
+//! let module = jsrt.import("./src/main.ts")?;
+//! let total: f64 = module.call_export("sum", (1, 2)).await?;
+//! ```
+//! Arguments and results are converted with `js_serde`. The handle keeps the namespace of
+//! the module, whose exports stay live like in javascript.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::rc::Rc;
+use rquickjs::{qjs, Context, Ctx, Function, Object, Persistent, Promise, Runtime, Value};
+use js_serde::raw::from_raw;
+use js_serde::{to_value, Serde};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::context_data;
+use crate::error::JsError;
+
+const MODULE_JS: &str = include_str!("module.js");
+/// Name of the module importing for `JsModule::import`, whose imports are recorded
+const IMPORTER: &str = "<import>";
+/// Name of the module importing the modules evaluated by the embedder
+const EVALUATOR: &str = "<evaluate>";
+/// Source of the importer modules, `import()` resolves the specifier against their name
+const IMPORTER_JS: &str = "export const load = (specifier) => import(specifier);";
+/// File name of scripts, like `Ctx::eval`
+const SCRIPT: &str = "eval_script";
+
+/// Helpers of module.js and the modules handed to Rust in a context
+struct Modules {
+    helpers: Persistent<Object<'static>>,
+    /// Namespaces of the modules imported with `JsModule::import`, by resolved name
+    imported: RefCell<HashMap<String, Persistent<Object<'static>>>>,
+    /// Names the modules evaluated by the embedder are compiled under, by their given name
+    evaluated: RefCell<HashMap<String, String>>,
+    /// What the last import of `JsModule::import` resolved to
+    resolved: RefCell<Option<String>>,
+    /// `load` functions of the importer modules, by their name. Each is compiled once per
+    /// context, the modules QuickJS compiles stay in the module list of the context.
+    importers: RefCell<HashMap<&'static str, Persistent<Function<'static>>>>,
+}
+
+fn modules(ctx: Ctx) -> rquickjs::Result<Rc<Modules>> {
+    context_data::get_or_init(ctx, || {
+        let helpers: Object = ctx.eval(MODULE_JS)?;
+        Ok(Modules {
+            helpers: Persistent::save(ctx, helpers),
+            imported: RefCell::default(),
+            evaluated: RefCell::default(),
+            resolved: RefCell::default(),
+            importers: RefCell::default(),
+        })
+    })
+}
+
+/// The `load` function of the importer module named `base`
+fn importer<'js>(ctx: Ctx<'js>, base: &'static str) -> Result<Function<'js>, JsError> {
+    let modules = modules(ctx)?;
+    let cached = modules.importers.borrow().get(base).cloned();
+    if let Some(load) = cached {
+        return Ok(load.restore(ctx)?);
+    }
+    let importer = rquickjs::Module::new(ctx, base, IMPORTER_JS)?.eval()?;
+    let load: Function = importer.get("load")?;
+    modules.importers.borrow_mut().insert(base, Persistent::save(ctx, load.clone()));
+    Ok(load)
+}
+
+fn helpers(ctx: Ctx) -> rquickjs::Result<Object> {
+    modules(ctx)?.helpers.clone().restore(ctx)
+}
+
+/// The compiled name of a module evaluated by the embedder, for the imports of `JsModule`.
+/// Other modules import them through the resolver like any module.
+pub(crate) fn evaluated_name(ctx: Ctx, base: &str, name: &str) -> Option<String> {
+    match base {
+        // Imports the compiled name
+        EVALUATOR => return Some(name.to_owned()),
+        IMPORTER => {}
+        _ => return None,
+    }
+    let modules = context_data::get::<Modules>(ctx)?;
+    let evaluated = modules.evaluated.borrow().get(name).cloned();
+    evaluated
+}
+
+/// Records what an import of `JsModule::import` resolved to
+pub(crate) fn resolved(ctx: Ctx, base: &str, name: &str) {
+    if base == IMPORTER {
+        if let Some(modules) = context_data::get::<Modules>(ctx) {
+            *modules.resolved.borrow_mut() = Some(name.to_owned());
+        }
+    }
+}
+
+/// An evaluated module of a context
+#[derive(Clone)]
+pub struct JsModule {
+    /// Declared first, so it's freed while `context` keeps the runtime alive
+    namespace: Persistent<Object<'static>>,
+    context: Context,
+    specifier: String,
+}
+
+impl JsModule {
+    /// Imports the module like `import * as namespace from "<specifier>"` would, also modules
+    /// evaluated by the embedder by their name. Modules resolving to the same name share
+    /// their namespace, and are evaluated once.
+    pub(crate) fn import(rt: &Runtime, context: &Context, specifier: &str) -> Result<Self, JsError> {
+        let loaded = load(rt, context, IMPORTER, specifier);
+        let namespace = context.with(|ctx| -> Result<_, JsError> {
+            let modules = modules(ctx)?;
+            let resolved = modules.resolved.borrow_mut().take();
+            Ok(match resolved {
+                Some(name) => modules.imported.borrow_mut().entry(name).or_insert(loaded?).clone(),
+                None => loaded?,
+            })
+        })?;
+        Ok(JsModule { namespace, context: context.clone(), specifier: specifier.to_owned() })
+    }
+
+    /// Evaluates the module `compile` creates, keeping its exports under its name.
+    /// `compile` gets a name no other module evaluated this way has and returns the
+    /// name it compiled the module under.
+    pub(crate) fn evaluate(
+        rt: &Runtime,
+        context: &Context,
+        name: &str,
+        compile: impl for<'js> FnOnce(Ctx<'js>, &str) -> Result<String, JsError>,
+    ) -> Result<Self, JsError> {
+        let compiled = context.with(|ctx| -> Result<_, JsError> {
+            let modules = modules(ctx)?;
+            let taken = |name: &String| modules.evaluated.borrow().values().any(|n| n == name);
+            let free = (1..)
+                .map(|n| if n == 1 { name.to_owned() } else { format!("{}#{}", name, n) })
+                .find(|n| !taken(n))
+                .unwrap();
+            let compiled = compile(ctx, &free)?;
+            if compiled != free && taken(&compiled) {
+                return Err(JsError::new("Error", format!("a module named \"{}\" was evaluated before", compiled)));
+            }
+            modules.evaluated.borrow_mut().insert(name.to_owned(), compiled.clone());
+            Ok(compiled)
+        })?;
+        let namespace = load(rt, context, EVALUATOR, &compiled)?;
+        Ok(JsModule { namespace, context: context.clone(), specifier: name.to_owned() })
+    }
+
+    pub fn specifier(&self) -> &str {
+        &self.specifier
+    }
+
+    /// Adds another reference to the context of the module
+    pub fn context(&self) -> Context {
+        self.context.clone()
+    }
+
+    /// Calls the exported function with `args`, a tuple (or `()`) whose elements are the
+    /// arguments, and waits for the returned promise, if any. Exceptions and rejections
+    /// are returned as errors. The executor of the runtime settles the call, see
+    /// `JsRuntime::spawn_executor`.
+    pub async fn call_export<A, R>(&self, name: &str, args: A) -> Result<R, JsError>
+    where
+        A: Serialize,
+        R: DeserializeOwned + 'static,
+    {
+        let settled: Promise<Serde<Result<R, JsError>>> = self.context.with(|ctx| {
+            let export: Value = self.namespace(ctx)?.get(name)?;
+            if !export.is_function() {
+                return Err(JsError::type_error(format!(
+                    "export \"{}\" of \"{}\" is not a function",
+                    name, self.specifier
+                )));
+            }
+            let args = match to_value(ctx, &args)? {
+                args if args.is_array() => args,
+                args if args.is_null() => rquickjs::Array::new(ctx)?.into_value(),
+                _ => return Err(JsError::type_error("the arguments must be a tuple")),
+            };
+            let call: Function = helpers(ctx)?.get("call")?;
+            Ok(call.call((export, args))?)
+        })?;
+        let Serde(result) = settled.await?;
+        result
+    }
+
+    fn namespace<'js>(&self, ctx: Ctx<'js>) -> Result<Object<'js>, JsError> {
+        Ok(self.namespace.clone().restore(ctx)?)
+    }
+}
+
+/// The namespace of the module `import()` in a module named `base` gets. The jobs of the
+/// runtime run until it settles, modules waiting for more than jobs fail.
+fn load(rt: &Runtime, context: &Context, base: &'static str, specifier: &str) -> Result<Persistent<Object<'static>>, JsError> {
+    let state = context.with(|ctx| -> Result<_, JsError> {
+        let load = importer(ctx, base)?;
+        let track: Function = helpers(ctx)?.get("track")?;
+        let state: Object = track.call((load.call::<_, Value>((specifier,))?,))?;
+        Ok(Persistent::save(ctx, state))
+    })?;
+    let settled = || context.with(|ctx| state.clone().restore(ctx).and_then(|s| s.contains_key("settled")));
+    while !settled()? {
+        // Failures of other jobs are theirs
+        if let Ok(false) = rt.execute_pending_job() {
+            let message = format!("\"{}\" didn't finish evaluating, only modules without pending operations can be imported", specifier);
+            return Err(JsError::new("Error", message));
+        }
+    }
+    context.with(|ctx| {
+        let state = state.restore(ctx)?;
+        match state.get::<_, Option<Object>>("namespace")? {
+            Some(namespace) => Ok(Persistent::save(ctx, namespace)),
+            None => Err(js_serde::from_value(ctx, state.get("error")?)?),
+        }
+    })
+}
+
+/// Compiles the module, keeping the class of a thrown `SyntaxError`
+pub(crate) fn compile(ctx: Ctx, name: &str, source: Vec<u8>) -> Result<(), JsError> {
+    let flags = qjs::JS_EVAL_TYPE_MODULE | qjs::JS_EVAL_FLAG_COMPILE_ONLY;
+    // The compiled module stays in the module list of the context, it isn't freed
+    unsafe { eval(ctx, name, source, flags) }.map(|_| ())
+}
+
+/// Evaluates the script like `Ctx::eval`, keeping the class of a thrown error
+pub(crate) fn eval_script<'js>(ctx: Ctx<'js>, source: &str) -> Result<Value<'js>, JsError> {
+    let flags = qjs::JS_EVAL_TYPE_GLOBAL | qjs::JS_EVAL_FLAG_STRICT;
+    unsafe {
+        let value = eval(ctx, SCRIPT, source.as_bytes().to_vec(), flags)?;
+        Ok(from_raw(ctx, value)?)
+    }
+}
+
+/// `JS_Eval`, a thrown value becomes an error with its class
+///
+/// # Safety
+/// The caller owns the returned value
+unsafe fn eval(ctx: Ctx, name: &str, source: Vec<u8>, flags: u32) -> Result<qjs::JSValue, JsError> {
+    let syntax_error = |_| JsError::new("SyntaxError", "the source contains a NUL character");
+    let (name, source) = (CString::new(name).map_err(syntax_error)?, CString::new(source).map_err(syntax_error)?);
+    let len = source.as_bytes().len();
+    let value = qjs::JS_Eval(ctx.as_ptr(), source.as_ptr(), len as _, name.as_ptr(), flags as _);
+    if qjs::JS_VALUE_GET_NORM_TAG(value) == qjs::JS_TAG_EXCEPTION {
+        let thrown = from_raw(ctx, qjs::JS_GetException(ctx.as_ptr()))?;
+        return Err(describe(ctx, thrown)?);
+    }
+    Ok(value)
+}
+
+/// The `describe` helper of module.js, turning a thrown value into a `JsError`
+pub(crate) fn describe_function(ctx: Ctx) -> rquickjs::Result<Function> {
+    helpers(ctx)?.get("describe")
+}
+
+/// The thrown value as an error, with the class of `Error`s
+pub(crate) fn describe<'js>(ctx: Ctx<'js>, thrown: Value<'js>) -> Result<JsError, JsError> {
+    let describe: Function = helpers(ctx)?.get("describe")?;
+    Ok(js_serde::from_value(ctx, describe.call((thrown,))?)?)
+}
+
+/// Settles the value, waiting for it if it's a promise. Rejections are errors.
+pub(crate) fn settle<'js, R>(ctx: Ctx<'js>, value: Value<'js>) -> Result<Promise<Serde<Result<R, JsError>>>, JsError>
+where
+    R: DeserializeOwned + 'static,
+{
+    let settle: Function = helpers(ctx)?.get("settle")?;
+    Ok(settle.call((value,))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use transpilers::Transpilers;
+    use crate::cache::NoCache;
+    use crate::loader::ExerumLoader;
+    use crate::resolver::ExerumResolver;
+    use crate::runtime::JsRuntime;
+
+    #[test]
+    fn test_call_export() {
+        let loader = ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        let jsrt = JsRuntime::new(loader, ExerumResolver::new(".")).with_executor().unwrap();
+        jsrt.block_on(async {
+            let source = r#"
+            export const add = (a, b) => a + b;
+            export const later = async (name) => ({ greeting: "hello " + name });
+            export const fail = async () => { throw new RangeError("nope"); };
+            export const version = 1;
+            "#;
+            let module = jsrt.eval_module("calc", source).unwrap();
+            assert_eq!(module.specifier(), "calc");
+            assert_eq!(module.call_export::<_, i32>("add", (1, 2)).await.unwrap(), 3);
+            let later: std::collections::BTreeMap<String, String> = module.call_export("later", ("ada",)).await.unwrap();
+            assert_eq!(later["greeting"], "hello ada");
+
+            let e = module.call_export::<_, ()>("fail", ()).await.unwrap_err();
+            assert_eq!((e.name(), e.message()), ("RangeError", "nope"));
+            let e = module.call_export::<_, ()>("version", ()).await.unwrap_err();
+            assert_eq!(e.to_string(), "TypeError: export \"version\" of \"calc\" is not a function");
+            assert!(module.call_export::<_, bool>("add", (1, 2)).await.is_err());
+
+            let imported = jsrt.import("calc").unwrap();
+            assert_eq!(imported.call_export::<_, String>("add", ("a", "b")).await.unwrap(), "ab");
+            assert!(jsrt.import("./missing.js").is_err());
+
+            // Exports are live, and modules resolving to the same name are shared
+            let handler = jsrt.import("./test_data/src/module/handler.js").unwrap();
+            assert_eq!(handler.call_export::<_, String>("handler", ()).await.unwrap(), "first");
+            handler.call_export::<_, ()>("swap", ()).await.unwrap();
+            assert_eq!(handler.call_export::<_, String>("handler", ()).await.unwrap(), "second");
+            let again = jsrt.import("test_data/src/module/handler.js").unwrap();
+            assert_eq!(again.call_export::<_, String>("handler", ()).await.unwrap(), "second");
+
+            // Thrown errors keep their class
+            let e = jsrt.eval_module("throws", "throw new RangeError(\"at load\");").unwrap_err();
+            assert_eq!((e.name(), e.message()), ("RangeError", "at load"));
+            let e = jsrt.eval_module("invalid", "export const = 1;").unwrap_err();
+            assert_eq!(e.name(), "SyntaxError");
+            let e = jsrt.eval::<()>("null.property").await.unwrap_err();
+            assert_eq!(e.name(), "TypeError");
+
+            let replaced = jsrt.eval_module("calc", "export const add = (a, b) => a * b;").unwrap();
+            assert_eq!(replaced.call_export::<_, i32>("add", (2, 3)).await.unwrap(), 6);
+            assert_eq!(module.call_export::<_, i32>("add", (2, 3)).await.unwrap(), 5);
+            let imported = jsrt.import("calc").unwrap();
+            assert_eq!(imported.call_export::<_, i32>("add", (2, 3)).await.unwrap(), 6);
+            jsrt.rt().idle().await;
+        });
+    }
+}
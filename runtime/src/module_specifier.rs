@@ -1,6 +1,18 @@
+use crate::ops::HOST_SCHEME;
+
+/// Scheme of the native modules the embedder defines, see `crate::native_module`
+pub const NATIVE_SCHEME: &str = "exerum:";
+
+/// Scheme the resolver gives to modules the permissions deny, the loader serves them
+/// as a module throwing the `PermissionDenied` error
+pub const DENIED_SCHEME: &str = "exerum-denied:";
+
 /// Module specifier may have a form of
 /// 1) {transpiler_name}:{path}
 /// 2) {path}
+/// where path may be a remote specifier (`https://...`, `http://...` or `npm:...`).
+/// Native modules (`exerum:...`), extension modules (`host:...`) and denied modules
+/// (`exerum-denied:...`) are reserved, their whole specifier is the path.
 pub struct ModuleSpecifier<'a> {
     transpiler_name: Option<&'a str>,
     path: &'a str
@@ -8,6 +20,12 @@ pub struct ModuleSpecifier<'a> {
 
 impl<'a> ModuleSpecifier<'a> {
     pub fn from(name: &'a str) -> ModuleSpecifier<'a> {
+        if crate::vendor::is_remote(name) || is_native(name) || name.starts_with(DENIED_SCHEME) {
+            return ModuleSpecifier {
+                transpiler_name: None,
+                path: name
+            };
+        }
         let mut index = 0;
         let mut transpiler_name = None;
         for c in name.chars() {
@@ -36,6 +54,16 @@ impl<'a> ModuleSpecifier<'a> {
         self.path
     }
 
+    /// Whether the path is served from the vendor directory, see `crate::vendor`
+    pub fn is_remote(&self) -> bool {
+        crate::vendor::is_remote(self.path)
+    }
+
+    /// Whether the module is defined by the host instead of a file
+    pub fn is_native(&self) -> bool {
+        is_native(self.path)
+    }
+
     pub fn extension(&self) -> Option<&str> {
         std::path::Path::new(self.path).extension().map(|os_str| {
             os_str.to_str().expect("non-utf8")
@@ -43,6 +71,11 @@ impl<'a> ModuleSpecifier<'a> {
     }
 }
 
+/// Whether the specifier names a native or an extension module, which the runtime serves
+pub fn is_native(specifier: &str) -> bool {
+    specifier.starts_with(NATIVE_SCHEME) || specifier.starts_with(HOST_SCHEME)
+}
+
 #[test]
 fn test_module_specifier() {
     let ms = ModuleSpecifier::from("tr_name:./mod_name");
@@ -70,4 +103,27 @@ fn test_empty_path_and_transpiler() {
 fn test_empty_transpiler() {
     let ms = ModuleSpecifier::from(":./test");
     assert_eq!(ms.transpiler(), Some(""))
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_remote() {
+    let ms = ModuleSpecifier::from("https://deno.land/std/path/mod.ts");
+    assert_eq!(ms.path(), "https://deno.land/std/path/mod.ts");
+    assert_eq!(ms.transpiler(), None);
+    assert!(ms.is_remote());
+
+    let ms = ModuleSpecifier::from("typescript:npm:preact@10");
+    assert_eq!(ms.path(), "npm:preact@10");
+    assert_eq!(ms.transpiler(), Some("typescript"));
+    assert!(ms.is_remote());
+}
+
+#[test]
+fn test_native() {
+    let ms = ModuleSpecifier::from("exerum:db");
+    assert_eq!(ms.path(), "exerum:db");
+    assert_eq!(ms.transpiler(), None);
+    assert!(ms.is_native());
+    assert!(ModuleSpecifier::from("host:math").is_native());
+    assert!(!ModuleSpecifier::from("typescript:./db.ts").is_native());
+}
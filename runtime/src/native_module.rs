@@ -0,0 +1,192 @@
+//! Native modules: ES modules whose exports the embedder defines in Rust,
+//! imported as `exerum:<name>`.
+//!
+//! ```ignore
+//! let db = NativeModule::new()
+//!     .constant("version", 3)
+//!     .function("query", |sql: String| Ok::<_, OpError>(run_query(&sql)))
+//!     .async_function("connect", |url: String| async move { Ok::<_, OpError>(open(url).await?) });
+//! jsrt.register_module("db", db)?;
+//! ```
+//! ```js
+//! import { query, version } from "exerum:db";
+//! ```
+//! Functions behave like the ops of `crate::ops` and check `Permission::Op` with the
+//! specifier of the module. The exports are created in every context of the runtime, and
+//! modules served by `ExerumLoader` or a bundle import them like any other module.
+//! Bundles leave these imports to the runtime running them.
+use std::rc::Rc;
+use rquickjs::{Ctx, Error, Object, Result, Value};
+use serde::Serialize;
+use stdlib::permissions::Permissions;
+use crate::ops::{install_exports, is_identifier, prelude, AsyncOp, OpDef, SyncOp, EXPORTS};
+
+/// The exports of a native module
+#[derive(Clone, Default)]
+pub struct NativeModule {
+    exports: Vec<(String, Export)>,
+}
+
+#[derive(Clone)]
+enum Export {
+    Op(OpDef),
+    Value(Rc<dyn ExportValue>),
+}
+
+/// Creates an exported value in a context
+trait ExportValue {
+    fn create<'js>(&self, ctx: Ctx<'js>) -> Result<Value<'js>>;
+}
+
+struct Constant<T>(T);
+
+impl<T: Serialize> ExportValue for Constant<T> {
+    fn create<'js>(&self, ctx: Ctx<'js>) -> Result<Value<'js>> {
+        js_serde::to_value(ctx, &self.0)
+            .map_err(|e| Error::new_into_js_message(std::any::type_name::<T>(), "value", e.to_string()))
+    }
+}
+
+struct Created<F>(F);
+
+impl<F: for<'js> Fn(Ctx<'js>) -> Result<Value<'js>>> ExportValue for Created<F> {
+    fn create<'js>(&self, ctx: Ctx<'js>) -> Result<Value<'js>> {
+        (self.0)(ctx)
+    }
+}
+
+impl NativeModule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exports a value converted with `js_serde`.
+    /// Panics if the name is neither a javascript identifier nor `default`.
+    pub fn constant<T: Serialize + 'static>(self, name: &str, value: T) -> Self {
+        self.export(name, Export::Value(Rc::new(Constant(value))))
+    }
+
+    /// Exports a synchronous function, see `crate::ops::SyncOp`.
+    /// Panics if the name is neither a javascript identifier nor `default`.
+    pub fn function<Args: 'static>(self, name: &str, op: impl SyncOp<Args>) -> Self {
+        self.export(name, Export::Op(OpDef::sync(name, op)))
+    }
+
+    /// Exports a function returning a promise, see `crate::ops::AsyncOp`.
+    /// Panics if the name is neither a javascript identifier nor `default`.
+    pub fn async_function<Args: 'static>(self, name: &str, op: impl AsyncOp<Args>) -> Self {
+        self.export(name, Export::Op(OpDef::asynchronous(name, op)))
+    }
+
+    /// Exports the value `create` makes in each context, like a class or an object with methods.
+    /// Panics if the name is neither a javascript identifier nor `default`.
+    pub fn value<F>(self, name: &str, create: F) -> Self
+    where
+        F: for<'js> Fn(Ctx<'js>) -> Result<Value<'js>> + 'static,
+    {
+        self.export(name, Export::Value(Rc::new(Created(create))))
+    }
+
+    pub fn export_names(&self) -> impl Iterator<Item = &str> {
+        self.exports.iter().map(|(name, _)| name.as_str())
+    }
+
+    fn export(mut self, name: &str, export: Export) -> Self {
+        assert!(
+            name == "default" || is_identifier(name),
+            "export name \"{}\" is not a javascript identifier",
+            name
+        );
+        self.exports.retain(|(n, _)| n != name);
+        self.exports.push((name.to_owned(), export));
+        self
+    }
+
+    /// Source of the module `specifier`, which exports the values installed in the context
+    pub(crate) fn source(&self, specifier: &str) -> String {
+        let missing = format!("the native module \"{}\" is not installed in this context", specifier);
+        let mut source = format!(
+            "const exports = import.meta.{};\nif (exports === undefined) throw new Error({:?});\n",
+            EXPORTS, missing
+        );
+        for (name, _) in &self.exports {
+            if name == "default" {
+                source.push_str("export default exports.default;\n");
+            } else {
+                source.push_str(&format!("export const {0} = exports.{0};\n", name));
+            }
+        }
+        source
+    }
+
+    /// Creates the exports in the context, calls of the functions check `permissions`
+    pub(crate) fn install(&self, ctx: Ctx, specifier: &str, permissions: &Permissions) -> Result<()> {
+        let prelude = prelude(ctx)?;
+        let exports = Object::new(ctx)?;
+        for (name, export) in &self.exports {
+            let value = match export {
+                Export::Op(def) => def.function(&prelude, permissions, specifier)?.into_value(),
+                Export::Value(value) => value.create(ctx)?,
+            };
+            exports.set(name.as_str(), value)?;
+        }
+        install_exports(ctx, specifier, exports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rquickjs::{Function, Module};
+    use transpilers::Transpilers;
+    use crate::cache::NoCache;
+    use crate::loader::ExerumLoader;
+    use crate::ops::OpError;
+    use crate::permissions::Permissions;
+    use crate::resolver::ExerumResolver;
+    use crate::runtime::JsRuntime;
+    use super::NativeModule;
+
+    fn greeter() -> NativeModule {
+        NativeModule::new()
+            .constant("punctuation", "!")
+            .function("greet", |name: String| Ok::<_, OpError>(format!("hello {}", name)))
+            .value("default", |ctx| ctx.eval::<Function, _>("(s) => s.toUpperCase()").map(|f| f.into_value()))
+    }
+
+    #[test]
+    fn test_native_module() {
+        let loader = ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        let mut jsrt = JsRuntime::with_permissions(loader, ExerumResolver::new("."), Permissions::allow_all());
+        jsrt.register_module("greeter", greeter()).unwrap();
+        let message: String = jsrt.context().with(|ctx| {
+            let source = r#"
+            import shout, { greet, punctuation } from "exerum:greeter";
+            export const message = shout(greet("ada")) + punctuation;
+            "#;
+            Module::new(ctx, "main", source).unwrap().eval().unwrap().get("message").unwrap()
+        });
+        assert_eq!(message, "HELLO ADA!");
+        jsrt.context().with(|ctx| {
+            assert!(Module::new(ctx, "missing", r#"import "exerum:missing";"#).unwrap().eval().is_err());
+            assert!(ctx.eval::<bool, _>(r#"!("__exerum_native_exports" in globalThis)"#).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_native_module_permissions() {
+        let loader = ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        let permissions = Permissions::none();
+        let mut jsrt = JsRuntime::with_permissions(loader, ExerumResolver::new("."), permissions);
+        jsrt.register_module("greeter", greeter()).unwrap();
+        let denied: String = jsrt.context().with(|ctx| {
+            let source = r#"
+            import { greet, punctuation } from "exerum:greeter";
+            let denied;
+            try { greet("ada"); } catch (e) { denied = e.permission.target + punctuation; }
+            export { denied };
+            "#;
+            Module::new(ctx, "main", source).unwrap().eval().unwrap().get("denied").unwrap()
+        });
+        assert_eq!(denied, "exerum:greeter!");
+    }
+}
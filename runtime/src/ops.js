@@ -0,0 +1,13 @@
+// Wraps the host operations of ops.rs, which return `{ value }` or `{ __error, message }`.
+// Failures are thrown as instances of the global error class named by `__error`.
+(() => {
+    const unwrap = (result) => {
+        if (result.__error === undefined) return result.value;
+        const ErrorClass = typeof globalThis[result.__error] === "function" ? globalThis[result.__error] : Error;
+        throw new ErrorClass(result.message, result.permission);
+    };
+    return {
+        sync: (op) => (...args) => unwrap(op(...args)),
+        async: (op) => async (...args) => unwrap(await op(...args)),
+    };
+})()
@@ -0,0 +1,535 @@
+//! Host operations: Rust functions callable from javascript, grouped into extensions
+//! that modules import as `host:<extension>`.
+//!
+//! ```ignore
+//! let timers = Extension::new("timers")
+//!     .op("now", || Ok::<_, OpError>(now_millis()))
+//!     .timer_op("sleep", |ms: u64| async move {
+//!         tokio::time::sleep(Duration::from_millis(ms)).await;
+//!         Ok::<_, OpError>(())
+//!     });
+//! jsrt.register_extension(timers)?;
+//! ```
+//! ```js
+//! import { now, sleep } from "host:timers";
+//! await sleep(10);
+//! ```
+//! Arguments and results are converted with `js_serde`. Missing arguments are `undefined`,
+//! so `Option` arguments can be left out. Async ops return a promise of their result.
+//! A failed op throws: an `OpError` names its error class, conversion failures are
+//! `TypeError`s. Calls check `Permission::Op` with the name of the extension.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use futures::future::LocalBoxFuture;
+use js_serde::{from_value, to_value};
+use rquickjs::{
+    generic_loader, Ctx, Error, Func, Function, IntoJs, Loaded, Loader, Module, Object, Persistent, Promised, Resolver,
+    Rest, Result, Script, Value,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use stdlib::permissions::{PermissionDenied, Permissions};
+use crate::context_data;
+use crate::event_loop;
+use crate::module;
+use crate::module_specifier::is_native;
+
+/// Specifiers of extension modules start with it
+pub const HOST_SCHEME: &str = "host:";
+const PRELUDE: &str = include_str!("ops.js");
+/// Property of `import.meta` holding the exports of an extension or native module
+pub(crate) const EXPORTS: &str = "exports";
+
+pub type OpResult<T> = std::result::Result<T, OpError>;
+
+/// Failure of an op, thrown as an instance of the named error class
+#[derive(Debug, Clone)]
+pub struct OpError {
+    class: String,
+    message: String,
+    denied: Option<PermissionDenied>,
+}
+
+impl OpError {
+    pub fn new(message: impl fmt::Display) -> Self {
+        OpError::with_class("Error", message)
+    }
+
+    pub fn type_error(message: impl fmt::Display) -> Self {
+        OpError::with_class("TypeError", message)
+    }
+
+    pub fn range_error(message: impl fmt::Display) -> Self {
+        OpError::with_class("RangeError", message)
+    }
+
+    /// An error of the global class named `class`, or `Error` if there is none
+    pub fn with_class(class: &str, message: impl fmt::Display) -> Self {
+        OpError { class: class.to_owned(), message: message.to_string(), denied: None }
+    }
+
+    pub fn class(&self) -> &str {
+        &self.class
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn to_js<'js>(&self, ctx: Ctx<'js>) -> Result<Value<'js>> {
+        if let Some(denied) = &self.denied {
+            return denied.to_js(ctx);
+        }
+        let error = Object::new(ctx)?;
+        error.set("__error", self.class.as_str())?;
+        error.set("message", self.message.as_str())?;
+        Ok(error.into_value())
+    }
+}
+
+impl fmt::Display for OpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.class, self.message)
+    }
+}
+
+impl From<PermissionDenied> for OpError {
+    fn from(denied: PermissionDenied) -> Self {
+        OpError { class: "PermissionDenied".to_owned(), message: denied.to_string(), denied: Some(denied) }
+    }
+}
+
+impl From<js_serde::Error> for OpError {
+    fn from(e: js_serde::Error) -> Self {
+        OpError::type_error(e)
+    }
+}
+
+impl From<std::io::Error> for OpError {
+    fn from(e: std::io::Error) -> Self {
+        OpError::new(e)
+    }
+}
+
+impl From<String> for OpError {
+    fn from(message: String) -> Self {
+        OpError::new(message)
+    }
+}
+
+impl From<&str> for OpError {
+    fn from(message: &str) -> Self {
+        OpError::new(message)
+    }
+}
+
+/// Arguments of an op call, converted in order
+pub struct Arguments<'a, 'js> {
+    ctx: Ctx<'js>,
+    op: &'a str,
+    values: std::vec::IntoIter<Value<'js>>,
+    position: usize,
+}
+
+impl<'a, 'js> Arguments<'a, 'js> {
+    pub fn next<T: DeserializeOwned>(&mut self) -> OpResult<T> {
+        self.position += 1;
+        let value = self.values.next().unwrap_or_else(|| Value::new_undefined(self.ctx));
+        from_value(self.ctx, value)
+            .map_err(|e| OpError::type_error(format!("{}: argument {}: {}", self.op, self.position, e)))
+    }
+}
+
+/// Functions usable as synchronous ops: closures of up to six arguments
+/// returning `Result<T, E>`, where `T: Serialize` and `E: Into<OpError>`
+pub trait SyncOp<Args>: 'static {
+    type Output: Serialize;
+
+    fn call(&self, args: &mut Arguments) -> OpResult<Self::Output>;
+}
+
+/// Functions usable as async ops: closures of up to six arguments
+/// returning a future of `Result<T, E>`, like synchronous ops
+pub trait AsyncOp<Args>: 'static {
+    type Output: Serialize;
+
+    fn call(&self, args: &mut Arguments) -> OpResult<LocalBoxFuture<'static, OpResult<Self::Output>>>;
+}
+
+macro_rules! impl_ops {
+    ($($arg:ident)*) => {
+        impl<F, T, E, $($arg,)*> SyncOp<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> std::result::Result<T, E> + 'static,
+            T: Serialize,
+            E: Into<OpError>,
+            $($arg: DeserializeOwned,)*
+        {
+            type Output = T;
+
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, args: &mut Arguments) -> OpResult<T> {
+                $(let $arg: $arg = args.next()?;)*
+                self($($arg),*).map_err(Into::into)
+            }
+        }
+
+        impl<F, Fut, T, E, $($arg,)*> AsyncOp<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> Fut + 'static,
+            Fut: Future<Output = std::result::Result<T, E>> + 'static,
+            T: Serialize + 'static,
+            E: Into<OpError> + 'static,
+            $($arg: DeserializeOwned,)*
+        {
+            type Output = T;
+
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, args: &mut Arguments) -> OpResult<LocalBoxFuture<'static, OpResult<T>>> {
+                $(let $arg: $arg = args.next()?;)*
+                let future = self($($arg),*);
+                Ok(Box::pin(async move { future.await.map_err(Into::into) }))
+            }
+        }
+    };
+}
+
+impl_ops!();
+impl_ops!(A1);
+impl_ops!(A1 A2);
+impl_ops!(A1 A2 A3);
+impl_ops!(A1 A2 A3 A4);
+impl_ops!(A1 A2 A3 A4 A5);
+impl_ops!(A1 A2 A3 A4 A5 A6);
+
+/// `{ value }`, or the `{ __error, message }` the prelude throws
+struct Outcome<T>(OpResult<T>);
+
+impl<'js, T: Serialize> IntoJs<'js> for Outcome<T> {
+    fn into_js(self, ctx: Ctx<'js>) -> Result<Value<'js>> {
+        match self.0.and_then(|value| Ok(to_value(ctx, &value)?)) {
+            Ok(value) => {
+                let outcome = Object::new(ctx)?;
+                outcome.set("value", value)?;
+                Ok(outcome.into_value())
+            }
+            Err(e) => e.to_js(ctx),
+        }
+    }
+}
+
+/// An op with its argument types erased
+trait HostOp {
+    fn call<'js>(&self, ctx: Ctx<'js>, name: &str, args: Vec<Value<'js>>) -> Result<Value<'js>>;
+}
+
+struct SyncCall<F, Args>(F, PhantomData<fn(Args)>);
+
+impl<F: SyncOp<Args>, Args> HostOp for SyncCall<F, Args> {
+    fn call<'js>(&self, ctx: Ctx<'js>, name: &str, args: Vec<Value<'js>>) -> Result<Value<'js>> {
+        let mut args = Arguments { ctx, op: name, values: args.into_iter(), position: 0 };
+        Outcome(self.0.call(&mut args)).into_js(ctx)
+    }
+}
+
+/// The call counts as `kind` of pending work until its future completes
+struct AsyncCall<F, Args>(F, event_loop::Kind, PhantomData<fn(Args)>);
+
+impl<F: AsyncOp<Args>, Args> HostOp for AsyncCall<F, Args> {
+    fn call<'js>(&self, ctx: Ctx<'js>, name: &str, args: Vec<Value<'js>>) -> Result<Value<'js>> {
+        let mut args = Arguments { ctx, op: name, values: args.into_iter(), position: 0 };
+        match self.0.call(&mut args) {
+            Ok(future) => {
+                let pending = event_loop::start(ctx, self.1);
+                Promised(async move {
+                    let outcome = Outcome(future.await);
+                    drop(pending);
+                    Ok(outcome)
+                })
+                .into_js(ctx)
+            }
+            Err(e) => Outcome::<()>(Err(e)).into_js(ctx),
+        }
+    }
+}
+
+/// An op with its name, as defined in an extension or a native module
+#[derive(Clone)]
+pub(crate) struct OpDef {
+    pub(crate) name: String,
+    is_async: bool,
+    op: Rc<dyn HostOp>,
+}
+
+impl OpDef {
+    pub(crate) fn sync<Args: 'static>(name: &str, op: impl SyncOp<Args>) -> Self {
+        OpDef { name: name.to_owned(), is_async: false, op: Rc::new(SyncCall(op, PhantomData)) }
+    }
+
+    pub(crate) fn asynchronous<Args: 'static>(name: &str, op: impl AsyncOp<Args>) -> Self {
+        OpDef::counted(name, op, event_loop::Kind::HostOp)
+    }
+
+    pub(crate) fn timer<Args: 'static>(name: &str, op: impl AsyncOp<Args>) -> Self {
+        OpDef::counted(name, op, event_loop::Kind::Timer)
+    }
+
+    fn counted<Args: 'static>(name: &str, op: impl AsyncOp<Args>, kind: event_loop::Kind) -> Self {
+        OpDef { name: name.to_owned(), is_async: true, op: Rc::new(AsyncCall(op, kind, PhantomData)) }
+    }
+
+    /// The javascript function calling the op, once `permissions` grant the op `target`.
+    /// `prelude` comes from `prelude()`.
+    pub(crate) fn function<'js>(&self, prelude: &Object<'js>, permissions: &Permissions, target: &str) -> Result<Function<'js>> {
+        let (op, name) = (self.op.clone(), self.name.clone());
+        let (target, permissions) = (target.to_owned(), permissions.clone());
+        let raw = Func::new("op", move |ctx: Ctx, args: Rest<Value>| -> Result<Value> {
+            if let Err(denied) = permissions.check_op(&target) {
+                return denied.to_js(ctx);
+            }
+            op.call(ctx, &name, args.0)
+        });
+        let wrap: Function = prelude.get(if self.is_async { "async" } else { "sync" })?;
+        wrap.call((raw,))
+    }
+}
+
+/// Wrappers throwing the errors of ops
+pub(crate) fn prelude(ctx: Ctx) -> Result<Object> {
+    ctx.eval(PRELUDE)
+}
+
+/// Named group of ops, importable as `host:<name>`
+#[derive(Clone)]
+pub struct Extension {
+    name: String,
+    ops: Vec<OpDef>,
+}
+
+impl Extension {
+    pub fn new(name: &str) -> Self {
+        Extension { name: name.to_owned(), ops: vec![] }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Adds a synchronous op. Panics if the name isn't a javascript identifier.
+    pub fn op<Args: 'static>(self, name: &str, op: impl SyncOp<Args>) -> Self {
+        self.with_op(OpDef::sync(name, op))
+    }
+
+    /// Adds an op returning a promise, which the executor of the runtime drives.
+    /// Panics if the name isn't a javascript identifier.
+    pub fn async_op<Args: 'static>(self, name: &str, op: impl AsyncOp<Args>) -> Self {
+        self.with_op(OpDef::asynchronous(name, op))
+    }
+
+    /// Adds an async op whose pending calls are timers of the runtime rather than host ops,
+    /// like a `sleep`. See `PendingWork`.
+    pub fn timer_op<Args: 'static>(self, name: &str, op: impl AsyncOp<Args>) -> Self {
+        self.with_op(OpDef::timer(name, op))
+    }
+
+    fn with_op(mut self, def: OpDef) -> Self {
+        assert!(is_identifier(&def.name), "op name \"{}\" is not a javascript identifier", def.name);
+        self.ops.retain(|d| d.name != def.name);
+        self.ops.push(def);
+        self
+    }
+
+    /// Source of the `host:<name>` module, which exports the ops installed in the context
+    pub(crate) fn source(&self) -> String {
+        let missing = format!("the extension \"{}\" is not installed in this context", self.name);
+        let mut source = format!(
+            "const ops = import.meta.{};\nif (ops === undefined) throw new Error({:?});\n",
+            EXPORTS, missing
+        );
+        for def in &self.ops {
+            source.push_str(&format!("export const {0} = ops.{0};\n", def.name));
+        }
+        source.push_str("export default ops;\n");
+        source
+    }
+
+    /// Defines the ops in the context, their calls check `permissions`
+    pub(crate) fn install(&self, ctx: Ctx, permissions: &Permissions) -> Result<()> {
+        let prelude = prelude(ctx)?;
+        let ops = Object::new(ctx)?;
+        for def in &self.ops {
+            ops.set(def.name.as_str(), def.function(&prelude, permissions, &self.name)?)?;
+        }
+        install_exports(ctx, &format!("{}{}", HOST_SCHEME, self.name), ops)
+    }
+}
+
+/// Exports of the extensions and native modules installed in a context, by specifier.
+/// Only the `import.meta` of their modules gets them, scripts can't reach them otherwise.
+#[derive(Default)]
+struct Installed(RefCell<BTreeMap<String, Persistent<Object<'static>>>>);
+
+/// Keeps the exports of the module `specifier` for the context
+pub(crate) fn install_exports<'js>(ctx: Ctx<'js>, specifier: &str, exports: Object<'js>) -> Result<()> {
+    let installed = context_data::get_or_init(ctx, || Ok::<_, Error>(Installed::default()))?;
+    let previous = installed.0.borrow_mut().insert(specifier.to_owned(), Persistent::save(ctx, exports));
+    // Dropping it may run finalizers
+    drop(previous);
+    Ok(())
+}
+
+fn installed_exports<'js>(ctx: Ctx<'js>, specifier: &str) -> Result<Option<Object<'js>>> {
+    let exports = context_data::get::<Installed>(ctx).and_then(|installed| installed.0.borrow().get(specifier).cloned());
+    exports.map(|exports| exports.restore(ctx)).transpose()
+}
+
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+/// Sources of the extension and native modules by specifier, shared with the loader
+pub(crate) type HostModules = Rc<RefCell<BTreeMap<String, String>>>;
+
+/// Resolves the `host:` and `exerum:` specifiers of registered modules and the imports of
+/// `JsModule`, passes the others to the runtime's resolver
+pub(crate) struct HostResolver {
+    pub(crate) modules: HostModules,
+    pub(crate) resolver: Box<dyn Resolver>,
+}
+
+impl Resolver for HostResolver {
+    fn resolve<'js>(&mut self, ctx: Ctx<'js>, base: &str, name: &str) -> Result<String> {
+        if let Some(evaluated) = module::evaluated_name(ctx, base, name) {
+            return Ok(evaluated);
+        }
+        let resolved = if is_native(name) {
+            self.resolve_host(base, name)?
+        } else {
+            self.resolver.resolve(ctx, base, name)?
+        };
+        module::resolved(ctx, base, &resolved);
+        Ok(resolved)
+    }
+}
+
+impl HostResolver {
+    fn resolve_host(&self, base: &str, name: &str) -> Result<String> {
+        if self.modules.borrow().contains_key(name) {
+            return Ok(name.to_owned());
+        }
+        let message = match name.strip_prefix(HOST_SCHEME) {
+            Some(extension) => format!("no host extension named \"{}\" is registered", extension),
+            None => format!("no native module \"{}\" is registered", name),
+        };
+        Err(Error::new_resolving_message(base, name, message))
+    }
+}
+
+generic_loader! {
+    HostLoader: Script,
+}
+
+/// Loads the modules of registered extensions and native modules, in front of the runtime's loader
+pub(crate) struct HostLoader {
+    pub(crate) modules: HostModules,
+}
+
+impl Loader<Script> for HostLoader {
+    fn load<'js>(&mut self, ctx: Ctx<'js>, name: &str) -> Result<Module<'js, Loaded<Script>>> {
+        let source = self.modules.borrow().get(name).cloned();
+        let source = source.ok_or_else(|| Error::new_loading(name))?;
+        let module = Module::new(ctx, name, source)?;
+        // Modules of extensions not installed in the context throw instead
+        if let Some(exports) = installed_exports(ctx, name)? {
+            module.meta::<Object>()?.set(EXPORTS, exports)?;
+        }
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rquickjs::{Module, Promise};
+    use transpilers::Transpilers;
+    use crate::cache::NoCache;
+    use crate::loader::ExerumLoader;
+    use crate::permissions::Permissions;
+    use crate::resolver::ExerumResolver;
+    use crate::runtime::JsRuntime;
+    use super::{Extension, OpError};
+
+    fn math() -> Extension {
+        Extension::new("math")
+            .op("add", |a: f64, b: f64| Ok::<_, OpError>(a + b))
+            .op("divide", |a: i64, b: i64| match a.checked_div(b) {
+                Some(quotient) => Ok(quotient),
+                None => Err(OpError::range_error("division by zero")),
+            })
+            .op("greet", |name: Option<String>| {
+                Ok::<_, OpError>(format!("hello {}", name.as_deref().unwrap_or("world")))
+            })
+            .async_op("double", |n: u64| async move {
+                tokio::task::yield_now().await;
+                Ok::<_, OpError>(n * 2)
+            })
+    }
+
+    fn runtime(permissions: Permissions) -> JsRuntime {
+        let loader = ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        let mut jsrt = JsRuntime::with_permissions(loader, ExerumResolver::new("."), permissions);
+        jsrt.register_extension(math()).unwrap();
+        jsrt
+    }
+
+    #[test]
+    fn test_ops() {
+        let jsrt = runtime(Permissions::allow_all()).with_executor().unwrap();
+        jsrt.block_on(async {
+            let promise: Promise<String> = jsrt.context().with(|ctx| {
+                let source = r#"
+                import { add, divide, greet, double } from "host:math";
+                const caught = (f) => {
+                    try { f(); } catch (e) { return e.constructor.name + ": " + e.message; }
+                };
+                export const result = double(21).then((n) => [
+                    add(1, 2), greet(), greet("ada"), n,
+                    caught(() => divide(1, 0)),
+                    caught(() => add("one", 2)),
+                ].join("|"));
+                "#;
+                let module = Module::new(ctx, "main", source).unwrap().eval().unwrap();
+                module.get("result").unwrap()
+            });
+            assert_eq!(
+                promise.await.unwrap(),
+                "3|hello world|hello ada|42|RangeError: division by zero|\
+                 TypeError: add: argument 1: invalid type: string \"one\", expected f64"
+            );
+            jsrt.context().with(|ctx| {
+                assert!(Module::new(ctx, "missing", r#"import "host:missing";"#).unwrap().eval().is_err());
+            });
+            jsrt.rt().idle().await;
+        });
+    }
+
+    #[test]
+    fn test_op_permissions() {
+        let jsrt = runtime(Permissions::none().allow_op("other"));
+        let denied: String = jsrt.context().with(|ctx| {
+            let source = r#"
+            import { add } from "host:math";
+            let denied;
+            try { add(1, 2); } catch (e) { denied = e instanceof PermissionDenied && e.permission.target; }
+            export { denied };
+            "#;
+            Module::new(ctx, "main", source).unwrap().eval().unwrap().get("denied").unwrap()
+        });
+        assert_eq!(denied, "math");
+    }
+}
@@ -0,0 +1,56 @@
+// Fires `unhandledrejection` and `rejectionhandled` on globalThis for the rejections
+// rejections.rs tracks, when the runtime is idle. `describe` is the helper of module.js.
+((describe) => {
+    // Reported as unhandled, a handler attached later fires `rejectionhandled`
+    const reported = new WeakMap();
+    const listeners = { unhandledrejection: [], rejectionhandled: [] };
+    // Returns whether the default action wasn't prevented
+    const dispatch = (type, promise, reason, errors) => {
+        let prevented = false;
+        const event = {
+            type, promise, reason, cancelable: type === "unhandledrejection",
+            get defaultPrevented() { return prevented; },
+            preventDefault() { if (this.cancelable) prevented = true; },
+        };
+        const handler = globalThis["on" + type];
+        for (const listener of typeof handler === "function" ? [handler, ...listeners[type]] : listeners[type]) {
+            try {
+                listener.call(globalThis, event);
+            } catch (e) {
+                errors.push(describe(e));
+            }
+        }
+        return !prevented;
+    };
+
+    const { addEventListener: add, removeEventListener: remove } = globalThis;
+    globalThis.onunhandledrejection = null;
+    globalThis.onrejectionhandled = null;
+    globalThis.addEventListener = (type, listener) => {
+        if (listeners[type] === undefined) add?.call(globalThis, type, listener);
+        else if (!listeners[type].includes(listener)) listeners[type].push(listener);
+    };
+    globalThis.removeEventListener = (type, listener) => {
+        if (listeners[type] === undefined) remove?.call(globalThis, type, listener);
+        else listeners[type] = listeners[type].filter((l) => l !== listener);
+    };
+
+    return {
+        // Fires the events for the promises that got a handler and the rejections without one,
+        // returns the errors of the rejections nobody prevented and of failed listeners
+        flush: (handled, rejections) => {
+            const errors = [];
+            for (const promise of handled) {
+                if (!reported.has(promise)) continue;
+                const reason = reported.get(promise);
+                reported.delete(promise);
+                dispatch("rejectionhandled", promise, reason, errors);
+            }
+            for (const [promise, reason] of rejections) {
+                reported.set(promise, reason);
+                if (dispatch("unhandledrejection", promise, reason, errors)) errors.push(describe(reason));
+            }
+            return errors;
+        },
+    };
+})
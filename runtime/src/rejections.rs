@@ -0,0 +1,175 @@
+//! Tracks promises rejected without a handler, with the rejection tracker of QuickJS.
+//!
+//! Rejections still unhandled when the runtime is idle fire `unhandledrejection` on
+//! `globalThis`, and unless a listener calls `preventDefault()` they are reported to the
+//! host, see `JsRuntime::on_unhandled_rejection`. A handler attached to a reported
+//! promise later fires `rejectionhandled` at the next check.
+use std::cell::RefCell;
+use std::os::raw::{c_int, c_void};
+use std::rc::Rc;
+use rquickjs::{qjs, Array, Ctx, Function, Object, Persistent, Result, Value};
+use js_serde::raw::Held;
+use crate::context_data;
+use crate::error::JsError;
+
+const REJECTIONS_JS: &str = include_str!("rejections.js");
+
+/// The promises the tracker saw in a context since the last flush
+struct Rejections {
+    /// Event functions of rejections.js
+    events: Persistent<Object<'static>>,
+    /// Rejected without a handler, with their reasons
+    pending: RefCell<Vec<(Held, Held)>>,
+    /// Got their first handler after being rejected, and weren't pending anymore
+    handled: RefCell<Vec<Held>>,
+}
+
+/// What the runtime does with unhandled rejections
+#[derive(Clone, Default)]
+pub(crate) struct RejectionPolicy {
+    /// Prints to stderr if not set
+    pub(crate) callback: Option<Rc<dyn Fn(&JsError)>>,
+    /// Whether checks fail with the first unhandled rejection
+    pub(crate) fail_fast: bool,
+}
+
+impl RejectionPolicy {
+    /// Reports the unhandled rejections of the checks, failing with the first one if fail fast
+    pub(crate) fn report(&self, errors: Vec<JsError>) -> std::result::Result<(), JsError> {
+        for error in &errors {
+            match &self.callback {
+                Some(callback) => callback(error),
+                None => eprintln!("Uncaught (in promise) {}", error),
+            }
+        }
+        match errors.into_iter().next() {
+            Some(error) if self.fail_fast => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Starts tracking the rejections of the context and defines the event listeners
+pub(crate) fn init(ctx: Ctx) -> Result<()> {
+    let events: Function = ctx.eval(REJECTIONS_JS)?;
+    let events: Object = events.call((crate::module::describe_function(ctx)?,))?;
+    context_data::set(ctx, Rejections {
+        events: Persistent::save(ctx, events),
+        pending: RefCell::default(),
+        handled: RefCell::default(),
+    });
+    Ok(())
+}
+
+/// Sets the rejection tracker of the runtime of `ctx`, which records the promises in the
+/// context they belong to
+pub(crate) fn set_tracker(ctx: Ctx) {
+    unsafe {
+        let rt = qjs::JS_GetRuntime(ctx.as_ptr());
+        qjs::JS_SetHostPromiseRejectionTracker(rt, Some(track), std::ptr::null_mut());
+    }
+}
+
+/// Fires the pending events and returns the rejections to report
+pub(crate) fn flush(ctx: Ctx) -> Result<Vec<JsError>> {
+    let rejections = match context_data::get::<Rejections>(ctx) {
+        Some(rejections) => rejections,
+        // The rejections of the context aren't tracked
+        None => return Ok(vec![]),
+    };
+    // Taken before the listeners run, which may reject more promises
+    let handled = std::mem::take(&mut *rejections.handled.borrow_mut());
+    let pending = std::mem::take(&mut *rejections.pending.borrow_mut());
+    let handled_array = Array::new(ctx)?;
+    for (i, promise) in handled.iter().enumerate() {
+        handled_array.set(i, promise.restore(ctx)?)?;
+    }
+    let pending_array = Array::new(ctx)?;
+    for (i, (promise, reason)) in pending.iter().enumerate() {
+        let entry = Array::new(ctx)?;
+        entry.set(0, promise.restore(ctx)?)?;
+        entry.set(1, reason.restore(ctx)?)?;
+        pending_array.set(i, entry)?;
+    }
+    let flush: Function = rejections.events.clone().restore(ctx)?.get("flush")?;
+    let errors: Value = flush.call((handled_array, pending_array))?;
+    js_serde::from_value(ctx, errors)
+        .map_err(|e| rquickjs::Error::new_from_js_message("array", "JsError", e.to_string()))
+}
+
+/// `JSHostPromiseRejectionTracker`, called while the promise is rejected or gets its first
+/// handler. It doesn't run javascript, the events wait for flushes.
+unsafe extern "C" fn track(ctx: *mut qjs::JSContext, promise: qjs::JSValue, reason: qjs::JSValue, is_handled: c_int, _opaque: *mut c_void) {
+    let rejections = match context_data::get_raw::<Rejections>(ctx) {
+        Some(rejections) => rejections,
+        None => return,
+    };
+    if is_handled == 0 {
+        rejections.pending.borrow_mut().push((Held::new(ctx, promise), Held::new(ctx, reason)));
+        return;
+    }
+    let mut pending = rejections.pending.borrow_mut();
+    match pending.iter().position(|(pending, _)| pending.is(promise)) {
+        Some(i) => drop(pending.remove(i)),
+        None => rejections.handled.borrow_mut().push(Held::new(ctx, promise)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use transpilers::Transpilers;
+    use crate::cache::NoCache;
+    use crate::loader::ExerumLoader;
+    use crate::resolver::ExerumResolver;
+    use crate::runtime::JsRuntime;
+
+    fn runtime() -> JsRuntime {
+        let loader = ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        JsRuntime::new(loader, ExerumResolver::new("."))
+    }
+
+    #[test]
+    fn test_unhandled_rejections() {
+        let reported = Rc::new(RefCell::new(vec![]));
+        let mut jsrt = runtime().with_executor().unwrap();
+        let log = reported.clone();
+        jsrt.on_unhandled_rejection(move |e| log.borrow_mut().push(e.to_string()));
+        jsrt.run(
+            r#"
+            globalThis.events = [];
+            addEventListener("unhandledrejection", (e) => {
+                events.push("unhandled " + e.reason.message);
+                if (e.reason.message === "quiet") e.preventDefault();
+            });
+            onrejectionhandled = (e) => events.push("handled " + e.reason.message);
+            Promise.reject(new TypeError("lost"));
+            Promise.reject(new Error("quiet"));
+            const caught = Promise.reject(new Error("caught"));
+            caught.catch(() => {});
+            globalThis.late = Promise.reject(new RangeError("late"));
+            "#,
+        );
+        jsrt.block_on(jsrt.idle()).unwrap();
+        assert_eq!(*reported.borrow(), ["TypeError: lost", "RangeError: late"]);
+
+        jsrt.run("late.catch(() => {});");
+        jsrt.block_on(jsrt.idle()).unwrap();
+        let events: String = jsrt.context().with(|ctx| ctx.eval("events.join('|')").unwrap());
+        assert_eq!(events, "unhandled lost|unhandled quiet|unhandled late|handled late");
+
+        jsrt.fail_on_unhandled_rejection(true);
+        jsrt.run("Promise.reject('plain');");
+        let e = jsrt.block_on(jsrt.idle()).unwrap_err();
+        assert_eq!(e.message(), "Uncaught plain");
+        jsrt.block_on(jsrt.idle()).unwrap();
+        let global: bool = jsrt.context().with(|ctx| ctx.eval("'__exerum_rejections' in globalThis").unwrap());
+        assert!(!global);
+
+        // Hosts polling the jobs get the rejections once there are none left
+        jsrt.run("Promise.reject('polled');");
+        assert_eq!(jsrt.poll_once().unwrap_err().message(), "Uncaught polled");
+        assert!(!jsrt.poll_once().unwrap());
+    }
+}
@@ -2,13 +2,20 @@ use rquickjs::{Ctx, Result, Resolver};
 use std::collections::HashMap;
 use relative_path::{RelativePathBuf, RelativePath};
 use std::path::PathBuf;
-use super::module_specifier::ModuleSpecifier;
+use super::module_specifier::{ModuleSpecifier, DENIED_SCHEME};
 use rquickjs::Error;
+use crate::graph::GraphRecorder;
+use crate::vendor::{join_url, remote_url, DEFAULT_NPM_CDN};
+use crate::loader::{check_module, module_permissions};
+use stdlib::permissions::{Permission, Permissions};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct ExerumResolver {
     project_root: PathBuf,
-    aliases: HashMap<String, String>
+    aliases: HashMap<String, String>,
+    recorder: Option<GraphRecorder>,
+    npm_cdn: Option<String>,
+    permissions: Option<Permissions>
 }
 
 impl ExerumResolver {
@@ -16,10 +23,47 @@ impl ExerumResolver {
     pub fn new(project_root: &str) -> Self {
         ExerumResolver {
             project_root: PathBuf::from(project_root),
-            aliases: HashMap::new()
+            aliases: HashMap::new(),
+            recorder: None,
+            npm_cdn: None,
+            permissions: None
         }
     }
 
+    /// Records resolved imports for the module graph
+    pub fn with_recorder(mut self, recorder: GraphRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Only resolves to modules the permissions allow reading, or fetching for remote modules
+    pub fn with_permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = Some(permissions);
+        self
+    }
+
+    /// CDN serving `npm:` specifiers as ES modules, `https://esm.sh/` by default
+    pub fn with_npm_cdn(mut self, url: &str) -> Self {
+        self.npm_cdn = Some(url.to_owned());
+        self
+    }
+
+    /// Resolves remote specifiers, and specifiers imported by remote modules, to URLs
+    fn resolve_remote(&self, base: &str, name: &str) -> Result<Option<String>> {
+        let ms = ModuleSpecifier::from(name);
+        let name = ms.path();
+        if ms.is_remote() {
+            let npm_cdn = self.npm_cdn.as_deref().unwrap_or(DEFAULT_NPM_CDN);
+            return Ok(remote_url(name, npm_cdn));
+        }
+        if ModuleSpecifier::from(base).is_remote() {
+            return join_url(base, name)
+                .map(Some)
+                .ok_or_else(|| Error::new_resolving_message(base, name, "remote modules can only import relative or remote specifiers"));
+        }
+        Ok(None)
+    }
+
     fn resolve_alias(&self, name: &str, relative_path: &RelativePath) -> Option<RelativePathBuf> {
         self.aliases.get(&name.to_owned())
             .map(|path| {
@@ -27,13 +71,33 @@ impl ExerumResolver {
             })
     }
 
-    fn resolve_node_modules(base: &RelativePathBuf, target: &str) -> Option<RelativePathBuf> {
+    /// Whether the candidate exists. It's only looked up if the permissions allow reading it,
+    /// otherwise the first denied candidate is kept in `denied`.
+    fn exists(permissions: &[Permissions], candidate: &RelativePath, denied: &mut Option<RelativePathBuf>) -> bool {
+        if !Self::readable(permissions, candidate) {
+            denied.get_or_insert_with(|| candidate.to_owned());
+            return false;
+        }
+        candidate.to_path(".").exists()
+    }
+
+    /// Whether the permissions allow reading the path, without prompting or auditing
+    fn readable(permissions: &[Permissions], path: &RelativePath) -> bool {
+        permissions.iter().all(|permissions| permissions.allows(Permission::Read(path.to_path("."))))
+    }
+
+    fn resolve_node_modules(
+        permissions: &[Permissions],
+        base: &RelativePathBuf,
+        target: &str,
+        denied: &mut Option<RelativePathBuf>,
+    ) -> Option<RelativePathBuf> {
         let mut base = base.parent();
         while let Some(dir) = base {
             let node_modules = dir.join("node_modules");
-            if node_modules.to_path(".").is_dir() {
+            if Self::readable(permissions, &node_modules) && node_modules.to_path(".").is_dir() {
                 let path = node_modules.join(target);
-                if path.to_path(".").exists() {
+                if Self::exists(permissions, &path, denied) {
                     return Some(path);
                 }
             }
@@ -42,13 +106,19 @@ impl ExerumResolver {
         None
     }
 
+    /// Candidates the permissions deny reading aren't looked up. If no other candidate
+    /// exists, the first denied one is returned for `resolve` to check.
     #[inline]
-    fn resolve_internal(&mut self, base: &str, name: &str) -> Result<RelativePathBuf> {
+    fn resolve_internal(&mut self, base: &str, name: &str, permissions: &[Permissions]) -> Result<RelativePathBuf> {
         // Strip the transpiler name if any
         let ms = ModuleSpecifier::from(name);
         let name = ms.path();
-        let project_root = RelativePath::new(self.project_root.to_str().unwrap());
+        let project_root = self.project_root
+            .to_str()
+            .map(RelativePath::new)
+            .ok_or_else(|| Error::new_resolving_message(base, name, "the project root is not valid utf-8"))?;
         let base_buf = project_root.join_normalized(base);
+        let mut denied = None;
         if name.starts_with('.') {
             // Resolve relative to file's parent only
             let target = if let Some(parent_dir) = base_buf.parent() {
@@ -56,25 +126,25 @@ impl ExerumResolver {
             } else {
                 RelativePathBuf::from(name)
             };
-            if target.to_path(".").exists() {
+            if Self::exists(permissions, &target, &mut denied) {
                 return Ok(target);
             }
         } else {
             // Current folder first
             if let Some(parent_dir) = base_buf.parent() {
                 let target = parent_dir.join_normalized(name);
-                if target.to_path(".").exists() {
+                if Self::exists(permissions, &target, &mut denied) {
                     return Ok(target);
                 }
             }
             // Node modules
-            let in_node_modules = Self::resolve_node_modules(&base_buf, name);
+            let in_node_modules = Self::resolve_node_modules(permissions, &base_buf, name, &mut denied);
             if let Some(p) = in_node_modules {
                 return Ok(p);
             }
             // Project root
             let target = project_root.join_normalized(name);
-            if target.to_path(".").exists() {
+            if Self::exists(permissions, &target, &mut denied) {
                 return Ok(target)
             }
         };
@@ -82,14 +152,33 @@ impl ExerumResolver {
         if let Some(alias) = self.resolve_alias(name, &project_root) {
             return Ok(alias)
         }
+        if let Some(denied) = denied {
+            return Ok(denied);
+        }
         return Err(Error::new_resolving(base, name));
     }
 }
 
 impl Resolver for ExerumResolver {
-    fn resolve<'js>(&mut self, _ctx: Ctx<'js>, base: &str, name: &str) -> Result<String> {
-        self.resolve_internal(base, name)
-            .map(|p| p.to_path(".").to_str().unwrap().to_owned())
+    fn resolve<'js>(&mut self, ctx: Ctx<'js>, base: &str, name: &str) -> Result<String> {
+        // The resolver's permissions and the ones of the importing context
+        let permissions = module_permissions(ctx, self.permissions.as_ref());
+        let resolved = match self.resolve_remote(base, name)? {
+            Some(url) => url,
+            None => self.resolve_internal(base, name, &permissions)?
+                .to_path(".")
+                .to_str()
+                .map(|p| p.to_owned())
+                .ok_or_else(|| Error::new_resolving_message(base, name, "the resolved path is not valid utf-8"))?,
+        };
+        if check_module(&permissions, &resolved).is_err() {
+            // The loader serves it as a module throwing the `PermissionDenied` error
+            return Ok(format!("{}{}", DENIED_SCHEME, resolved));
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record_resolution(base, name, &resolved);
+        }
+        Ok(resolved)
     }
 }
 
@@ -97,7 +186,7 @@ impl Resolver for ExerumResolver {
 fn test_resolver_relative_to_current_file() {
     let mut resolver = ExerumResolver::new("test_data");
     // import * as React from './react.js' // in src/main.tsx
-    let resolved = resolver.resolve_internal("src/main.tsx", "./react.js").unwrap();
+    let resolved = resolver.resolve_internal("src/main.tsx", "./react.js", &[]).unwrap();
     assert_eq!(resolved, "test_data/src/react.js");
 }
 
@@ -105,7 +194,7 @@ fn test_resolver_relative_to_current_file() {
 fn test_resolver_up_dir() {
     let mut resolver = ExerumResolver::new("test_data");
     // import * as React from '../src/react.js' // in src/main.tsx
-    let resolved = resolver.resolve_internal("src/main.tsx", "../src/react.js").unwrap();
+    let resolved = resolver.resolve_internal("src/main.tsx", "../src/react.js", &[]).unwrap();
     assert_eq!(resolved, "test_data/src/react.js");
 }
 
@@ -115,7 +204,7 @@ fn test_resolver_up_dir() {
 fn test_resolver_relative_to_project_root() {
     let mut resolver = ExerumResolver::new("test_data");
     // import * as React from 'src/react.js' // in src/main.tsx
-    let resolved = resolver.resolve_internal("src/main.tsx", "src/react.js").unwrap();
+    let resolved = resolver.resolve_internal("src/main.tsx", "src/react.js", &[]).unwrap();
     assert_eq!(resolved, "test_data/src/react.js");
 }
 
@@ -123,6 +212,30 @@ fn test_resolver_relative_to_project_root() {
 fn test_resolver_node_modules() {
     let mut resolver = ExerumResolver::new("test_data");
     // import * as React from 'react/umd/react.js' // in src/main.tsx
-    let resolved = resolver.resolve_internal("src/main.tsx", "react/umd/react.js").unwrap();
+    let resolved = resolver.resolve_internal("src/main.tsx", "react/umd/react.js", &[]).unwrap();
     assert_eq!(resolved, "test_data/node_modules/react/umd/react.js");
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_resolver_remote() {
+    let resolver = ExerumResolver::new("test_data").with_npm_cdn("http://localhost:8000");
+    let resolved = resolver.resolve_remote("src/main.tsx", "npm:preact@10.5.0/hooks").unwrap();
+    assert_eq!(resolved.unwrap(), "http://localhost:8000/preact@10.5.0/hooks");
+    let base = "https://deno.land/std@0.120.0/path/mod.ts";
+    let resolved = resolver.resolve_remote(base, "./_util.ts").unwrap();
+    assert_eq!(resolved.unwrap(), "https://deno.land/std@0.120.0/path/_util.ts");
+    assert!(resolver.resolve_remote(base, "react").is_err());
+    assert_eq!(resolver.resolve_remote("src/main.tsx", "./react.js").unwrap(), None);
+}
+
+#[test]
+fn test_resolver_permissions() {
+    let permissions = [Permissions::none().allow_read("test_data/src")];
+    let mut resolver = ExerumResolver::new("test_data");
+    let resolved = resolver.resolve_internal("src/main.tsx", "./react.js", &permissions).unwrap();
+    assert_eq!(resolved, "test_data/src/react.js");
+    // Denied candidates aren't looked up, the first one is left for `resolve` to check
+    let resolved = resolver.resolve_internal("src/main.tsx", "react/umd/react.js", &permissions).unwrap();
+    assert_eq!(resolved, "test_data/react/umd/react.js");
+    assert!(resolver.resolve_internal("src/main.tsx", "./missing.js", &permissions).is_err());
+}
@@ -1,22 +1,137 @@
-use rquickjs::{Context, Runtime, Value, Tokio};
-use rquickjs::{Loader, Resolver};
-use stdlib::init_stdlib;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::Instant;
+use futures::future::{select, Either};
+use js_serde::Serde;
+use rquickjs::{Context, Module, Runtime, Value, Tokio};
+use serde::de::DeserializeOwned;
+use rquickjs::{Loader, Resolver, Result};
+use stdlib::{init_stdlib, StdlibConfig};
+use stdlib::permissions::Permissions;
+use crate::bundle::Bundler;
+use crate::context_data;
+use crate::loader::ContextPermissions;
+use crate::error::JsError;
+use crate::event_loop::{self, Activity, EventLoopError, PendingWork, WorkGuard};
+use crate::executor::Executor;
+use crate::graph::ModuleGraph;
+use crate::memory::{self, MemoryUsage};
+use crate::module::{self, JsModule};
+use crate::module_specifier::NATIVE_SCHEME;
+use crate::native_module::NativeModule;
+use crate::ops::{Extension, HostLoader, HostModules, HostResolver, HOST_SCHEME};
+use crate::rejections::{self, RejectionPolicy};
+
+type InterruptHandlers = Rc<RefCell<Vec<Box<dyn FnMut() -> bool>>>>;
 
 pub struct JsRuntime {
+    /// Declared first so the tasks of the executor are dropped before the runtime
+    executor: Option<Executor>,
     rt: Runtime,
     executor_spawned: bool,
     pub(crate) context: Context,
+    permissions: Permissions,
+    /// Additional contexts by name with the permissions of their stdlib, see `create_context`
+    contexts: HashMap<String, (Context, Permissions)>,
+    extensions: Vec<Extension>,
+    /// Native modules by specifier
+    native_modules: Vec<(String, NativeModule)>,
+    host_modules: HostModules,
+    rejections: RejectionPolicy,
+    activity: Rc<Activity>,
+    /// Walks the imports for `module_graph`
+    graph_walker: Option<RefCell<Bundler>>,
+    /// Called by the interrupt handler of the runtime, see `add_interrupt_handler`
+    interrupt_handlers: InterruptHandlers,
 }
 
 impl JsRuntime {
+    /// Creates a runtime with every permission granted
     pub fn new(loader: impl Loader + 'static, resolver: impl Resolver + 'static) -> Self {
+        Self::with_permissions(loader, resolver, Permissions::allow_all())
+    }
+
+    /// Creates a runtime whose stdlib checks `permissions`. `ExerumResolver` and `ExerumLoader`
+    /// check the modules a context imports against the permissions of the context.
+    pub fn with_permissions(loader: impl Loader + 'static, resolver: impl Resolver + 'static, permissions: Permissions) -> Self {
         let rt = Runtime::new().unwrap();
-        rt.set_loader(resolver, loader);
+        let host_modules = HostModules::default();
+        rt.set_loader(
+            HostResolver { modules: host_modules.clone(), resolver: Box::new(resolver) },
+            (HostLoader { modules: host_modules.clone() }, loader),
+        );
         let context = Context::full(&rt).unwrap();
-        init_stdlib(&context);
-        JsRuntime { rt, context, executor_spawned: false }
+        init_stdlib(&context, &permissions).unwrap();
+        context.with(|ctx| {
+            context_data::set(ctx, ContextPermissions(permissions.clone()));
+            rejections::set_tracker(ctx);
+            rejections::init(ctx)?;
+            memory::init(ctx, &permissions)
+        }).unwrap();
+        let activity = Rc::new(Activity::default());
+        context.with(|ctx| event_loop::register(ctx, &activity));
+        let jsrt = JsRuntime {
+            executor: None,
+            rt,
+            context,
+            executor_spawned: false,
+            permissions,
+            contexts: HashMap::new(),
+            extensions: vec![],
+            native_modules: vec![],
+            host_modules,
+            rejections: RejectionPolicy::default(),
+            activity,
+            graph_walker: None,
+            interrupt_handlers: InterruptHandlers::default(),
+        };
+        let handlers = jsrt.interrupt_handlers.clone();
+        jsrt.rt.set_interrupt_handler(Some(Box::new(move || {
+            handlers.borrow_mut().iter_mut().any(|handler| handler())
+        })));
+        jsrt
+    }
+
+    /// Defines the ops of the extension in every context of the runtime, including
+    /// contexts created later, and makes them importable from `host:<name>`.
+    /// An extension with the same name is replaced in the contexts created afterwards.
+    pub fn register_extension(&mut self, extension: Extension) -> Result<()> {
+        self.context.with(|ctx| extension.install(ctx, &self.permissions))?;
+        for (context, permissions) in self.contexts.values() {
+            context.with(|ctx| extension.install(ctx, permissions))?;
+        }
+        let specifier = format!("{}{}", HOST_SCHEME, extension.name());
+        self.host_modules.borrow_mut().insert(specifier, extension.source());
+        self.extensions.retain(|e| e.name() != extension.name());
+        self.extensions.push(extension);
+        Ok(())
+    }
+
+    /// Creates the exports of the module in every context of the runtime, including
+    /// contexts created later, and makes them importable from `exerum:<name>`, also by
+    /// bundled modules. A module with the same name is replaced in the contexts created
+    /// afterwards, contexts which imported it keep the exports they got.
+    pub fn register_module(&mut self, name: &str, module: NativeModule) -> Result<()> {
+        let specifier = format!("{}{}", NATIVE_SCHEME, name);
+        self.context.with(|ctx| module.install(ctx, &specifier, &self.permissions))?;
+        for (context, permissions) in self.contexts.values() {
+            context.with(|ctx| module.install(ctx, &specifier, permissions))?;
+        }
+        self.host_modules.borrow_mut().insert(specifier.clone(), module.source(&specifier));
+        self.native_modules.retain(|(s, _)| *s != specifier);
+        self.native_modules.push((specifier, module));
+        Ok(())
+    }
+
+    /// Permissions checked by the stdlib, for host functions defined on the runtime
+    pub fn permissions(&self) -> &Permissions {
+        &self.permissions
     }
 
+    /// The QuickJS runtime. Its interrupt handler belongs to `JsRuntime`, see `add_interrupt_handler`.
     pub fn rt(&self) -> &Runtime {
         &self.rt
     }
@@ -29,11 +144,255 @@ impl JsRuntime {
         }
     }
 
+    /// Makes the runtime own a current-thread Tokio runtime and spawns the executor in it,
+    /// for `block_on`. Call it instead of `spawn_executor`.
+    pub fn with_executor(mut self) -> std::io::Result<Self> {
+        let executor = Executor::new()?;
+        executor.block_on(async { self.spawn_executor() });
+        self.executor = Some(executor);
+        Ok(self)
+    }
+
+    /// Runs the future on the executor the runtime owns.
+    /// Panics if it doesn't own one, see `with_executor`.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let executor = self.executor.as_ref().expect("the runtime doesn't own an executor, see JsRuntime::with_executor");
+        executor.block_on(future)
+    }
+
+    /// Blocking `eval`, on the executor the runtime owns
+    pub fn block_on_eval<R: DeserializeOwned + 'static>(&self, code: &str) -> std::result::Result<R, JsError> {
+        self.block_on(self.eval(code))
+    }
+
+    /// Evaluates the script in the main context and converts its value with `js_serde`,
+    /// once settled if it's a promise. Exceptions and rejections are errors.
+    pub async fn eval<R: DeserializeOwned + 'static>(&self, code: &str) -> std::result::Result<R, JsError> {
+        let settled = self.context.with(|ctx| {
+            let value = module::eval_script(ctx, code)?;
+            module::settle::<R>(ctx, value)
+        })?;
+        let Serde(result) = settled.await?;
+        result
+    }
+
     /// Adds another reference to the context
     pub fn context(&self) -> Context {
         self.context.clone()
     }
 
+    /// Creates a context with its own globals and stdlib on this runtime. It shares the
+    /// loader and its module cache, the resolver and the executor with the other contexts,
+    /// but modules are instantiated per context. A context with the same name is replaced.
+    ///
+    /// A `ShadowRealm` API isn't provided: a context can't be created while javascript
+    /// of the runtime is running, which is where `new ShadowRealm()` would do it.
+    pub fn create_context(&mut self, name: &str, stdlib: StdlibConfig) -> Result<Context> {
+        let context = Context::full(&self.rt)?;
+        stdlib.init(&context)?;
+        context.with(|ctx| {
+            context_data::set(ctx, ContextPermissions(stdlib.permissions.clone()));
+            event_loop::register(ctx, &self.activity);
+            rejections::init(ctx)?;
+            memory::init(ctx, &stdlib.permissions)
+        })?;
+        for extension in &self.extensions {
+            context.with(|ctx| extension.install(ctx, &stdlib.permissions))?;
+        }
+        for (specifier, module) in &self.native_modules {
+            context.with(|ctx| module.install(ctx, specifier, &stdlib.permissions))?;
+        }
+        if let Some((replaced, _)) = self.contexts.insert(name.to_owned(), (context.clone(), stdlib.permissions)) {
+            replaced.with(context_data::free);
+        }
+        Ok(context)
+    }
+
+    /// Calls `callback` with the unhandled rejections the checks find, instead of printing them
+    pub fn on_unhandled_rejection(&mut self, callback: impl Fn(&JsError) + 'static) {
+        self.rejections.callback = Some(Rc::new(callback));
+    }
+
+    /// Makes the checks fail with the first unhandled rejection, after reporting them all
+    pub fn fail_on_unhandled_rejection(&mut self, fail: bool) {
+        self.rejections.fail_fast = fail;
+    }
+
+    /// Fires `unhandledrejection` and `rejectionhandled` in every context and reports the
+    /// rejections still unhandled, see `crate::rejections`
+    pub fn check_rejections(&self) -> std::result::Result<(), JsError> {
+        let mut errors = self.context.with(rejections::flush)?;
+        for (context, _) in self.contexts.values() {
+            errors.extend(context.with(rejections::flush)?);
+        }
+        self.rejections.report(errors)
+    }
+
+    /// Waits until there are no pending jobs, then checks for unhandled rejections
+    pub async fn idle(&self) -> std::result::Result<(), JsError> {
+        self.rt.idle().await;
+        self.check_rejections()
+    }
+
+    /// Memory used by the runtime, all its contexts included
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.context.with(memory::usage)
+    }
+
+    /// Runs the garbage collector, which frees unreachable cycles. Other garbage is freed right away.
+    pub fn run_gc(&self) {
+        self.rt.run_gc();
+    }
+
+    /// Adds a handler QuickJS calls every few thousand instructions, after the ones added
+    /// before. A handler returning true interrupts the running javascript with an uncatchable
+    /// error. Setting the interrupt handler of `rt()` directly replaces all of them.
+    pub fn add_interrupt_handler(&self, handler: impl FnMut() -> bool + 'static) {
+        self.interrupt_handlers.borrow_mut().push(Box::new(handler));
+    }
+
+    /// The work keeping the runtime busy
+    pub fn pending(&self) -> PendingWork {
+        PendingWork {
+            jobs: self.rt.is_job_pending(),
+            timers: self.activity.timers(),
+            host_ops: self.activity.host_ops(),
+            resources: self.activity.resources(),
+        }
+    }
+
+    /// Runs one pending job, returns whether there was one. For hosts driving the runtime
+    /// without `spawn_executor`, which async host ops need though. Checks for unhandled
+    /// rejections when there was none.
+    pub fn poll_once(&self) -> std::result::Result<bool, JsError> {
+        let ran = self.rt.execute_pending_job()?;
+        if !ran {
+            self.check_rejections()?;
+        }
+        Ok(ran)
+    }
+
+    /// Counts as an open resource of the runtime until the guard is dropped,
+    /// for work of the embedder that can still call into javascript
+    pub fn open_resource(&self) -> WorkGuard {
+        event_loop::open_resource(&self.activity)
+    }
+
+    /// Runs until there is no pending work, checking for unhandled rejections each time
+    /// there are no pending jobs. Fails with the pending work if `deadline` passes first.
+    pub async fn run_event_loop(&self, deadline: Option<Instant>) -> std::result::Result<(), EventLoopError> {
+        let run = async {
+            loop {
+                self.rt.idle().await;
+                self.check_rejections()?;
+                if self.activity.resources() == 0 {
+                    return Ok::<_, JsError>(());
+                }
+                self.activity.released().await;
+            }
+        };
+        Ok(self.with_deadline(run, deadline).await??)
+    }
+
+    /// Runs the event loop until `future` completes, like the call of an export.
+    /// Fails if `deadline` passes first, or if the runtime runs out of work while
+    /// `future` is still pending, since nothing could complete it anymore.
+    pub async fn run_until<F: Future>(&self, future: F, deadline: Option<Instant>) -> std::result::Result<F::Output, EventLoopError> {
+        let run = async {
+            let mut future = Box::pin(future);
+            loop {
+                if self.activity.resources() > 0 {
+                    match select(future.as_mut(), Box::pin(self.activity.released())).await {
+                        Either::Left((output, _)) => return Ok(output),
+                        Either::Right(_) => continue,
+                    }
+                }
+                match select(future.as_mut(), Box::pin(self.rt.idle())).await {
+                    Either::Left((output, _)) => return Ok(output),
+                    Either::Right(_) if self.activity.resources() > 0 => continue,
+                    // The last job may have completed it
+                    Either::Right(_) => match futures::poll!(future.as_mut()) {
+                        Poll::Ready(output) => return Ok(output),
+                        Poll::Pending => return Err(EventLoopError::Deadlock),
+                    },
+                }
+            }
+        };
+        self.with_deadline(run, deadline).await?
+    }
+
+    async fn with_deadline<F: Future>(&self, future: F, deadline: Option<Instant>) -> std::result::Result<F::Output, EventLoopError> {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), future)
+                .await
+                .map_err(|_| EventLoopError::Timeout(self.pending())),
+            None => Ok(future.await),
+        }
+    }
+
+    /// Imports the module from the main context, to call its exports from Rust.
+    /// Importing the same specifier again returns a handle to the same module.
+    pub fn import(&self, specifier: &str) -> std::result::Result<JsModule, JsError> {
+        JsModule::import(&self.rt, &self.context, specifier)
+    }
+
+    /// Lets `module_graph` find the imports with the resolver, transpilers and vendor
+    /// directory of the bundler
+    pub fn enable_module_graph(&mut self, bundler: Bundler) {
+        self.graph_walker = Some(RefCell::new(bundler));
+    }
+
+    /// Graph of the modules `entry` imports statically or by `import("...")` with a string
+    /// literal. The modules are read and transpiled like `Bundler::bundle` does, nothing is
+    /// evaluated. Resolutions go through the main context. See `enable_module_graph`.
+    pub fn module_graph(&self, entry: &str) -> std::result::Result<ModuleGraph, JsError> {
+        let walker = self
+            .graph_walker
+            .as_ref()
+            .ok_or_else(|| JsError::type_error("module graphs need JsRuntime::enable_module_graph"))?;
+        Ok(self.context.with(|ctx| walker.borrow_mut().graph(ctx, entry))?)
+    }
+
+    /// Evaluates the source as a module named `name` in the main context.
+    /// `import` of the name gets the last module evaluated with it.
+    pub fn eval_module(&self, name: &str, source: impl Into<Vec<u8>>) -> std::result::Result<JsModule, JsError> {
+        let source = source.into();
+        JsModule::evaluate(&self.rt, &self.context, name, |ctx, free| {
+            module::compile(ctx, free, source)?;
+            Ok(free.to_owned())
+        })
+    }
+
+    /// Evaluates module bytecode (see `Module::write_object`) in the main context,
+    /// with a handle like `eval_module`.
+    pub fn eval_bytecode(&self, name: &str, bytecode: &[u8]) -> std::result::Result<JsModule, JsError> {
+        JsModule::evaluate(&self.rt, &self.context, name, |ctx, _| {
+            let module = Module::read_object(ctx, bytecode)?;
+            Ok(module.name::<String>()?)
+        })
+    }
+
+    /// Adds another reference to the named context
+    pub fn named_context(&self, name: &str) -> Option<Context> {
+        self.contexts.get(name).map(|(context, _)| context.clone())
+    }
+
+    pub fn context_names(&self) -> impl Iterator<Item = &str> {
+        self.contexts.keys().map(String::as_str)
+    }
+
+    /// Drops the runtime's reference to the named context. It's freed once the
+    /// references handed out are dropped too. Returns whether the context existed.
+    pub fn destroy_context(&mut self, name: &str) -> bool {
+        match self.contexts.remove(name) {
+            Some((context, _)) => {
+                context.with(context_data::free);
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Evaluates javascript at the global context
     pub fn run(&mut self, code: &str) {
         self.context.with(|ctx| {
@@ -42,6 +401,15 @@ impl JsRuntime {
     }
 }
 
+impl Drop for JsRuntime {
+    fn drop(&mut self) {
+        for (context, _) in self.contexts.values() {
+            context.with(context_data::free);
+        }
+        self.context.with(context_data::free);
+    }
+}
+
 /// Returns default loader and resolver
 pub fn _init_default_loader_and_resolver() -> (impl Loader, impl Resolver) {
     use rquickjs::{FileResolver, ScriptLoader};
@@ -63,7 +431,8 @@ impl From<u32> for Box<JsRuntime> {
 #[cfg(test)]
 mod tests {
     use crate::cache::NoCache;
-    use rquickjs::{Promise, Tokio};
+    use crate::ops::{Extension, OpError};
+    use rquickjs::{Module, Promise, Tokio};
     use transpilers::Transpilers;
     #[test]
     fn test_reusable_runtime() {
@@ -118,59 +487,291 @@ mod tests {
             .unwrap();
         let local_set = tokio::task::LocalSet::new();
 
-        // TODO: error when enabling "macro" feature for rquickjs
-        // Updating crates.io index
-        // error: failed to select a version for `syn`.
-        //     ... required by package `darling_core v0.13.0`
-        //     ... which satisfies dependency `darling_core = "=0.13.0"` of package `darling v0.13.0`
-        //     ... which satisfies dependency `darling = "^0.13"` of package `rquickjs-macro v0.1.3 (https://github.com/exerum/quickrs?branch=wasm32-wasi#9341972b)`
-        //     ... which satisfies git dependency `rquickjs-macro` of package `rquickjs v0.1.3 (https://github.com/exerum/quickrs?branch=wasm32-wasi#9341972b)`
-        //     ... which satisfies git dependency `rquickjs` (locked to 0.1.3) of package `exerum-wasm32-wasi v0.1.0 (/home/kein/Work/js-runtime/exerum-wasm32-wasi)`
-        // versions that meet the requirements `^1.0.69` are: 1.0.82, 1.0.81, 1.0.80, 1.0.79, 1.0.78, 1.0.77, 1.0.76, 1.0.75, 1.0.74, 1.0.73, 1.0.72, 1.0.71, 1.0.70, 1.0.69
-
-        // all possible versions conflict with previously selected packages.
-
-        //   previously selected package `syn v1.0.65`
-        //     ... which satisfies dependency `syn = "^1"` (locked to 1.0.65) of package `ast_node v0.7.4`
-        //     ... which satisfies dependency `ast_node = "^0.7.3"` (locked to 0.7.4) of package `swc_common v0.14.7`
-        //     ... which satisfies dependency `swc_common = "^0.14.0"` (locked to 0.14.7) of package `swc v0.89.0`
-        //     ... which satisfies dependency `swc = "^0.89"` (locked to 0.89.0) of package `swc-tools v0.1.0 (/home/kein/Work/js-runtime/swc-tools)`
-        //     ... which satisfies path dependency `swc-tools` (locked to 0.1.0) of package `transpiler-jsx v0.1.0 (/home/kein/Work/js-runtime/transpiler-jsx)`
-        //     ... which satisfies path dependency `transpiler-jsx` (locked to 0.1.0) of package `runtime v0.1.0 (/home/kein/Work/js-runtime/runtime)`
-        // #[bind(object)]
-        // pub async fn sleep(msecs: u64) {
-        //     tokio::task::sleep(
-        //         std::time::Duration::from_millis(msecs)
-        //     ).await;
-        // }
         let transpilers = Transpilers::default();
         let resolver = crate::resolver::ExerumResolver::new("./test_data/");
         let loader = crate::loader::ExerumLoader::new(Box::new(NoCache {}), transpilers);
-        let jsrt = crate::runtime::JsRuntime::new(loader, resolver);
+        let mut jsrt = crate::runtime::JsRuntime::new(loader, resolver);
+        let timers = Extension::new("timers").async_op("sleep", |msecs: u64| async move {
+            tokio::time::sleep(std::time::Duration::from_millis(msecs)).await;
+            Ok::<_, OpError>(msecs)
+        });
+        jsrt.register_extension(timers).unwrap();
         let ctx = jsrt.context();
         let fut = local_set.run_until(async move {
             jsrt.rt().spawn_executor(Tokio);
-            // ctx.with(|ctx| {
-            // ctx.globals().init_def::<Sleep>().unwrap();
-            // });
-
             let promise: Promise<String> = ctx.with(|ctx| {
-                ctx.eval(
-                    r#"
+                let source = r#"
+            import { sleep } from "host:timers";
             async function test() {
-                return "ok";
+                const slept = await sleep(10);
+                return "ok " + slept;
             }
-            test()
-        "#,
-                )
-                .unwrap()
+            export const result = test();
+        "#;
+                let module = Module::new(ctx, "main", source).unwrap().eval().unwrap();
+                module.get("result").unwrap()
             });
 
             let res = promise.await.unwrap();
-            assert_eq!(res, "ok");
+            assert_eq!(res, "ok 10");
 
             jsrt.rt().idle().await;
         });
         tokio_rt.block_on(fut);
     }
+
+    #[test]
+    fn test_webassembly() {
+        let resolver = crate::resolver::ExerumResolver::new("./test_data/");
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        let jsrt = crate::runtime::JsRuntime::new(loader, resolver);
+        let (sum, imports, compile_error): (i32, i32, bool) = jsrt.context().with(|ctx| {
+            ctx.eval(
+                r#"
+            // (module
+            //   (import "env" "log" (func $log (param i32)))
+            //   (func (export "add") (param i32 i32) (result i32)
+            //     local.get 0 local.get 1 i32.add))
+            const bytes = new Uint8Array([
+                0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+                0x01, 0x0b, 0x02, 0x60, 0x01, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f,
+                0x02, 0x0b, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x03, 0x6c, 0x6f, 0x67, 0x00, 0x00,
+                0x03, 0x02, 0x01, 0x01,
+                0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64, 0x00, 0x01,
+                0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+            ]);
+            const module = new WebAssembly.Module(bytes);
+            const instance = new WebAssembly.Instance(module, { env: { log: (x) => {} } });
+            let compileError = false;
+            try {
+                new WebAssembly.Module(new Uint8Array([0, 1, 2]));
+            } catch (e) {
+                compileError = e instanceof WebAssembly.CompileError;
+            }
+            [instance.exports.add(2, 3), WebAssembly.Module.imports(module).length, compileError]
+        "#,
+            )
+            .unwrap()
+        });
+        assert_eq!(sum, 5);
+        assert_eq!(imports, 1);
+        assert!(compile_error);
+        let (logged, wrapped): (Vec<i32>, i32) = jsrt.context().with(|ctx| {
+            ctx.eval(
+                r#"
+            // (module
+            //   (import "env" "log" (func $log (param i32)))
+            //   (func (export "add") (param i32 i32) (result i32)
+            //     local.get 0 local.get 1 i32.add)
+            //   (func (export "run") (param i32) local.get 0 call $log)
+            //   (memory (export "memory") 1))
+            const bytes = new Uint8Array([
+                0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+                0x01, 0x0b, 0x02, 0x60, 0x01, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f,
+                0x02, 0x0b, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x03, 0x6c, 0x6f, 0x67, 0x00, 0x00,
+                0x03, 0x03, 0x02, 0x01, 0x00,
+                0x05, 0x03, 0x01, 0x00, 0x01,
+                0x07, 0x17, 0x03, 0x03, 0x61, 0x64, 0x64, 0x00, 0x01, 0x03, 0x72, 0x75, 0x6e, 0x00, 0x02,
+                0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+                0x0a, 0x10, 0x02, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b, 0x06, 0x00, 0x20, 0x00, 0x10, 0x00, 0x0b,
+            ]);
+            let logged;
+            // Imported functions can use the memory and the exports of the running instance
+            const log = (x) => {
+                const { memory, add } = instance.exports;
+                new Uint8Array(memory.buffer)[0] = x;
+                memory.grow(1);
+                logged = [x, memory.buffer.byteLength, add(x, 1), new Uint8Array(memory.buffer)[0]];
+            };
+            const instance = new WebAssembly.Instance(new WebAssembly.Module(bytes), { env: { log } });
+            instance.exports.run(7);
+            [logged, instance.exports.add(2 ** 70, 3)]
+        "#,
+            )
+            .unwrap()
+        });
+        assert_eq!(logged, vec![7, 131072, 8, 7]);
+        assert_eq!(wrapped, 3);
+    }
+
+    #[test]
+    fn test_static_module_graph() {
+        use crate::bundle::Bundler;
+        use transpiler_js::JsTranspiler;
+        use transpilers::register;
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache {}), transpilers.clone());
+        let resolver = crate::resolver::ExerumResolver::new(".");
+        let mut jsrt = crate::runtime::JsRuntime::new(loader, resolver.clone());
+        assert!(jsrt.module_graph("test_data/src/bundle/main.js").is_err());
+        jsrt.enable_module_graph(Bundler::new(resolver, transpilers));
+        let graph = jsrt.module_graph("test_data/src/bundle/main.js").unwrap();
+        let names: Vec<&str> = graph.modules.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["test_data/src/bundle/main.js", "test_data/src/bundle/static.js", "test_data/src/bundle/lazy.js"]);
+        let imports: Vec<(&str, bool)> = graph.modules[0].imports.iter().map(|i| (i.specifier.as_str(), i.dynamic)).collect();
+        assert_eq!(imports, [("./static.js", false), ("./lazy.js", true)]);
+        assert!(graph.modules.iter().all(|m| m.stats.is_none()));
+    }
+
+    #[test]
+    fn test_permissions() {
+        use std::sync::{Arc, Mutex};
+        use transpiler_js::JsTranspiler;
+        use transpilers::register;
+        use crate::permissions::Permissions;
+        let audited = Arc::new(Mutex::new(vec![]));
+        let log = audited.clone();
+        let permissions = Permissions::none()
+            .allow_read("test_data/src/bundle")
+            .with_audit(move |permission, granted| log.lock().unwrap().push((permission.to_string(), granted)));
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let resolver = crate::resolver::ExerumResolver::new(".").with_permissions(permissions.clone());
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache {}), transpilers).with_permissions(permissions.clone());
+        let jsrt = crate::runtime::JsRuntime::with_permissions(loader, resolver, permissions);
+        jsrt.context().with(|ctx| {
+            assert!(Module::new(ctx, "allowed", r#"import "test_data/src/bundle/static.js";"#).unwrap().eval().is_ok());
+            let denied = Module::new(ctx, "denied", r#"import "test_data/src/meta/main.js";"#).unwrap().eval();
+            assert!(denied.err().unwrap().to_string().contains("PermissionDenied"));
+            let (denied, name): (bool, String) = ctx
+                .eval(
+                    r#"
+                let result;
+                try {
+                    WebAssembly.validate(new Uint8Array([0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00]));
+                } catch (e) {
+                    result = [e instanceof PermissionDenied, e.permission.name + ":" + e.permission.target];
+                }
+                result
+            "#,
+                )
+                .unwrap();
+            assert!(denied);
+            assert_eq!(name, "op:webassembly");
+            let _: Value = ctx
+                .eval(r#"import("test_data/src/meta/main.js").catch((e) => { globalThis.importDenied = e instanceof PermissionDenied && e.permission.name; })"#)
+                .unwrap();
+        });
+        while jsrt.rt().execute_pending_job().unwrap() {}
+        let import_denied: String = jsrt.context().with(|ctx| ctx.globals().get("importDenied").unwrap());
+        assert_eq!(import_denied, "read");
+        let audited = audited.lock().unwrap();
+        assert!(audited.iter().any(|(permission, granted)| permission.contains("static.js") && *granted));
+        assert!(audited.iter().any(|(permission, granted)| permission.contains("meta") && !*granted));
+    }
+
+    #[test]
+    fn test_named_contexts() {
+        use stdlib::StdlibConfig;
+        use transpiler_js::JsTranspiler;
+        use transpilers::register;
+        use crate::permissions::Permissions;
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let resolver = crate::resolver::ExerumResolver::new(".");
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache {}), transpilers);
+        let mut jsrt = crate::runtime::JsRuntime::new(loader, resolver);
+        let tenant_a = jsrt.create_context("a", StdlibConfig::default()).unwrap();
+        let tenant_b = jsrt
+            .create_context("b", StdlibConfig::new(Permissions::none()).without_webassembly())
+            .unwrap();
+
+        tenant_a.with(|ctx| ctx.globals().set("secret", "a").unwrap());
+        let (secret, wasm): (String, String) = tenant_b.with(|ctx| {
+            ctx.eval("[typeof secret, typeof WebAssembly]").unwrap()
+        });
+        assert_eq!((secret.as_str(), wasm.as_str()), ("undefined", "undefined"));
+        let wasm: String = jsrt.named_context("a").unwrap().with(|ctx| ctx.eval("typeof WebAssembly").unwrap());
+        assert_eq!(wasm, "object");
+
+        // Modules are instantiated per context, and checked against the permissions of the context
+        let source = r#"import { name } from "test_data/src/bundle/static.js"; export const v = name;"#;
+        tenant_a.with(|ctx| Module::new(ctx, "tenant", source).unwrap().eval().unwrap());
+        let denied = tenant_b.with(|ctx| Module::new(ctx, "tenant", source).unwrap().eval().unwrap_err().to_string());
+        assert!(denied.contains("PermissionDenied: requires read access"), "{}", denied);
+
+        let mut names: Vec<&str> = jsrt.context_names().collect();
+        names.sort_unstable();
+        assert_eq!(names, ["a", "b"]);
+        assert!(jsrt.destroy_context("a"));
+        assert!(!jsrt.destroy_context("a"));
+        assert!(jsrt.named_context("a").is_none());
+    }
+
+    #[test]
+    fn test_structured_clone() {
+        use stdlib::structured_clone::{deserialize, serialize};
+        use stdlib::StdlibConfig;
+        let resolver = crate::resolver::ExerumResolver::new("./test_data/");
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache {}), Transpilers::default());
+        let mut jsrt = crate::runtime::JsRuntime::new(loader, resolver);
+        let checks: Vec<bool> = jsrt.context().with(|ctx| {
+            ctx.eval(
+                r#"
+            const buffer = new Uint8Array([1, 2, 3, 4]).buffer;
+            const original = {
+                date: new Date(1000),
+                regexp: /a+b/gi,
+                map: new Map([[1, { one: true }]]),
+                set: new Set(["x"]),
+                bytes: new Uint8Array(buffer, 1, 2),
+                words: new Uint16Array(buffer),
+                error: new RangeError("out of range"),
+                boxed: new String("boxed"),
+                big: 12345678901234567890n,
+                sparse: [1, , 3],
+                own: JSON.parse('{ "__proto__": { "polluted": true } }'),
+            };
+            original.self = original;
+            const copy = structuredClone(original);
+            copy.bytes[0] = 9;
+            const transferred = new Uint8Array([7]).buffer;
+            const moved = structuredClone({ transferred }, { transfer: [transferred] });
+            let dataCloneError = false;
+            try {
+                structuredClone({ f: () => {} });
+            } catch (e) {
+                dataCloneError = e instanceof DataCloneError;
+            }
+            let spoofed = false;
+            try {
+                structuredClone({ [Symbol.toStringTag]: "Date" });
+            } catch (e) {
+                spoofed = e instanceof DataCloneError;
+            }
+            [
+                copy !== original && copy.self === copy,
+                copy.date instanceof Date && copy.date.getTime() === 1000,
+                copy.regexp.source === "a+b" && copy.regexp.flags === "gi",
+                copy.map.get(1).one === true && copy.set.has("x"),
+                copy.bytes.buffer === copy.words.buffer && copy.words[0] === 0x0901 && original.bytes[0] === 2,
+                copy.error instanceof RangeError && copy.error.message === "out of range",
+                typeof copy.boxed === "object" && copy.boxed.valueOf() === "boxed",
+                copy.big === 12345678901234567890n,
+                copy.sparse.length === 3 && !(1 in copy.sparse),
+                transferred.byteLength === 0 && new Uint8Array(moved.transferred)[0] === 7,
+                dataCloneError && spoofed,
+                Object.getPrototypeOf(copy.own) === Object.prototype && copy.own.__proto__.polluted === true,
+                !("__exerum_clone_helpers" in globalThis),
+            ]
+        "#,
+            )
+            .unwrap()
+        });
+        assert!(checks.iter().all(|c| *c), "{:?}", checks);
+
+        // Across contexts through the binary format
+        let other = jsrt.create_context("other", StdlibConfig::default()).unwrap();
+        let bytes = jsrt.context().with(|ctx| {
+            let value = ctx.eval("({ when: new Date(5), tags: new Set(['a']) })").unwrap();
+            serialize(ctx, value).unwrap()
+        });
+        let ok: bool = other.with(|ctx| {
+            let value = deserialize(ctx, &bytes).unwrap();
+            ctx.globals().set("value", value).unwrap();
+            ctx.eval("value.when.getTime() === 5 && value.tags.has('a')").unwrap()
+        });
+        assert!(ok);
+    }
 }
@@ -0,0 +1,194 @@
+//! Startup snapshots: the precompiled bytecode of the modules an application preloads,
+//! replayed into a fresh runtime to skip resolving, reading and transpiling them.
+//!
+//! QuickJS can't serialize its heap, so unlike V8 snapshots the state of the modules
+//! is recreated by evaluating their bytecode again, in the order they were preloaded.
+//! Globals set up by preloaded modules are recreated with them. The native modules and
+//! extensions they import are registered before, by the closure `restore` takes.
+//! ```ignore
+//! let snapshot = Snapshot::create(&mut bundler, &["./src/framework.ts", "./src/app.ts"])?;
+//! snapshot.write_to("app.snapshot")?;
+//! // In another process
+//! let jsrt = Snapshot::read_from("app.snapshot")?.restore(Permissions::allow_all(), |jsrt| {
+//!     jsrt.register_module("db", db)?;
+//!     Ok(())
+//! })?;
+//! ```
+//!
+//! Layout, integers are little endian `u32`:
+//! ```text
+//! magic "EXRMSNAP" | format version | preloaded count | preloaded module names... | bundle archive
+//! ```
+use rquickjs::{Loader, Resolver, Result};
+use stdlib::permissions::Permissions;
+use crate::bundle::{invalid, write_bytes, Bundle, Bundler, Reader};
+use crate::bundle_loader::from_bundle;
+use crate::error::JsError;
+use crate::runtime::JsRuntime;
+
+const MAGIC: &[u8; 8] = b"EXRMSNAP";
+pub const FORMAT_VERSION: u32 = 1;
+
+pub struct Snapshot {
+    /// Every module the preloaded modules import, compiled
+    pub bundle: Bundle,
+    /// Names of the modules in the bundle evaluated by `restore`, in order
+    pub preloaded: Vec<String>,
+}
+
+impl Snapshot {
+    /// Compiles the modules and everything they import. `preload` are paths the loader can
+    /// read, modules already compiled for a previous one are shared.
+    pub fn create(bundler: &mut Bundler, preload: &[&str]) -> Result<Self> {
+        let mut snapshot: Option<Snapshot> = None;
+        for entry in preload {
+            let bundle = bundler.bundle(entry)?;
+            match &mut snapshot {
+                None => {
+                    snapshot = Some(Snapshot { preloaded: vec![bundle.entry.clone()], bundle });
+                }
+                Some(snapshot) => {
+                    snapshot.preloaded.push(bundle.entry.clone());
+                    for module in bundle.modules {
+                        if snapshot.bundle.module(&module.name).is_none() {
+                            snapshot.bundle.modules.push(module);
+                        }
+                    }
+                }
+            }
+        }
+        snapshot.ok_or_else(|| invalid("a snapshot needs a module to preload"))
+    }
+
+    /// Creates a runtime loading modules from the snapshot only. `configure` registers the
+    /// native modules and extensions of the runtime, then the preloaded modules are evaluated.
+    pub fn restore(
+        self,
+        permissions: Permissions,
+        configure: impl FnOnce(&mut JsRuntime) -> std::result::Result<(), JsError>,
+    ) -> std::result::Result<JsRuntime, JsError> {
+        let (loader, resolver) = from_bundle(self.bundle)?;
+        let jsrt = JsRuntime::with_permissions(loader, resolver, permissions);
+        preload(jsrt, &self.preloaded, configure)
+    }
+
+    /// Like `restore`, modules missing from the snapshot are loaded by `loader` and `resolver`
+    pub fn restore_with(
+        self,
+        loader: impl Loader + 'static,
+        resolver: impl Resolver + 'static,
+        permissions: Permissions,
+        configure: impl FnOnce(&mut JsRuntime) -> std::result::Result<(), JsError>,
+    ) -> std::result::Result<JsRuntime, JsError> {
+        let (bundle_loader, bundle_resolver) = from_bundle(self.bundle)?;
+        let jsrt = JsRuntime::with_permissions((bundle_loader, loader), (bundle_resolver, resolver), permissions);
+        preload(jsrt, &self.preloaded, configure)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.preloaded.len() as u32).to_le_bytes());
+        for name in &self.preloaded {
+            write_bytes(&mut out, name.as_bytes());
+        }
+        out.extend_from_slice(&self.bundle.to_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        let version = reader.u32()?;
+        if version != FORMAT_VERSION {
+            return Err(invalid(&format!(
+                "snapshot format version {} is not supported, expected {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        let count = reader.u32()?;
+        let mut preloaded = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            preloaded.push(reader.string()?);
+        }
+        let bundle = Bundle::from_bytes(reader.rest())?;
+        if let Some(missing) = preloaded.iter().find(|name| bundle.module(name).is_none()) {
+            return Err(invalid(&format!("the preloaded module \"{}\" is not in the snapshot", missing)));
+        }
+        Ok(Snapshot { bundle, preloaded })
+    }
+
+    pub fn write_to(&self, path: &str) -> Result<()> {
+        Ok(std::fs::write(path, self.to_bytes())?)
+    }
+
+    pub fn read_from(path: &str) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+fn preload(
+    mut jsrt: JsRuntime,
+    preloaded: &[String],
+    configure: impl FnOnce(&mut JsRuntime) -> std::result::Result<(), JsError>,
+) -> std::result::Result<JsRuntime, JsError> {
+    configure(&mut jsrt)?;
+    for name in preloaded {
+        jsrt.import(name)?;
+    }
+    Ok(jsrt)
+}
+
+#[cfg(test)]
+mod tests {
+    use transpiler_js::JsTranspiler;
+    use transpilers::{register, Transpilers};
+    use crate::bundle::Bundler;
+    use crate::native_module::NativeModule;
+    use crate::permissions::Permissions;
+    use crate::resolver::ExerumResolver;
+    use super::Snapshot;
+
+    #[test]
+    fn test_snapshot() {
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let mut bundler = Bundler::new(ExerumResolver::new("."), transpilers);
+        let preload = ["test_data/src/bundle/static.js", "test_data/src/bundle/main.js"];
+        let snapshot = Snapshot::create(&mut bundler, &preload).unwrap();
+        assert_eq!(snapshot.preloaded, preload);
+        // static.js is compiled once, and lazy.js is imported by main.js
+        assert_eq!(snapshot.bundle.modules.len(), 3);
+
+        let snapshot = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        let jsrt = snapshot.restore(Permissions::allow_all(), |_| Ok(())).unwrap().with_executor().unwrap();
+        let main = jsrt.import("test_data/src/bundle/main.js").unwrap();
+        let result: String = jsrt.block_on(main.call_export("run", ())).unwrap();
+        assert_eq!(result, "static:lazy");
+
+        assert!(Snapshot::from_bytes(b"EXRMBNDL").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_native_imports() {
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let mut bundler = Bundler::new(ExerumResolver::new("."), transpilers);
+        let snapshot = Snapshot::create(&mut bundler, &["test_data/src/snapshot/native.js"]).unwrap();
+        let restored = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap().restore(Permissions::allow_all(), |jsrt| {
+            jsrt.register_module("greeting", NativeModule::new().constant("greeting", "hello"))?;
+            Ok(())
+        });
+        let jsrt = restored.unwrap().with_executor().unwrap();
+        let preloaded: String = jsrt.block_on_eval("preloadedGreeting").unwrap();
+        assert_eq!(preloaded, "hello");
+        let native = jsrt.import("test_data/src/snapshot/native.js").unwrap();
+        let greeted: String = jsrt.block_on(native.call_export("greet", ("ada",))).unwrap();
+        assert_eq!(greeted, "hello ada");
+
+        let missing = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap().restore(Permissions::allow_all(), |_| Ok(()));
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Remote modules (`https://...`, `http://...` and `npm:` specifiers) served from a local vendor directory.
+//!
+//! Remote modules keep their URL as module name, so the modules they import are resolved
+//! against it. `npm:` specifiers are rewritten to the URL of an npm CDN serving ES modules.
+//! The loader reads a remote module from the vendor directory and checks it against
+//! the integrity hash recorded in the lockfile. Modules are only downloaded, and added to
+//! the lockfile, when the vendor has a `Fetcher`; without one, a module that is missing
+//! or not locked is an error, so runs are offline and reproducible.
+//!
+//! ```text
+//! vendor/
+//!   lock.json                   {"version": 1, "remote": {"<url>": "sha256-<base64>"}}
+//!   https/deno.land/std@0.120.0/path/mod-<hash of the url>.ts
+//! ```
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use rquickjs::{Error, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+pub const DEFAULT_NPM_CDN: &str = "https://esm.sh/";
+pub const LOCKFILE: &str = "lock.json";
+const LOCKFILE_VERSION: u64 = 1;
+/// Extensions the vendored modules keep, the ones of the transpilers
+const MODULE_EXTENSIONS: [&str; 12] = ["js", "mjs", "cjs", "jsx", "ts", "mts", "tsx", "css", "json", "toml", "yaml", "yml"];
+
+pub fn is_remote(specifier: &str) -> bool {
+    specifier.starts_with("https://") || specifier.starts_with("http://") || specifier.starts_with("npm:")
+}
+
+/// URL of a remote specifier, `npm:` specifiers are served by `npm_cdn`
+pub fn remote_url(specifier: &str, npm_cdn: &str) -> Option<String> {
+    if let Some(package) = specifier.strip_prefix("npm:") {
+        Some(format!("{}/{}", npm_cdn.trim_end_matches('/'), package.trim_start_matches('/')))
+    } else if is_remote(specifier) {
+        Some(specifier.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Resolves a relative (`./`, `../`) or absolute (`/`) specifier imported by the remote module `base`.
+/// Returns `None` for other specifiers.
+pub fn join_url(base: &str, specifier: &str) -> Option<String> {
+    let scheme_end = base.find("://")? + 3;
+    let path_start = base[scheme_end..].find('/').map(|i| i + scheme_end).unwrap_or(base.len());
+    let origin = &base[..path_start];
+    let base_path = base[path_start..].split(['?', '#']).next().unwrap_or("");
+    let (path, query) = match specifier.find(['?', '#']) {
+        Some(i) => (&specifier[..i], &specifier[i..]),
+        None => (specifier, ""),
+    };
+    let joined = if path.starts_with('/') {
+        path.to_owned()
+    } else if path.starts_with("./") || path.starts_with("../") {
+        let dir = &base_path[..base_path.rfind('/').map(|i| i + 1).unwrap_or(0)];
+        format!("{}{}", if dir.is_empty() { "/" } else { dir }, path)
+    } else {
+        return None;
+    };
+    let mut segments: Vec<&str> = vec![];
+    for segment in joined.split('/').skip(1) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some(format!("{}/{}{}", origin, segments.join("/"), query))
+}
+
+/// `sha256-<base64>` integrity hash, as used by subresource integrity and package lockfiles
+pub fn integrity(bytes: &[u8]) -> String {
+    format!("sha256-{}", base64::encode(Sha256::digest(bytes)))
+}
+
+/// Downloads remote modules for the vendor directory
+pub trait Fetcher {
+    fn fetch(&mut self, url: &str) -> std::result::Result<Vec<u8>, String>;
+}
+
+impl<F: FnMut(&str) -> std::result::Result<Vec<u8>, String>> Fetcher for F {
+    fn fetch(&mut self, url: &str) -> std::result::Result<Vec<u8>, String> {
+        self(url)
+    }
+}
+
+pub struct Vendor {
+    dir: PathBuf,
+    /// Module URL to integrity hash
+    lockfile: RefCell<BTreeMap<String, String>>,
+    fetcher: RefCell<Option<Box<dyn Fetcher>>>,
+}
+
+impl Vendor {
+    /// Opens the vendor directory and reads its lockfile, if there is one
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let lockfile_path = dir.join(LOCKFILE);
+        let mut lockfile = BTreeMap::new();
+        if lockfile_path.exists() {
+            let invalid = |message: String| Error::new_loading_message(lockfile_path.to_string_lossy(), message);
+            let text = std::fs::read_to_string(&lockfile_path).map_err(|e| invalid(e.to_string()))?;
+            let value: Value = serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+            if value["version"].as_u64() != Some(LOCKFILE_VERSION) {
+                return Err(invalid(format!("expected lockfile version {}", LOCKFILE_VERSION)));
+            }
+            let remote = value["remote"]
+                .as_object()
+                .ok_or_else(|| invalid("\"remote\" is not an object".to_owned()))?;
+            for (url, hash) in remote {
+                let hash = hash
+                    .as_str()
+                    .ok_or_else(|| invalid(format!("the hash of {} is not a string", url)))?;
+                lockfile.insert(url.clone(), hash.to_owned());
+            }
+        }
+        Ok(Vendor {
+            dir,
+            lockfile: RefCell::new(lockfile),
+            fetcher: RefCell::new(None),
+        })
+    }
+
+    /// Downloads missing modules with the fetcher and adds them to the lockfile
+    pub fn with_fetcher(self, fetcher: impl Fetcher + 'static) -> Self {
+        *self.fetcher.borrow_mut() = Some(Box::new(fetcher));
+        self
+    }
+
+    /// Integrity hash recorded for the module
+    pub fn locked(&self, url: &str) -> Option<String> {
+        self.lockfile.borrow().get(url).cloned()
+    }
+
+    /// Where the module is stored in the vendor directory. The path starts with the URL,
+    /// made safe for file names, and the file name ends with a hash of the whole URL,
+    /// so URLs differing only by characters replaced in the path or by the query don't collide.
+    /// Known extensions are kept so the module is transpiled like a local file, other modules
+    /// (`esm.sh/react`, `npm:left-pad@1.3.0`) get `.js`.
+    pub fn local_path(&self, url: &str) -> PathBuf {
+        let (scheme, rest) = url.split_once("://").unwrap_or(("npm", url.trim_start_matches("npm:")));
+        let rest = rest.split(['?', '#']).next().unwrap_or("");
+        let mut segments: Vec<String> = rest
+            .split('/')
+            .filter(|s| !s.is_empty() && *s != "." && *s != "..")
+            .map(|segment| {
+                segment
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() || "._-@+~".contains(c) { c } else { '_' })
+                    .collect()
+            })
+            .collect();
+        if rest.ends_with('/') || segments.len() < 2 {
+            segments.push("index".to_owned());
+        }
+        let last = segments.pop().unwrap_or_default();
+        let (stem, extension) = match last.rsplit_once('.') {
+            Some((stem, extension)) if MODULE_EXTENSIONS.contains(&extension) => (stem, extension),
+            _ => (last.as_str(), "js"),
+        };
+        let hash: String = Sha256::digest(url.as_bytes())[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        let mut path = self.dir.join(scheme);
+        path.extend(&segments);
+        path.push(format!("{}-{}.{}", stem, hash, extension));
+        path
+    }
+
+    /// Returns the path of the vendored module after checking its integrity hash.
+    /// Missing modules are downloaded if the vendor has a fetcher.
+    pub fn module_path(&self, url: &str) -> Result<PathBuf> {
+        let path = self.local_path(url);
+        let can_fetch = self.fetcher.borrow().is_some();
+        let (bytes, fetched) = match std::fs::read(&path) {
+            Ok(bytes) => (bytes, false),
+            Err(_) => {
+                let mut fetcher = self.fetcher.borrow_mut();
+                let fetcher = fetcher.as_mut().ok_or_else(|| {
+                    Error::new_loading_message(url, format!("the module is not vendored in {}", self.dir.display()))
+                })?;
+                let bytes = fetcher
+                    .fetch(url)
+                    .map_err(|e| Error::new_loading_message(url, format!("failed to fetch the module: {}", e)))?;
+                (bytes, true)
+            }
+        };
+        let hash = integrity(&bytes);
+        match self.locked(url) {
+            Some(expected) if expected != hash => {
+                return Err(Error::new_loading_message(
+                    url,
+                    format!("integrity check failed, the lockfile expects {} but the module hashes to {}", expected, hash),
+                ))
+            }
+            Some(_) => {}
+            None if can_fetch => {
+                self.lockfile.borrow_mut().insert(url.to_owned(), hash);
+                self.save_lockfile()?;
+            }
+            None => return Err(Error::new_loading_message(url, "the module is not in the lockfile")),
+        }
+        if fetched {
+            let error = |e: std::io::Error| Error::new_loading_message(url, e.to_string());
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).map_err(error)?;
+            }
+            std::fs::write(&path, &bytes).map_err(error)?;
+        }
+        Ok(path)
+    }
+
+    fn save_lockfile(&self) -> Result<()> {
+        let lockfile = json!({
+            "version": LOCKFILE_VERSION,
+            "remote": &*self.lockfile.borrow(),
+        });
+        let path = self.dir.join(LOCKFILE);
+        let error = |e: std::io::Error| Error::new_loading_message(path.to_string_lossy(), e.to_string());
+        std::fs::create_dir_all(&self.dir).map_err(error)?;
+        let text = serde_json::to_string_pretty(&lockfile).expect("lockfile is valid json");
+        std::fs::write(&path, text + "\n").map_err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("exerum-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_urls() {
+        assert_eq!(remote_url("npm:lodash-es@4.17.21/fp", DEFAULT_NPM_CDN).unwrap(), "https://esm.sh/lodash-es@4.17.21/fp");
+        assert_eq!(remote_url("https://deno.land/x/a.ts", DEFAULT_NPM_CDN).unwrap(), "https://deno.land/x/a.ts");
+        assert_eq!(remote_url("./a.ts", DEFAULT_NPM_CDN), None);
+        let base = "https://deno.land/std@0.120.0/path/mod.ts?v=1";
+        assert_eq!(join_url(base, "./posix.ts").unwrap(), "https://deno.land/std@0.120.0/path/posix.ts");
+        assert_eq!(join_url(base, "../fs/mod.ts?x#y").unwrap(), "https://deno.land/std@0.120.0/fs/mod.ts?x#y");
+        assert_eq!(join_url(base, "/x/other.ts").unwrap(), "https://deno.land/x/other.ts");
+        assert_eq!(join_url("https://esm.sh", "./a.js").unwrap(), "https://esm.sh/a.js");
+        assert_eq!(join_url(base, "react"), None);
+    }
+
+    #[test]
+    fn test_vendor() {
+        let dir = temp_dir("vendor");
+        let url = "https://example.com/lib/mod.js";
+        // Stands in for an HTTP server
+        let fetcher = move |requested: &str| match requested {
+            "https://example.com/lib/mod.js" => Ok(b"export const a = 1;".to_vec()),
+            _ => Err("404 Not Found".to_owned()),
+        };
+        let vendor = Vendor::open(&dir).unwrap().with_fetcher(fetcher);
+        let path = vendor.module_path(url).unwrap();
+        assert_eq!(path, vendor.local_path(url));
+        let name = |url: &str| {
+            let path = vendor.local_path(url);
+            assert!(path.starts_with(&dir));
+            let parent = path.parent().unwrap().strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/");
+            let file = path.file_name().unwrap().to_string_lossy().into_owned();
+            let (stem, extension) = file.rsplit_once('.').unwrap();
+            (parent, stem[..stem.rfind('-').unwrap()].to_owned(), extension.to_owned())
+        };
+        let expected = |parent: &str, stem: &str, extension: &str| (parent.to_owned(), stem.to_owned(), extension.to_owned());
+        assert_eq!(name(url), expected("https/example.com/lib", "mod", "js"));
+        assert_eq!(name("https://esm.sh/react"), expected("https/esm.sh", "react", "js"));
+        assert_eq!(name("https://esm.sh/"), expected("https/esm.sh", "index", "js"));
+        assert_eq!(name("npm:left-pad@1.3.0"), expected("npm", "left-pad@1.3.0", "js"));
+        assert_eq!(name("https://deno.land/x/a.ts?v=1"), expected("https/deno.land/x", "a", "ts"));
+        // Same readable prefix, different files
+        for (a, b) in [("https://esm.sh/a?x", "https://esm.sh/a?y"), ("https://esm.sh/a b", "https://esm.sh/a_b"), ("https://esm.sh/a", "https://esm.sh/a.js")] {
+            assert_ne!(vendor.local_path(a), vendor.local_path(b));
+        }
+        assert!(vendor.module_path("https://example.com/missing.js").unwrap_err().to_string().contains("404"));
+
+        // Offline, from the vendor directory and the lockfile
+        let vendor = Vendor::open(&dir).unwrap();
+        assert_eq!(vendor.locked(url).unwrap(), integrity(b"export const a = 1;"));
+        assert_eq!(vendor.module_path(url).unwrap(), path);
+        assert!(vendor.module_path("https://example.com/other.js").unwrap_err().to_string().contains("not vendored"));
+
+        std::fs::write(&path, "export const a = 2;").unwrap();
+        assert!(vendor.module_path(url).unwrap_err().to_string().contains("integrity check failed"));
+        std::fs::remove_file(dir.join(LOCKFILE)).unwrap();
+        let vendor = Vendor::open(&dir).unwrap();
+        assert!(vendor.module_path(url).unwrap_err().to_string().contains("not in the lockfile"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+// `Worker` for the parent context and the global scope of a worker, on top of
+// the operations implemented in worker.rs. Messages cross threads as structured clones.
+(() => {
+    let detach;
+    // Checks the transfer list, the buffers are detached once the message is sent
+    const send = (post, message, transfer) => {
+        for (const item of transfer) {
+            if (!(item instanceof ArrayBuffer)) throw new DataCloneError("only ArrayBuffers can be transferred");
+        }
+        const result = post(message);
+        if (result !== undefined) throw new DataCloneError(result.message);
+        transfer.forEach((buffer) => detach(buffer));
+    };
+    const dispatch = (target, listeners, type, event) => {
+        const handler = target["on" + type];
+        if (typeof handler === "function") handler.call(target, event);
+        for (const listener of listeners[type]) listener.call(target, event);
+    };
+    // Other event types go to the listener methods the target already has, like the global ones of rejections.js
+    const eventTarget = (target, listeners) => {
+        const { addEventListener: add, removeEventListener: remove } = target;
+        target.addEventListener = (type, listener) => {
+            if (listeners[type] === undefined) add?.call(target, type, listener);
+            else if (!listeners[type].includes(listener)) listeners[type].push(listener);
+        };
+        target.removeEventListener = (type, listener) => {
+            if (listeners[type] === undefined) remove?.call(target, type, listener);
+            else listeners[type] = listeners[type].filter((l) => l !== listener);
+        };
+    };
+
+    // Returns the function delivering messages and errors of the workers
+    const parent = (ops) => {
+        const ID = Symbol("id");
+        const LISTENERS = Symbol("listeners");
+        const workers = new Map();
+        class Worker {
+            constructor(url) {
+                this[ID] = ops.spawn(String(url));
+                this[LISTENERS] = { message: [], error: [] };
+                this.onmessage = null;
+                this.onerror = null;
+                eventTarget(this, this[LISTENERS]);
+                workers.set(this[ID], this);
+            }
+            postMessage(message, transfer = []) {
+                send((message) => ops.post(this[ID], message), message, transfer);
+            }
+            terminate() {
+                ops.terminate(this[ID]);
+                workers.delete(this[ID]);
+            }
+        }
+        detach = ops.detach;
+        globalThis.Worker = Worker;
+        return (id, type, data) => {
+            const worker = workers.get(id);
+            if (worker === undefined) return;
+            dispatch(worker, worker[LISTENERS], type, type === "message" ? { data } : { message: data });
+        };
+    };
+
+    // Sets up the global scope of a worker and returns the function delivering its messages
+    const worker = (ops) => {
+        const scope = globalThis;
+        const listeners = { message: [] };
+        scope.self = scope;
+        scope.onmessage = null;
+        eventTarget(scope, listeners);
+        detach = ops.detach;
+        scope.postMessage = (message, transfer = []) => send(ops.post, message, transfer);
+        scope.close = () => ops.close();
+        return (data) => dispatch(scope, listeners, "message", { data });
+    };
+
+    return { parent, worker };
+})()
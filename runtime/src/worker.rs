@@ -0,0 +1,279 @@
+//! Web workers: `new Worker(url)` runs the module in its own `JsRuntime`, on its own
+//! thread with its own Tokio runtime, and exchanges messages with the parent.
+//!
+//! ```ignore
+//! let workers = Workers::new(|| {
+//!     let (loader, resolver) = configure();
+//!     JsRuntime::new(loader, resolver)
+//! });
+//! workers.install(rt.context())?;
+//! ```
+//! The parent context has to run inside a `LocalSet`, which receives the messages of its workers.
+//! Messages are sent as structured clones. Transferred array buffers are detached in the sender.
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use rquickjs::{Context, Ctx, Func, Function, Module, Object, Result, Value};
+use stdlib::array_buffer::detach;
+use stdlib::structured_clone::{deserialize, serialize, CloneError};
+use futures::future::{select, Either};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use crate::event_loop;
+use crate::runtime::JsRuntime;
+
+const PRELUDE: &str = include_str!("worker.js");
+/// Global functions delivering messages, set by the prelude
+const PARENT_DELIVER: &str = "__exerum_workers_deliver";
+const WORKER_DELIVER: &str = "__exerum_worker_deliver";
+
+enum ToWorker {
+    /// Serialized structured clone
+    Message(Vec<u8>),
+    Terminate,
+}
+
+enum ToParent {
+    Message(Vec<u8>),
+    Error(String),
+}
+
+struct WorkerHandle {
+    inbox: UnboundedSender<ToWorker>,
+    /// Interrupts the javascript running in the worker
+    terminated: Arc<AtomicBool>,
+}
+
+/// Creates the runtimes of workers, on their threads
+pub type RuntimeFactory = Arc<dyn Fn() -> JsRuntime + Send + Sync>;
+
+pub struct Workers {
+    factory: RuntimeFactory,
+}
+
+impl Workers {
+    /// `factory` creates the runtime of each worker, with its loader configuration.
+    /// The worker's module is resolved as an import of a module named "worker".
+    pub fn new(factory: impl Fn() -> JsRuntime + Send + Sync + 'static) -> Self {
+        Workers { factory: Arc::new(factory) }
+    }
+
+    /// Defines `Worker` in the context
+    pub fn install(&self, context: Context) -> Result<()> {
+        let workers: Rc<RefCell<HashMap<u32, WorkerHandle>>> = Default::default();
+        let next_id = Rc::new(Cell::new(0u32));
+        context.with(|ctx| {
+            let ops = Object::new(ctx)?;
+            let (w, factory) = (workers.clone(), self.factory.clone());
+            ops.set("spawn", Func::new("spawn", move |ctx: Ctx, url: String| -> Result<u32> {
+                let id = next_id.get();
+                next_id.set(id + 1);
+                let handle = spawn(ctx, factory.clone(), id, url)?;
+                w.borrow_mut().insert(id, handle);
+                Ok(id)
+            }))?;
+            let w = workers.clone();
+            ops.set("post", Func::new("post", move |ctx: Ctx, id: u32, message: Value| {
+                let message = serialize(ctx, message);
+                if let (Ok(message), Some(worker)) = (&message, w.borrow().get(&id)) {
+                    let _ = worker.inbox.send(ToWorker::Message(message.clone()));
+                }
+                clone_failure(ctx, message.err())
+            }))?;
+            let w = workers.clone();
+            ops.set("terminate", Func::new("terminate", move |id: u32| {
+                if let Some(worker) = w.borrow_mut().remove(&id) {
+                    worker.terminated.store(true, Ordering::SeqCst);
+                    let _ = worker.inbox.send(ToWorker::Terminate);
+                }
+            }))?;
+            ops.set("detach", Func::new("detach", |ctx: Ctx, buffer: Value| detach(ctx, buffer)))?;
+            let prelude: Object = ctx.eval(PRELUDE)?;
+            let parent: Function = prelude.get("parent")?;
+            let deliver: Function = parent.call((ops,))?;
+            ctx.globals().set(PARENT_DELIVER, deliver)
+        })
+    }
+}
+
+/// `{ message }` for values that can't be cloned, which the javascript side throws as `DataCloneError`
+fn clone_failure<'js>(ctx: Ctx<'js>, error: Option<CloneError>) -> Result<Value<'js>> {
+    match error {
+        None => Ok(Value::new_undefined(ctx)),
+        Some(CloneError::Js(e)) => Err(e),
+        Some(e) => {
+            let failure = Object::new(ctx)?;
+            failure.set("message", e.to_string())?;
+            Ok(failure.into_value())
+        }
+    }
+}
+
+fn clone_error(e: CloneError) -> rquickjs::Error {
+    match e {
+        CloneError::Js(e) => e,
+        e => rquickjs::Error::new_from_js_message("message", "structured clone", e.to_string()),
+    }
+}
+
+/// Starts the worker thread and the task receiving its messages
+fn spawn(ctx: Ctx, factory: RuntimeFactory, id: u32, url: String) -> Result<WorkerHandle> {
+    let (inbox, worker_inbox) = unbounded_channel();
+    let (worker_outbox, mut outbox) = unbounded_channel();
+    let terminated = Arc::new(AtomicBool::new(false));
+    let interrupt = terminated.clone();
+    // The worker ends once the parent drops its handle, it doesn't hold a sender to its own inbox
+    std::thread::spawn(move || run_worker(factory, url, worker_inbox, worker_outbox, interrupt));
+
+    // Ends once the worker thread is done and drops its sender, the worker is an open resource until then
+    let context = Context::from_ctx(ctx)?;
+    let open = event_loop::resource(ctx);
+    tokio::task::spawn_local(async move {
+        let _open = open;
+        while let Some(message) = outbox.recv().await {
+            let _ = context.with(|ctx| -> Result<()> {
+                let deliver: Function = ctx.globals().get(PARENT_DELIVER)?;
+                match message {
+                    ToParent::Message(message) => {
+                        let data = deserialize(ctx, &message).map_err(clone_error)?;
+                        deliver.call((id, "message", data))
+                    }
+                    ToParent::Error(message) => deliver.call((id, "error", message)),
+                }
+            });
+        }
+    });
+    Ok(WorkerHandle { inbox, terminated })
+}
+
+fn run_worker(
+    factory: RuntimeFactory,
+    url: String,
+    mut inbox: UnboundedReceiver<ToWorker>,
+    outbox: UnboundedSender<ToParent>,
+    terminated: Arc<AtomicBool>,
+) {
+    let mut jsrt = factory();
+    jsrt.add_interrupt_handler(move || terminated.load(Ordering::SeqCst));
+    let jsrt = match jsrt.with_executor() {
+        Ok(jsrt) => jsrt,
+        Err(e) => {
+            let _ = outbox.send(ToParent::Error(e.to_string()));
+            return;
+        }
+    };
+    jsrt.block_on(async {
+        let context = jsrt.context();
+        let closed = Rc::new(Notify::new());
+        let started = context.with(|ctx| -> Result<()> {
+            install_scope(ctx, outbox.clone(), closed.clone())?;
+            Module::new(ctx, "worker", format!("import {:?};", url))?.eval()?;
+            Ok(())
+        });
+        if let Err(e) = started {
+            let _ = outbox.send(ToParent::Error(e.to_string()));
+            return;
+        }
+        loop {
+            let message = match select(Box::pin(inbox.recv()), Box::pin(closed.notified())).await {
+                Either::Left((Some(ToWorker::Message(message)), _)) => message,
+                // Terminated, closed by `close()` or the parent dropped the handle
+                _ => break,
+            };
+            let delivered = context.with(|ctx| -> Result<()> {
+                let deliver: Function = ctx.globals().get(WORKER_DELIVER)?;
+                deliver.call((deserialize(ctx, &message).map_err(clone_error)?,))
+            });
+            if let Err(e) = delivered {
+                let _ = outbox.send(ToParent::Error(e.to_string()));
+            }
+        }
+    });
+}
+
+/// Defines `self`, `postMessage`, `onmessage` and `close` in the worker
+fn install_scope(ctx: Ctx, outbox: UnboundedSender<ToParent>, closed: Rc<Notify>) -> Result<()> {
+    let ops = Object::new(ctx)?;
+    ops.set("post", Func::new("post", move |ctx: Ctx, message: Value| {
+        let message = serialize(ctx, message);
+        if let Ok(message) = &message {
+            let _ = outbox.send(ToParent::Message(message.clone()));
+        }
+        clone_failure(ctx, message.err())
+    }))?;
+    ops.set("close", Func::new("close", move || closed.notify_one()))?;
+    ops.set("detach", Func::new("detach", |ctx: Ctx, buffer: Value| detach(ctx, buffer)))?;
+    let prelude: Object = ctx.eval(PRELUDE)?;
+    let worker: Function = prelude.get("worker")?;
+    let deliver: Function = worker.call((ops,))?;
+    ctx.globals().set(WORKER_DELIVER, deliver)
+}
+
+#[cfg(test)]
+mod tests {
+    use rquickjs::Promise;
+    use transpiler_js::JsTranspiler;
+    use transpilers::{register, Transpilers};
+    use crate::cache::NoCache;
+    use crate::runtime::JsRuntime;
+    use super::Workers;
+
+    fn new_runtime() -> JsRuntime {
+        let mut transpilers = Transpilers::default();
+        register!(transpilers, "javascript", [.js], JsTranspiler);
+        let resolver = crate::resolver::ExerumResolver::new(".");
+        let loader = crate::loader::ExerumLoader::new(Box::new(NoCache {}), transpilers);
+        JsRuntime::new(loader, resolver)
+    }
+
+    #[test]
+    fn test_worker() {
+        let rt = new_runtime().with_executor().unwrap();
+        Workers::new(new_runtime).install(rt.context()).unwrap();
+        // `new Worker` spawns the task receiving its messages on the `LocalSet`
+        rt.block_on(async {
+            let promise: Promise<String> = rt.context().with(|ctx| {
+                ctx.eval(
+                    r#"
+                const worker = new Worker("test_data/src/worker/sum.js");
+                const bytes = new Uint8Array([1, 2, 3]);
+                let uncloneable = false;
+                try {
+                    worker.postMessage({ f() {} });
+                } catch (e) {
+                    uncloneable = e instanceof DataCloneError;
+                }
+                const result = new Promise((resolve, reject) => {
+                    worker.onmessage = ({ data }) => {
+                        worker.terminate();
+                        const when = data.when instanceof Date ? data.when.getTime() : "not a date";
+                        resolve([data.sum, Array.from(data.bytes).join(","), bytes.byteLength, when, uncloneable].join("|"));
+                    };
+                    worker.onerror = (e) => reject(new Error(e.message));
+                });
+                worker.postMessage({ numbers: new Set([1, 2, 3]), bytes, when: new Date(5) }, [bytes.buffer]);
+                result
+            "#,
+                )
+                .unwrap()
+            });
+            assert_eq!(promise.await.unwrap(), "6|2,4,6|0|5|true");
+
+            let promise: Promise<String> = rt.context().with(|ctx| {
+                ctx.eval(
+                    r#"
+                new Promise((resolve) => {
+                    const worker = new Worker("test_data/src/worker/missing.js");
+                    worker.onerror = (e) => resolve(e.message);
+                })
+            "#,
+                )
+                .unwrap()
+            });
+            assert!(promise.await.unwrap().contains("missing.js"));
+            rt.rt().idle().await;
+        });
+    }
+}
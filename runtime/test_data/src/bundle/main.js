@@ -0,0 +1,6 @@
+import { name } from "./static.js";
+
+export async function run() {
+    const { lazy } = await import("./lazy.js");
+    return name + ":" + lazy;
+}
@@ -0,0 +1,3 @@
+import { greeting } from "exerum:greeter";
+
+export const message = greeting("bundle");
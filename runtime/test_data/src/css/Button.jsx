@@ -0,0 +1,9 @@
+import React from "./react.js";
+import styles from "./button.module.css";
+import { stylesheet } from "./theme.css";
+
+export function Button({ label }) {
+    return <button className={styles.button}>{label}</button>;
+}
+
+export const theme = stylesheet;
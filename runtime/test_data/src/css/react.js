@@ -0,0 +1,3 @@
+export default {
+    createElement: (type, props, ...children) => ({ type, props, children }),
+};
@@ -0,0 +1,5 @@
+export async function load() {
+    const yaml = await import("./config.yaml");
+    const toml = await import("./config.toml");
+    return [yaml.name, yaml.default.then, typeof yaml.then, toml.port, toml.default["then"]];
+}
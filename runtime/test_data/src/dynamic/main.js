@@ -0,0 +1,15 @@
+async function failure(load) {
+    try {
+        await load();
+        return "no error";
+    } catch (e) {
+        return e.message;
+    }
+}
+
+export async function run() {
+    const [a, b] = await Promise.all([import("../bundle/lazy.js"), import("../bundle/lazy.js")]);
+    const missing = await failure(() => import("./missing.js"));
+    const broken = await failure(() => import("./broken.ts"));
+    return [a.lazy + ":" + (a === b), missing, broken].join("|");
+}
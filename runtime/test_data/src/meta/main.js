@@ -0,0 +1,4 @@
+export const url = import.meta.url;
+export const dirname = import.meta.dirname;
+export const resolved = import.meta.resolve("../bundle/lazy.js");
+export const mode = import.meta.env.MODE;
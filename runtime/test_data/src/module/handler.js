@@ -0,0 +1,4 @@
+export let handler = () => "first";
+export const swap = () => {
+    handler = () => "second";
+};
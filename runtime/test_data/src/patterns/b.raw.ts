@@ -0,0 +1 @@
+export const kind = typeof banner === "undefined" ? "raw" : "banner";
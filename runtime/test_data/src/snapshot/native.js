@@ -0,0 +1,4 @@
+import { greeting } from "exerum:greeting";
+
+globalThis.preloadedGreeting = greeting;
+export const greet = (name) => greeting + " " + name;
@@ -0,0 +1,5 @@
+export const logged = [];
+
+export function log(value) {
+    logged.push(value);
+}
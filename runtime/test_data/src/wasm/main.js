@@ -0,0 +1,6 @@
+import { add } from "./add.wasm";
+import { logged } from "./env.js";
+
+export function run(a, b) {
+    return [add(a, b), logged];
+}
@@ -0,0 +1,5 @@
+onmessage = ({ data }) => {
+    const sum = [...data.numbers].reduce((a, b) => a + b, 0);
+    const bytes = data.bytes.map((b) => b * 2);
+    postMessage({ sum, bytes, when: data.when }, [bytes.buffer]);
+};
@@ -0,0 +1,32 @@
+//! Array buffer operations rquickjs doesn't expose
+use rquickjs::{qjs, Ctx, Result, Value};
+use js_serde::raw::{clear_exception, from_raw, with_raw};
+
+/// Creates an array buffer viewing `data` without copying it.
+///
+/// # Safety
+/// The buffer borrows `data` beyond the lifetime of the slice. The caller must detach it
+/// with `detach` before `data` moves, is resized or is freed, and must not access `data`
+/// through other references while javascript may use the buffer.
+pub(crate) unsafe fn external<'js>(ctx: Ctx<'js>, data: &mut [u8]) -> Result<Value<'js>> {
+    let buffer = qjs::JS_NewArrayBuffer(ctx.as_ptr(), data.as_mut_ptr(), data.len() as _, None, std::ptr::null_mut(), 0);
+    from_raw(ctx, buffer)
+}
+
+/// Detaches the array buffer, its views see zero bytes afterwards
+pub fn detach<'js>(ctx: Ctx<'js>, buffer: Value<'js>) -> Result<()> {
+    with_raw(ctx, buffer, |buffer| unsafe { qjs::JS_DetachArrayBuffer(ctx.as_ptr(), buffer) })
+}
+
+/// A copy of the bytes of the array buffer, `None` if the value isn't an array buffer or is detached
+pub fn bytes<'js>(ctx: Ctx<'js>, buffer: Value<'js>) -> Result<Option<Vec<u8>>> {
+    with_raw(ctx, buffer, |buffer| unsafe {
+        let mut size = 0;
+        let data = qjs::JS_GetArrayBuffer(ctx.as_ptr(), &mut size, buffer);
+        if data.is_null() {
+            clear_exception(ctx);
+            return None;
+        }
+        Some(std::slice::from_raw_parts(data, size as usize).to_vec())
+    })
+}
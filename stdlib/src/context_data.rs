@@ -0,0 +1,108 @@
+//! Rust state of a context, kept behind the context's opaque pointer rather than on the
+//! global object, where scripts could read or replace it.
+//!
+//! Values are stored by type, each crate using it keeps its own types private.
+//! They may hold javascript values (`Persistent`), so they must be dropped while the
+//! runtime is alive: `JsRuntime` frees the data of its contexts when it's dropped.
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use rquickjs::{qjs, Ctx};
+
+#[derive(Default)]
+struct ContextData {
+    values: RefCell<HashMap<TypeId, Rc<dyn Any>>>,
+}
+
+/// Data of the context, created on first use
+fn data<'a>(ctx: Ctx) -> &'a ContextData {
+    // Safety: `ctx` is a live context
+    unsafe { raw_data(ctx.as_ptr()) }
+}
+
+/// `data` for the raw context
+///
+/// # Safety
+/// `ctx` must be a live context
+unsafe fn raw_data<'a>(ctx: *mut qjs::JSContext) -> &'a ContextData {
+    let mut data = qjs::JS_GetContextOpaque(ctx) as *const ContextData;
+    if data.is_null() {
+        data = Box::into_raw(Box::<ContextData>::default());
+        qjs::JS_SetContextOpaque(ctx, data as *mut _);
+    }
+    // Safety: the data lives until `free`, which isn't called while it's borrowed
+    &*data
+}
+
+/// The value of type `T` stored in the context
+pub fn get<T: 'static>(ctx: Ctx) -> Option<Rc<T>> {
+    // Safety: `ctx` is a live context
+    unsafe { get_raw(ctx.as_ptr()) }
+}
+
+/// `get` for callbacks of QuickJS, which only have the raw context
+///
+/// # Safety
+/// `ctx` must be a live context
+pub unsafe fn get_raw<T: 'static>(ctx: *mut qjs::JSContext) -> Option<Rc<T>> {
+    let value = raw_data(ctx).values.borrow().get(&TypeId::of::<T>()).cloned()?;
+    value.downcast().ok()
+}
+
+/// The value of type `T` stored in the context, stores the one `init` creates if there's none.
+/// `init` may run javascript, which may use the stored values.
+pub fn get_or_init<T: 'static, E>(ctx: Ctx, init: impl FnOnce() -> Result<T, E>) -> Result<Rc<T>, E> {
+    if let Some(value) = get(ctx) {
+        return Ok(value);
+    }
+    let value = Rc::new(init()?);
+    data(ctx).values.borrow_mut().insert(TypeId::of::<T>(), value.clone());
+    Ok(value)
+}
+
+/// Stores the value, replacing the one of the same type
+pub fn set<T: 'static>(ctx: Ctx, value: T) {
+    let previous = data(ctx).values.borrow_mut().insert(TypeId::of::<T>(), Rc::new(value));
+    // Dropping it may run finalizers using the data
+    drop(previous);
+}
+
+/// Drops the data of the context. Using the context afterwards creates new, empty data.
+pub fn free(ctx: Ctx) {
+    unsafe {
+        let data = qjs::JS_GetContextOpaque(ctx.as_ptr()) as *mut ContextData;
+        if data.is_null() {
+            return;
+        }
+        qjs::JS_SetContextOpaque(ctx.as_ptr(), std::ptr::null_mut());
+        drop(Box::from_raw(data));
+    }
+    // Finalizers run by dropping the values may have created new data
+    if !unsafe { qjs::JS_GetContextOpaque(ctx.as_ptr()) }.is_null() {
+        free(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rquickjs::{Context, Runtime};
+
+    #[test]
+    fn test_context_data() {
+        struct Counter(std::cell::Cell<u32>);
+        let rt = Runtime::new().unwrap();
+        let (a, b) = (Context::full(&rt).unwrap(), Context::full(&rt).unwrap());
+        a.with(|ctx| {
+            super::set(ctx, Counter(Default::default()));
+            super::get::<Counter>(ctx).unwrap().0.set(1);
+        });
+        assert!(b.with(|ctx| super::get::<Counter>(ctx).is_none()));
+        let value = a.with(|ctx| super::get_or_init::<_, ()>(ctx, || Ok(Counter(Default::default()))).unwrap().0.get());
+        assert_eq!(value, 1);
+        a.with(super::free);
+        b.with(super::free);
+        assert!(a.with(|ctx| super::get::<Counter>(ctx).is_none()));
+        a.with(super::free);
+    }
+}
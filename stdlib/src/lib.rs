@@ -1,5 +1,54 @@
-use rquickjs::Context;
+#[cfg(feature = "webassembly")]
+pub mod webassembly;
+pub mod permissions;
+pub mod array_buffer;
+pub mod context_data;
+pub mod structured_clone;
+use rquickjs::{Context, Result};
+use permissions::Permissions;
 
-pub fn init_stdlib(_context: &Context) {
+/// Which parts of the stdlib are installed into a context, and the permissions their operations check
+#[derive(Debug, Clone)]
+pub struct StdlibConfig {
+    pub permissions: Permissions,
+    /// `WebAssembly` namespace, requires the `webassembly` feature
+    pub webassembly: bool,
+}
 
-}
\ No newline at end of file
+impl StdlibConfig {
+    /// Everything the enabled features provide
+    pub fn new(permissions: Permissions) -> Self {
+        StdlibConfig {
+            permissions,
+            webassembly: true,
+        }
+    }
+
+    pub fn without_webassembly(mut self) -> Self {
+        self.webassembly = false;
+        self
+    }
+
+    pub fn init(&self, context: &Context) -> Result<()> {
+        context.with(|ctx| {
+            permissions::init(ctx)?;
+            structured_clone::init(ctx)?;
+            #[cfg(feature = "webassembly")]
+            if self.webassembly {
+                webassembly::init(ctx, &self.permissions)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Default for StdlibConfig {
+    fn default() -> Self {
+        StdlibConfig::new(Permissions::allow_all())
+    }
+}
+
+/// Installs the stdlib into the context. Its operations check `permissions`.
+pub fn init_stdlib(context: &Context, permissions: &Permissions) -> Result<()> {
+    StdlibConfig::new(permissions.clone()).init(context)
+}
@@ -0,0 +1,13 @@
+// Defines the error thrown when an operation isn't permitted, see permissions.rs.
+// Operations report denials as `{ __error: "PermissionDenied", message, permission }`.
+(() => {
+    class PermissionDenied extends Error {
+        constructor(message, permission) {
+            super(message);
+            this.name = "PermissionDenied";
+            // { name: "read" | "write" | "net" | "env" | "run" | "op", target }
+            this.permission = permission;
+        }
+    }
+    globalThis.PermissionDenied = PermissionDenied;
+})()
@@ -0,0 +1,395 @@
+//! Capability based permissions of a runtime.
+//!
+//! Every operation touching the outside world checks a `Permission` first: stdlib
+//! operations, file access of the resolver and the loader, and host operations.
+//! Denied operations return `{ __error: "PermissionDenied", message, permission }`,
+//! which the javascript side throws as a `PermissionDenied` error.
+//!
+//! ```ignore
+//! let permissions = Permissions::none()
+//!     .allow_read("./src")
+//!     .allow_net("deno.land")
+//!     .with_audit(|permission, granted| log::info!("{} granted: {}", permission, granted));
+//! ```
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use rquickjs::{Ctx, Object, Result, Value};
+
+const PRELUDE: &str = include_str!("permissions.js");
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Permission {
+    /// Reading a file or a directory, with everything below it
+    Read(PathBuf),
+    /// Writing a file or a directory, with everything below it
+    Write(PathBuf),
+    /// Connecting to a host, `host` or `host:port`
+    Net(String),
+    /// Reading an environment variable
+    Env(String),
+    /// Running a program as a subprocess
+    Run(String),
+    /// Calling a host operation, by its name
+    Op(String),
+}
+
+impl Permission {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::Read(_) => "read",
+            Permission::Write(_) => "write",
+            Permission::Net(_) => "net",
+            Permission::Env(_) => "env",
+            Permission::Run(_) => "run",
+            Permission::Op(_) => "op",
+        }
+    }
+
+    pub fn target(&self) -> String {
+        match self {
+            Permission::Read(path) | Permission::Write(path) => path.to_string_lossy().into_owned(),
+            Permission::Net(target) | Permission::Env(target) | Permission::Run(target) | Permission::Op(target) => {
+                target.clone()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} access to \"{}\"", self.name(), self.target())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermissionDenied {
+    pub permission: Permission,
+}
+
+impl PermissionDenied {
+    /// The `{ __error: "PermissionDenied", ... }` result of a denied operation
+    pub fn to_js<'js>(&self, ctx: Ctx<'js>) -> Result<Value<'js>> {
+        let permission = Object::new(ctx)?;
+        permission.set("name", self.permission.name())?;
+        permission.set("target", self.permission.target())?;
+        let obj = Object::new(ctx)?;
+        obj.set("__error", "PermissionDenied")?;
+        obj.set("message", self.to_string())?;
+        obj.set("permission", permission)?;
+        Ok(obj.into_value())
+    }
+}
+
+impl fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PermissionDenied: requires {}", self.permission)
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
+
+enum Grant<T> {
+    All,
+    Only(Vec<T>),
+}
+
+impl<T> Grant<T> {
+    fn add(&mut self, value: T) {
+        match self {
+            Grant::All => {}
+            Grant::Only(values) => values.push(value),
+        }
+    }
+
+    fn any(&self, f: impl Fn(&T) -> bool) -> bool {
+        match self {
+            Grant::All => true,
+            Grant::Only(values) => values.iter().any(f),
+        }
+    }
+}
+
+/// The callbacks are called without holding the grants, so they may check permissions themselves
+type Prompt = Arc<Mutex<dyn FnMut(&Permission) -> bool + Send>>;
+type Audit = Arc<Mutex<dyn FnMut(&Permission, bool) + Send>>;
+
+struct Granted {
+    read: Grant<PathBuf>,
+    write: Grant<PathBuf>,
+    net: Grant<String>,
+    env: Grant<String>,
+    run: Grant<String>,
+    ops: Grant<String>,
+    prompt: Option<Prompt>,
+    audit: Option<Audit>,
+}
+
+impl Granted {
+    fn allows(&self, permission: &Permission) -> bool {
+        match permission {
+            Permission::Read(path) => self.read.any(|allowed| path.starts_with(allowed)),
+            Permission::Write(path) => self.write.any(|allowed| path.starts_with(allowed)),
+            Permission::Net(target) => {
+                let host = host_of(target);
+                self.net.any(|allowed| allowed == "*" || allowed == target || allowed == host)
+            }
+            Permission::Env(name) => self.env.any(|allowed| allowed == "*" || allowed == name),
+            Permission::Run(program) => self.run.any(|allowed| allowed == "*" || allowed == program),
+            Permission::Op(name) => self.ops.any(|allowed| allowed == "*" || allowed == name),
+        }
+    }
+
+    fn grant(&mut self, permission: Permission) {
+        match permission {
+            Permission::Read(path) => self.read.add(path),
+            Permission::Write(path) => self.write.add(path),
+            Permission::Net(target) => self.net.add(target),
+            Permission::Env(name) => self.env.add(name),
+            Permission::Run(program) => self.run.add(program),
+            Permission::Op(name) => self.ops.add(name),
+        }
+    }
+}
+
+/// Permissions shared by the runtime, its stdlib, resolver and loader.
+/// Clones share the grants, so permissions granted by the prompt apply everywhere.
+#[derive(Clone)]
+pub struct Permissions {
+    granted: Arc<Mutex<Granted>>,
+}
+
+impl Permissions {
+    /// Grants everything, the default of `JsRuntime`
+    pub fn allow_all() -> Self {
+        Permissions::new(true)
+    }
+
+    /// Grants nothing, use the `allow_*` methods to add grants
+    pub fn none() -> Self {
+        Permissions::new(false)
+    }
+
+    fn new(all: bool) -> Self {
+        fn grant<T>(all: bool) -> Grant<T> {
+            if all {
+                Grant::All
+            } else {
+                Grant::Only(vec![])
+            }
+        }
+        Permissions {
+            granted: Arc::new(Mutex::new(Granted {
+                read: grant(all),
+                write: grant(all),
+                net: grant(all),
+                env: grant(all),
+                run: grant(all),
+                ops: grant(all),
+                prompt: None,
+                audit: None,
+            })),
+        }
+    }
+
+    /// Whether both are clones of the same permissions
+    pub fn ptr_eq(&self, other: &Permissions) -> bool {
+        Arc::ptr_eq(&self.granted, &other.granted)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Granted> {
+        self.granted.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn allow(self, permission: Permission) -> Self {
+        self.lock().grant(normalize(permission));
+        self
+    }
+
+    /// Allows reading the path and everything below it
+    pub fn allow_read(self, path: impl AsRef<Path>) -> Self {
+        self.allow(Permission::Read(path.as_ref().to_path_buf()))
+    }
+
+    /// Allows writing the path and everything below it
+    pub fn allow_write(self, path: impl AsRef<Path>) -> Self {
+        self.allow(Permission::Write(path.as_ref().to_path_buf()))
+    }
+
+    /// Allows connecting to `host` on any port, or only to `host:port`. `*` allows every host.
+    pub fn allow_net(self, host: &str) -> Self {
+        self.allow(Permission::Net(host.to_owned()))
+    }
+
+    /// Allows reading the environment variable, `*` allows all of them
+    pub fn allow_env(self, name: &str) -> Self {
+        self.allow(Permission::Env(name.to_owned()))
+    }
+
+    /// Allows running the program, `*` allows every program
+    pub fn allow_run(self, program: &str) -> Self {
+        self.allow(Permission::Run(program.to_owned()))
+    }
+
+    /// Allows calling the host operation, `*` allows every operation
+    pub fn allow_op(self, name: &str) -> Self {
+        self.allow(Permission::Op(name.to_owned()))
+    }
+
+    /// Asks the callback about permissions that weren't granted.
+    /// Permissions it grants are remembered.
+    pub fn with_prompt(self, prompt: impl FnMut(&Permission) -> bool + Send + 'static) -> Self {
+        self.lock().prompt = Some(Arc::new(Mutex::new(prompt)));
+        self
+    }
+
+    /// Reports every checked permission and whether it was granted
+    pub fn with_audit(self, audit: impl FnMut(&Permission, bool) + Send + 'static) -> Self {
+        self.lock().audit = Some(Arc::new(Mutex::new(audit)));
+        self
+    }
+
+    /// Whether the permission is granted, without prompting or auditing
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.lock().allows(&normalize(permission))
+    }
+
+    pub fn check(&self, permission: Permission) -> std::result::Result<(), PermissionDenied> {
+        let permission = normalize(permission);
+        let (mut allowed, prompt, audit) = {
+            let granted = self.lock();
+            (granted.allows(&permission), granted.prompt.clone(), granted.audit.clone())
+        };
+        if !allowed {
+            if let Some(prompt) = prompt {
+                allowed = (&mut *prompt.lock().unwrap_or_else(|e| e.into_inner()))(&permission);
+                if allowed {
+                    self.lock().grant(permission.clone());
+                }
+            }
+        }
+        if let Some(audit) = audit {
+            (&mut *audit.lock().unwrap_or_else(|e| e.into_inner()))(&permission, allowed);
+        }
+        if allowed {
+            Ok(())
+        } else {
+            Err(PermissionDenied { permission })
+        }
+    }
+
+    pub fn check_read(&self, path: impl AsRef<Path>) -> std::result::Result<(), PermissionDenied> {
+        self.check(Permission::Read(path.as_ref().to_path_buf()))
+    }
+
+    pub fn check_write(&self, path: impl AsRef<Path>) -> std::result::Result<(), PermissionDenied> {
+        self.check(Permission::Write(path.as_ref().to_path_buf()))
+    }
+
+    pub fn check_net(&self, target: &str) -> std::result::Result<(), PermissionDenied> {
+        self.check(Permission::Net(target.to_owned()))
+    }
+
+    pub fn check_env(&self, name: &str) -> std::result::Result<(), PermissionDenied> {
+        self.check(Permission::Env(name.to_owned()))
+    }
+
+    pub fn check_run(&self, program: &str) -> std::result::Result<(), PermissionDenied> {
+        self.check(Permission::Run(program.to_owned()))
+    }
+
+    pub fn check_op(&self, name: &str) -> std::result::Result<(), PermissionDenied> {
+        self.check(Permission::Op(name.to_owned()))
+    }
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions::allow_all()
+    }
+}
+
+impl fmt::Debug for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Permissions")
+    }
+}
+
+/// Defines the `PermissionDenied` error class
+pub fn init(ctx: Ctx) -> Result<()> {
+    let _: Value = ctx.eval(PRELUDE)?;
+    Ok(())
+}
+
+/// Paths are compared absolute, with symlinks of existing paths resolved
+fn normalize(permission: Permission) -> Permission {
+    match permission {
+        Permission::Read(path) => Permission::Read(absolute(&path)),
+        Permission::Write(path) => Permission::Write(absolute(&path)),
+        permission => permission,
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    if let Ok(path) = std::fs::canonicalize(path) {
+        return path;
+    }
+    let joined = std::env::current_dir().unwrap_or_default().join(path);
+    let mut normalized = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    // The closest existing ancestor may be a symlink
+    let mut existing = normalized.as_path();
+    while let Some(parent) = existing.parent() {
+        if let Ok(canonical) = std::fs::canonicalize(parent) {
+            return canonical.join(normalized.strip_prefix(parent).unwrap_or(&normalized));
+        }
+        existing = parent;
+    }
+    normalized
+}
+
+fn host_of(target: &str) -> &str {
+    match target.rsplit_once(':') {
+        // IPv6 addresses without a port are bracketed
+        Some((host, port)) if !port.contains(']') && port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => target,
+    }
+}
+
+#[test]
+fn test_permissions() {
+    let audited = Arc::new(Mutex::new(vec![]));
+    let log = audited.clone();
+    let permissions = Permissions::none()
+        .allow_read("./src")
+        .allow_net("example.com")
+        .allow_env("HOME")
+        .with_prompt(|permission| permission == &Permission::Run("git".to_owned()))
+        .with_audit(move |permission, granted| log.lock().unwrap().push((permission.name(), granted)));
+    assert!(permissions.check_read("src/lib.rs").is_ok());
+    assert!(permissions.check_read("src/../Cargo.toml").is_err());
+    assert!(permissions.check_write("src/lib.rs").is_err());
+    assert!(permissions.check_net("example.com:443").is_ok());
+    assert!(permissions.check_net("example.org").is_err());
+    assert!(permissions.check_env("HOME").is_ok());
+    assert!(permissions.check_env("PATH").is_err());
+    assert!(permissions.check_run("git").is_ok());
+    assert!(permissions.check_run("sh").is_err());
+    let denied = permissions.check_op("fs.read").unwrap_err();
+    assert_eq!(denied.to_string(), "PermissionDenied: requires op access to \"fs.read\"");
+    assert!(!permissions.allows(Permission::Run("sh".to_owned())));
+    assert_eq!(audited.lock().unwrap().len(), 10);
+    assert!(Permissions::allow_all().check_write("/etc/passwd").is_ok());
+    // The prompt runs without the grants locked
+    let inner = Permissions::none().allow_env("HOME");
+    let outer = inner.clone().with_prompt(move |_| inner.check_env("HOME").is_ok());
+    assert!(outer.check_env("USER").is_ok());
+}
@@ -0,0 +1,114 @@
+// Helpers used by structured_clone.rs to inspect and build javascript values,
+// and the `structuredClone` global built on the operations it implements.
+// The builtins are captured when the prelude runs, before any script can replace them.
+(() => {
+    class DataCloneError extends Error {
+        constructor(message) { super(message); this.name = "DataCloneError"; }
+    }
+    const { apply } = Reflect;
+    const uncurry = (f) => (self, ...args) => apply(f, self, args);
+    const getter = (proto, name) => uncurry(Object.getOwnPropertyDescriptor(proto, name).get);
+    const TypedArray = Object.getPrototypeOf(Int8Array);
+    const errors = { __proto__: null, Error, EvalError, RangeError, ReferenceError, SyntaxError, TypeError, URIError };
+    const views = {
+        __proto__: null, Int8Array, Uint8Array, Uint8ClampedArray, Int16Array, Uint16Array, Int32Array,
+        Uint32Array, Float32Array, Float64Array, BigInt64Array, BigUint64Array, DataView,
+    };
+    const { Array, Date, Map, Object, RegExp, Set, String } = globalThis;
+    const regexpPrototype = RegExp.prototype;
+    const toString = uncurry(Object.prototype.toString);
+    const time = uncurry(Date.prototype.getTime);
+    const source = getter(RegExp.prototype, "source");
+    const flags = getter(RegExp.prototype, "flags");
+    const mapSize = getter(Map.prototype, "size");
+    const mapForEach = uncurry(Map.prototype.forEach);
+    const mapSet = uncurry(Map.prototype.set);
+    const setSize = getter(Set.prototype, "size");
+    const setForEach = uncurry(Set.prototype.forEach);
+    const setAdd = uncurry(Set.prototype.add);
+    const viewKind = getter(TypedArray.prototype, Symbol.toStringTag);
+    const viewBuffer = getter(TypedArray.prototype, "buffer");
+    const viewOffset = getter(TypedArray.prototype, "byteOffset");
+    const viewLength = getter(TypedArray.prototype, "length");
+    const dataViewBuffer = getter(DataView.prototype, "buffer");
+    const dataViewOffset = getter(DataView.prototype, "byteOffset");
+    const dataViewLength = getter(DataView.prototype, "byteLength");
+    const unbox = [Boolean, Number, String, BigInt].map((type) => [type.name, uncurry(type.prototype.valueOf)]);
+    // Whether `check` accepts the value, the checks throw for values of other classes
+    const is = (check, v) => {
+        try {
+            check(v);
+            return true;
+        } catch {
+            return false;
+        }
+    };
+    const helpers = {
+        // Class of an object that isn't an array, error or array buffer by its internal slots,
+        // undefined for other objects
+        kind: (v) => {
+            const view = viewKind(v);
+            if (view !== undefined) return view;
+            if (is(time, v)) return "Date";
+            if (v !== regexpPrototype && is(source, v)) return "RegExp";
+            if (is(mapSize, v)) return "Map";
+            if (is(setSize, v)) return "Set";
+            if (is(dataViewLength, v)) return "DataView";
+            for (const [name, valueOf] of unbox) {
+                if (is(valueOf, v)) return name;
+            }
+        },
+        // `Symbol.toStringTag` or the builtin tag, "Object" for plain objects
+        tag: (v) => toString(v).slice(8, -1),
+        entries: (v) => {
+            const out = [];
+            if (is(mapSize, v)) {
+                mapForEach(v, (value, key) => { out[out.length] = [key, value]; });
+            } else {
+                setForEach(v, (value) => { out[out.length] = value; });
+            }
+            return out;
+        },
+        time,
+        regexpParts: (v) => [source(v), flags(v)],
+        viewParts: (v) => is(dataViewLength, v)
+            ? ["DataView", dataViewBuffer(v), dataViewOffset(v), dataViewLength(v)]
+            : [viewKind(v), viewBuffer(v), viewOffset(v), viewLength(v)],
+        errorParts: (v) => [String(v.name), String(v.message), v.stack === undefined ? undefined : String(v.stack)],
+        unbox: (v) => {
+            for (const [, valueOf] of unbox) {
+                if (is(valueOf, v)) return valueOf(v);
+            }
+        },
+
+        array: (length) => new Array(length),
+        date: (time) => new Date(time),
+        regexp: (source, flags) => new RegExp(source, flags),
+        map: () => new Map(),
+        mapSet,
+        set: () => new Set(),
+        setAdd,
+        buffer: viewBuffer,
+        view: (kind, buffer, offset, length) => new views[kind](buffer, offset, length),
+        error: (name, message, stack) => {
+            const error = new (errors[name] ?? Error)(message);
+            if (stack === undefined) delete error.stack; else error.stack = stack;
+            return error;
+        },
+        box: (v) => Object(v),
+    };
+    const install = (ops) => {
+        globalThis.DataCloneError = DataCloneError;
+        globalThis.structuredClone = (value, options = {}) => {
+            const transfer = options.transfer ?? [];
+            for (const item of transfer) {
+                if (!ops.isArrayBuffer(item)) throw new DataCloneError("only ArrayBuffers can be transferred");
+            }
+            const result = ops.clone(value);
+            if (result.__error !== undefined) throw new DataCloneError(result.message);
+            transfer.forEach(ops.detach);
+            return result.value;
+        };
+    };
+    return { helpers, install };
+})()
@@ -0,0 +1,712 @@
+//! Structured clone of javascript values, following the HTML structured clone algorithm,
+//! and a compact binary format to move the clones between contexts, threads and hosts.
+//!
+//! Supported: primitives (except symbols), plain objects, arrays, `Date`, `RegExp`, `Map`,
+//! `Set`, `ArrayBuffer`, typed arrays, `DataView`, errors and boxed primitives. Shared and
+//! cyclic references are kept. Functions, symbols and other objects fail with `DataCloneError`.
+//!
+//! Format: `0xEC`, format version, then one value. A value is a tag byte followed by its
+//! payload, integers are LEB128 varints, strings and byte blobs are length prefixed.
+//! Objects are numbered in order of appearance, references to seen objects use the number.
+use std::collections::HashMap;
+use std::fmt;
+use rquickjs::{Array, Ctx, FromJs, Func, Function, IntoJs, Object, Persistent, Result, TypedArray, Value};
+use js_serde::raw::{self, Type};
+use crate::array_buffer::{self, detach};
+use crate::context_data;
+
+const PRELUDE: &str = include_str!("structured_clone.js");
+const MAGIC: u8 = 0xEC;
+pub const FORMAT_VERSION: u8 = 1;
+/// Deeper values fail instead of overflowing the stack
+const MAX_DEPTH: usize = 2048;
+
+/// Typed arrays and `DataView`, by their code in the format
+pub const VIEW_KINDS: [&str; 12] = [
+    "Int8Array", "Uint8Array", "Uint8ClampedArray", "Int16Array", "Uint16Array", "Int32Array",
+    "Uint32Array", "Float32Array", "Float64Array", "BigInt64Array", "BigUint64Array", "DataView",
+];
+
+/// A cloned value, independent of any context
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cloned {
+    Undefined,
+    Null,
+    Bool(bool),
+    Number(f64),
+    /// Decimal digits
+    BigInt(String),
+    String(String),
+    /// An object seen before, by its number
+    Ref(u32),
+    Object(Vec<(String, Cloned)>),
+    /// Own enumerable properties, indices included
+    Array { length: u32, properties: Vec<(String, Cloned)> },
+    Date(f64),
+    RegExp { source: String, flags: String },
+    Map(Vec<(Cloned, Cloned)>),
+    Set(Vec<Cloned>),
+    ArrayBuffer(Vec<u8>),
+    /// `kind` is one of `VIEW_KINDS`, `buffer` an `ArrayBuffer` or a reference to one.
+    /// `length` counts elements, or bytes for a `DataView`.
+    View { kind: String, buffer: Box<Cloned>, offset: u32, length: u32 },
+    Error { name: String, message: String, stack: Option<String> },
+    /// `Boolean`, `Number`, `String` or `BigInt` object
+    Boxed(Box<Cloned>),
+}
+
+#[derive(Debug)]
+pub enum CloneError {
+    /// The value can't be cloned
+    DataClone(String),
+    /// The bytes aren't in the format
+    Malformed(String),
+    Js(rquickjs::Error),
+}
+
+impl fmt::Display for CloneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloneError::DataClone(message) => write!(f, "DataCloneError: {}", message),
+            CloneError::Malformed(message) => write!(f, "malformed structured clone: {}", message),
+            CloneError::Js(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CloneError {}
+
+impl From<rquickjs::Error> for CloneError {
+    fn from(e: rquickjs::Error) -> Self {
+        CloneError::Js(e)
+    }
+}
+
+type CloneResult<T> = std::result::Result<T, CloneError>;
+
+/// Clones the value out of its context
+pub fn clone_value<'js>(ctx: Ctx<'js>, value: Value<'js>) -> CloneResult<Cloned> {
+    Reader { ctx, helpers: helpers(ctx)?, ids: HashMap::new(), seen: vec![] }.read(value, 0)
+}
+
+/// Creates the value in the context
+pub fn restore<'js>(ctx: Ctx<'js>, cloned: &Cloned) -> CloneResult<Value<'js>> {
+    Builder { ctx, helpers: helpers(ctx)?, objects: vec![] }.build(cloned, 0)
+}
+
+/// Serializes the value into the binary format
+pub fn serialize<'js>(ctx: Ctx<'js>, value: Value<'js>) -> CloneResult<Vec<u8>> {
+    Ok(clone_value(ctx, value)?.to_bytes())
+}
+
+/// Creates the value serialized by `serialize` in the context
+pub fn deserialize<'js>(ctx: Ctx<'js>, bytes: &[u8]) -> CloneResult<Value<'js>> {
+    restore(ctx, &Cloned::from_bytes(bytes)?)
+}
+
+/// Defines `structuredClone` and `DataCloneError`
+pub fn init(ctx: Ctx) -> Result<()> {
+    let prelude = prelude(ctx)?;
+    let ops = Object::new(ctx)?;
+    ops.set("clone", Func::new("clone", |ctx: Ctx, value: Value| -> Result<Value> {
+        let result = Object::new(ctx)?;
+        match clone_value(ctx, value).and_then(|cloned| restore(ctx, &cloned)) {
+            Ok(value) => result.set("value", value)?,
+            Err(CloneError::Js(e)) => return Err(e),
+            Err(e) => {
+                result.set("__error", "DataCloneError")?;
+                result.set("message", e.to_string())?;
+            }
+        }
+        Ok(result.into_value())
+    }))?;
+    ops.set("detach", Func::new("detach", |ctx: Ctx, buffer: Value| detach(ctx, buffer)))?;
+    ops.set(
+        "isArrayBuffer",
+        Func::new("isArrayBuffer", |ctx: Ctx, value: Value| Ok(array_buffer::bytes(ctx, value)?.is_some())),
+    )?;
+    let install: Function = prelude.get("install")?;
+    install.call((ops,))
+}
+
+/// Helpers of the prelude, kept in the context data rather than on the global object
+struct Helpers(Persistent<Object<'static>>);
+
+/// Evaluates the prelude, keeping its helpers
+fn prelude(ctx: Ctx) -> Result<Object> {
+    let prelude: Object = ctx.eval(PRELUDE)?;
+    let helpers: Object = prelude.get("helpers")?;
+    context_data::set(ctx, Helpers(Persistent::save(ctx, helpers)));
+    Ok(prelude)
+}
+
+fn helpers(ctx: Ctx) -> Result<Object> {
+    // Contexts without the stdlib get them on first use
+    let helpers = context_data::get_or_init(ctx, || {
+        let helpers: Object = ctx.eval::<Object, _>(PRELUDE)?.get("helpers")?;
+        Ok::<_, rquickjs::Error>(Helpers(Persistent::save(ctx, helpers)))
+    })?;
+    Ok(helpers.0.clone().restore(ctx)?)
+}
+
+fn call<'js, A: rquickjs::IntoArgs<'js>, R: FromJs<'js>>(helpers: &Object<'js>, name: &str, args: A) -> Result<R> {
+    let f: Function = helpers.get(name)?;
+    f.call(args)
+}
+
+struct Reader<'js> {
+    ctx: Ctx<'js>,
+    helpers: Object<'js>,
+    /// Numbers of the objects seen so far, by their identity
+    ids: HashMap<usize, u32>,
+    /// Keeps the seen objects alive, so their identities aren't reused
+    seen: Vec<Value<'js>>,
+}
+
+impl<'js> Reader<'js> {
+    fn call<A: rquickjs::IntoArgs<'js>, R: FromJs<'js>>(&self, name: &str, args: A) -> Result<R> {
+        call(&self.helpers, name, args)
+    }
+
+    fn read(&mut self, value: Value<'js>, depth: usize) -> CloneResult<Cloned> {
+        if depth > MAX_DEPTH {
+            return Err(CloneError::DataClone("the value is nested too deeply".to_owned()));
+        }
+        let ctx = self.ctx;
+        let kind = match raw::type_of(ctx, &value)? {
+            Type::Undefined => return Ok(Cloned::Undefined),
+            Type::Null => return Ok(Cloned::Null),
+            Type::Bool => return Ok(Cloned::Bool(bool::from_js(ctx, value)?)),
+            Type::Number => return Ok(Cloned::Number(f64::from_js(ctx, value)?)),
+            Type::String => return Ok(Cloned::String(String::from_js(ctx, value)?)),
+            Type::BigInt => return Ok(Cloned::BigInt(raw::to_string(ctx, &value)?)),
+            t @ (Type::Symbol | Type::Function) => {
+                return Err(CloneError::DataClone(format!("a {} can't be cloned", t.name())))
+            }
+            Type::Array => "Array".to_owned(),
+            Type::Error => "Error".to_owned(),
+            Type::Object => match array_buffer::bytes(ctx, value.clone())? {
+                Some(bytes) => {
+                    return Ok(match self.number(&value)? {
+                        Some(id) => Cloned::Ref(id),
+                        None => Cloned::ArrayBuffer(bytes),
+                    })
+                }
+                None => self.kind(&value)?,
+            },
+        };
+        if let Some(id) = self.number(&value)? {
+            return Ok(Cloned::Ref(id));
+        }
+        let depth = depth + 1;
+        Ok(match kind.as_str() {
+            "Object" => Cloned::Object(self.properties(&value, depth)?),
+            "Array" => Cloned::Array {
+                length: Array::from_js(ctx, value.clone())?.len() as u32,
+                properties: self.properties(&value, depth)?,
+            },
+            "Date" => Cloned::Date(self.call("time", (value,))?),
+            "RegExp" => {
+                let (source, flags) = self.call("regexpParts", (value,))?;
+                Cloned::RegExp { source, flags }
+            }
+            "Map" => {
+                let entries: Vec<(Value, Value)> = self.call("entries", (value,))?;
+                let mut map = Vec::with_capacity(entries.len());
+                for (key, value) in entries {
+                    map.push((self.read(key, depth)?, self.read(value, depth)?));
+                }
+                Cloned::Map(map)
+            }
+            "Set" => {
+                let values: Vec<Value> = self.call("entries", (value,))?;
+                Cloned::Set(values.into_iter().map(|v| self.read(v, depth)).collect::<CloneResult<_>>()?)
+            }
+            "Error" => {
+                let (name, message, stack) = self.call("errorParts", (value,))?;
+                Cloned::Error { name, message, stack }
+            }
+            "Boolean" | "Number" | "String" | "BigInt" => {
+                let primitive: Value = self.call("unbox", (value,))?;
+                Cloned::Boxed(Box::new(self.read(primitive, depth)?))
+            }
+            kind if VIEW_KINDS.contains(&kind) => {
+                let (kind, buffer, offset, length): (String, Value, u32, u32) = self.call("viewParts", (value,))?;
+                let buffer = Box::new(self.read(buffer, depth)?);
+                Cloned::View { kind, buffer, offset, length }
+            }
+            _ => unreachable!("kind returns the kinds above"),
+        })
+    }
+
+    /// Class of an object by its internal slots, plain objects are `"Object"`
+    fn kind(&self, object: &Value<'js>) -> CloneResult<String> {
+        if let Some(kind) = self.call("kind", (object.clone(),))? {
+            return Ok(kind);
+        }
+        // A tag other than "Object" is a builtin class without a clone, or made up
+        let tag: String = self.call("tag", (object.clone(),))?;
+        match tag.as_str() {
+            "Object" => Ok(tag),
+            _ => Err(CloneError::DataClone(format!("{} objects can't be cloned", tag))),
+        }
+    }
+
+    /// The number of an object seen before, or `None` after numbering it
+    fn number(&mut self, object: &Value<'js>) -> Result<Option<u32>> {
+        let id = raw::object_id(self.ctx, object)?;
+        if let Some(number) = self.ids.get(&id) {
+            return Ok(Some(*number));
+        }
+        self.ids.insert(id, self.seen.len() as u32);
+        self.seen.push(object.clone());
+        Ok(None)
+    }
+
+    fn properties(&mut self, value: &Value<'js>, depth: usize) -> CloneResult<Vec<(String, Cloned)>> {
+        let object = Object::from_js(self.ctx, value.clone())?;
+        let keys = object.keys::<String>().collect::<Result<Vec<_>>>()?;
+        let mut properties = Vec::with_capacity(keys.len());
+        for key in keys {
+            let property: Value = object.get(key.as_str())?;
+            let cloned = self.read(property, depth)?;
+            properties.push((key, cloned));
+        }
+        Ok(properties)
+    }
+}
+
+struct Builder<'js> {
+    ctx: Ctx<'js>,
+    helpers: Object<'js>,
+    /// Created objects by their number
+    objects: Vec<Value<'js>>,
+}
+
+impl<'js> Builder<'js> {
+    fn call<A: rquickjs::IntoArgs<'js>, R: FromJs<'js>>(&self, name: &str, args: A) -> Result<R> {
+        call(&self.helpers, name, args)
+    }
+
+    /// Numbers an object whose value is set once its children are built
+    fn reserve(&mut self) -> usize {
+        self.objects.push(Value::new_undefined(self.ctx));
+        self.objects.len() - 1
+    }
+
+    fn build(&mut self, cloned: &Cloned, depth: usize) -> CloneResult<Value<'js>> {
+        if depth > MAX_DEPTH {
+            return Err(CloneError::Malformed("the value is nested too deeply".to_owned()));
+        }
+        let ctx = self.ctx;
+        let depth = depth + 1;
+        let value = match cloned {
+            Cloned::Undefined => Value::new_undefined(ctx),
+            Cloned::Null => Value::new_null(ctx),
+            Cloned::Bool(b) => b.into_js(ctx)?,
+            Cloned::Number(n) => n.into_js(ctx)?,
+            Cloned::BigInt(digits) => raw::bigint(ctx, digits)?,
+            Cloned::String(s) => s.as_str().into_js(ctx)?,
+            Cloned::Ref(id) => self
+                .objects
+                .get(*id as usize)
+                .cloned()
+                .ok_or_else(|| CloneError::Malformed(format!("reference to unknown object {}", id)))?,
+            Cloned::Object(properties) => {
+                let object = Object::new(ctx)?;
+                self.objects.push(object.clone().into_value());
+                self.set_properties(&object, properties, depth)?;
+                object.into_value()
+            }
+            Cloned::Array { length, properties } => {
+                let array: Object = self.call("array", (*length,))?;
+                self.objects.push(array.clone().into_value());
+                self.set_properties(&array, properties, depth)?;
+                array.into_value()
+            }
+            Cloned::Map(entries) => {
+                let map: Value = self.call("map", ())?;
+                self.objects.push(map.clone());
+                for (key, value) in entries {
+                    let (key, value) = (self.build(key, depth)?, self.build(value, depth)?);
+                    self.call("mapSet", (map.clone(), key, value))?;
+                }
+                map
+            }
+            Cloned::Set(values) => {
+                let set: Value = self.call("set", ())?;
+                self.objects.push(set.clone());
+                for value in values {
+                    let value = self.build(value, depth)?;
+                    self.call("setAdd", (set.clone(), value))?;
+                }
+                set
+            }
+            Cloned::View { kind, buffer, offset, length } => {
+                if !VIEW_KINDS.contains(&kind.as_str()) {
+                    return Err(CloneError::Malformed(format!("unknown view {}", kind)));
+                }
+                let slot = self.reserve();
+                let buffer = self.build(buffer, depth)?;
+                let view: Value = self.call("view", (kind.as_str(), buffer, *offset, *length))?;
+                self.objects[slot] = view.clone();
+                view
+            }
+            Cloned::Boxed(primitive) => {
+                let slot = self.reserve();
+                let primitive = self.build(primitive, depth)?;
+                let boxed: Value = self.call("box", (primitive,))?;
+                self.objects[slot] = boxed.clone();
+                boxed
+            }
+            leaf => {
+                let value: Value = match leaf {
+                    Cloned::Date(time) => self.call("date", (*time,))?,
+                    Cloned::RegExp { source, flags } => self.call("regexp", (source.as_str(), flags.as_str()))?,
+                    Cloned::ArrayBuffer(bytes) => {
+                        let bytes = TypedArray::new(ctx, bytes.clone())?;
+                        self.call("buffer", (bytes,))?
+                    }
+                    Cloned::Error { name, message, stack } => {
+                        self.call("error", (name.as_str(), message.as_str(), stack.as_deref()))?
+                    }
+                    _ => unreachable!("primitives are handled above"),
+                };
+                self.objects.push(value.clone());
+                value
+            }
+        };
+        Ok(value)
+    }
+
+    fn set_properties(&mut self, object: &Object<'js>, properties: &[(String, Cloned)], depth: usize) -> CloneResult<()> {
+        for (key, value) in properties {
+            let value = self.build(value, depth)?;
+            // Defined, so a `__proto__` key is a property rather than the prototype
+            raw::define_value(self.ctx, object, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+mod tag {
+    pub const UNDEFINED: u8 = 0;
+    pub const NULL: u8 = 1;
+    pub const FALSE: u8 = 2;
+    pub const TRUE: u8 = 3;
+    /// Integral numbers in the i32 range, zigzag encoded
+    pub const INT: u8 = 4;
+    pub const NUMBER: u8 = 5;
+    pub const BIGINT: u8 = 6;
+    pub const STRING: u8 = 7;
+    pub const REF: u8 = 8;
+    pub const OBJECT: u8 = 9;
+    pub const ARRAY: u8 = 10;
+    pub const DATE: u8 = 11;
+    pub const REGEXP: u8 = 12;
+    pub const MAP: u8 = 13;
+    pub const SET: u8 = 14;
+    pub const ARRAY_BUFFER: u8 = 15;
+    pub const VIEW: u8 = 16;
+    pub const ERROR: u8 = 17;
+    pub const ERROR_WITH_STACK: u8 = 18;
+    pub const BOXED: u8 = 19;
+}
+
+impl Cloned {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![MAGIC, FORMAT_VERSION];
+        self.write(&mut out);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> CloneResult<Cloned> {
+        let mut reader = Bytes { bytes, position: 0 };
+        if reader.byte()? != MAGIC {
+            return Err(CloneError::Malformed("not a structured clone".to_owned()));
+        }
+        let version = reader.byte()?;
+        if version != FORMAT_VERSION {
+            return Err(CloneError::Malformed(format!("format version {} is not supported", version)));
+        }
+        let value = reader.value(0)?;
+        if reader.position != bytes.len() {
+            return Err(CloneError::Malformed("trailing bytes".to_owned()));
+        }
+        Ok(value)
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Cloned::Undefined => out.push(tag::UNDEFINED),
+            Cloned::Null => out.push(tag::NULL),
+            Cloned::Bool(false) => out.push(tag::FALSE),
+            Cloned::Bool(true) => out.push(tag::TRUE),
+            Cloned::Number(n) => {
+                let int = *n as i32;
+                if int as f64 == *n && !(*n == 0.0 && n.is_sign_negative()) {
+                    out.push(tag::INT);
+                    write_varint(out, ((int << 1) ^ (int >> 31)) as u32 as u64);
+                } else {
+                    out.push(tag::NUMBER);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+            }
+            Cloned::BigInt(digits) => {
+                out.push(tag::BIGINT);
+                write_str(out, digits);
+            }
+            Cloned::String(s) => {
+                out.push(tag::STRING);
+                write_str(out, s);
+            }
+            Cloned::Ref(id) => {
+                out.push(tag::REF);
+                write_varint(out, *id as u64);
+            }
+            Cloned::Object(properties) => {
+                out.push(tag::OBJECT);
+                write_properties(out, properties);
+            }
+            Cloned::Array { length, properties } => {
+                out.push(tag::ARRAY);
+                write_varint(out, *length as u64);
+                write_properties(out, properties);
+            }
+            Cloned::Date(time) => {
+                out.push(tag::DATE);
+                out.extend_from_slice(&time.to_le_bytes());
+            }
+            Cloned::RegExp { source, flags } => {
+                out.push(tag::REGEXP);
+                write_str(out, source);
+                write_str(out, flags);
+            }
+            Cloned::Map(entries) => {
+                out.push(tag::MAP);
+                write_varint(out, entries.len() as u64);
+                for (key, value) in entries {
+                    key.write(out);
+                    value.write(out);
+                }
+            }
+            Cloned::Set(values) => {
+                out.push(tag::SET);
+                write_varint(out, values.len() as u64);
+                for value in values {
+                    value.write(out);
+                }
+            }
+            Cloned::ArrayBuffer(bytes) => {
+                out.push(tag::ARRAY_BUFFER);
+                write_varint(out, bytes.len() as u64);
+                out.extend_from_slice(bytes);
+            }
+            Cloned::View { kind, buffer, offset, length } => {
+                out.push(tag::VIEW);
+                out.push(VIEW_KINDS.iter().position(|k| k == kind).unwrap_or(VIEW_KINDS.len()) as u8);
+                buffer.write(out);
+                write_varint(out, *offset as u64);
+                write_varint(out, *length as u64);
+            }
+            Cloned::Error { name, message, stack } => {
+                out.push(if stack.is_some() { tag::ERROR_WITH_STACK } else { tag::ERROR });
+                write_str(out, name);
+                write_str(out, message);
+                if let Some(stack) = stack {
+                    write_str(out, stack);
+                }
+            }
+            Cloned::Boxed(primitive) => {
+                out.push(tag::BOXED);
+                primitive.write(out);
+            }
+        }
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_varint(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_properties(out: &mut Vec<u8>, properties: &[(String, Cloned)]) {
+    write_varint(out, properties.len() as u64);
+    for (key, value) in properties {
+        write_str(out, key);
+        value.write(out);
+    }
+}
+
+struct Bytes<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn take(&mut self, len: usize) -> CloneResult<&'a [u8]> {
+        let end = self.position.checked_add(len).filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or_else(|| CloneError::Malformed("unexpected end".to_owned()))?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> CloneResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> CloneResult<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(CloneError::Malformed("varint is too long".to_owned()))
+    }
+
+    fn u32(&mut self) -> CloneResult<u32> {
+        u32::try_from(self.varint()?).map_err(|_| CloneError::Malformed("integer out of range".to_owned()))
+    }
+
+    /// Length of a sequence, each item takes at least one byte
+    fn count(&mut self) -> CloneResult<usize> {
+        let count = self.varint()? as usize;
+        if count > self.bytes.len() - self.position {
+            return Err(CloneError::Malformed("unexpected end".to_owned()));
+        }
+        Ok(count)
+    }
+
+    fn f64(&mut self) -> CloneResult<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> CloneResult<String> {
+        let len = self.count()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| CloneError::Malformed(e.to_string()))
+    }
+
+    fn properties(&mut self, depth: usize) -> CloneResult<Vec<(String, Cloned)>> {
+        let count = self.count()?;
+        let mut properties = Vec::with_capacity(count);
+        for _ in 0..count {
+            properties.push((self.string()?, self.value(depth)?));
+        }
+        Ok(properties)
+    }
+
+    fn value(&mut self, depth: usize) -> CloneResult<Cloned> {
+        if depth > MAX_DEPTH {
+            return Err(CloneError::Malformed("the value is nested too deeply".to_owned()));
+        }
+        let depth = depth + 1;
+        Ok(match self.byte()? {
+            tag::UNDEFINED => Cloned::Undefined,
+            tag::NULL => Cloned::Null,
+            tag::FALSE => Cloned::Bool(false),
+            tag::TRUE => Cloned::Bool(true),
+            tag::INT => {
+                let zigzag = self.u32()?;
+                Cloned::Number(((zigzag >> 1) as i32 ^ -((zigzag & 1) as i32)) as f64)
+            }
+            tag::NUMBER => Cloned::Number(self.f64()?),
+            tag::BIGINT => Cloned::BigInt(self.string()?),
+            tag::STRING => Cloned::String(self.string()?),
+            tag::REF => Cloned::Ref(self.u32()?),
+            tag::OBJECT => Cloned::Object(self.properties(depth)?),
+            tag::ARRAY => {
+                let length = self.u32()?;
+                Cloned::Array { length, properties: self.properties(depth)? }
+            }
+            tag::DATE => Cloned::Date(self.f64()?),
+            tag::REGEXP => Cloned::RegExp { source: self.string()?, flags: self.string()? },
+            tag::MAP => {
+                let count = self.count()?;
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    entries.push((self.value(depth)?, self.value(depth)?));
+                }
+                Cloned::Map(entries)
+            }
+            tag::SET => {
+                let count = self.count()?;
+                Cloned::Set((0..count).map(|_| self.value(depth)).collect::<CloneResult<_>>()?)
+            }
+            tag::ARRAY_BUFFER => {
+                let len = self.count()?;
+                Cloned::ArrayBuffer(self.take(len)?.to_vec())
+            }
+            tag::VIEW => {
+                let code = self.byte()? as usize;
+                let kind = VIEW_KINDS
+                    .get(code)
+                    .ok_or_else(|| CloneError::Malformed(format!("unknown view {}", code)))?;
+                let buffer = Box::new(self.value(depth)?);
+                Cloned::View { kind: kind.to_string(), buffer, offset: self.u32()?, length: self.u32()? }
+            }
+            error @ (tag::ERROR | tag::ERROR_WITH_STACK) => Cloned::Error {
+                name: self.string()?,
+                message: self.string()?,
+                stack: if error == tag::ERROR_WITH_STACK { Some(self.string()?) } else { None },
+            },
+            tag::BOXED => Cloned::Boxed(Box::new(self.value(depth)?)),
+            other => return Err(CloneError::Malformed(format!("unknown tag {}", other))),
+        })
+    }
+}
+
+#[test]
+fn test_format() {
+    let value = Cloned::Array {
+        length: 3,
+        properties: vec![
+            ("0".to_owned(), Cloned::Number(-7.0)),
+            ("1".to_owned(), Cloned::Number(0.5)),
+            ("2".to_owned(), Cloned::Ref(0)),
+            ("meta".to_owned(), Cloned::Map(vec![(Cloned::String("ü".to_owned()), Cloned::Date(1e12))])),
+            (
+                "view".to_owned(),
+                Cloned::View {
+                    kind: "Uint16Array".to_owned(),
+                    buffer: Box::new(Cloned::ArrayBuffer(vec![1, 0, 2, 0])),
+                    offset: 2,
+                    length: 1,
+                },
+            ),
+            (
+                "error".to_owned(),
+                Cloned::Error { name: "TypeError".to_owned(), message: "bad".to_owned(), stack: None },
+            ),
+            ("zero".to_owned(), Cloned::Number(-0.0)),
+        ],
+    };
+    let bytes = value.to_bytes();
+    assert_eq!(&bytes[..5], &[MAGIC, FORMAT_VERSION, tag::ARRAY, 3, 7]);
+    let decoded = Cloned::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded, value);
+    match &decoded {
+        Cloned::Array { properties, .. } => match properties[6].1 {
+            Cloned::Number(zero) => assert!(zero.is_sign_negative()),
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+    assert!(Cloned::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    assert!(Cloned::from_bytes(&[MAGIC, FORMAT_VERSION, tag::OBJECT, 0xff, 0xff, 0xff, 0x0f]).is_err());
+}
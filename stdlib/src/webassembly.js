@@ -0,0 +1,158 @@
+// Builds the `WebAssembly` namespace on top of the host operations
+// implemented in webassembly.rs. Operations report failures by returning
+// `{ __error: kind, message }` which is turned into the matching error here.
+(wasm) => {
+    class CompileError extends Error {
+        constructor(message) { super(message); this.name = "CompileError"; }
+    }
+    class LinkError extends Error {
+        constructor(message) { super(message); this.name = "LinkError"; }
+    }
+    class RuntimeError extends Error {
+        constructor(message) { super(message); this.name = "RuntimeError"; }
+    }
+    const errors = { CompileError, LinkError, RuntimeError, TypeError, RangeError, PermissionDenied };
+    const unwrap = (result) => {
+        if (result !== null && typeof result === "object" && result.__error !== undefined) {
+            throw new errors[result.__error](result.message, result.permission);
+        }
+        return result;
+    };
+    const bytesOf = (source) => {
+        if (source instanceof Uint8Array) return source;
+        if (source instanceof ArrayBuffer) return new Uint8Array(source);
+        if (ArrayBuffer.isView(source)) return new Uint8Array(source.buffer, source.byteOffset, source.byteLength);
+        throw new TypeError("WebAssembly: argument must be an ArrayBuffer or a typed array");
+    };
+
+    // Modules, instances and memories hold a handle `{ id }` of the host object,
+    // which is released once the handle is garbage collected
+    const MODULE = Symbol("module");
+    const MEMORY = Symbol("memory");
+    // Memory id -> ArrayBuffer viewing the memory
+    const buffers = new Map();
+    // Buffers must be detached once the memory moved, e.g. after growing, or was released
+    const syncMemories = () => {
+        for (const id of wasm.changedMemories()) {
+            const buffer = buffers.get(id);
+            if (buffer !== undefined) {
+                wasm.detach(buffer);
+                buffers.delete(id);
+            }
+        }
+    };
+    const track = (f) => {
+        try {
+            return unwrap(f());
+        } finally {
+            syncMemories();
+        }
+    };
+
+    class Module {
+        constructor(bytes) {
+            this[MODULE] = unwrap(wasm.compile(bytesOf(bytes)));
+        }
+        static imports(module) {
+            return unwrap(wasm.moduleImports(module[MODULE].id));
+        }
+        static exports(module) {
+            return unwrap(wasm.moduleExports(module[MODULE].id));
+        }
+    }
+    const moduleFromHandle = (handle) => {
+        const module = Object.create(Module.prototype);
+        module[MODULE] = handle;
+        return module;
+    };
+
+    class Memory {
+        constructor(descriptor) {
+            this[MEMORY] = unwrap(wasm.memoryNew(descriptor.initial, descriptor.maximum));
+        }
+        get buffer() {
+            const id = this[MEMORY].id;
+            let buffer = buffers.get(id);
+            if (buffer === undefined) {
+                buffer = unwrap(wasm.memoryBuffer(id));
+                buffers.set(id, buffer);
+            }
+            return buffer;
+        }
+        grow(delta) {
+            return track(() => wasm.memoryGrow(this[MEMORY].id, delta));
+        }
+    }
+    const memoryFromHandle = (handle) => {
+        const memory = Object.create(Memory.prototype);
+        memory[MEMORY] = handle;
+        return memory;
+    };
+
+    // Keeps the instance alive with its handle, `functions` are the imported functions it calls
+    const exportedFunction = (handle, functions, name) => function (...args) {
+        return track(() => wasm.call(handle.id, name, args, functions, syncMemories));
+    };
+
+    class Instance {
+        constructor(module, importObject = {}) {
+            if (!(module instanceof Module)) {
+                throw new TypeError("WebAssembly.Instance: first argument must be a WebAssembly.Module");
+            }
+            // Imported memory id -> Memory object, so exported memories keep their identity
+            const importedMemories = new Map();
+            const imports = Module.imports(module).map(({ module: from, name, kind }) => {
+                const namespace = importObject[from];
+                if (namespace === undefined || namespace === null) {
+                    throw new TypeError(`WebAssembly.Instance: import module "${from}" is missing`);
+                }
+                const value = namespace[name];
+                switch (kind) {
+                    case "function":
+                        if (typeof value !== "function") throw new LinkError(`import ${from}.${name} must be a function`);
+                        return value;
+                    case "memory":
+                        if (!(value instanceof Memory)) throw new LinkError(`import ${from}.${name} must be a WebAssembly.Memory`);
+                        importedMemories.set(value[MEMORY].id, value);
+                        return { memory: value[MEMORY].id };
+                    case "global":
+                        if (typeof value !== "number" && typeof value !== "bigint") throw new LinkError(`import ${from}.${name} must be a number`);
+                        return value;
+                    default:
+                        throw new LinkError(`import ${from}.${name}: ${kind} imports are not supported`);
+                }
+            });
+            const functions = imports.filter((value) => typeof value === "function");
+            const handle = track(() => wasm.instantiate(module[MODULE].id, imports, functions, syncMemories));
+            const exports = Object.create(null);
+            for (const { name, kind, memory, memoryHandle } of unwrap(wasm.instanceExports(handle.id, [...importedMemories.keys()]))) {
+                if (kind === "function") {
+                    exports[name] = exportedFunction(handle, functions, name);
+                } else if (kind === "memory") {
+                    exports[name] = importedMemories.get(memory) ?? memoryFromHandle(memoryHandle);
+                } else if (kind === "global") {
+                    Object.defineProperty(exports, name, { enumerable: true, get: () => unwrap(wasm.globalGet(handle.id, name)) });
+                }
+            }
+            this.exports = Object.freeze(exports);
+        }
+    }
+
+    const compile = (bytes) => new Promise((resolve) => resolve(new Module(bytes)));
+    const instantiate = (source, importObject) => new Promise((resolve) => {
+        if (source instanceof Module) {
+            resolve(new Instance(source, importObject));
+        } else {
+            const module = new Module(source);
+            resolve({ module, instance: new Instance(module, importObject) });
+        }
+    });
+    const validate = (bytes) => unwrap(wasm.validate(bytesOf(bytes)));
+
+    const WebAssembly = { Module, Instance, Memory, CompileError, LinkError, RuntimeError, compile, instantiate, validate };
+    // Used by the modules generated for `.wasm` imports
+    Object.defineProperty(WebAssembly, Symbol.for("exerum.moduleFromBase64"), {
+        value: (base64) => moduleFromHandle(unwrap(wasm.compileBase64(base64)))
+    });
+    globalThis.WebAssembly = WebAssembly;
+}
@@ -0,0 +1,858 @@
+//! `WebAssembly` namespace backed by the wasmi interpreter.
+//!
+//! The javascript API lives in webassembly.js, this module implements the
+//! operations it calls. All modules and instances of a context share one store,
+//! so memories can be passed between instances.
+//!
+//! Modules, instances and memories are handed to javascript as handles, and released once
+//! their handle is garbage collected. wasmi can't remove instances and memories from its
+//! store, their data is freed with the context. The ids of released items are reused.
+//!
+//! The store is taken out of the shared state while wasm code runs, imported functions
+//! use the store of the running call, so they can read memories or call exports.
+//! The javascript context and functions of a call are only reachable from the store
+//! while the call runs.
+//!
+//! Limitations: tables, reference types and `WebAssembly.Global` objects are not supported.
+
+use std::cell::{Cell, RefCell};
+use std::os::raw::{c_char, c_void};
+use std::rc::{Rc, Weak};
+use std::sync::OnceLock;
+use rquickjs::{qjs, Ctx, FromJs, Func, Function, IntoJs, Object, Rest, Result, TypedArray, Value};
+use crate::array_buffer::{detach, external};
+use crate::permissions::Permissions;
+use js_serde::raw::from_raw;
+use wasmi::core::{Pages, Trap, ValueType, F32, F64};
+use wasmi::{
+    AsContextMut, Caller, Engine, Extern, ExternType, Func as WasmFunc, FuncType, Global, Instance, Linker, Memory,
+    MemoryType, Module, Mutability, Store, StoreContextMut, Value as WasmValue,
+};
+
+const PRELUDE: &str = include_str!("webassembly.js");
+/// Name of the op permission required by the `WebAssembly` namespace
+pub const OP: &str = "webassembly";
+const HANDLE_CLASS_NAME: &[u8] = b"WebAssemblyHandle\0";
+
+/// Data of the store, set for each javascript call running wasm code and cleared when it returns
+#[derive(Default)]
+struct Host {
+    /// Context of the javascript call
+    ctx: Option<Ctx<'static>>,
+    /// Imported javascript functions of the instance called
+    functions: Vec<Function<'static>>,
+    /// Detaches the buffers of moved memories before an imported function runs
+    sync: Option<Function<'static>>,
+    state: Weak<RefCell<State>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Module(usize),
+    Instance(usize),
+    Memory(usize),
+}
+
+/// Slots of the finalized handles. Handles may be finalized while an operation runs,
+/// so the queue is never borrowed.
+type Released = Rc<Cell<Vec<Slot>>>;
+
+/// Owned by a handle, queues its slot for release when the handle is finalized
+struct Release {
+    released: Weak<Cell<Vec<Slot>>>,
+    slot: Slot,
+}
+
+impl Drop for Release {
+    fn drop(&mut self) {
+        if let Some(released) = self.released.upgrade() {
+            let mut slots = released.take();
+            slots.push(self.slot);
+            released.set(slots);
+        }
+    }
+}
+
+/// Items by id, the ids of removed items are reused
+struct Slots<T> {
+    items: Vec<Option<T>>,
+    free: Vec<usize>,
+}
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Slots { items: vec![], free: vec![] }
+    }
+}
+
+impl<T> Slots<T> {
+    fn insert(&mut self, item: T) -> usize {
+        match self.free.pop() {
+            Some(id) => {
+                self.items[id] = Some(item);
+                id
+            }
+            None => {
+                self.items.push(Some(item));
+                self.items.len() - 1
+            }
+        }
+    }
+
+    fn get(&self, id: usize) -> Option<&T> {
+        self.items.get(id)?.as_ref()
+    }
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut T> {
+        self.items.get_mut(id)?.as_mut()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.items.iter().enumerate().filter_map(|(id, item)| Some((id, item.as_ref()?)))
+    }
+
+    fn remove(&mut self, id: usize) {
+        if self.take(id).is_some() {
+            self.free.push(id);
+        }
+    }
+
+    /// Removes the item, its id is only reused once it's freed
+    fn take(&mut self, id: usize) -> Option<T> {
+        self.items.get_mut(id)?.take()
+    }
+
+    fn free(&mut self, id: usize) {
+        if self.items.get(id).map_or(false, |item| item.is_none()) {
+            self.free.push(id);
+        }
+    }
+}
+
+struct MemorySlot {
+    memory: Memory,
+    /// Address and length of the array buffer handed out for the memory
+    exposed: Option<(usize, usize)>,
+}
+
+struct State {
+    engine: Engine,
+    /// Taken out while wasm code runs
+    store: Option<Store<Host>>,
+    /// Caller of the imported function running, whose store the operations use meanwhile.
+    /// Shared with the scope of the imported function, which resets it however the function exits.
+    caller: Rc<Cell<Option<*mut Caller<'static, Host>>>>,
+    modules: Slots<Module>,
+    instances: Slots<Instance>,
+    /// The id of a released memory whose buffer was handed out is only reused once
+    /// the prelude detached the buffer, so it can't be taken for the one of a new memory
+    memories: Slots<MemorySlot>,
+    /// Released memories whose buffer must be detached
+    detached: Vec<u32>,
+    released: Released,
+}
+
+impl State {
+    /// The store, or the store of the running call
+    fn context(&mut self) -> Option<StoreContextMut<'_, Host>> {
+        match (&mut self.store, self.caller.get()) {
+            (Some(store), _) => Some(store.as_context_mut()),
+            // Safety: the caller is only set while its imported function runs
+            (None, Some(caller)) => Some(unsafe { (*caller).as_context_mut() }),
+            (None, None) => None,
+        }
+    }
+
+    /// Frees the slots of the finalized handles
+    fn collect(&mut self) {
+        for slot in self.released.take() {
+            match slot {
+                Slot::Module(id) => self.modules.remove(id),
+                Slot::Instance(id) => self.instances.remove(id),
+                Slot::Memory(id) => match self.memories.take(id) {
+                    Some(MemorySlot { exposed: Some(_), .. }) => self.detached.push(id as u32),
+                    _ => self.memories.free(id),
+                },
+            }
+        }
+    }
+
+    fn memory(&self, id: usize) -> Option<Memory> {
+        self.memories.get(id).map(|m| m.memory)
+    }
+
+    /// Ids of the memories whose buffer moved or which were released, forgetting their buffers
+    fn moved_memories(&mut self) -> Vec<u32> {
+        self.collect();
+        let mut moved = std::mem::take(&mut self.detached);
+        // The prelude forgets the buffers of the reported ids, so released ones can be reused
+        for id in &moved {
+            self.memories.free(*id as usize);
+        }
+        let exposed: Vec<(usize, Memory, (usize, usize))> = self
+            .memories
+            .iter()
+            .filter_map(|(id, m)| Some((id, m.memory, m.exposed?)))
+            .collect();
+        let context = match self.context() {
+            Some(context) => context,
+            None => return moved,
+        };
+        let changed: Vec<usize> = exposed
+            .into_iter()
+            .filter(|(_, memory, view)| {
+                let data = memory.data(&context);
+                (data.as_ptr() as usize, data.len()) != *view
+            })
+            .map(|(id, _, _)| id)
+            .collect();
+        for id in changed {
+            if let Some(memory) = self.memories.get_mut(id) {
+                memory.exposed = None;
+            }
+            moved.push(id as u32);
+        }
+        moved
+    }
+}
+
+/// Every operation creating a module, an instance or a memory, or calling wasm code
+/// checks the `webassembly` op permission
+pub fn init(ctx: Ctx, permissions: &Permissions) -> Result<()> {
+    let engine = Engine::default();
+    let store = Store::new(&engine, Host::default());
+    let state = Rc::new(RefCell::new(State {
+        engine,
+        store: Some(store),
+        caller: Rc::default(),
+        modules: Slots::default(),
+        instances: Slots::default(),
+        memories: Slots::default(),
+        detached: vec![],
+        released: Rc::default(),
+    }));
+    let ops = Object::new(ctx)?;
+
+    let (s, p) = (state.clone(), permissions.clone());
+    ops.set("compile", Func::new("compile", move |ctx: Ctx, bytes: TypedArray<u8>| {
+        if let Err(denied) = p.check_op(OP) {
+            return denied.to_js(ctx);
+        }
+        compile(&s, ctx, bytes.as_ref())
+    }))?;
+    let (s, p) = (state.clone(), permissions.clone());
+    ops.set("compileBase64", Func::new("compileBase64", move |ctx: Ctx, encoded: String| {
+        if let Err(denied) = p.check_op(OP) {
+            return denied.to_js(ctx);
+        }
+        match base64::decode(encoded) {
+            Ok(bytes) => compile(&s, ctx, &bytes),
+            Err(e) => fail(ctx, "CompileError", &e.to_string()),
+        }
+    }))?;
+    let (s, p) = (state.clone(), permissions.clone());
+    ops.set("validate", Func::new("validate", move |ctx: Ctx, bytes: TypedArray<u8>| {
+        if let Err(denied) = p.check_op(OP) {
+            return denied.to_js(ctx);
+        }
+        with_state(&s, ctx, |state| Module::new(&state.engine, bytes.as_ref()).is_ok().into_js(ctx))
+    }))?;
+    let s = state.clone();
+    ops.set("moduleImports", Func::new("moduleImports", move |ctx: Ctx, id: usize| {
+        with_state(&s, ctx, |state| {
+            let module = match state.modules.get(id) {
+                Some(module) => module,
+                None => return released(ctx),
+            };
+            let imports = module
+                .imports()
+                .map(|i| {
+                    let import = Object::new(ctx)?;
+                    import.set("module", i.module())?;
+                    import.set("name", i.name())?;
+                    import.set("kind", kind(i.ty()))?;
+                    Ok(import)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            imports.into_js(ctx)
+        })
+    }))?;
+    let s = state.clone();
+    ops.set("moduleExports", Func::new("moduleExports", move |ctx: Ctx, id: usize| {
+        with_state(&s, ctx, |state| {
+            let module = match state.modules.get(id) {
+                Some(module) => module,
+                None => return released(ctx),
+            };
+            let exports = module
+                .exports()
+                .map(|e| {
+                    let export = Object::new(ctx)?;
+                    export.set("name", e.name())?;
+                    export.set("kind", kind(e.ty()))?;
+                    Ok(export)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            exports.into_js(ctx)
+        })
+    }))?;
+    let (s, p) = (state.clone(), permissions.clone());
+    ops.set("instantiate", Func::new(
+        "instantiate",
+        move |ctx: Ctx, module: usize, imports: Vec<Value>, functions: Vec<Function>, sync: Function| {
+            // The module may have been compiled before the permission changed, or in another context
+            if let Err(denied) = p.check_op(OP) {
+                return denied.to_js(ctx);
+            }
+            instantiate(&s, ctx, module, imports, functions, sync)
+        },
+    ))?;
+    let s = state.clone();
+    ops.set("instanceExports", Func::new("instanceExports", move |ctx: Ctx, id: usize, imported: Vec<usize>| {
+        with_state(&s, ctx, |state| instance_exports(state, ctx, id, &imported))
+    }))?;
+    let (s, p) = (state.clone(), permissions.clone());
+    ops.set("call", Func::new(
+        "call",
+        move |ctx: Ctx, instance: usize, name: String, args: Vec<Value>, functions: Vec<Function>, sync: Function| {
+            if let Err(denied) = p.check_op(OP) {
+                return denied.to_js(ctx);
+            }
+            call(&s, ctx, instance, &name, args, functions, sync)
+        },
+    ))?;
+    let s = state.clone();
+    ops.set("globalGet", Func::new("globalGet", move |ctx: Ctx, instance: usize, name: String| {
+        let value = match s.try_borrow_mut() {
+            Ok(mut state) => {
+                let instance = match state.instances.get(instance) {
+                    Some(instance) => *instance,
+                    None => return released(ctx),
+                };
+                let context = match state.context() {
+                    Some(context) => context,
+                    None => return busy(ctx),
+                };
+                instance.get_export(&context, &name).and_then(|e| e.into_global()).map(|g| g.get(&context))
+            }
+            Err(_) => return busy(ctx),
+        };
+        match value {
+            Some(value) => to_js(ctx, &value),
+            None => Ok(Value::new_undefined(ctx)),
+        }
+    }))?;
+    let (s, p) = (state.clone(), permissions.clone());
+    ops.set("memoryNew", Func::new("memoryNew", move |ctx: Ctx, initial: u32, maximum: Option<u32>| {
+        if let Err(denied) = p.check_op(OP) {
+            return denied.to_js(ctx);
+        }
+        with_state(&s, ctx, |state| {
+            state.collect();
+            let mut context = match state.context() {
+                Some(context) => context,
+                None => return busy(ctx),
+            };
+            let memory = MemoryType::new(initial, maximum).and_then(|ty| Memory::new(&mut context, ty));
+            match memory {
+                Ok(memory) => {
+                    let id = state.memories.insert(MemorySlot { memory, exposed: None });
+                    handle(ctx, &state.released, Slot::Memory(id))
+                }
+                Err(e) => fail(ctx, "RangeError", &e.to_string()),
+            }
+        })
+    }))?;
+    let s = state.clone();
+    ops.set("memoryGrow", Func::new("memoryGrow", move |ctx: Ctx, id: usize, delta: u32| {
+        with_state(&s, ctx, |state| {
+            let memory = match state.memory(id) {
+                Some(memory) => memory,
+                None => return released(ctx),
+            };
+            let mut context = match state.context() {
+                Some(context) => context,
+                None => return busy(ctx),
+            };
+            let grown = Pages::new(delta).and_then(|delta| memory.grow(&mut context, delta).ok());
+            match grown {
+                Some(previous) => u32::from(previous).into_js(ctx),
+                None => fail(ctx, "RangeError", "WebAssembly.Memory.grow: failed to grow memory"),
+            }
+        })
+    }))?;
+    let s = state.clone();
+    ops.set("memoryBuffer", Func::new("memoryBuffer", move |ctx: Ctx, id: usize| {
+        with_state(&s, ctx, |state| {
+            let memory = match state.memory(id) {
+                Some(memory) => memory,
+                None => return released(ctx),
+            };
+            let mut context = match state.context() {
+                Some(context) => context,
+                None => return busy(ctx),
+            };
+            let data = memory.data_mut(&mut context);
+            let view = (data.as_ptr() as usize, data.len());
+            // Safety: the memory is recorded as exposed, the prelude detaches the buffer when
+            // `changedMemories` reports it moved and when the memory is released
+            let buffer = unsafe { external(ctx, data)? };
+            if let Some(memory) = state.memories.get_mut(id) {
+                memory.exposed = Some(view);
+            }
+            Ok(buffer)
+        })
+    }))?;
+    let s = state.clone();
+    ops.set("changedMemories", Func::new("changedMemories", move || match s.try_borrow_mut() {
+        Ok(mut state) => state.moved_memories(),
+        // Synced by the operation running
+        Err(_) => vec![],
+    }))?;
+    ops.set("detach", Func::new("detach", |ctx: Ctx, buffer: Value| detach(ctx, buffer)))?;
+
+    let prelude: Function = ctx.eval(PRELUDE)?;
+    prelude.call((ops,))
+}
+
+/// Borrows the state, which is never borrowed while javascript runs
+fn with_state<'js>(
+    state: &RefCell<State>,
+    ctx: Ctx<'js>,
+    f: impl FnOnce(&mut State) -> Result<Value<'js>>,
+) -> Result<Value<'js>> {
+    match state.try_borrow_mut() {
+        Ok(mut state) => f(&mut state),
+        Err(_) => busy(ctx),
+    }
+}
+
+fn busy<'js>(ctx: Ctx<'js>) -> Result<Value<'js>> {
+    fail(ctx, "RangeError", "WebAssembly objects can't be used while this operation runs")
+}
+
+fn released<'js>(ctx: Ctx<'js>) -> Result<Value<'js>> {
+    fail(ctx, "TypeError", "the WebAssembly object was released")
+}
+
+fn compile<'js>(state: &Rc<RefCell<State>>, ctx: Ctx<'js>, bytes: &[u8]) -> Result<Value<'js>> {
+    with_state(state, ctx, |state| {
+        state.collect();
+        match Module::new(&state.engine, bytes) {
+            Ok(module) => {
+                let id = state.modules.insert(module);
+                handle(ctx, &state.released, Slot::Module(id))
+            }
+            Err(e) => fail(ctx, "CompileError", &e.to_string()),
+        }
+    })
+}
+
+enum Import {
+    Func(FuncType, usize),
+    Memory(Memory),
+    Global(WasmValue),
+}
+
+fn instantiate<'js>(
+    state: &Rc<RefCell<State>>,
+    ctx: Ctx<'js>,
+    module: usize,
+    imports: Vec<Value<'js>>,
+    functions: Vec<Function<'js>>,
+    sync: Function<'js>,
+) -> Result<Value<'js>> {
+    let (module, engine) = match state.try_borrow() {
+        Ok(state) => match state.modules.get(module) {
+            Some(module) => (module.clone(), state.engine.clone()),
+            None => return released(ctx),
+        },
+        Err(_) => return busy(ctx),
+    };
+    // Converting the imported globals may run javascript, so the state isn't borrowed meanwhile
+    let mut items = vec![];
+    let mut function_index = 0;
+    for (import, value) in module.imports().zip(imports) {
+        let (from, name) = (import.module().to_owned(), import.name().to_owned());
+        let item = match import.ty().clone() {
+            ExternType::Func(ty) => {
+                function_index += 1;
+                Import::Func(ty, function_index - 1)
+            }
+            ExternType::Memory(_) => {
+                let descriptor = Object::from_js(ctx, value)?;
+                let id: usize = descriptor.get("memory")?;
+                match state.try_borrow().ok().and_then(|state| state.memory(id)) {
+                    Some(memory) => Import::Memory(memory),
+                    None => return fail(ctx, "LinkError", &format!("import {}.{}: the memory was released", from, name)),
+                }
+            }
+            ExternType::Global(ty) => Import::Global(to_wasm(ctx, value, ty.content())?),
+            ExternType::Table(_) => {
+                return fail(ctx, "LinkError", &format!("import {}.{}: tables are not supported", from, name));
+            }
+        };
+        items.push((from, name, item));
+    }
+    let instance = run(state, ctx, functions, sync, |context: &mut StoreContextMut<'_, Host>| -> std::result::Result<Instance, String> {
+        let mut linker = Linker::<Host>::new(&engine);
+        for (from, name, item) in items {
+            let item: Extern = match item {
+                Import::Func(ty, index) => {
+                    let results = ty.results().to_vec();
+                    WasmFunc::new(&mut *context, ty, move |mut caller: Caller<'_, Host>, params: &[WasmValue], outputs: &mut [WasmValue]| {
+                        call_import(&mut caller, index, &results, params, outputs)
+                    })
+                    .into()
+                }
+                Import::Memory(memory) => memory.into(),
+                Import::Global(value) => Global::new(&mut *context, value, Mutability::Const).into(),
+            };
+            linker.define(&from, &name, item).map_err(|e| e.to_string())?;
+        }
+        linker
+            .instantiate(&mut *context, &module)
+            .and_then(|pre| pre.start(&mut *context))
+            .map_err(|e| e.to_string())
+    });
+    match instance {
+        Some(Ok(instance)) => with_state(state, ctx, |state| {
+            let id = state.instances.insert(instance);
+            handle(ctx, &state.released, Slot::Instance(id))
+        }),
+        Some(Err(e)) => fail(ctx, "LinkError", &e),
+        None => busy(ctx),
+    }
+}
+
+/// `{ name, kind }` of the exports, with `memory`, the memory id, for memories. Memories
+/// that aren't in `imported` get a new id and its handle as `memoryHandle`.
+fn instance_exports<'js>(
+    state: &mut State,
+    ctx: Ctx<'js>,
+    id: usize,
+    imported: &[usize],
+) -> Result<Value<'js>> {
+    let instance = match state.instances.get(id) {
+        Some(instance) => *instance,
+        None => return released(ctx),
+    };
+    let imported: Vec<(usize, Memory)> = imported.iter().filter_map(|id| Some((*id, state.memory(*id)?))).collect();
+    let context = match state.context() {
+        Some(context) => context,
+        None => return busy(ctx),
+    };
+    let address = |memory: &Memory| memory.data(&context).as_ptr() as usize;
+    let exports: Vec<(String, &'static str, Option<std::result::Result<usize, Memory>>)> = instance
+        .exports(&context)
+        .map(|e| (e.name().to_owned(), e.into_extern()))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|(name, export)| match export {
+            Extern::Memory(memory) => {
+                let found = imported.iter().find(|(_, m)| address(m) == address(&memory)).map(|(id, _)| *id);
+                (name, "memory", Some(found.ok_or(memory)))
+            }
+            export => (name, kind(&export.ty(&context)), None),
+        })
+        .collect();
+    let mut list = vec![];
+    for (name, kind, memory) in exports {
+        let export = Object::new(ctx)?;
+        export.set("name", name)?;
+        export.set("kind", kind)?;
+        match memory {
+            Some(Ok(id)) => export.set("memory", id)?,
+            Some(Err(memory)) => {
+                let id = state.memories.insert(MemorySlot { memory, exposed: None });
+                export.set("memory", id)?;
+                export.set("memoryHandle", handle(ctx, &state.released, Slot::Memory(id))?)?;
+            }
+            None => {}
+        }
+        list.push(export);
+    }
+    list.into_js(ctx)
+}
+
+fn call<'js>(
+    state: &Rc<RefCell<State>>,
+    ctx: Ctx<'js>,
+    instance: usize,
+    name: &str,
+    args: Vec<Value<'js>>,
+    functions: Vec<Function<'js>>,
+    sync: Function<'js>,
+) -> Result<Value<'js>> {
+    let func = match state.try_borrow_mut() {
+        Ok(mut state) => {
+            let instance = match state.instances.get(instance) {
+                Some(instance) => *instance,
+                None => return released(ctx),
+            };
+            let context = match state.context() {
+                Some(context) => context,
+                None => return busy(ctx),
+            };
+            match instance.get_export(&context, name).and_then(|e| e.into_func()) {
+                Some(func) => (func, func.ty(&context)),
+                None => return fail(ctx, "TypeError", &format!("{} is not an exported function", name)),
+            }
+        }
+        Err(_) => return busy(ctx),
+    };
+    let (func, ty) = func;
+    // Converting the arguments may run javascript, so the state isn't borrowed meanwhile
+    let mut params = Vec::with_capacity(ty.params().len());
+    let mut args = args.into_iter();
+    for param in ty.params() {
+        let value = match args.next() {
+            Some(arg) => to_wasm(ctx, arg, *param)?,
+            None => WasmValue::default(*param),
+        };
+        params.push(value);
+    }
+    let mut results: Vec<WasmValue> = ty.results().iter().map(|r| WasmValue::default(*r)).collect();
+    match run(state, ctx, functions, sync, |context| func.call(context, &params, &mut results)) {
+        Some(Ok(())) => {}
+        Some(Err(e)) => return fail(ctx, "RuntimeError", &e.to_string()),
+        None => return busy(ctx),
+    }
+    match results.len() {
+        0 => Ok(Value::new_undefined(ctx)),
+        1 => to_js(ctx, &results[0]),
+        _ => {
+            let values = results.iter().map(|r| to_js(ctx, r)).collect::<Result<Vec<_>>>()?;
+            values.into_js(ctx)
+        }
+    }
+}
+
+/// Runs wasm code with the store taken out of the state, or with the store of the running
+/// call if it's called from an imported function. The state isn't borrowed meanwhile.
+fn run<'js, R>(
+    state: &Rc<RefCell<State>>,
+    ctx: Ctx<'js>,
+    functions: Vec<Function<'js>>,
+    sync: Function<'js>,
+    f: impl FnOnce(&mut StoreContextMut<'_, Host>) -> R,
+) -> Option<R> {
+    // Safety: `enter` takes the context and the functions out of the store when `f` returns or
+    // unwinds, they are only used while the javascript call that entered wasm is running
+    let host = unsafe {
+        Host {
+            ctx: Some(std::mem::transmute::<Ctx<'js>, Ctx<'static>>(ctx)),
+            functions: std::mem::transmute::<Vec<Function<'js>>, Vec<Function<'static>>>(functions),
+            sync: Some(std::mem::transmute::<Function<'js>, Function<'static>>(sync)),
+            state: Rc::downgrade(state),
+        }
+    };
+    let (store, caller) = {
+        let mut state = state.try_borrow_mut().ok()?;
+        (state.store.take(), state.caller.get())
+    };
+    match (store, caller) {
+        (Some(mut store), _) => {
+            let result = enter(store.as_context_mut(), host, f);
+            state.borrow_mut().store = Some(store);
+            Some(result)
+        }
+        // Safety: the caller is only set while its imported function runs
+        (None, Some(caller)) => Some(enter(unsafe { (*caller).as_context_mut() }, host, f)),
+        (None, None) => None,
+    }
+}
+
+/// Runs `f` with the data of the store set to `host`, restoring the data of the outer call
+fn enter<R>(mut context: StoreContextMut<'_, Host>, host: Host, f: impl FnOnce(&mut StoreContextMut<'_, Host>) -> R) -> R {
+    let outer = std::mem::replace(context.data_mut(), host);
+    let mut scope = HostScope { context, outer };
+    f(&mut scope.context)
+}
+
+/// Drops the data of the call and restores the one of the outer call, also when the call unwinds
+struct HostScope<'a> {
+    context: StoreContextMut<'a, Host>,
+    outer: Host,
+}
+
+impl Drop for HostScope<'_> {
+    fn drop(&mut self) {
+        *self.context.data_mut() = std::mem::take(&mut self.outer);
+    }
+}
+
+/// Resets the caller of the state to the one of the outer imported function when dropped
+struct CallerScope {
+    caller: Rc<Cell<Option<*mut Caller<'static, Host>>>>,
+    previous: Option<*mut Caller<'static, Host>>,
+}
+
+impl Drop for CallerScope {
+    fn drop(&mut self) {
+        self.caller.set(self.previous);
+    }
+}
+
+fn call_import(
+    caller: &mut Caller<'_, Host>,
+    index: usize,
+    results: &[ValueType],
+    params: &[WasmValue],
+    outputs: &mut [WasmValue],
+) -> std::result::Result<(), Trap> {
+    let host = caller.data();
+    let ctx = host.ctx.ok_or_else(|| Trap::new("imported function called outside of javascript"))?;
+    let f = host.functions.get(index).cloned().ok_or_else(|| Trap::new("imported function missing"))?;
+    let sync = host.sync.clone();
+    let state = host.state.upgrade().ok_or_else(|| Trap::new("the WebAssembly state was freed"))?;
+    // The operations the function calls use the store of this call
+    let caller_cell = match state.try_borrow() {
+        Ok(state) => state.caller.clone(),
+        Err(_) => return Err(Trap::new("imported function called while the WebAssembly state is in use")),
+    };
+    let previous = caller_cell.replace(Some(caller as *mut Caller<'_, Host> as *mut Caller<'static, Host>));
+    let _scope = CallerScope { caller: caller_cell, previous };
+    let result = (|| -> Result<()> {
+        if let Some(sync) = sync {
+            sync.call::<_, ()>(())?;
+        }
+        let args = params.iter().map(|p| to_js(ctx, p)).collect::<Result<Vec<_>>>()?;
+        let returned: Value = f.call((Rest(args),))?;
+        match results.len() {
+            0 => {}
+            1 => outputs[0] = to_wasm(ctx, returned, results[0])?,
+            _ => {
+                let returned: Vec<Value> = Vec::from_js(ctx, returned)?;
+                for ((output, value), ty) in outputs.iter_mut().zip(returned).zip(results) {
+                    *output = to_wasm(ctx, value, *ty)?;
+                }
+            }
+        }
+        Ok(())
+    })();
+    result.map_err(|e| Trap::new(e.to_string()))
+}
+
+fn handle_class() -> qjs::JSClassID {
+    static CLASS_ID: OnceLock<qjs::JSClassID> = OnceLock::new();
+    *CLASS_ID.get_or_init(|| {
+        let mut id = 0;
+        unsafe { qjs::JS_NewClassID(&mut id) };
+        id
+    })
+}
+
+unsafe extern "C" fn finalize_handle(_rt: *mut qjs::JSRuntime, value: qjs::JSValue) {
+    let release = qjs::JS_GetOpaque(value, handle_class()) as *mut Release;
+    if !release.is_null() {
+        drop(Box::from_raw(release));
+    }
+}
+
+/// `{ id }` object releasing the slot once it's garbage collected
+fn handle<'js>(ctx: Ctx<'js>, released: &Released, slot: Slot) -> Result<Value<'js>> {
+    let class = handle_class();
+    let value = unsafe {
+        let rt = qjs::JS_GetRuntime(ctx.as_ptr());
+        if qjs::JS_IsRegisteredClass(rt, class) == 0 {
+            let def = qjs::JSClassDef {
+                class_name: HANDLE_CLASS_NAME.as_ptr() as *const c_char,
+                finalizer: Some(finalize_handle),
+                gc_mark: None,
+                call: None,
+                exotic: std::ptr::null_mut(),
+            };
+            qjs::JS_NewClass(rt, class, &def);
+        }
+        let object = qjs::JS_NewObjectClass(ctx.as_ptr(), class as _);
+        if qjs::JS_VALUE_GET_NORM_TAG(object) == qjs::JS_TAG_EXCEPTION {
+            return Err(rquickjs::Error::Allocation);
+        }
+        let release = Box::new(Release { released: Rc::downgrade(released), slot });
+        qjs::JS_SetOpaque(object, Box::into_raw(release) as *mut c_void);
+        from_raw(ctx, object)?
+    };
+    let id = match slot {
+        Slot::Module(id) | Slot::Instance(id) | Slot::Memory(id) => id,
+    };
+    let handle = Object::from_js(ctx, value)?;
+    handle.set("id", id)?;
+    Ok(handle.into_value())
+}
+
+fn to_js<'js>(ctx: Ctx<'js>, value: &WasmValue) -> Result<Value<'js>> {
+    match value {
+        WasmValue::I32(v) => v.into_js(ctx),
+        WasmValue::I64(v) => {
+            let bigint: Function = ctx.globals().get("BigInt")?;
+            bigint.call((v.to_string(),))
+        }
+        WasmValue::F32(v) => (f32::from(*v) as f64).into_js(ctx),
+        WasmValue::F64(v) => f64::from(*v).into_js(ctx),
+        _ => Ok(Value::new_null(ctx)),
+    }
+}
+
+/// ToInt32: wraps around modulo 2^32, `NaN` and infinities are 0
+fn to_int32(number: f64) -> i32 {
+    if number.is_finite() {
+        number.trunc().rem_euclid(4294967296.0) as u32 as i32
+    } else {
+        0
+    }
+}
+
+fn to_wasm<'js>(ctx: Ctx<'js>, value: Value<'js>, ty: ValueType) -> Result<WasmValue> {
+    let number = |value: Value<'js>| -> Result<f64> {
+        let number: Function = ctx.globals().get("Number")?;
+        number.call((value,))
+    };
+    Ok(match ty {
+        ValueType::I32 => WasmValue::I32(to_int32(number(value)?)),
+        ValueType::I64 => {
+            let string: Function = ctx.globals().get("String")?;
+            let digits: String = string.call((value,))?;
+            let parsed = digits
+                .parse::<i64>()
+                .or_else(|_| digits.parse::<u64>().map(|v| v as i64))
+                .map_err(|_| rquickjs::Error::new_from_js_message("value", "i64", format!("{} is not an integer", digits)))?;
+            WasmValue::I64(parsed)
+        }
+        ValueType::F32 => WasmValue::F32(F32::from(number(value)? as f32)),
+        ValueType::F64 => WasmValue::F64(F64::from(number(value)?)),
+        _ => return Err(rquickjs::Error::new_from_js_message("value", "reference", "reference types are not supported")),
+    })
+}
+
+fn kind(ty: &ExternType) -> &'static str {
+    match ty {
+        ExternType::Func(_) => "function",
+        ExternType::Memory(_) => "memory",
+        ExternType::Global(_) => "global",
+        ExternType::Table(_) => "table",
+    }
+}
+
+/// Failures are returned as `{ __error, message }` and thrown by the javascript side
+fn fail<'js>(ctx: Ctx<'js>, error: &str, message: &str) -> Result<Value<'js>> {
+    let obj = Object::new(ctx)?;
+    obj.set("__error", error)?;
+    obj.set("message", message)?;
+    Ok(obj.into_value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_int32;
+
+    #[test]
+    fn test_to_int32() {
+        assert_eq!(to_int32(5.7), 5);
+        assert_eq!(to_int32(-1.0), -1);
+        assert_eq!(to_int32(2147483648.0), -2147483648);
+        assert_eq!(to_int32(4294967301.0), 5);
+        assert_eq!(to_int32(2f64.powi(70)), 0);
+        assert_eq!(to_int32(f64::NAN), 0);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use transpilers::AssetTranspiler;
+use transpilers::data_module::js_string;
+use transpilers::rquickjs::Result;
+
+/// Turns `.css` files into modules exporting the stylesheet text:
+/// ```js
+/// export const stylesheet = "...";
+/// export default stylesheet;
+/// ```
+/// `.module.css` files get their class names scoped to the file and
+/// export the map from the original class names to the scoped ones:
+/// ```js
+/// export const stylesheet = ".button_1a2b3c { ... }";
+/// export const classes = { "button": "button_1a2b3c" };
+/// export default classes;
+/// ```
+#[derive(Default)]
+pub struct CssTranspiler {
+    collector: Option<CssCollector>
+}
+
+impl CssTranspiler {
+    /// Records every transpiled stylesheet in the collector.
+    pub fn with_collector(collector: CssCollector) -> Self {
+        CssTranspiler { collector: Some(collector) }
+    }
+}
+
+impl AssetTranspiler for CssTranspiler {
+    fn transform(&mut self, path: &str, source: String) -> Result<String> {
+        let (stylesheet, classes) = compile(path, source);
+        let js = match classes {
+            Some(classes) => {
+                let classes = classes
+                    .iter()
+                    .map(|(name, scoped)| format!("{}: {}", js_string(name), js_string(scoped)))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(
+                    "export const stylesheet = {};\nexport const classes = {{ {} }};\nexport default classes;\n",
+                    js_string(&stylesheet),
+                    classes
+                )
+            }
+            None => format!("export const stylesheet = {};\nexport default stylesheet;\n", js_string(&stylesheet)),
+        };
+        if let Some(c) = &self.collector {
+            c.add(path, stylesheet);
+        }
+        Ok(js)
+    }
+
+    fn cached(&mut self, path: &str) -> Result<()> {
+        // The cached module doesn't go through `transform`, the stylesheet is compiled again for the collector
+        if let Some(c) = &self.collector {
+            let source = self.load(path)?;
+            c.add(path, compile(path, source).0);
+        }
+        Ok(())
+    }
+}
+
+/// The stylesheet of the css file, and the scoped class names of `.module.css` files
+fn compile(path: &str, source: String) -> (String, Option<BTreeMap<String, String>>) {
+    if path.ends_with(".module.css") {
+        let mut classes = BTreeMap::new();
+        let stylesheet = scope(&source, &file_hash(path), &mut classes);
+        (stylesheet, Some(classes))
+    } else {
+        (source, None)
+    }
+}
+
+/// Collects the stylesheets of all loaded css files, e.g. to inline them
+/// into server-side rendered html.
+#[derive(Default, Clone)]
+pub struct CssCollector {
+    inner: Arc<Mutex<Vec<(String, String)>>>
+}
+
+impl CssCollector {
+    fn add(&self, path: &str, stylesheet: String) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.iter_mut().find(|(p, _)| p == path) {
+            Some(entry) => entry.1 = stylesheet,
+            None => inner.push((path.to_owned(), stylesheet))
+        }
+    }
+
+    /// Paths and stylesheets in load order
+    pub fn stylesheets(&self) -> Vec<(String, String)> {
+        self.inner.lock().unwrap().clone()
+    }
+
+    /// All stylesheets concatenated in load order
+    pub fn css(&self) -> String {
+        self.inner
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, css)| css.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+    }
+}
+
+/// At-rules whose blocks contain rules rather than declarations
+const RULE_LIST_AT_RULES: [&str; 7] = ["media", "supports", "layer", "container", "document", "scope", "keyframes"];
+
+/// Renames the class selectors of the stylesheet to `{class}_{suffix}`.
+/// Selectors wrapped into `:global(...)` are kept as is.
+fn scope(css: &str, suffix: &str, classes: &mut BTreeMap<String, String>) -> String {
+    let chars: Vec<char> = css.chars().collect();
+    let mut out = String::with_capacity(css.len());
+    // Whether each open block contains rules (true) or declarations (false)
+    let mut blocks: Vec<bool> = vec![];
+    // Text since the last block boundary, i.e. the selector or at-rule prelude
+    let mut prelude = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let in_rules = blocks.last().copied().unwrap_or(true);
+        match c {
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                let end = find(&chars, i + 2, "*/").map(|e| e + 2).unwrap_or(chars.len());
+                out.extend(&chars[i..end]);
+                i = end;
+                continue;
+            }
+            '"' | '\'' => {
+                let mut end = i + 1;
+                while end < chars.len() && chars[end] != c {
+                    if chars[end] == '\\' {
+                        end += 1;
+                    }
+                    end += 1;
+                }
+                let end = (end + 1).min(chars.len());
+                out.extend(&chars[i..end]);
+                prelude.extend(&chars[i..end]);
+                i = end;
+                continue;
+            }
+            '{' => {
+                let prelude_trimmed = prelude.trim_start();
+                let contains_rules = in_rules
+                    && prelude_trimmed.starts_with('@')
+                    && RULE_LIST_AT_RULES.iter().any(|r| {
+                        prelude_trimmed[1..].starts_with(r) || prelude_trimmed[1..].contains(&format!("-{}", r))
+                    });
+                blocks.push(contains_rules);
+                prelude.clear();
+            }
+            '}' => {
+                blocks.pop();
+                prelude.clear();
+            }
+            ';' => prelude.clear(),
+            ':' if in_rules && starts_with(&chars, i, ":global(") => {
+                // Finds the matching paren, the selector may contain others like `:not(.b)`
+                let start = i + ":global(".len();
+                let mut depth = 1;
+                let mut end = start;
+                while end < chars.len() {
+                    match chars[end] {
+                        '(' => depth += 1,
+                        ')' if depth == 1 => break,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    end += 1;
+                }
+                out.extend(&chars[start..end]);
+                prelude.extend(&chars[start..end]);
+                i = (end + 1).min(chars.len());
+                continue;
+            }
+            '.' if in_rules
+                && !prelude.trim_start().starts_with('@')
+                && chars.get(i + 1).map(|c| is_ident_start(*c)).unwrap_or(false) =>
+            {
+                let mut end = i + 1;
+                while end < chars.len() && is_ident_char(chars[end]) {
+                    end += 1;
+                }
+                let name: String = chars[i + 1..end].iter().collect();
+                let scoped = format!("{}_{}", name, suffix);
+                out.push('.');
+                out.push_str(&scoped);
+                prelude.push('.');
+                prelude.push_str(&scoped);
+                classes.insert(name, scoped);
+                i = end;
+                continue;
+            }
+            _ => prelude.push(c),
+        }
+        out.push(c);
+        i += 1;
+    }
+    out
+}
+
+fn starts_with(chars: &[char], from: usize, s: &str) -> bool {
+    s.chars().enumerate().all(|(i, c)| chars.get(from + i) == Some(&c))
+}
+
+fn find(chars: &[char], from: usize, s: &str) -> Option<usize> {
+    (from..chars.len()).find(|i| starts_with(chars, *i, s))
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '-' || !c.is_ascii()
+}
+
+fn is_ident_char(c: char) -> bool {
+    is_ident_start(c) || c.is_ascii_digit()
+}
+
+/// Short stable hash of the file path (FNV-1a)
+fn file_hash(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let path = path.trim_start_matches("./");
+    let hash = path
+        .bytes()
+        .fold(0x811c9dc5u32, |h, b| (h ^ b as u32).wrapping_mul(0x01000193));
+    format!("{:08x}", hash)[..6].to_owned()
+}
+
+#[test]
+fn test_scope_class_names() {
+    let mut classes = BTreeMap::new();
+    let css = r#"
+        @import url("theme.css");
+        /* .comment */
+        .button, div > .button-primary:hover { margin: .5em; background: url("a.png"); }
+        :global(.app) .title { color: red }
+        @media (min-width: 10.5em) { .button { padding: 0 } }
+        @font-face { font-family: x; src: url(x.woff) }
+    "#;
+    let scoped = scope(css, "abc123", &mut classes);
+    assert!(scoped.contains(r#"@import url("theme.css");"#));
+    assert!(scoped.contains("/* .comment */"));
+    assert!(scoped.contains(".button_abc123, div > .button-primary_abc123:hover { margin: .5em;"));
+    assert!(scoped.contains(".app .title_abc123"));
+    assert!(scoped.contains("@media (min-width: 10.5em) { .button_abc123 { padding: 0 } }"));
+    assert!(scoped.contains("src: url(x.woff)"));
+    assert_eq!(classes.len(), 3);
+    assert_eq!(classes["button-primary"], "button-primary_abc123");
+}
+
+#[test]
+fn test_scope_nested_global() {
+    let mut classes = BTreeMap::new();
+    let scoped = scope(":global(.a:not(.b)) .c { color: red }", "abc123", &mut classes);
+    assert_eq!(scoped, ".a:not(.b) .c_abc123 { color: red }");
+    assert_eq!(classes.len(), 1);
+}
+
+#[test]
+fn test_css_module() {
+    let collector = CssCollector::default();
+    let mut t = CssTranspiler::with_collector(collector.clone());
+    let js = t.transform("src/a.module.css", ".a { color: red }".to_owned()).unwrap();
+    let suffix = file_hash("src/a.module.css");
+    assert!(js.contains(&format!(r#"export const classes = {{ "a": "a_{}" }};"#, suffix)));
+    let js = t.transform("src/b.css", ".b {\n}".to_owned()).unwrap();
+    assert!(js.starts_with(r#"export const stylesheet = ".b {\n}";"#));
+    assert_eq!(collector.css(), format!(".a_{} {{ color: red }}\n.b {{\n}}", suffix));
+}
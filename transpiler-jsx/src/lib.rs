@@ -1,6 +1,5 @@
-use std::path::Path;
 use transpilers::AssetTranspiler;
-use transpilers::rquickjs::{Module as QJSModule, Ctx, Loaded, Result, Script};
+use transpilers::rquickjs::{Error, Result};
 use swc_ecma_codegen::{text_writer::JsWriter, Emitter};
 use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax, EsConfig};
 use swc_ecma_transforms_base::fixer::fixer;
@@ -9,6 +8,7 @@ use swc_ecma_visit::FoldWith;
 use swc_ecma_ast::{EsVersion, Module};
 use swc_common::{
     self,
+    FileName,
     GLOBALS,
     Globals,
     Mark,
@@ -22,12 +22,10 @@ use swc_common::{
 pub struct JsxTranspiler {}
 
 impl AssetTranspiler for JsxTranspiler {
-    fn transpile<'js>(&mut self, ctx: Ctx<'js>, path: &str) -> Result<QJSModule<'js, Loaded<Script>>> {
+    fn transform(&mut self, path: &str, source: String) -> Result<String> {
         let cm: Lrc<SourceMap> = Default::default();
         let handler = Handler::with_tty_emitter(ColorConfig::Auto, true, false, Some(cm.clone()));
-        let fm = cm
-            .load_file(Path::new(path))
-            .expect("failed to load source");
+        let fm = cm.new_source_file(FileName::Real(path.into()), source);
         // let comments = SingleThreadedComments::default();
         let lexer = Lexer::new(
             Syntax::Es(EsConfig {
@@ -44,10 +42,12 @@ impl AssetTranspiler for JsxTranspiler {
             e.into_diagnostic(&handler).emit();
         }
 
-        let module = parser
-            .parse_module()
-            .map_err(|e| e.into_diagnostic(&handler).emit())
-            .expect("failed to parse module.");
+        let module = parser.parse_module().map_err(|e| {
+            let loc = cm.lookup_char_pos(e.span().lo);
+            let message = format!("{}:{}:{}: {}", path, loc.line, loc.col_display + 1, e.kind().msg());
+            e.into_diagnostic(&handler).emit();
+            Error::new_loading_message(path, message)
+        })?;
         // Ensure that we have enough parenthesis.
         let module = module.fold_with(&mut fixer(None));
             
@@ -64,12 +64,11 @@ impl AssetTranspiler for JsxTranspiler {
             module
         });
 
-        let js_source = emit(&cm, &module);
-        QJSModule::new(ctx, path, js_source)
+        emit(&cm, &module).map_err(|e| Error::new_loading_message(path, format!("{}: {}", path, e)))
     }
 }
 
-fn emit(cm: &Lrc<SourceMap>, module: &Module) -> String {
+fn emit(cm: &Lrc<SourceMap>, module: &Module) -> std::io::Result<String> {
     let mut buf = vec![];
     {
         let mut emitter = Emitter {
@@ -83,7 +82,7 @@ fn emit(cm: &Lrc<SourceMap>, module: &Module) -> String {
             comments: None,
             wr: JsWriter::new(cm.clone(), "\n", &mut buf, None),
         };
-        emitter.emit_module(&module).unwrap();
+        emitter.emit_module(module)?;
     }
-    String::from_utf8(buf).expect("non-utf8?")
+    Ok(String::from_utf8_lossy(&buf).into_owned())
 }
\ No newline at end of file
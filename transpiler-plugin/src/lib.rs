@@ -0,0 +1,244 @@
+//! Transpilers implemented in javascript.
+//!
+//! A plugin is an ES module exporting `load(path)` and/or `transform(source, path)` hooks:
+//! ```js
+//! export function transform(source, path) {
+//!     return { code: compile(source), map: sourceMap }
+//! }
+//! ```
+//! Hooks return either javascript code, an object with `code` and an optional `map`
+//! (a string or a source map object), or `undefined`/`null` to leave the asset as is.
+//! Async hooks return a promise of one of these, which is awaited by running the jobs of
+//! the plugin runtime. The plugin has no event loop, so it can't wait for timers or I/O.
+//! The plugin runs in its own runtime on a dedicated thread, so it never touches the
+//! application's context.
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use transpilers::AssetTranspiler;
+use transpilers::source_map::append_inline;
+use transpilers::rquickjs::{
+    qjs, Context, Ctx, Error, FileResolver, Function, Module, Object, Result, Runtime, ScriptLoader, Value,
+};
+
+/// Tracks the settlement of the value returned by a hook
+const SETTLE_JS: &str = r#"(value) => {
+    const state = { done: false, rejected: false };
+    Promise.resolve(value).then(
+        (value) => Object.assign(state, { done: true, value }),
+        (reason) => Object.assign(state, { done: true, rejected: true, reason: String(reason) }),
+    );
+    return state;
+}"#;
+
+type Reply = Sender<std::result::Result<Option<String>, String>>;
+
+enum Request {
+    Load { path: String, reply: Reply },
+    Transform { path: String, source: String, reply: Reply },
+}
+
+pub struct PluginTranspiler {
+    name: String,
+    requests: Option<Sender<Request>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PluginTranspiler {
+    /// Starts the plugin defined by the module source.
+    /// `name` is used as the module name and in error messages.
+    pub fn new(name: &str, source: &str) -> Result<Self> {
+        let (requests, receiver) = channel();
+        let (ready, is_ready) = channel();
+        let (module_name, module_source) = (name.to_owned(), source.to_owned());
+        let thread = std::thread::Builder::new()
+            .name(format!("plugin {}", name))
+            .spawn(move || serve(module_name, module_source, ready, receiver))?;
+        match is_ready.recv() {
+            Ok(Ok(())) => Ok(PluginTranspiler {
+                name: name.to_owned(),
+                requests: Some(requests),
+                thread: Some(thread),
+            }),
+            Ok(Err(message)) => Err(Error::new_loading_message(name, message)),
+            Err(_) => Err(Error::new_loading_message(name, "plugin thread exited")),
+        }
+    }
+
+    /// Starts the plugin from a module file.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let source = std::fs::read_to_string(path)?;
+        Self::new(path, &source)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn request(&self, path: &str, make: impl FnOnce(Reply) -> Request) -> Result<Option<String>> {
+        let (reply, response) = channel();
+        let sent = self
+            .requests
+            .as_ref()
+            .map(|r| r.send(make(reply)).is_ok())
+            .unwrap_or(false);
+        if !sent {
+            return Err(Error::new_loading_message(path, format!("plugin {} is not running", self.name)));
+        }
+        match response.recv() {
+            Ok(result) => result.map_err(|message| {
+                Error::new_loading_message(path, format!("plugin {}: {}", self.name, message))
+            }),
+            Err(_) => Err(Error::new_loading_message(path, format!("plugin {} crashed", self.name))),
+        }
+    }
+}
+
+impl AssetTranspiler for PluginTranspiler {
+    fn load(&mut self, path: &str) -> Result<String> {
+        let loaded = self.request(path, |reply| Request::Load {
+            path: path.to_owned(),
+            reply,
+        })?;
+        match loaded {
+            Some(source) => Ok(source),
+            None => Ok(std::fs::read_to_string(path)?),
+        }
+    }
+
+    fn transform(&mut self, path: &str, source: String) -> Result<String> {
+        let transformed = self.request(path, |reply| Request::Transform {
+            path: path.to_owned(),
+            source,
+            reply,
+        })?;
+        // The plugin thread sends the source back when the hook leaves it as is
+        Ok(transformed.unwrap_or_default())
+    }
+}
+
+impl Drop for PluginTranspiler {
+    fn drop(&mut self) {
+        // Closing the channel stops the plugin thread
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(
+    name: String,
+    source: String,
+    ready: Sender<std::result::Result<(), String>>,
+    requests: Receiver<Request>,
+) {
+    let rt = match Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            let _ = ready.send(Err(e.to_string()));
+            return;
+        }
+    };
+    // Plugins may import their helpers relative to the working directory
+    rt.set_loader(FileResolver::default().with_path("./"), ScriptLoader::default());
+    let context = match Context::full(&rt) {
+        Ok(context) => context,
+        Err(e) => {
+            let _ = ready.send(Err(e.to_string()));
+            return;
+        }
+    };
+    context.with(|ctx| {
+        let hooks = Module::new(ctx, name.as_str(), source)
+            .and_then(|m| m.eval())
+            .and_then(|m| {
+                let load: Option<Function> = m.get("load")?;
+                let transform: Option<Function> = m.get("transform")?;
+                Ok((load, transform))
+            });
+        let (load, transform) = match hooks {
+            Ok(hooks) => hooks,
+            Err(e) => {
+                let _ = ready.send(Err(e.to_string()));
+                return;
+            }
+        };
+        let _ = ready.send(Ok(()));
+        for request in requests {
+            match request {
+                Request::Load { path, reply } => {
+                    let result = match &load {
+                        Some(f) => f.call((path,)).and_then(|v| output(ctx, &name, v)),
+                        None => Ok(None),
+                    };
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                }
+                Request::Transform { path, source, reply } => {
+                    let result = match &transform {
+                        Some(f) => f.call((source.as_str(), path)).and_then(|v| output(ctx, &name, v)),
+                        None => Ok(None),
+                    };
+                    let result = result.map(|code| code.or(Some(source)));
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                }
+            }
+        }
+    });
+}
+
+/// Converts the value returned by a hook of the plugin `name` into code with an inline source map.
+fn output<'js>(ctx: Ctx<'js>, name: &str, value: Value<'js>) -> Result<Option<String>> {
+    let value = settle(ctx, name, value)?;
+    if value.is_undefined() || value.is_null() {
+        return Ok(None);
+    }
+    if let Some(code) = value.as_string() {
+        return Ok(Some(code.to_string()?));
+    }
+    let obj = match value.as_object() {
+        Some(obj) => obj.clone(),
+        None => return Err(Error::new_loading_message(name, "hooks must return a string or { code, map }")),
+    };
+    let mut code: String = obj.get("code")?;
+    let map: Value = obj.get("map")?;
+    if let Some(map) = map.as_string() {
+        append_inline(&mut code, &map.to_string()?);
+    } else if map.as_object().is_some() {
+        let json: Object = ctx.globals().get("JSON")?;
+        let stringify: Function = json.get("stringify")?;
+        let map: String = stringify.call((map,))?;
+        append_inline(&mut code, &map);
+    }
+    Ok(Some(code))
+}
+
+/// Awaits the value if it's a promise or another thenable, running the pending jobs
+fn settle<'js>(ctx: Ctx<'js>, name: &str, value: Value<'js>) -> Result<Value<'js>> {
+    let thenable = match value.as_object() {
+        Some(obj) => obj.get::<_, Value>("then")?.is_function(),
+        None => false,
+    };
+    if !thenable {
+        return Ok(value);
+    }
+    let track: Function = ctx.eval(SETTLE_JS)?;
+    let state: Object = track.call((value,))?;
+    let rt = unsafe { qjs::JS_GetRuntime(ctx.as_ptr()) };
+    while !state.get::<_, bool>("done")? {
+        let mut job_ctx = std::ptr::null_mut();
+        match unsafe { qjs::JS_ExecutePendingJob(rt, &mut job_ctx) } {
+            0 => return Err(Error::new_loading_message(name, "the promise returned by the hook never settles")),
+            failed if failed < 0 => unsafe {
+                // The job threw outside of a promise, drops the exception left on its context
+                qjs::JS_FreeValue(job_ctx, qjs::JS_GetException(job_ctx));
+            },
+            _ => {}
+        }
+    }
+    if state.get("rejected")? {
+        let reason: String = state.get("reason")?;
+        return Err(Error::new_loading_message(name, format!("the hook rejected with {}", reason)));
+    }
+    state.get("value")
+}
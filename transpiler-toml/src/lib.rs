@@ -0,0 +1,66 @@
+use transpilers::AssetTranspiler;
+use transpilers::data_module::{check_safe_integer, data_module, line_column};
+use transpilers::rquickjs::{Error, Result};
+
+/// Exposes a toml document as the default export of a module.
+/// Top-level keys that are valid identifiers are also exported by name.
+/// Dates and times are exported as strings.
+/// Integers a javascript number can't represent exactly are rejected.
+#[derive(Default)]
+pub struct TomlTranspiler {}
+
+impl AssetTranspiler for TomlTranspiler {
+    fn transform(&mut self, path: &str, source: String) -> Result<String> {
+        let document: toml::Table = toml::from_str(&source).map_err(|e| {
+            let message = match e.span() {
+                Some(span) => {
+                    let (line, column) = line_column(&source, span.start);
+                    format!("{} at line {} column {}", e.message(), line, column)
+                }
+                None => e.message().to_owned(),
+            };
+            Error::new_loading_message(path, message)
+        })?;
+        let json = to_json(toml::Value::Table(document)).map_err(|message| Error::new_loading_message(path, message))?;
+        let keys: Vec<&str> = match &json {
+            serde_json::Value::Object(map) => map.keys().map(|k| k.as_str()).collect(),
+            _ => vec![],
+        };
+        Ok(data_module(&json.to_string(), keys))
+    }
+}
+
+fn to_json(value: toml::Value) -> std::result::Result<serde_json::Value, String> {
+    use serde_json::Value as Json;
+    Ok(match value {
+        toml::Value::String(s) => Json::String(s),
+        toml::Value::Integer(i) => {
+            check_safe_integer(i.into())?;
+            Json::from(i)
+        }
+        // Non-finite floats become null like in JSON.stringify
+        toml::Value::Float(f) => serde_json::Number::from_f64(f).map(Json::Number).unwrap_or(Json::Null),
+        toml::Value::Boolean(b) => Json::Bool(b),
+        toml::Value::Datetime(d) => Json::String(d.to_string()),
+        toml::Value::Array(a) => Json::Array(a.into_iter().map(to_json).collect::<std::result::Result<_, _>>()?),
+        toml::Value::Table(t) => Json::Object(
+            t.into_iter()
+                .map(|(k, v)| Ok((k, to_json(v)?)))
+                .collect::<std::result::Result<_, String>>()?,
+        ),
+    })
+}
+
+#[test]
+fn test_toml_module() {
+    let mut t = TomlTranspiler::default();
+    let js = t.transform("config.toml", "port = 8080\n[server]\nstarted = 1979-05-27T07:32:00Z\n".to_owned()).unwrap();
+    assert!(js.starts_with(r#"const data = JSON.parse("{\"port\":8080,\"server\":{\"started\":\"1979-05-27T07:32:00Z\"}}");"#));
+    assert!(js.contains("export const server = data.server;"));
+    let err = t.transform("ids.toml", "id = -9007199254740993\n".to_owned()).unwrap_err();
+    assert!(err.to_string().contains("-9007199254740993 is too large"), "{}", err);
+
+    let err = t.transform("config.toml", "port = 8080\nhost = = 1\n".to_owned()).unwrap_err();
+    assert!(err.to_string().contains("at line 2 column 8"), "{}", err);
+    assert_eq!(err.to_string().matches("config.toml").count(), 1);
+}
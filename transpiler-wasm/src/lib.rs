@@ -0,0 +1,93 @@
+use transpilers::AssetTranspiler;
+use transpilers::data_module::{is_identifier, js_string};
+use transpilers::rquickjs::{Error, Result};
+use wasmi::{Engine, ExternType, Module};
+
+/// Turns `.wasm` files into modules instantiating the binary:
+/// ```js
+/// import * as __imports0 from "./env.js";
+/// const __wasm_module = WebAssembly[Symbol.for("exerum.moduleFromBase64")]("AGFzbQ...");
+/// const __wasm_instance = new WebAssembly.Instance(__wasm_module, { "./env.js": __imports0 });
+/// export const add = __wasm_instance.exports["add"];
+/// export default __wasm_instance.exports;
+/// ```
+/// Module names of the wasm imports are imported as javascript modules.
+/// Requires the `WebAssembly` namespace of the stdlib.
+#[derive(Default)]
+pub struct WasmTranspiler {}
+
+impl AssetTranspiler for WasmTranspiler {
+    /// The binary is passed on base64 encoded
+    fn load(&mut self, path: &str) -> Result<String> {
+        Ok(base64::encode(std::fs::read(path)?))
+    }
+
+    fn transform(&mut self, path: &str, source: String) -> Result<String> {
+        let bytes = base64::decode(&source)
+            .map_err(|e| Error::new_loading_message(path, format!("{}: {}", path, e)))?;
+        let module = Module::new(&Engine::default(), &bytes[..])
+            .map_err(|e| Error::new_loading_message(path, format!("{}: {}", path, e)))?;
+
+        let mut namespaces: Vec<String> = vec![];
+        for import in module.imports() {
+            if let ExternType::Table(_) = import.ty() {
+                let message = format!("{}: table import {}.{} is not supported", path, import.module(), import.name());
+                return Err(Error::new_loading_message(path, message));
+            }
+            if !namespaces.iter().any(|n| n == import.module()) {
+                namespaces.push(import.module().to_owned());
+            }
+        }
+
+        let mut js = String::new();
+        for (i, namespace) in namespaces.iter().enumerate() {
+            js.push_str(&format!("import * as __imports{} from {};\n", i, js_string(namespace)));
+        }
+        js.push_str(&format!(
+            "const __wasm_module = WebAssembly[Symbol.for(\"exerum.moduleFromBase64\")](\"{}\");\n",
+            source
+        ));
+        let imports = namespaces
+            .iter()
+            .enumerate()
+            .map(|(i, namespace)| format!("{}: __imports{}", js_string(namespace), i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        js.push_str(&format!(
+            "const __wasm_instance = new WebAssembly.Instance(__wasm_module, {{ {} }});\n",
+            imports
+        ));
+        for export in module.exports().filter(|e| is_identifier(e.name())) {
+            js.push_str(&format!(
+                "export const {} = __wasm_instance.exports[{}];\n",
+                export.name(),
+                js_string(export.name())
+            ));
+        }
+        js.push_str("export default __wasm_instance.exports;\n");
+        Ok(js)
+    }
+}
+
+#[test]
+fn test_wasm_module() {
+    // (module
+    //   (import "./env.js" "log" (func $log (param i32)))
+    //   (func (export "add") (param i32 i32) (result i32) local.get 0 local.get 1 i32.add))
+    let wasm: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x0b, 0x02, 0x60, 0x01, 0x7f, 0x00, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, // types
+        0x02, 0x10, 0x01, 0x08, b'.', b'/', b'e', b'n', b'v', b'.', b'j', b's', 0x03, b'l', b'o', b'g', 0x00, 0x00, // imports
+        0x03, 0x02, 0x01, 0x01, // functions
+        0x07, 0x07, 0x01, 0x03, b'a', b'd', b'd', 0x00, 0x01, // exports
+        0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b, // code
+    ];
+    let mut t = WasmTranspiler::default();
+    let js = t.transform("add.wasm", base64::encode(wasm)).unwrap();
+    assert!(js.starts_with("import * as __imports0 from \"./env.js\";\n"));
+    assert!(js.contains("{ \"./env.js\": __imports0 }"));
+    assert!(js.contains("export const add = __wasm_instance.exports[\"add\"];\n"));
+
+    let err = t.transform("bad.wasm", base64::encode(b"\0asm")).unwrap_err();
+    assert!(err.to_string().contains("bad.wasm"));
+}
@@ -0,0 +1,85 @@
+use transpilers::AssetTranspiler;
+use transpilers::data_module::{check_safe_integer, data_module};
+use transpilers::rquickjs::{Error, Result};
+
+/// Exposes a yaml document as the default export of a module.
+/// Top-level keys that are valid identifiers are also exported by name.
+/// Number, boolean and null keys become strings, like object keys in javascript.
+/// Integers a javascript number can't represent exactly are rejected.
+#[derive(Default)]
+pub struct YamlTranspiler {}
+
+impl AssetTranspiler for YamlTranspiler {
+    fn transform(&mut self, path: &str, source: String) -> Result<String> {
+        // The message includes the location
+        let document: serde_yaml::Value =
+            serde_yaml::from_str(&source).map_err(|e| Error::new_loading_message(path, e.to_string()))?;
+        let json = to_json(document).map_err(|message| Error::new_loading_message(path, message))?;
+        let keys: Vec<&str> = match &json {
+            serde_json::Value::Object(map) => map.keys().map(|k| k.as_str()).collect(),
+            _ => vec![],
+        };
+        Ok(data_module(&json.to_string(), keys))
+    }
+}
+
+fn to_json(value: serde_yaml::Value) -> std::result::Result<serde_json::Value, String> {
+    use serde_json::Value as Json;
+    use serde_yaml::Value as Yaml;
+    Ok(match value {
+        Yaml::Null => Json::Null,
+        Yaml::Bool(b) => Json::Bool(b),
+        Yaml::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => {
+                check_safe_integer(i.into())?;
+                Json::from(i)
+            }
+            (_, Some(u), _) => {
+                check_safe_integer(u.into())?;
+                Json::from(u)
+            }
+            // Non-finite floats become null like in JSON.stringify
+            (_, _, Some(f)) => serde_json::Number::from_f64(f).map(Json::Number).unwrap_or(Json::Null),
+            _ => Json::Null,
+        },
+        Yaml::String(s) => Json::String(s),
+        Yaml::Sequence(s) => Json::Array(s.into_iter().map(to_json).collect::<std::result::Result<_, _>>()?),
+        Yaml::Mapping(m) => {
+            let mut map = serde_json::Map::new();
+            for (key, value) in m {
+                map.insert(key_string(key)?, to_json(value)?);
+            }
+            Json::Object(map)
+        }
+        Yaml::Tagged(tagged) => to_json(tagged.value)?,
+    })
+}
+
+/// Scalar keys as `String(key)` would print them
+fn key_string(key: serde_yaml::Value) -> std::result::Result<String, String> {
+    use serde_yaml::Value as Yaml;
+    match key {
+        Yaml::String(s) => Ok(s),
+        Yaml::Number(n) => Ok(n.to_string()),
+        Yaml::Bool(b) => Ok(b.to_string()),
+        Yaml::Null => Ok("null".to_owned()),
+        Yaml::Tagged(tagged) => key_string(tagged.value),
+        Yaml::Sequence(_) | Yaml::Mapping(_) => Err("sequences and mappings can't be used as keys".to_owned()),
+    }
+}
+
+#[test]
+fn test_yaml_module() {
+    let mut t = YamlTranspiler::default();
+    let js = t.transform("config.yaml", "port: 8080\nhosts:\n  - a\n  - b\n".to_owned()).unwrap();
+    assert!(js.starts_with(r#"const data = JSON.parse("{\"hosts\":[\"a\",\"b\"],\"port\":8080}");"#));
+    assert!(js.contains("export const hosts = data.hosts;"));
+    let js = t.transform("codes.yaml", "200: ok\ntrue: yes\n~: none\n".to_owned()).unwrap();
+    assert!(js.starts_with(r#"const data = JSON.parse("{\"200\":\"ok\",\"null\":\"none\",\"true\":\"yes\"}");"#));
+    let err = t.transform("ids.yaml", "id: 9007199254740993\n".to_owned()).unwrap_err();
+    assert!(err.to_string().contains("9007199254740993 is too large"), "{}", err);
+
+    let err = t.transform("config.yaml", "port: 8080\nhosts: [a, b\n".to_owned()).unwrap_err();
+    assert!(err.to_string().contains("at line 3 column 1"), "{}", err);
+    assert_eq!(err.to_string().matches("config.yaml").count(), 1);
+}
@@ -0,0 +1,93 @@
+/// Words that can't be used as exported binding names in a module
+const RESERVED: [&str; 46] = [
+    "await", "break", "case", "catch", "class", "const", "continue", "debugger", "default",
+    "delete", "do", "else", "enum", "export", "extends", "false", "finally", "for", "function",
+    "if", "import", "in", "instanceof", "new", "null", "return", "super", "switch", "this",
+    "throw", "true", "try", "typeof", "var", "void", "while", "with", "yield", "let", "static",
+    "implements", "interface", "package", "private", "protected", "public",
+];
+
+/// Whether the name can be exported as `export const {name}`
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let first_ok = match chars.next() {
+        Some(c) => c.is_alphabetic() || c == '_' || c == '$',
+        None => false,
+    };
+    first_ok
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+        && !RESERVED.contains(&name)
+        && name != "eval"
+        && name != "arguments"
+}
+
+/// Quotes the string as a javascript string literal, which is valid json as well
+pub fn js_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{2028}' => out.push_str("\\u2028"),
+            '\u{2029}' => out.push_str("\\u2029"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Largest integer `JSON.parse` reads without losing precision, `Number.MAX_SAFE_INTEGER`
+pub const MAX_SAFE_INTEGER: i128 = (1 << 53) - 1;
+
+/// Fails for integers a javascript number can't represent exactly
+pub fn check_safe_integer(n: i128) -> Result<(), String> {
+    if n.abs() > MAX_SAFE_INTEGER {
+        Err(format!("the integer {} is too large for a javascript number, quote it to keep it as a string", n))
+    } else {
+        Ok(())
+    }
+}
+
+/// Builds a module exporting the json document as the default export
+/// and its top-level `keys` as named exports when they are valid identifiers.
+/// The document is parsed with `JSON.parse`, which is faster than evaluating an object
+/// literal and keeps keys like `__proto__` as plain properties.
+/// A `then` export would make the module namespace thenable and `await import(...)` resolve to
+/// the value of the key, so it's only available on the default export.
+pub fn data_module<'a>(json: &str, keys: impl IntoIterator<Item = &'a str>) -> String {
+    let mut js = format!("const data = JSON.parse({});\nexport default data;\n", js_string(json));
+    for key in keys.into_iter().filter(|k| is_identifier(k) && *k != "then") {
+        js.push_str(&format!("export const {} = data.{};\n", key, key));
+    }
+    js
+}
+
+/// Converts a byte offset into 1-based line and column
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map(|l| l.chars().count()).unwrap_or(0) + 1;
+    (line, column)
+}
+
+#[test]
+fn test_data_module() {
+    let js = data_module(r#"{"name":"x","default":1,"two words":2,"then":3}"#, ["name", "default", "two words", "then"]);
+    assert_eq!(
+        js,
+        r#"const data = JSON.parse("{\"name\":\"x\",\"default\":1,\"two words\":2,\"then\":3}");
+export default data;
+export const name = data.name;
+"#
+    );
+    assert_eq!(js_string("a\"b\\c\n\u{1}\u{2028}"), r#""a\"b\\c\n\u0001\u2028""#);
+    assert_eq!(line_column("a = 1\nb = =", 10), (2, 5));
+    assert!(check_safe_integer(9007199254740991).is_ok());
+    assert!(check_safe_integer(-9007199254740992).is_err());
+}
@@ -0,0 +1,91 @@
+/// Glob pattern matched against resolved module paths.
+/// Supports `*` (anything but a path separator), `**` (anything including
+/// separators) and `?` (a single character). A pattern without `/` is matched
+/// against the file name only, so `*.module.css` works for any directory.
+/// Other patterns match from a path segment, since resolved paths start with the
+/// project root: `src/**/*.ts` matches `/app/src/main.ts`. Patterns starting with
+/// `/` match from the start of the path only.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pattern: String,
+    file_name_only: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        Pattern {
+            pattern: pattern.to_owned(),
+            file_name_only: !pattern.contains('/'),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.replace('\\', "/");
+        let pattern = self.pattern.as_bytes();
+        if self.file_name_only {
+            return matches(pattern, path.rsplit('/').next().unwrap_or(&path).as_bytes());
+        }
+        let path = path.trim_start_matches("./");
+        if self.pattern.starts_with('/') {
+            return matches(pattern, path.as_bytes());
+        }
+        // From the start, or from any segment
+        matches(pattern, path.as_bytes())
+            || path.match_indices('/').any(|(i, _)| matches(pattern, path[i + 1..].as_bytes()))
+    }
+}
+
+fn matches(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.first() {
+        None => s.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            // `**/` also matches no directory at all
+            if rest.first() == Some(&b'/') && matches(&rest[1..], s) {
+                return true;
+            }
+            (0..=s.len()).any(|i| matches(rest, &s[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for i in 0..=s.len() {
+                if matches(rest, &s[i..]) {
+                    return true;
+                }
+                if i < s.len() && s[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => !s.is_empty() && s[0] != b'/' && matches(&pattern[1..], &s[1..]),
+        Some(c) => s.first() == Some(c) && matches(&pattern[1..], &s[1..]),
+    }
+}
+
+#[test]
+fn test_file_name_pattern() {
+    let p = Pattern::new("*.module.css");
+    assert!(p.matches("./test_data/src/button.module.css"));
+    assert!(!p.matches("./test_data/src/button.css"));
+}
+
+#[test]
+fn test_path_pattern() {
+    let p = Pattern::new("src/**/*.tsx");
+    assert!(p.matches("./src/main.tsx"));
+    assert!(p.matches("src/components/deep/b.tsx"));
+    assert!(!p.matches("lib/main.tsx"));
+    // Resolved paths start with the project root
+    assert!(p.matches("/app/src/main.tsx"));
+    assert!(p.matches("test_data/src/a/b.tsx"));
+    assert!(!p.matches("/app/mysrc/main.tsx"));
+    assert!(!Pattern::new("/src/*.tsx").matches("/app/src/main.tsx"));
+    let p = Pattern::new("src/*.ts?");
+    assert!(p.matches("src/main.tsx"));
+    assert!(!p.matches("src/a/main.tsx"));
+}
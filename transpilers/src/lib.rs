@@ -1,15 +1,49 @@
 pub mod rquickjs;
+pub mod glob;
+pub mod pipeline;
+pub mod source_map;
+pub mod data_module;
 use std::collections::HashMap;
-use std::rc::Rc;
-use std::cell::RefCell;
-use core::cell::RefMut;
-use crate::rquickjs::{Ctx, Module, Loaded, Result, Script};
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::rquickjs::{Ctx, Error, Module, Loaded, Result, Script};
+pub use crate::glob::Pattern;
+pub use crate::pipeline::Pipeline;
+
 /// Reusable trinspiler
 pub trait AssetTranspiler {
-    fn transpile<'js>(&mut self, ctx: Ctx<'js>, path: &str) -> Result<Module<'js, Loaded<Script>>>;
+    /// Reads the asset into source text. Defaults to reading an utf-8 file.
+    fn load(&mut self, path: &str) -> Result<String> {
+        Ok(std::fs::read_to_string(path)?)
+    }
+
+    /// Turns the source text of the asset into javascript.
+    /// The source may be the output of a previous pipeline stage.
+    fn transform(&mut self, path: &str, source: String) -> Result<String>;
+
+    /// Called instead of `load` and `transform` when the loader serves the asset
+    /// from its module cache, e.g. to record side outputs of the transform again.
+    fn cached(&mut self, _path: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn transpile<'js>(&mut self, ctx: Ctx<'js>, path: &str) -> Result<Module<'js, Loaded<Script>>> {
+        let source = self.load(path)?;
+        let js_source = self.transform(path, source)?;
+        Module::new(ctx, path, js_source)
+    }
 }
 
-#[derive(Hash, Eq, PartialEq)]
+/// Transpiler shared between registrations, pipelines and loader threads
+pub type SharedTranspiler = Arc<Mutex<dyn AssetTranspiler + Send>>;
+
+/// Locks the transpiler. Fails if the transpiler panicked while locked, its state may be broken.
+pub fn lock(transpiler: &SharedTranspiler) -> Result<MutexGuard<'_, dyn AssetTranspiler + Send + 'static>> {
+    transpiler
+        .lock()
+        .map_err(|_| Error::new_loading_message("transpiler", "the transpiler panicked while transpiling another asset"))
+}
+
+#[derive(Hash, Eq, PartialEq, Clone)]
 pub enum TKey {
     Name(String),
     Extension(String)
@@ -17,36 +51,105 @@ pub enum TKey {
 
 #[macro_export]
 macro_rules! register {
-    ($obj:ident, $name:literal, [$(.$ext:tt),*], $transpiler:ident) => {
-        let __t: std::rc::Rc<std::cell::RefCell<dyn $crate::AssetTranspiler>> = std::rc::Rc::new(std::cell::RefCell::new($transpiler::default()));
-        for __ext in vec![$(stringify!($ext),)*] {
-            $obj.register_transpiler($crate::TKey::Extension(__ext.to_owned()), std::rc::Rc::clone(&__t));
-        };
-        $obj.register_transpiler($crate::TKey::Name($name.to_owned()), std::rc::Rc::clone(&__t));
+    ($obj:ident, $name:expr, [$(.$ext:tt),*], $transpiler:ident) => {
+        $obj.register($name, &[$(stringify!($ext),)*], $transpiler::default());
     }
 }
 
-#[derive(Default)]
+#[derive(Clone)]
+struct PatternEntry {
+    pattern: Pattern,
+    priority: i32,
+    transpiler: SharedTranspiler
+}
+
+/// Clones share the registered transpilers
+#[derive(Default, Clone)]
 pub struct Transpilers {
-    inner: HashMap<TKey, Rc<RefCell<dyn AssetTranspiler>>>
+    inner: HashMap<TKey, SharedTranspiler>,
+    /// Names the extensions were registered with
+    extension_names: HashMap<String, String>,
+    /// Sorted by priority, highest first
+    patterns: Vec<PatternEntry>
 }
 
 impl Transpilers {
-    pub fn register_transpiler(&mut self, key: TKey, transpiler: Rc<RefCell<dyn AssetTranspiler>>) {
+    pub fn register_transpiler(&mut self, key: TKey, transpiler: SharedTranspiler) {
         self.inner.insert(key, transpiler);
     }
 
+    /// Registers the transpiler under a name and a list of extensions (without the dot).
+    /// Returns the shared transpiler so it can be reused as a pipeline stage.
+    pub fn register(
+        &mut self,
+        name: &str,
+        extensions: &[&str],
+        transpiler: impl AssetTranspiler + Send + 'static
+    ) -> SharedTranspiler {
+        let t: SharedTranspiler = Arc::new(Mutex::new(transpiler));
+        for ext in extensions {
+            self.register_transpiler(TKey::Extension(ext.to_string()), Arc::clone(&t));
+            self.extension_names.insert(ext.to_string(), name.to_owned());
+        }
+        self.register_transpiler(TKey::Name(name.to_owned()), Arc::clone(&t));
+        t
+    }
+
+    /// Registers the transpiler for paths matching a glob pattern.
+    /// Patterns are checked before extensions, the highest priority first.
+    /// Patterns of equal priority are checked in registration order.
+    pub fn register_pattern(&mut self, pattern: &str, priority: i32, transpiler: SharedTranspiler) {
+        let index = self
+            .patterns
+            .iter()
+            .position(|e| e.priority < priority)
+            .unwrap_or(self.patterns.len());
+        self.patterns.insert(index, PatternEntry {
+            pattern: Pattern::new(pattern),
+            priority,
+            transpiler
+        });
+    }
+
     #[inline]
-    pub fn by_name(&mut self, name: &str) -> Option<RefMut<'_, (dyn AssetTranspiler + 'static)>>  {
+    pub fn by_name(&mut self, name: &str) -> Result<Option<MutexGuard<'_, dyn AssetTranspiler + Send + 'static>>> {
         self.inner
-            .get_mut(&TKey::Name(name.to_owned()))
-            .map(|t| t.borrow_mut())
+            .get(&TKey::Name(name.to_owned()))
+            .map(lock)
+            .transpose()
     }
 
     #[inline]
-    pub fn by_ext(&mut self, ext: &str) -> Option<RefMut<'_, (dyn AssetTranspiler + 'static)>>  {
+    pub fn by_ext(&mut self, ext: &str) -> Result<Option<MutexGuard<'_, dyn AssetTranspiler + Send + 'static>>> {
         self.inner
-            .get_mut(&TKey::Extension(ext.to_owned()))
-            .map(|t| t.borrow_mut())
+            .get(&TKey::Extension(ext.to_owned()))
+            .map(lock)
+            .transpose()
     }
-}
\ No newline at end of file
+
+    /// Picks a transpiler by matching patterns first, then by the file extension.
+    pub fn by_path(&mut self, path: &str) -> Result<Option<MutexGuard<'_, dyn AssetTranspiler + Send + 'static>>> {
+        if let Some(entry) = self.patterns.iter().find(|e| e.pattern.matches(path)) {
+            return lock(&entry.transpiler).map(Some);
+        }
+        match std::path::Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => self.by_ext(ext),
+            None => Ok(None),
+        }
+    }
+
+    /// Describes the transpiler `by_path` picks: the matching pattern,
+    /// the name the extension was registered with, or the extension.
+    pub fn label_by_path(&self, path: &str) -> Option<String> {
+        if let Some(entry) = self.patterns.iter().find(|e| e.pattern.matches(path)) {
+            return Some(entry.pattern.as_str().to_owned());
+        }
+        let ext = std::path::Path::new(path).extension()?.to_str()?;
+        if let Some(name) = self.extension_names.get(ext) {
+            return Some(name.clone());
+        }
+        self.inner
+            .contains_key(&TKey::Extension(ext.to_owned()))
+            .then(|| format!(".{}", ext))
+    }
+}
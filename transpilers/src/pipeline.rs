@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex};
+use crate::{lock, AssetTranspiler, SharedTranspiler};
+use crate::rquickjs::Result;
+
+/// Chains several transpilers, feeding the output of each stage into the next one,
+/// e.g. a custom preprocessor followed by the typescript transpiler.
+/// The asset is read by the first stage.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<SharedTranspiler>
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    /// Appends a stage
+    pub fn stage(self, transpiler: impl AssetTranspiler + Send + 'static) -> Self {
+        self.shared_stage(Arc::new(Mutex::new(transpiler)))
+    }
+
+    /// Appends a stage that is shared with other pipelines or registrations
+    pub fn shared_stage(mut self, transpiler: SharedTranspiler) -> Self {
+        self.stages.push(transpiler);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+impl AssetTranspiler for Pipeline {
+    fn load(&mut self, path: &str) -> Result<String> {
+        match self.stages.first() {
+            Some(first) => lock(first)?.load(path),
+            None => Ok(std::fs::read_to_string(path)?)
+        }
+    }
+
+    fn transform(&mut self, path: &str, source: String) -> Result<String> {
+        let mut source = source;
+        for stage in self.stages.iter() {
+            source = lock(stage)?.transform(path, source)?;
+        }
+        Ok(source)
+    }
+
+    fn cached(&mut self, path: &str) -> Result<()> {
+        for stage in self.stages.iter() {
+            lock(stage)?.cached(path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+//! Source maps travel together with the transpiled code as an inline
+//! `sourceMappingURL` comment, so pipeline stages don't need a separate channel for them.
+
+const INLINE_PREFIX: &str = "//# sourceMappingURL=data:application/json;base64,";
+
+/// Appends the source map to the code as an inline comment.
+pub fn append_inline(code: &mut String, map: &str) {
+    if !code.ends_with('\n') {
+        code.push('\n');
+    }
+    code.push_str(INLINE_PREFIX);
+    code.push_str(&base64::encode(map));
+    code.push('\n');
+}
+
+/// Returns the json of the last inline source map of the code if any.
+pub fn extract_inline(code: &str) -> Option<String> {
+    let start = code.rfind(INLINE_PREFIX)? + INLINE_PREFIX.len();
+    let encoded = code[start..].lines().next()?.trim();
+    let decoded = base64::decode(encoded).ok()?;
+    String::from_utf8(decoded).ok()
+}
+
+#[test]
+fn test_inline_source_map() {
+    let mut code = "export const a = 1".to_owned();
+    append_inline(&mut code, r#"{"version":3}"#);
+    assert!(code.starts_with("export const a = 1\n//# sourceMappingURL="));
+    assert_eq!(extract_inline(&code).unwrap(), r#"{"version":3}"#);
+    assert_eq!(extract_inline("export const a = 1"), None);
+}